@@ -0,0 +1,56 @@
+mod motion;
+mod stats;
+
+pub use motion::{Motion, MotionDetector, Region};
+pub use stats::{is_probably_dark, is_probably_overexposed, FrameStats, StatsStage};
+
+use crate::{
+    camera::rtsp_session::FrameError,
+    decoders::{FrameFormat, PixelFormat},
+};
+
+// Calls `row` with the index and the luminance of every row of the frame, top to bottom. Packed
+// formats use the BT.601 weights, planar ones read their Y plane as is.
+fn for_each_luma_row(
+    data: &[u8],
+    format: FrameFormat,
+    stride: usize,
+    mut row: impl FnMut(usize, &[u8]),
+) -> Result<(), FrameError> {
+    let bpp = match format.pixel_format {
+        PixelFormat::Yuv420Planar | PixelFormat::Nv12 => 1,
+        pixel_format => pixel_format
+            .bytes_per_pixel()
+            .ok_or(FrameError::UnsupportedFormat(pixel_format))?,
+    };
+    let row_bytes = format.width * bpp;
+    let needed = match format.height {
+        0 => 0,
+        h => stride * (h - 1) + row_bytes,
+    };
+    if stride < row_bytes || data.len() < needed {
+        return Err(FrameError::SizeMismatch {
+            expected: row_bytes * format.height,
+            actual: data.len(),
+        });
+    }
+
+    // Offsets of the red and blue channels
+    let (r, b) = match format.pixel_format {
+        PixelFormat::Bgr8 | PixelFormat::Bgra8 => (2, 0),
+        _ => (0, 2),
+    };
+    let mut luma = vec![0u8; format.width];
+    for y in 0..format.height {
+        let pixels = &data[y * stride..y * stride + row_bytes];
+        if bpp == 1 {
+            row(y, pixels);
+            continue;
+        }
+        for (l, px) in luma.iter_mut().zip(pixels.chunks_exact(bpp)) {
+            *l = ((77 * px[r] as u32 + 150 * px[1] as u32 + 29 * px[b] as u32) >> 8) as u8;
+        }
+        row(y, &luma);
+    }
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use crate::{
+    camera::rtsp_session::{FrameError, FrameResponse},
+    decoders::FrameFormat,
+};
+
+use super::for_each_luma_row;
+
+// Area of a frame, in pixels of the frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Motion {
+    // Fraction of the frame that changed since the previous one, from 0 to 1
+    pub score: f32,
+    // Smallest region holding every change, `None` when the score is 0
+    pub bbox: Option<Region>,
+}
+
+struct Grid {
+    // Size of the frame it was sampled from
+    frame: (usize, usize),
+    // Frame pixels per cell, in each direction
+    factor: usize,
+    width: usize,
+    cells: Vec<u8>,
+}
+
+// Compares every frame with the one before it, on a downsampled and blurred grayscale copy so
+// sensor noise and compression artifacts don't count as motion
+pub struct MotionDetector {
+    grid_width: usize,
+    blur: usize,
+    threshold: u8,
+    min_area: f32,
+    previous: Option<Grid>,
+}
+
+impl Default for MotionDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MotionDetector {
+    pub fn new() -> Self {
+        Self {
+            grid_width: 160,
+            blur: 1,
+            threshold: 25,
+            min_area: 0.001,
+            previous: None,
+        }
+    }
+
+    // Width frames are downsampled to before comparing, wider means finer but slower
+    pub fn with_grid_width(mut self, grid_width: usize) -> Self {
+        self.grid_width = grid_width.max(1);
+        self
+    }
+
+    // Radius of the box blur applied to the downsampled frame, 0 disables it
+    pub fn with_blur(mut self, radius: usize) -> Self {
+        self.blur = radius;
+        self
+    }
+
+    // Luminance difference at which a cell counts as changed
+    pub fn with_threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    // Changes covering less than this fraction of the frame are ignored
+    pub fn with_min_area(mut self, min_area: f32) -> Self {
+        self.min_area = min_area.clamp(0.0, 1.0);
+        self
+    }
+
+    // The next frame is only stored, and scores 0
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    pub fn detect(&mut self, frame: &FrameResponse) -> Result<Motion, FrameError> {
+        self.detect_buffer(frame.frame(), frame.format(), frame.stride())
+    }
+
+    // For frames that didn't come from a session, rows `stride` bytes apart
+    pub fn detect_buffer(
+        &mut self,
+        data: &[u8],
+        format: FrameFormat,
+        stride: usize,
+    ) -> Result<Motion, FrameError> {
+        let grid = self.sample(data, format, stride)?;
+        // A resolution change makes the frames incomparable, start over from this one
+        let motion = match self.previous.take().filter(|p| p.frame == grid.frame) {
+            Some(previous) => self.compare(&previous, &grid),
+            None => Motion::default(),
+        };
+        self.previous = Some(grid);
+        Ok(motion)
+    }
+
+    // Frames that can't be analysed score 0, for filtering streams:
+    // `frames.filter(|f| ready(detector.score(f) > 0.1))`
+    pub fn score(&mut self, frame: &FrameResponse) -> f32 {
+        self.detect(frame).map_or(0.0, |m| m.score)
+    }
+
+    fn sample(&self, data: &[u8], format: FrameFormat, stride: usize) -> Result<Grid, FrameError> {
+        let factor = format.width.div_ceil(self.grid_width).max(1);
+        let (width, height) = (
+            format.width.div_ceil(factor),
+            format.height.div_ceil(factor),
+        );
+        let mut sums = vec![0u32; width * height];
+        let mut counts = vec![0u32; width * height];
+        for_each_luma_row(data, format, stride, |y, row| {
+            let cells = (y / factor) * width;
+            for (x, l) in row.iter().enumerate() {
+                sums[cells + x / factor] += *l as u32;
+                counts[cells + x / factor] += 1;
+            }
+        })?;
+        let cells = sums
+            .iter()
+            .zip(&counts)
+            .map(|(s, c)| (s / (*c).max(1)) as u8)
+            .collect();
+        Ok(Grid {
+            frame: (format.width, format.height),
+            factor,
+            width,
+            cells: box_blur(cells, width, height, self.blur),
+        })
+    }
+
+    fn compare(&self, previous: &Grid, current: &Grid) -> Motion {
+        let mut changed = 0;
+        // min x, min y, max x, max y of the changed cells
+        let mut bounds = (usize::MAX, usize::MAX, 0, 0);
+        for (i, (a, b)) in previous.cells.iter().zip(&current.cells).enumerate() {
+            if a.abs_diff(*b) < self.threshold {
+                continue;
+            }
+            let (x, y) = (i % current.width, i / current.width);
+            changed += 1;
+            bounds = (
+                bounds.0.min(x),
+                bounds.1.min(y),
+                bounds.2.max(x),
+                bounds.3.max(y),
+            );
+        }
+
+        let score = changed as f32 / current.cells.len().max(1) as f32;
+        if changed == 0 || score < self.min_area {
+            return Motion::default();
+        }
+        // Cells back to frame pixels, the last row and column of cells may be partial
+        let factor = current.factor;
+        let (x, y) = (bounds.0 * factor, bounds.1 * factor);
+        let right = ((bounds.2 + 1) * factor).min(current.frame.0);
+        let bottom = ((bounds.3 + 1) * factor).min(current.frame.1);
+        Motion {
+            score,
+            bbox: Some(Region {
+                x,
+                y,
+                width: right - x,
+                height: bottom - y,
+            }),
+        }
+    }
+}
+
+// Separable box blur, the window shrinks at the edges
+fn box_blur(cells: Vec<u8>, width: usize, height: usize, radius: usize) -> Vec<u8> {
+    if radius == 0 || cells.is_empty() {
+        return cells;
+    }
+    let pass = |src: &[u8], len: usize, lines: usize, at: &dyn Fn(usize, usize) -> usize| {
+        let mut out = vec![0u8; src.len()];
+        for line in 0..lines {
+            for i in 0..len {
+                let (from, to) = (i.saturating_sub(radius), (i + radius).min(len - 1));
+                let sum: u32 = (from..=to).map(|j| src[at(line, j)] as u32).sum();
+                out[at(line, i)] = (sum / (to - from + 1) as u32) as u8;
+            }
+        }
+        out
+    };
+    let horizontal = pass(&cells, width, height, &|y, x| y * width + x);
+    pass(&horizontal, height, width, &|x, y| y * width + x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoders::PixelFormat;
+
+    const SIZE: (usize, usize) = (64, 48);
+
+    fn gray(width: usize, height: usize) -> FrameFormat {
+        FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Gray8,
+        }
+    }
+
+    // Dark frame with a bright square of side 8 at `at`, `bpp` bytes per pixel
+    fn square(at: (usize, usize), bpp: usize) -> Vec<u8> {
+        let mut data = vec![20; SIZE.0 * SIZE.1 * bpp];
+        for y in at.1..at.1 + 8 {
+            for x in at.0..at.0 + 8 {
+                let px = (y * SIZE.0 + x) * bpp;
+                data[px..px + bpp].fill(200);
+            }
+        }
+        data
+    }
+
+    fn exact() -> MotionDetector {
+        MotionDetector::new()
+            .with_grid_width(SIZE.0)
+            .with_blur(0)
+            .with_min_area(0.0)
+    }
+
+    #[test]
+    fn static_frames_score_zero() {
+        let mut detector = MotionDetector::new();
+        let frame = square((8, 8), 1);
+        for _ in 0..3 {
+            let motion = detector
+                .detect_buffer(&frame, gray(SIZE.0, SIZE.1), SIZE.0)
+                .unwrap();
+            assert_eq!(motion, Motion::default());
+        }
+    }
+
+    #[test]
+    fn moved_square_is_boxed() {
+        let expected = Region {
+            x: 8,
+            y: 8,
+            width: 16,
+            height: 8,
+        };
+        // Cells of 4x4 pixels line up with the square, the box comes out the same
+        for grid_width in [SIZE.0, SIZE.0 / 4] {
+            let mut detector = exact().with_grid_width(grid_width);
+            let format = gray(SIZE.0, SIZE.1);
+            detector
+                .detect_buffer(&square((8, 8), 1), format, SIZE.0)
+                .unwrap();
+            let motion = detector
+                .detect_buffer(&square((16, 8), 1), format, SIZE.0)
+                .unwrap();
+            assert_eq!(motion.bbox, Some(expected), "{}", grid_width);
+            // Where the square left and where it went
+            let changed = 2.0 * 64.0 / (SIZE.0 * SIZE.1) as f32;
+            assert!((motion.score - changed).abs() < 1e-6, "{}", motion.score);
+        }
+    }
+
+    #[test]
+    fn rgb_frames_are_compared_on_luminance() {
+        let mut detector = exact();
+        let format = FrameFormat {
+            pixel_format: PixelFormat::Rgb8,
+            ..gray(SIZE.0, SIZE.1)
+        };
+        let stride = SIZE.0 * 3;
+        detector
+            .detect_buffer(&square((8, 8), 3), format, stride)
+            .unwrap();
+        let motion = detector
+            .detect_buffer(&square((8, 16), 3), format, stride)
+            .unwrap();
+        assert_eq!(
+            motion.bbox,
+            Some(Region {
+                x: 8,
+                y: 8,
+                width: 8,
+                height: 16,
+            })
+        );
+    }
+
+    #[test]
+    fn small_changes_are_ignored() {
+        let mut detector = exact().with_min_area(0.01);
+        let format = gray(SIZE.0, SIZE.1);
+        let mut frame = [20; SIZE.0 * SIZE.1];
+        detector.detect_buffer(&frame, format, SIZE.0).unwrap();
+        frame[100] = 250;
+        let motion = detector.detect_buffer(&frame, format, SIZE.0).unwrap();
+        assert_eq!(motion, Motion::default());
+    }
+
+    #[test]
+    fn resolution_change_starts_over() {
+        let mut detector = exact();
+        detector
+            .detect_buffer(&square((8, 8), 1), gray(SIZE.0, SIZE.1), SIZE.0)
+            .unwrap();
+        let motion = detector
+            .detect_buffer(&[200; 32 * 24], gray(32, 24), 32)
+            .unwrap();
+        assert_eq!(motion, Motion::default());
+        let motion = detector
+            .detect_buffer(&[20; 32 * 24], gray(32, 24), 32)
+            .unwrap();
+        assert_eq!(motion.score, 1.0);
+    }
+
+    #[test]
+    fn unusable_frames_score_zero() {
+        let mut detector = exact();
+        let jpeg = FrameFormat {
+            pixel_format: PixelFormat::Jpeg,
+            ..gray(SIZE.0, SIZE.1)
+        };
+        assert!(matches!(
+            detector.detect_buffer(&[0; 16], jpeg, SIZE.0),
+            Err(FrameError::UnsupportedFormat(PixelFormat::Jpeg))
+        ));
+
+        let short = FrameResponse::synthetic(vec![0; 16], gray(SIZE.0, SIZE.1), 1, 0);
+        assert_eq!(detector.score(&short), 0.0);
+        let moved = |at| FrameResponse::synthetic(square(at, 1), gray(SIZE.0, SIZE.1), 1, 0);
+        assert_eq!(detector.score(&moved((0, 0))), 0.0);
+        assert!(detector.score(&moved((32, 32))) > 0.0);
+    }
+}
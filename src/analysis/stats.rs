@@ -0,0 +1,128 @@
+use std::time::Instant;
+
+use tokio::sync::watch;
+use tracing::trace;
+
+use crate::{
+    camera::rtsp_session::{FrameError, FrameResponse},
+    decoders::{DecodedFrame, DecoderError, FrameFormat, ImageDecoder},
+};
+
+use super::for_each_luma_row;
+
+// Luminance distribution of a frame
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameStats {
+    pub mean: f32,
+    pub stddev: f32,
+    // 256 bins, pixel counts per luminance value
+    pub histogram: Vec<u32>,
+    // Fractions of the pixels at exactly 0 and 255
+    pub black: f32,
+    pub white: f32,
+}
+
+impl FrameStats {
+    pub fn of(frame: &FrameResponse) -> Result<Self, FrameError> {
+        Self::compute(frame.frame(), frame.format(), frame.stride())
+    }
+
+    // Rows `stride` bytes apart. Only the histogram is built per pixel, the rest comes from it.
+    pub fn compute(data: &[u8], format: FrameFormat, stride: usize) -> Result<Self, FrameError> {
+        let mut histogram = vec![0u32; 256];
+        for_each_luma_row(data, format, stride, |_, row| {
+            for l in row {
+                histogram[*l as usize] += 1;
+            }
+        })?;
+
+        let (mut pixels, mut sum, mut squares) = (0u64, 0u64, 0u64);
+        for (l, count) in histogram.iter().enumerate() {
+            let (l, count) = (l as u64, *count as u64);
+            pixels += count;
+            sum += l * count;
+            squares += l * l * count;
+        }
+        if pixels == 0 {
+            return Ok(Self {
+                mean: 0.0,
+                stddev: 0.0,
+                histogram,
+                black: 0.0,
+                white: 0.0,
+            });
+        }
+        let n = pixels as f64;
+        let mean = sum as f64 / n;
+        let variance = (squares as f64 / n - mean * mean).max(0.0);
+        Ok(Self {
+            mean: mean as f32,
+            stddev: variance.sqrt() as f32,
+            black: (histogram[0] as f64 / n) as f32,
+            white: (histogram[255] as f64 / n) as f32,
+            histogram,
+        })
+    }
+}
+
+// Rough checks for a stuck IR cut filter or a lens covered at night
+pub fn is_probably_dark(stats: &FrameStats) -> bool {
+    stats.mean < 40.0 || stats.black > 0.5
+}
+
+// Rough checks for a blown out picture, e.g. facing the sun or IR reflecting off something close
+pub fn is_probably_overexposed(stats: &FrameStats) -> bool {
+    stats.mean > 215.0 || stats.white > 0.25
+}
+
+// Passes frames through unchanged, publishing the stats of each one. Frames of any other format
+// than the one it was built for are refused.
+pub struct StatsStage {
+    format: FrameFormat,
+    tx: watch::Sender<Option<FrameStats>>,
+    buf: Vec<u8>,
+}
+
+impl StatsStage {
+    pub fn new(format: FrameFormat) -> Self {
+        Self {
+            format,
+            tx: watch::channel(None).0,
+            buf: Vec::new(),
+        }
+    }
+
+    // Stats of the last frame that went through, `None` until one did
+    pub fn subscribe(&self) -> watch::Receiver<Option<FrameStats>> {
+        self.tx.subscribe()
+    }
+}
+
+impl ImageDecoder for StatsStage {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let b = Instant::now();
+        let stats =
+            FrameStats::compute(data, self.format, self.format.stride()).map_err(|e| match e {
+                FrameError::UnsupportedFormat(format) => DecoderError::UnsupportedInput(format),
+                FrameError::SizeMismatch { expected, actual } => {
+                    DecoderError::InputSizeMismatch { expected, actual }
+                }
+                FrameError::NonContiguous { stride, row_bytes } => {
+                    DecoderError::InputSizeMismatch {
+                        expected: row_bytes * self.format.height,
+                        actual: stride * self.format.height,
+                    }
+                }
+            })?;
+        self.tx.send_replace(Some(stats));
+        self.buf.clear();
+        self.buf.extend_from_slice(data);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "frame stats");
+        Ok(DecodedFrame::new(&self.buf, self.format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(self.format)
+    }
+}
@@ -0,0 +1,332 @@
+use std::{fmt, sync::Arc};
+
+use url::Url;
+
+use super::{
+    onvif::{media2::MediaOps, services::ProfileSummary, AuthMode, OnvifError, OnvifHelper},
+    rtsp_session::{
+        SessionConfig, SessionError, SessionInstanceManager, SessionWrapper, TransportPreference,
+    },
+};
+use crate::decoders::{
+    AVCCDecoder, AsyncImageDecoder, Chain, DecoderError, DecoderPool, H264BGRDecoder,
+    H264GrayDecoder, H264RGBDecoder, H264YUVDecoder, ImageDecoder,
+};
+
+// Builds a fresh decoder chain, called on every (re)start of a camera
+pub type DecoderFactory =
+    Arc<dyn Fn() -> Result<Box<dyn AsyncImageDecoder + Sync + Send>, DecoderError> + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ProfileChoice {
+    First,
+    // Position in the device's profile list
+    Index(usize),
+    Token(String),
+    // The profile name, or the token when no name matches
+    Name(String),
+}
+
+impl ProfileChoice {
+    pub fn select<'a>(&self, profiles: &'a [ProfileSummary]) -> Option<&'a ProfileSummary> {
+        match self {
+            Self::First => profiles.first(),
+            Self::Index(i) => profiles.get(*i),
+            Self::Token(token) => profiles.iter().find(|p| p.token == *token),
+            Self::Name(name) => profiles
+                .iter()
+                .find(|p| p.name == *name)
+                .or_else(|| profiles.iter().find(|p| p.token == *name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DecoderChoice {
+    Rgb,
+    Bgr,
+    Gray,
+    Yuv,
+}
+
+impl DecoderChoice {
+    // H.264 behind `AVCCDecoder`, for `SessionWrapper::new_sync`
+    pub fn build(self) -> Result<Box<dyn ImageDecoder + Sync + Send>, DecoderError> {
+        Ok(match self {
+            Self::Rgb => Box::new(AVCCDecoder::new().chain(H264RGBDecoder::new(false)?)),
+            Self::Bgr => Box::new(AVCCDecoder::new().chain(H264BGRDecoder::new(false)?)),
+            Self::Gray => Box::new(AVCCDecoder::new().chain(H264GrayDecoder::new(false)?)),
+            Self::Yuv => Box::new(AVCCDecoder::new().chain(H264YUVDecoder::new(false)?)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CameraCredentials {
+    pub user: String,
+    pub password: String,
+}
+
+// Everything `ConnectedCamera::connect` needs, e.g. read from a config file with the `serde`
+// feature. `CameraPool` starts its cameras from it too.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CameraConfig {
+    pub host: String,
+    // Port 80 when unset
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub onvif_port: Option<u16>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub credentials: Option<CameraCredentials>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub auth_mode: AuthMode,
+    pub profile_selector: ProfileChoice,
+    // Overrides `session.transport` when set
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub transport: Option<TransportPreference>,
+    pub decoder: DecoderChoice,
+    // Takes the place of `decoder` when set, e.g. to decode on a `DecoderPool`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub decoder_factory: Option<DecoderFactory>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub session: SessionConfig,
+}
+
+impl CameraConfig {
+    // First profile, decoded to RGB
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            onvif_port: None,
+            credentials: None,
+            auth_mode: AuthMode::Auto,
+            profile_selector: ProfileChoice::First,
+            transport: None,
+            decoder: DecoderChoice::Rgb,
+            decoder_factory: None,
+            session: SessionConfig::default(),
+        }
+    }
+
+    pub fn with_onvif_port(mut self, port: u16) -> Self {
+        self.onvif_port = Some(port);
+        self
+    }
+
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some(CameraCredentials {
+            user: user.to_string(),
+            password: password.to_string(),
+        });
+        self
+    }
+
+    pub fn with_auth_mode(mut self, mode: AuthMode) -> Self {
+        self.auth_mode = mode;
+        self
+    }
+
+    pub fn with_profile(mut self, profile: ProfileChoice) -> Self {
+        self.profile_selector = profile;
+        self
+    }
+
+    pub fn with_transport(mut self, transport: TransportPreference) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub fn with_decoder(mut self, decoder: DecoderChoice) -> Self {
+        self.decoder = decoder;
+        self
+    }
+
+    pub fn with_decoder_factory(mut self, factory: DecoderFactory) -> Self {
+        self.decoder_factory = Some(factory);
+        self
+    }
+
+    // Decodes on the threads of `pool` rather than on a decoder of its own, to bound the decode
+    // parallelism of all the cameras sharing it
+    pub fn with_decoder_pool(self, pool: &DecoderPool) -> Self {
+        let pool = pool.clone();
+        self.with_decoder_factory(Arc::new(move || {
+            Ok(Box::new(pool.decoder()) as Box<dyn AsyncImageDecoder + Sync + Send>)
+        }))
+    }
+
+    pub fn with_session_config(mut self, session: SessionConfig) -> Self {
+        self.session = session;
+        self
+    }
+
+    pub fn onvif(&self) -> Result<OnvifHelper, OnvifError> {
+        let mut onvif = OnvifHelper::new(&self.host)?.with_auth_mode(self.auth_mode);
+        if let Some(port) = self.onvif_port {
+            onvif = onvif.with_port(port);
+        }
+        if let Some(creds) = &self.credentials {
+            onvif = onvif.with_credentials(&creds.user, &creds.password);
+        }
+        Ok(onvif)
+    }
+
+    // The ONVIF half of `ConnectedCamera::connect`: the selected profile, its stream url and the
+    // media service with that profile selected
+    pub async fn resolve_stream(
+        &self,
+    ) -> Result<(ProfileSummary, Url, Box<dyn MediaOps>), CameraError> {
+        let mut media = self
+            .onvif()
+            .map_err(CameraError::InvalidHost)?
+            .get_media()
+            .await
+            .map_err(CameraError::MediaService)?;
+        let profiles = media
+            .get_profile_summaries()
+            .await
+            .map_err(CameraError::Profiles)?;
+        let profile = self
+            .profile_selector
+            .select(&profiles)
+            .cloned()
+            .ok_or_else(|| CameraError::ProfileNotFound {
+                selector: self.profile_selector.clone(),
+                available: profiles.clone(),
+            })?;
+        media.select_profile(&profile.token);
+        let stream_url = media
+            .get_stream_uri()
+            .await
+            .map_err(CameraError::StreamUri)?;
+        Ok((profile, stream_url, media))
+    }
+}
+
+impl fmt::Debug for CameraConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CameraConfig")
+            .field("host", &self.host)
+            .field("onvif_port", &self.onvif_port)
+            .field("credentials", &self.credentials)
+            .field("auth_mode", &self.auth_mode)
+            .field("profile_selector", &self.profile_selector)
+            .field("transport", &self.transport)
+            .field("decoder", &self.decoder)
+            .field("decoder_factory", &self.decoder_factory.is_some())
+            .field("session", &self.session)
+            .finish()
+    }
+}
+
+// The step of `ConnectedCamera::connect` that failed
+#[derive(Debug)]
+pub enum CameraError {
+    InvalidHost(OnvifError),
+    MediaService(OnvifError),
+    Profiles(OnvifError),
+    ProfileNotFound {
+        selector: ProfileChoice,
+        available: Vec<ProfileSummary>,
+    },
+    StreamUri(OnvifError),
+    Decoder(DecoderError),
+    Session(SessionError),
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHost(e) => write!(f, "invalid camera host: {}", e),
+            Self::MediaService(e) => write!(f, "failed to reach the media service: {}", e),
+            Self::Profiles(e) => write!(f, "failed to list the media profiles: {}", e),
+            Self::ProfileNotFound {
+                selector,
+                available,
+            } => {
+                write!(f, "no profile matches {:?}, available:", selector)?;
+                for profile in available {
+                    write!(f, " [{}]", profile)?;
+                }
+                Ok(())
+            }
+            Self::StreamUri(e) => write!(f, "failed to get the stream uri: {}", e),
+            Self::Decoder(e) => write!(f, "failed to create the decoder: {}", e),
+            Self::Session(e) => write!(f, "failed to start the session: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CameraError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidHost(e)
+            | Self::MediaService(e)
+            | Self::Profiles(e)
+            | Self::StreamUri(e) => Some(e),
+            Self::Decoder(e) => Some(e),
+            Self::Session(e) => Some(e),
+            Self::ProfileNotFound { .. } => None,
+        }
+    }
+}
+
+// A camera set up from a `CameraConfig`. Each step is a plain call on `OnvifHelper`, the media
+// service and `SessionWrapper`, see `CameraConfig::resolve_stream`, do them by hand for anything
+// this doesn't cover.
+pub struct ConnectedCamera {
+    pub manager: SessionInstanceManager,
+    pub profile: ProfileSummary,
+    pub stream_url: Url,
+    // With the profile selected, the session's resync and url refresh hooks go through it
+    pub media: Arc<dyn MediaOps>,
+}
+
+impl ConnectedCamera {
+    pub async fn connect(config: &CameraConfig) -> Result<Self, CameraError> {
+        let (profile, stream_url, media) = config.resolve_stream().await?;
+        let media: Arc<dyn MediaOps> = Arc::from(media);
+
+        let mut session_config = config.session.clone();
+        if let Some(transport) = config.transport {
+            session_config.transport = transport;
+        }
+        let session = match &config.decoder_factory {
+            Some(factory) => {
+                SessionWrapper::new(stream_url.clone(), factory().map_err(CameraError::Decoder)?)
+            }
+            None => SessionWrapper::new_sync(
+                stream_url.clone(),
+                config.decoder.build().map_err(CameraError::Decoder)?,
+            ),
+        };
+        let refresh_media = Arc::clone(&media);
+        let resync_media = Arc::clone(&media);
+        let mut session = session
+            .with_config(session_config)
+            .with_url_refresh_hook(Arc::new(move || {
+                let media = Arc::clone(&refresh_media);
+                Box::pin(async move { media.get_stream_uri().await.map_err(|e| e.into()) })
+            }))
+            .with_resync_hook(Box::new(move || {
+                let media = Arc::clone(&resync_media);
+                Box::pin(async move { media.sync_iframe_or_wait().await.map_err(|e| e.into()) })
+            }));
+        if let Some(creds) = &config.credentials {
+            session = session.with_credentials(&creds.user, &creds.password);
+        }
+        let manager = session.start().await.map_err(CameraError::Session)?;
+
+        Ok(Self {
+            manager,
+            profile,
+            stream_url,
+            media,
+        })
+    }
+}
@@ -1,3 +1,4 @@
+pub mod connect;
 pub mod onvif;
 pub mod rtsp_session;
 
@@ -0,0 +1,269 @@
+use onvif::{schema::transport::Transport, soap::client::Client};
+use url::Url;
+
+use super::{
+    services::{fault_error, ClientWrapper, Connection},
+    xml::{elements, escape, Element},
+    OnvifError, RetryPolicy,
+};
+
+const ANALYTICS_NS: &str = "http://www.onvif.org/ver20/analytics/wsdl";
+const SCHEMA_NS: &str = "http://www.onvif.org/ver10/schema";
+
+// A rule or analytics module, both are sent as a `tt:Config`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnalyticsConfig {
+    pub name: String,
+    // As the camera sent it, e.g. `tt:CellMotionDetector` or `tt:CellMotionEngine`
+    pub kind: String,
+    pub simple_items: Vec<(String, String)>,
+    // Name and XML body, e.g. the `tt:Polyline` of a line detector's `Segments`. Sent back as is.
+    pub element_items: Vec<(String, String)>,
+}
+
+impl AnalyticsConfig {
+    pub fn new(name: &str, kind: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // `active_cells` is the packbits encoded, base64 cell mask in the layout of the camera's
+    // `tt:CellMotionEngine` module, see `get_analytics_modules`
+    pub fn cell_motion(name: &str, active_cells: &str) -> Self {
+        Self::new(name, "tt:CellMotionDetector")
+            .with_simple_item("MinCount", "5")
+            .with_simple_item("AlarmOnDelay", "1000")
+            .with_simple_item("AlarmOffDelay", "1000")
+            .with_simple_item("ActiveCells", active_cells)
+    }
+
+    // Points are normalized, from -1 to 1 with the origin in the middle of the picture
+    pub fn line_crossing(name: &str, points: &[(f32, f32)]) -> Self {
+        let polyline: String = points
+            .iter()
+            .map(|(x, y)| format!(r#"<tt:Point x="{}" y="{}"/>"#, x, y))
+            .collect();
+        Self::new(name, "tt:LineDetector")
+            .with_simple_item("Direction", "Any")
+            .with_element_item(
+                "Segments",
+                &format!("<tt:Polyline>{}</tt:Polyline>", polyline),
+            )
+    }
+
+    // Replaces an item of the same name
+    pub fn with_simple_item(mut self, name: &str, value: &str) -> Self {
+        self.simple_items.retain(|(n, _)| n != name);
+        self.simple_items
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_element_item(mut self, name: &str, xml: &str) -> Self {
+        self.element_items.retain(|(n, _)| n != name);
+        self.element_items.push((name.to_string(), xml.to_string()));
+        self
+    }
+
+    pub fn simple_item(&self, name: &str) -> Option<&str> {
+        self.simple_items
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn parse(config: &Element<'_>) -> Self {
+        let parameters = elements(config.body, "Parameters").into_iter().next();
+        let items = |local: &str| {
+            parameters
+                .map(|p| elements(p.body, local))
+                .unwrap_or_default()
+        };
+        Self {
+            name: config.attribute("Name").unwrap_or_default(),
+            kind: config.attribute("Type").unwrap_or_default(),
+            simple_items: items("SimpleItem")
+                .iter()
+                .filter_map(|i| Some((i.attribute("Name")?, i.attribute("Value")?)))
+                .collect(),
+            element_items: items("ElementItem")
+                .iter()
+                .filter_map(|i| Some((i.attribute("Name")?, i.body.trim().to_string())))
+                .collect(),
+        }
+    }
+
+    fn to_xml(&self, element: &str) -> String {
+        let mut xml = format!(
+            r#"<{} xmlns:tt="{}" Name="{}" Type="{}"><tt:Parameters>"#,
+            element,
+            SCHEMA_NS,
+            escape(&self.name),
+            escape(&self.kind)
+        );
+        for (name, value) in &self.simple_items {
+            xml.push_str(&format!(
+                r#"<tt:SimpleItem Name="{}" Value="{}"/>"#,
+                escape(name),
+                escape(value)
+            ));
+        }
+        for (name, body) in &self.element_items {
+            xml.push_str(&format!(
+                r#"<tt:ElementItem Name="{}">{}</tt:ElementItem>"#,
+                escape(name),
+                body
+            ));
+        }
+        xml.push_str(&format!("</tt:Parameters></{}>", element));
+        xml
+    }
+}
+
+// Client of the ver20 analytics service. Every call is scoped to a video analytics
+// configuration, see `MediaClient::get_analytics_configuration_token`.
+#[derive(Clone)]
+pub struct AnalyticsClient {
+    inner: Client,
+    endpoint: Url,
+    retry: RetryPolicy,
+}
+
+impl AnalyticsClient {
+    // Type names of the modules the configuration can hold, e.g. `tt:CellMotionEngine`
+    pub async fn get_supported_analytics_modules(
+        &self,
+        config_token: &str,
+    ) -> Result<Vec<String>, OnvifError> {
+        let resp = self
+            .read("GetSupportedAnalyticsModules", config_token)
+            .await?;
+        Ok(elements(&resp, "AnalyticsModuleDescription")
+            .iter()
+            .filter_map(|d| d.attribute("Name"))
+            .collect())
+    }
+
+    pub async fn get_analytics_modules(
+        &self,
+        config_token: &str,
+    ) -> Result<Vec<AnalyticsConfig>, OnvifError> {
+        let resp = self.read("GetAnalyticsModules", config_token).await?;
+        Ok(elements(&resp, "AnalyticsModule")
+            .iter()
+            .map(AnalyticsConfig::parse)
+            .collect())
+    }
+
+    pub async fn get_rules(&self, config_token: &str) -> Result<Vec<AnalyticsConfig>, OnvifError> {
+        let resp = self.read("GetRules", config_token).await?;
+        Ok(elements(&resp, "Rule")
+            .iter()
+            .map(AnalyticsConfig::parse)
+            .collect())
+    }
+
+    // The camera refuses rules whose name is taken, delete the old one first to replace it
+    pub async fn create_rule(
+        &self,
+        config_token: &str,
+        rule: &AnalyticsConfig,
+    ) -> Result<(), OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tan:CreateRules xmlns:tan="{}">"#,
+                "<tan:ConfigurationToken>{}</tan:ConfigurationToken>",
+                "{}",
+                "</tan:CreateRules>",
+            ),
+            ANALYTICS_NS,
+            escape(config_token),
+            rule.to_xml("tan:Rule")
+        );
+        self.write("CreateRules", &req).await
+    }
+
+    pub async fn delete_rule(&self, config_token: &str, name: &str) -> Result<(), OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tan:DeleteRules xmlns:tan="{}">"#,
+                "<tan:ConfigurationToken>{}</tan:ConfigurationToken>",
+                "<tan:RuleName>{}</tan:RuleName>",
+                "</tan:DeleteRules>",
+            ),
+            ANALYTICS_NS,
+            escape(config_token),
+            escape(name)
+        );
+        self.write("DeleteRules", &req).await
+    }
+
+    // Replaces the rule of the same name, if any
+    pub async fn replace_rule(
+        &self,
+        config_token: &str,
+        rule: &AnalyticsConfig,
+    ) -> Result<(), OnvifError> {
+        if self
+            .get_rules(config_token)
+            .await?
+            .iter()
+            .any(|r| r.name == rule.name)
+        {
+            self.delete_rule(config_token, &rule.name).await?;
+        }
+        self.create_rule(config_token, rule).await
+    }
+
+    // The reads all take the configuration token only
+    async fn read(
+        &self,
+        operation: &'static str,
+        config_token: &str,
+    ) -> Result<String, OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tan:{op} xmlns:tan="{}">"#,
+                "<tan:ConfigurationToken>{}</tan:ConfigurationToken>",
+                "</tan:{op}>",
+            ),
+            ANALYTICS_NS,
+            escape(config_token),
+            op = operation
+        );
+        let req = &req;
+        self.retry
+            .run(|| async move {
+                self.inner
+                    .request(req)
+                    .await
+                    .map_err(fault_error(operation, &self.endpoint))
+            })
+            .await
+    }
+
+    // Never retried, see `RetryPolicy`
+    async fn write(&self, operation: &'static str, req: &str) -> Result<(), OnvifError> {
+        self.inner
+            .request(req)
+            .await
+            .map_err(fault_error(operation, &self.endpoint))
+            .map(|_| ())
+    }
+}
+
+impl ClientWrapper for AnalyticsClient {
+    fn connect(endpoint: &Url, conn: &Connection) -> Self {
+        Self {
+            inner: conn.soap_client(endpoint),
+            endpoint: endpoint.clone(),
+            retry: conn.retry.clone(),
+        }
+    }
+    fn get_service_name() -> String {
+        "analytics".to_string()
+    }
+}
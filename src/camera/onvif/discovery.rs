@@ -0,0 +1,230 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::join_all;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time};
+use tracing::{debug, warn};
+use url::Url;
+
+use super::{
+    xml::{elements, unescape},
+    OnvifError, OnvifHelper,
+};
+
+const MULTICAST_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 3702);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    // Device service urls, the device may answer on several addresses
+    pub xaddrs: Vec<Url>,
+    // e.g. `onvif://www.onvif.org/name/...`, `onvif://www.onvif.org/hardware/...`
+    pub scopes: Vec<String>,
+    // Stable id of the device, usually a `urn:uuid:`
+    pub endpoint_reference: String,
+}
+
+impl DiscoveredDevice {
+    // Connects through the first advertised url
+    pub fn to_helper(&self) -> Result<OnvifHelper, OnvifError> {
+        let url = self.xaddrs.first().ok_or(OnvifError::InvalidDeviceUrl {
+            url: self.endpoint_reference.clone(),
+            reason: "device advertised no service url",
+        })?;
+        OnvifHelper::from_url(url.clone())
+    }
+}
+
+impl TryFrom<&DiscoveredDevice> for OnvifHelper {
+    type Error = OnvifError;
+
+    fn try_from(device: &DiscoveredDevice) -> Result<Self, Self::Error> {
+        device.to_helper()
+    }
+}
+
+// WS-Discovery probe for ONVIF video transmitters
+pub struct Discovery {
+    duration: Duration,
+    // Addresses of the interfaces to probe from, the default route only when empty
+    interfaces: Vec<Ipv4Addr>,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Discovery {
+    pub fn new() -> Self {
+        Self {
+            duration: Duration::from_secs(3),
+            interfaces: Vec::new(),
+        }
+    }
+
+    // How long answers are collected for after the probe went out
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    // Probes from the interface with this address, can be called once per interface
+    pub fn with_interface(mut self, addr: Ipv4Addr) -> Self {
+        self.interfaces.push(addr);
+        self
+    }
+
+    // Devices answering on several interfaces, or several times, are only listed once. Answers
+    // that can't be parsed are skipped. Only fails when no interface could send the probe.
+    pub async fn probe(&self) -> Result<Vec<DiscoveredDevice>, OnvifError> {
+        let interfaces = if self.interfaces.is_empty() {
+            vec![Ipv4Addr::UNSPECIFIED]
+        } else {
+            self.interfaces.clone()
+        };
+        let scans = join_all(interfaces.iter().map(|i| self.probe_interface(*i))).await;
+
+        let (mut failed, mut last_err) = (0, None);
+        let mut seen = HashSet::new();
+        let mut devices = Vec::new();
+        for (interface, scan) in interfaces.iter().zip(scans) {
+            let answers = match scan {
+                Ok(answers) => answers,
+                Err(e) => {
+                    warn!(%interface, "WS-Discovery probe failed: {}", e);
+                    failed += 1;
+                    last_err = Some(e);
+                    continue;
+                }
+            };
+            for (from, answer) in answers {
+                let matches = parse_probe_matches(&answer);
+                if matches.is_empty() {
+                    debug!(%from, "Ignoring a WS-Discovery answer without usable matches");
+                }
+                for device in matches {
+                    if seen.insert(device.endpoint_reference.clone()) {
+                        devices.push(device);
+                    }
+                }
+            }
+        }
+        match last_err {
+            Some(e) if failed == interfaces.len() => Err(OnvifError::DiscoveryFailed(e)),
+            _ => Ok(devices),
+        }
+    }
+
+    async fn probe_interface(&self, interface: Ipv4Addr) -> io::Result<Vec<(SocketAddr, String)>> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        if !interface.is_unspecified() {
+            socket.set_multicast_if_v4(&interface)?;
+        }
+        socket.set_multicast_ttl_v4(1)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(interface, 0).into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        socket
+            .send_to(probe_message().as_bytes(), MULTICAST_ADDR)
+            .await?;
+
+        let deadline = time::Instant::now() + self.duration;
+        let mut answers = Vec::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            match received {
+                Ok((len, from)) => {
+                    answers.push((from, String::from_utf8_lossy(&buf[..len]).into_owned()))
+                }
+                // Keeps what was collected so far
+                Err(e) => {
+                    debug!(%interface, "Stopped collecting WS-Discovery answers: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(answers)
+    }
+}
+
+fn probe_message() -> String {
+    // Only has to be unique per probe, so devices don't take a retry for a duplicate
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let id = format!(
+        "{:08x}-{:04x}-4{:03x}-8{:03x}-{:012x}",
+        (nanos >> 32) as u32,
+        (nanos >> 16) as u16,
+        nanos & 0xfff,
+        process::id() & 0xfff,
+        nanos & 0xffff_ffff_ffff,
+    );
+    format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<e:Envelope xmlns:e="http://www.w3.org/2003/05/soap-envelope""#,
+            r#" xmlns:w="http://schemas.xmlsoap.org/ws/2004/08/addressing""#,
+            r#" xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery""#,
+            r#" xmlns:dn="http://www.onvif.org/ver10/network/wsdl">"#,
+            "<e:Header>",
+            "<w:MessageID>uuid:{}</w:MessageID>",
+            r#"<w:To e:mustUnderstand="true">urn:schemas-xmlsoap-org:ws:2005:04:discovery</w:To>"#,
+            r#"<w:Action e:mustUnderstand="true">"#,
+            "http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</w:Action>",
+            "</e:Header>",
+            "<e:Body><d:Probe><d:Types>dn:NetworkVideoTransmitter</d:Types></d:Probe></e:Body>",
+            "</e:Envelope>",
+        ),
+        id
+    )
+}
+
+fn parse_probe_matches(xml: &str) -> Vec<DiscoveredDevice> {
+    elements(xml, "ProbeMatch")
+        .into_iter()
+        .filter_map(|m| {
+            let m = m.body;
+            let xaddrs: Vec<Url> = elements(m, "XAddrs")
+                .first()
+                .map(|x| {
+                    unescape(x.body)
+                        .split_whitespace()
+                        .filter_map(|u| Url::parse(u).ok())
+                        .collect()
+                })
+                .unwrap_or_default();
+            if xaddrs.is_empty() {
+                return None;
+            }
+            let scopes = elements(m, "Scopes")
+                .first()
+                .map(|s| {
+                    unescape(s.body)
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default();
+            // Devices without a reference are told apart by their urls
+            let endpoint_reference = elements(m, "Address")
+                .first()
+                .map(|a| unescape(a.body.trim()))
+                .filter(|a| !a.is_empty())
+                .unwrap_or_else(|| xaddrs[0].to_string());
+            Some(DiscoveredDevice {
+                xaddrs,
+                scopes,
+                endpoint_reference,
+            })
+        })
+        .collect()
+}
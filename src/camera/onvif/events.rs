@@ -0,0 +1,226 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use futures::{stream, Stream};
+use onvif::{schema::transport::Transport, soap::client::Client};
+use tracing::{debug, warn};
+use url::Url;
+
+use super::{
+    services::{ClientWrapper, Connection},
+    transport_error,
+    xml::{elements, unescape, Element},
+    OnvifError,
+};
+
+const EVENTS_NS: &str = "http://www.onvif.org/ver10/events/wsdl";
+const NOTIFICATION_NS: &str = "http://docs.oasis-open.org/wsn/b-2";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CameraEvent {
+    // As the camera sent it, e.g. `tns1:RuleEngine/CellMotionDetector/Motion`. Prefixes and
+    // vendor namespaces are left alone since they vary between cameras.
+    pub topic: String,
+    // Name and value of the simple items, e.g. `VideoSourceConfigurationToken` in `source` and
+    // `IsMotion` in `data`
+    pub source: Vec<(String, String)>,
+    pub data: Vec<(String, String)>,
+    // ISO 8601, as the camera sent it
+    pub utc_time: Option<String>,
+    // `Initialized`, `Changed` or `Deleted` for property events
+    pub operation: Option<String>,
+}
+
+impl CameraEvent {
+    pub fn data_value(&self, name: &str) -> Option<&str> {
+        self.data
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Clone)]
+pub struct EventsClient {
+    inner: Client,
+    endpoint: Url,
+    // Subscriptions live at an address of their own, which needs another client
+    conn: Connection,
+}
+
+impl EventsClient {
+    // The subscription expires unless renewed within `termination`
+    pub async fn create_pull_point(&self, termination: Duration) -> Result<PullPoint, OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tev:CreatePullPointSubscription xmlns:tev="{}">"#,
+                "<tev:InitialTerminationTime>{}</tev:InitialTerminationTime>",
+                "</tev:CreatePullPointSubscription>",
+            ),
+            EVENTS_NS,
+            xs_duration(termination)
+        );
+        let resp = self.inner.request(&req).await.map_err(transport_error(
+            "CreatePullPointSubscription",
+            &self.endpoint,
+        ))?;
+        let address = elements(&resp, "SubscriptionReference")
+            .first()
+            .and_then(|r| {
+                elements(r.body, "Address")
+                    .first()
+                    .map(|a| unescape(a.body.trim()))
+            })
+            .ok_or(OnvifError::IncompleteResponse("a subscription address"))?;
+        let address = Url::parse(&address).map_err(|_| OnvifError::UrlParseError(address))?;
+        debug!(%address, "Created pull point subscription");
+        Ok(PullPoint {
+            client: self.conn.soap_client(&address),
+            address,
+            termination,
+        })
+    }
+}
+
+impl ClientWrapper for EventsClient {
+    fn connect(endpoint: &Url, conn: &Connection) -> Self {
+        Self {
+            inner: conn.soap_client(endpoint),
+            endpoint: endpoint.clone(),
+            conn: conn.clone(),
+        }
+    }
+    fn get_service_name() -> String {
+        "events".to_string()
+    }
+}
+
+pub struct PullPoint {
+    client: Client,
+    address: Url,
+    termination: Duration,
+}
+
+impl PullPoint {
+    // Waits up to `timeout` on the camera side for at least one message
+    pub async fn pull_messages(
+        &self,
+        timeout: Duration,
+        limit: u32,
+    ) -> Result<Vec<CameraEvent>, OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tev:PullMessages xmlns:tev="{}">"#,
+                "<tev:Timeout>{}</tev:Timeout>",
+                "<tev:MessageLimit>{}</tev:MessageLimit>",
+                "</tev:PullMessages>",
+            ),
+            EVENTS_NS,
+            xs_duration(timeout),
+            limit.max(1)
+        );
+        let resp = self
+            .client
+            .request(&req)
+            .await
+            .map_err(transport_error("PullMessages", &self.address))?;
+        Ok(elements(&resp, "NotificationMessage")
+            .iter()
+            .map(parse_notification)
+            .collect())
+    }
+
+    // Pushes the expiry `termination` past now
+    pub async fn renew(&self) -> Result<(), OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<wsnt:Renew xmlns:wsnt="{}">"#,
+                "<wsnt:TerminationTime>{}</wsnt:TerminationTime>",
+                "</wsnt:Renew>",
+            ),
+            NOTIFICATION_NS,
+            xs_duration(self.termination)
+        );
+        self.client
+            .request(&req)
+            .await
+            .map_err(transport_error("Renew", &self.address))
+            .map(|_| ())
+    }
+
+    pub async fn unsubscribe(self) -> Result<(), OnvifError> {
+        let req = format!(r#"<wsnt:Unsubscribe xmlns:wsnt="{}"/>"#, NOTIFICATION_NS);
+        self.client
+            .request(&req)
+            .await
+            .map_err(transport_error("Unsubscribe", &self.address))
+            .map(|_| ())
+    }
+
+    // Pulls for as long as the stream is polled, renewing halfway to expiry. Ends after yielding
+    // the first error, the subscription can't be trusted to still exist by then.
+    pub fn into_stream(self) -> impl Stream<Item = Result<CameraEvent, OnvifError>> {
+        let renewed = Instant::now();
+        stream::unfold(Some((self, VecDeque::new(), renewed)), |state| async move {
+            let (pull_point, mut queue, mut renewed) = state?;
+            loop {
+                if let Some(event) = queue.pop_front() {
+                    return Some((Ok(event), Some((pull_point, queue, renewed))));
+                }
+                if renewed.elapsed() >= pull_point.termination / 2 {
+                    if let Err(e) = pull_point.renew().await {
+                        warn!("Failed to renew the pull point subscription: {}", e);
+                        return Some((Err(e), None));
+                    }
+                    renewed = Instant::now();
+                }
+                let timeout = (pull_point.termination / 4)
+                    .clamp(Duration::from_secs(1), Duration::from_secs(10));
+                match pull_point.pull_messages(timeout, 32).await {
+                    Ok(events) => queue.extend(events),
+                    Err(e) => return Some((Err(e), None)),
+                }
+            }
+        })
+    }
+}
+
+fn parse_notification(message: &Element<'_>) -> CameraEvent {
+    let topic = elements(message.body, "Topic")
+        .first()
+        .map(|t| unescape(t.body.trim()))
+        .unwrap_or_default();
+    // The wsnt:Message wrapper holds the tt:Message, some cameras leave the wrapper out
+    let outer = elements(message.body, "Message").into_iter().next();
+    let inner = outer.and_then(|o| elements(o.body, "Message").into_iter().next());
+    let Some(inner) = inner.or(outer) else {
+        return CameraEvent {
+            topic,
+            ..Default::default()
+        };
+    };
+    let items = |section: &str| -> Vec<(String, String)> {
+        elements(inner.body, section)
+            .first()
+            .map(|s| {
+                elements(s.body, "SimpleItem")
+                    .iter()
+                    .filter_map(|i| Some((i.attribute("Name")?, i.attribute("Value")?)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    CameraEvent {
+        topic,
+        source: items("Source"),
+        data: items("Data"),
+        utc_time: inner.attribute("UtcTime"),
+        operation: inner.attribute("PropertyOperation"),
+    }
+}
+
+fn xs_duration(d: Duration) -> String {
+    format!("PT{}S", d.as_secs().max(1))
+}
@@ -0,0 +1,289 @@
+use async_trait::async_trait;
+use onvif::{
+    schema::{onvif::VideoEncoding, transport::Transport},
+    soap::client::Client,
+};
+use tracing::debug;
+use url::Url;
+
+use super::{
+    services::{fault_error, ClientWrapper, Connection, ProfileSummary},
+    transport_error,
+    xml::{elements, escape, unescape, Element},
+    OnvifError, RetryPolicy,
+};
+
+const MEDIA2_NS: &str = "http://www.onvif.org/ver20/media/wsdl";
+
+// What stream setup needs from either media service, see `OnvifHelper::get_media`
+#[async_trait]
+pub trait MediaOps: Send + Sync {
+    async fn get_profile_summaries(&self) -> Result<Vec<ProfileSummary>, OnvifError>;
+    // The profile the other calls operate on from now on
+    fn select_profile(&mut self, token: &str);
+    async fn get_stream_uri(&self) -> Result<Url, OnvifError>;
+    async fn sync_iframe(&self) -> Result<(), OnvifError>;
+
+    // For the session's resync hook: on cameras without SetSynchronizationPoint the session waits
+    // for the next keyframe of the regular GOP instead
+    async fn sync_iframe_or_wait(&self) -> Result<(), OnvifError> {
+        match self.sync_iframe().await {
+            Err(OnvifError::OperationNotSupported(_)) => {
+                debug!("The camera can't be asked for an iframe, waiting for the next one");
+                Ok(())
+            }
+            res => res,
+        }
+    }
+}
+
+// Device-side settings are in the ver20 form, which unlike ver10 allows for H.265
+#[derive(Debug, Clone, PartialEq)]
+pub struct Media2Profile {
+    pub token: String,
+    pub name: String,
+    pub video_source_token: Option<String>,
+    pub video_encoder: Option<Media2EncoderConfiguration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Media2EncoderConfiguration {
+    pub token: String,
+    pub name: String,
+    // As the camera sent it, e.g. `H264`, `H265` or `JPEG`
+    pub encoding: String,
+    pub resolution: (u32, u32),
+    pub frame_rate_limit: Option<f32>,
+    // kbit/s
+    pub bitrate_limit: Option<u32>,
+    pub gov_length: Option<u32>,
+}
+
+impl Media2EncoderConfiguration {
+    fn parse(config: &Element<'_>) -> Self {
+        let text = |xml: &str, local: &str| {
+            elements(xml, local)
+                .first()
+                .map(|e| unescape(e.body.trim()))
+        };
+        let number = |xml: &str, local: &str| text(xml, local).and_then(|t| t.parse::<u32>().ok());
+        let resolution = elements(config.body, "Resolution")
+            .first()
+            .map(|r| {
+                (
+                    number(r.body, "Width").unwrap_or_default(),
+                    number(r.body, "Height").unwrap_or_default(),
+                )
+            })
+            .unwrap_or_default();
+        let rate_control = elements(config.body, "RateControl").into_iter().next();
+        Self {
+            token: config.attribute("token").unwrap_or_default(),
+            name: text(config.body, "Name").unwrap_or_default(),
+            encoding: text(config.body, "Encoding").unwrap_or_default(),
+            resolution,
+            frame_rate_limit: rate_control
+                .and_then(|r| text(r.body, "FrameRateLimit"))
+                .and_then(|t| t.parse().ok()),
+            bitrate_limit: rate_control.and_then(|r| number(r.body, "BitrateLimit")),
+            gov_length: number(config.body, "GovLength"),
+        }
+    }
+}
+
+// Client of the ver20 media service of Profile T cameras. The calls are built by hand, like the
+// events ones, the onvif crate only covers the ver10 service.
+#[derive(Clone)]
+pub struct Media2Client {
+    inner: Client,
+    endpoint: Url,
+    profile_token: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl Media2Client {
+    pub async fn with_first_profile_token(self) -> Result<Self, OnvifError> {
+        let profiles = self.get_profiles().await?;
+        let first = profiles.first().ok_or(OnvifError::EmptyProfileList)?;
+        let token = first.token.clone();
+        Ok(self.with_token(&token))
+    }
+
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.profile_token = Some(token.to_string());
+        self
+    }
+
+    pub async fn get_profiles(&self) -> Result<Vec<Media2Profile>, OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tr2:GetProfiles xmlns:tr2="{}">"#,
+                "<tr2:Type>VideoSource</tr2:Type>",
+                "<tr2:Type>VideoEncoder</tr2:Type>",
+                "</tr2:GetProfiles>",
+            ),
+            MEDIA2_NS
+        );
+        let resp = self.request("GetProfiles", &req).await?;
+        Ok(elements(&resp, "Profiles")
+            .iter()
+            .map(|p| {
+                let configurations = elements(p.body, "Configurations").into_iter().next();
+                let section = |local: &str| {
+                    configurations.and_then(|c| elements(c.body, local).into_iter().next())
+                };
+                Media2Profile {
+                    token: p.attribute("token").unwrap_or_default(),
+                    name: elements(p.body, "Name")
+                        .first()
+                        .map(|n| unescape(n.body.trim()))
+                        .unwrap_or_default(),
+                    video_source_token: section("VideoSource").and_then(|s| {
+                        elements(s.body, "SourceToken")
+                            .first()
+                            .map(|t| unescape(t.body.trim()))
+                    }),
+                    video_encoder: section("VideoEncoder")
+                        .as_ref()
+                        .map(Media2EncoderConfiguration::parse),
+                }
+            })
+            .collect())
+    }
+
+    // `RtspUnicast`, see `get_stream_uri_with` for the other protocols
+    pub async fn get_stream_uri(&self) -> Result<Url, OnvifError> {
+        self.get_stream_uri_with("RtspUnicast").await
+    }
+
+    // `protocol` is one of `RtspUnicast`, `RtspMulticast`, `RtspOverHttp`, `RTSPS` or `RTSP`,
+    // cameras may accept others
+    pub async fn get_stream_uri_with(&self, protocol: &str) -> Result<Url, OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tr2:GetStreamUri xmlns:tr2="{}">"#,
+                "<tr2:Protocol>{}</tr2:Protocol>",
+                "<tr2:ProfileToken>{}</tr2:ProfileToken>",
+                "</tr2:GetStreamUri>",
+            ),
+            MEDIA2_NS,
+            escape(protocol),
+            escape(self.profile_token()?)
+        );
+        let resp = self.request("GetStreamUri", &req).await?;
+        let uri = elements(&resp, "Uri")
+            .first()
+            .map(|u| unescape(u.body.trim()))
+            .ok_or(OnvifError::IncompleteResponse("a stream uri"))?;
+        Url::parse(&uri).map_err(|_| OnvifError::UrlParseError(uri))
+    }
+
+    // Of the profile set with `with_token`, or all of them if there is none
+    pub async fn get_video_encoder_configurations(
+        &self,
+    ) -> Result<Vec<Media2EncoderConfiguration>, OnvifError> {
+        let filter = match &self.profile_token {
+            Some(token) => format!("<tr2:ProfileToken>{}</tr2:ProfileToken>", escape(token)),
+            None => String::new(),
+        };
+        let req = format!(
+            concat!(
+                r#"<tr2:GetVideoEncoderConfigurations xmlns:tr2="{}">"#,
+                "{}",
+                "</tr2:GetVideoEncoderConfigurations>",
+            ),
+            MEDIA2_NS, filter
+        );
+        let resp = self.request("GetVideoEncoderConfigurations", &req).await?;
+        Ok(elements(&resp, "Configurations")
+            .iter()
+            .map(Media2EncoderConfiguration::parse)
+            .collect())
+    }
+
+    // Never retried, see `RetryPolicy`
+    pub async fn set_synchronization_point(&self) -> Result<(), OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<tr2:SetSynchronizationPoint xmlns:tr2="{}">"#,
+                "<tr2:ProfileToken>{}</tr2:ProfileToken>",
+                "</tr2:SetSynchronizationPoint>",
+            ),
+            MEDIA2_NS,
+            escape(self.profile_token()?)
+        );
+        self.inner
+            .request(&req)
+            .await
+            .map_err(fault_error("SetSynchronizationPoint", &self.endpoint))
+            .map(|_| ())
+    }
+
+    fn profile_token(&self) -> Result<&str, OnvifError> {
+        self.profile_token.as_deref().ok_or(OnvifError::UnsetToken)
+    }
+
+    // For reads only
+    async fn request(&self, operation: &'static str, req: &str) -> Result<String, OnvifError> {
+        self.retry
+            .run(|| async move {
+                self.inner
+                    .request(req)
+                    .await
+                    .map_err(transport_error(operation, &self.endpoint))
+            })
+            .await
+    }
+}
+
+impl ClientWrapper for Media2Client {
+    fn connect(endpoint: &Url, conn: &Connection) -> Self {
+        Self {
+            inner: conn.soap_client(endpoint),
+            endpoint: endpoint.clone(),
+            profile_token: None,
+            retry: conn.retry.clone(),
+        }
+    }
+    fn get_service_name() -> String {
+        "media2".to_string()
+    }
+}
+
+#[async_trait]
+impl MediaOps for Media2Client {
+    async fn get_profile_summaries(&self) -> Result<Vec<ProfileSummary>, OnvifError> {
+        Ok(self
+            .get_profiles()
+            .await?
+            .into_iter()
+            .map(|p| ProfileSummary {
+                name: p.name,
+                token: p.token,
+                // Encodings ver10 has no name for, like H.265, are left out
+                encoding: p
+                    .video_encoder
+                    .as_ref()
+                    .and_then(|e| match e.encoding.as_str() {
+                        "H264" => Some(VideoEncoding::H264),
+                        "JPEG" => Some(VideoEncoding::Jpeg),
+                        "MPV4-ES" => Some(VideoEncoding::Mpeg4),
+                        _ => None,
+                    }),
+                resolution: p.video_encoder.map(|e| e.resolution),
+            })
+            .collect())
+    }
+
+    fn select_profile(&mut self, token: &str) {
+        self.profile_token = Some(token.to_string());
+    }
+
+    async fn get_stream_uri(&self) -> Result<Url, OnvifError> {
+        Media2Client::get_stream_uri(self).await
+    }
+
+    async fn sync_iframe(&self) -> Result<(), OnvifError> {
+        self.set_synchronization_point().await
+    }
+}
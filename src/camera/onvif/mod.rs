@@ -1,39 +1,508 @@
+pub mod analytics;
+pub mod discovery;
+pub mod events;
+pub mod media2;
 pub mod services;
-use std::collections::HashMap;
+pub mod tls;
+mod xml;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use analytics::AnalyticsClient;
+use chrono::TimeDelta;
+use futures::stream::{FuturesUnordered, StreamExt};
+use media2::{Media2Client, MediaOps};
 use onvif::{schema::transport, soap::client::Credentials};
-use services::{ClientWrapper, ManagementClient};
+use services::{ClientWrapper, Connection, ManagementClient, MediaClient, UserLevel};
+use tls::TlsConfig;
+use tracing::{debug, info, warn};
 use url::Url;
+use xml::{elements, unescape};
 
 const DEVICE_MGMT_PATH: &str = "/onvif/device_service";
-const ONVIF_PORT: &str = "8899";
+// What many DVRs serve ONVIF on, instead of the default http port
+pub const DVR_ONVIF_PORT: u16 = 8899;
+// Tried by `OnvifHelper::probe`, along with the path some devices use instead of the standard one
+pub const DEFAULT_PROBE_PORTS: &[u16] = &[80, 8000, 8080, DVR_ONVIF_PORT, 2020];
+const PROBE_PATHS: [&str; 2] = [DEVICE_MGMT_PATH, "/onvif/devices"];
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+// Offsets below this are within what cameras tolerate, not worth blaming for a rejection
+const CLOCK_SKEW_TOLERANCE_SECS: i64 = 5;
 
 #[derive(Debug)]
 pub enum OnvifError {
-    UrlParseError,
-    TransportError(transport::Error),
+    // The url as it was given or received
+    UrlParseError(String),
+    // The device service url handed to `OnvifHelper::from_url` can't be used
+    InvalidDeviceUrl {
+        url: String,
+        reason: &'static str,
+    },
+    // `operation` is the SOAP operation, e.g. `GetProfiles`
+    TransportError {
+        operation: &'static str,
+        endpoint: String,
+        source: transport::Error,
+    },
+    // A SOAP fault without a variant of its own, `code` is the most specific code given, e.g.
+    // `ter:InvalidArgVal`
+    Fault {
+        operation: &'static str,
+        code: String,
+        reason: String,
+    },
     UnsetToken,
     EmptyProfileList,
-    ServiceNotFound(String),
+    // What the camera offers instead
+    NoMatchingProfile(Vec<services::ProfileSummary>),
+    // `available` lists the services the device does advertise
+    ServiceNotFound {
+        service: String,
+        available: Vec<String>,
+    },
+    // The device answered without the part that was asked for
+    IncompleteResponse(&'static str),
+    // The device has several video sources and no profile picks one, these are the tokens
+    AmbiguousVideoSource(Vec<String>),
+    // The device answered with a fault saying it doesn't implement the operation
+    OperationNotSupported(String),
+    // An imaging setting outside what the camera advertises
+    OutOfRange {
+        setting: &'static str,
+        value: f64,
+        min: f64,
+        max: f64,
+    },
+    UnsupportedMode {
+        setting: &'static str,
+        mode: String,
+    },
+    // The camera accepted the encoder configuration with this token but reads back something else
+    ConfigurationRejected(String),
+    // The snapshot uri was reported but fetching it failed, `status` is `None` when no response
+    // came back
+    HttpError {
+        status: Option<u16>,
+        reason: String,
+    },
+    // No WS-Discovery probe could be sent
+    DiscoveryFailed(io::Error),
+    // Authenticated requests are still rejected after correcting for the device clock, which is
+    // `offset_secs` ahead of the local one
+    ClockSkew {
+        offset_secs: i64,
+    },
+    // The TLS handshake failed, e.g. on an untrusted certificate, see `TlsConfig`
+    TlsError(String),
+    // No answer within `OnvifHelper::with_timeout`
+    Timeout {
+        operation: &'static str,
+        endpoint: String,
+    },
+    // No credentials, or ones without the privileges for the operation
+    Unauthorized(String),
+    // A destructive operation was called without its confirmation flag
+    NotConfirmed(&'static str),
+    // The device can't store another user
+    TooManyUsers,
+    // The video source has no room for another OSD
+    MaxOsdsReached,
+    UsernameExists(String),
+    UnknownUser(String),
+    // Nothing answered `OnvifHelper::probe`, with how each device service url failed
+    ProbeFailed(Vec<(Url, OnvifError)>),
+}
+
+impl fmt::Display for OnvifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UrlParseError(url) => write!(f, "failed to parse onvif url {}", url),
+            Self::InvalidDeviceUrl { url, reason } => {
+                write!(f, "invalid device service url {}: {}", url, reason)
+            }
+            Self::TransportError {
+                operation,
+                endpoint,
+                source,
+            } => write!(f, "{} to {} failed: {}", operation, endpoint, source),
+            Self::Fault {
+                operation,
+                code,
+                reason,
+            } => write!(f, "{} failed with fault {}: {}", operation, code, reason),
+            Self::UnsetToken => write!(f, "no profile token set on the client"),
+            Self::EmptyProfileList => write!(f, "device returned no media profiles"),
+            Self::NoMatchingProfile(available) => {
+                write!(f, "no profile matches, available:")?;
+                for profile in available {
+                    write!(f, " [{}]", profile)?;
+                }
+                Ok(())
+            }
+            Self::ServiceNotFound { service, available } => write!(
+                f,
+                "device does not expose the {} service, it has: {}",
+                service,
+                available.join(", ")
+            ),
+            Self::IncompleteResponse(part) => write!(f, "device answered without {}", part),
+            Self::AmbiguousVideoSource(tokens) => write!(
+                f,
+                "device has several video sources, choose one of: {}",
+                tokens.join(", ")
+            ),
+            Self::OperationNotSupported(op) => write!(f, "device does not support {}", op),
+            Self::OutOfRange {
+                setting,
+                value,
+                min,
+                max,
+            } => write!(
+                f,
+                "{} {} is outside the supported range {} to {}",
+                setting, value, min, max
+            ),
+            Self::UnsupportedMode { setting, mode } => {
+                write!(f, "camera does not support {} mode {}", setting, mode)
+            }
+            Self::ConfigurationRejected(token) => {
+                write!(f, "camera did not apply encoder configuration {}", token)
+            }
+            Self::HttpError {
+                status: Some(status),
+                reason,
+            } => write!(f, "http request failed with {}: {}", status, reason),
+            Self::HttpError {
+                status: None,
+                reason,
+            } => write!(f, "http request failed: {}", reason),
+            Self::DiscoveryFailed(e) => write!(f, "failed to send the discovery probe: {}", e),
+            Self::ClockSkew { offset_secs } => write!(
+                f,
+                "device rejected the credentials with its clock {}s off the local one, check the \
+                 credentials or set the device clock (e.g. enable NTP on it)",
+                offset_secs
+            ),
+            Self::TlsError(reason) => write!(f, "tls connection failed: {}", reason),
+            Self::Timeout {
+                operation,
+                endpoint,
+            } => write!(f, "{} to {} timed out", operation, endpoint),
+            Self::Unauthorized(op) => write!(f, "credentials are not allowed to call {}", op),
+            Self::NotConfirmed(op) => write!(f, "{} was not confirmed", op),
+            Self::TooManyUsers => write!(f, "device has no room for another user"),
+            Self::MaxOsdsReached => write!(f, "video source has no room for another osd"),
+            Self::UsernameExists(user) => write!(f, "user {} already exists", user),
+            Self::UnknownUser(user) => write!(f, "device has no user {}", user),
+            Self::ProbeFailed(attempts) => {
+                write!(f, "no onvif device service answered, tried:")?;
+                for (url, e) in attempts {
+                    write!(f, " [{}: {}]", url, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for OnvifError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::TransportError { source, .. } => Some(source),
+            Self::DiscoveryFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// How the credentials are presented to the device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AuthMode {
+    // WS-UsernameToken first, HTTP digest when the device rejects it. What worked is kept for the
+    // clients created afterwards.
+    #[default]
+    Auto,
+    WsUsernameToken,
+    HttpDigest,
+    // A token in every request, and an answer to digest challenges
+    Both,
+}
+
+impl fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::WsUsernameToken => write!(f, "WS-UsernameToken"),
+            Self::HttpDigest => write!(f, "HTTP digest"),
+            Self::Both => write!(f, "WS-UsernameToken with HTTP digest"),
+        }
+    }
+}
+
+// For the requests that only read from the device, those changing something are never retried.
+// Clones share the retry counter.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    // Fraction of the delay taken off at random, so cameras failing together aren't retried in
+    // lockstep
+    jitter: f64,
+    retries: Arc<AtomicU64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            retries: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // `max_attempts` counts the first try, the delay before the next one starts at `base_delay`
+    // and doubles after every failure
+    pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    // Clamped to 0..=1
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    // Retries made so far by every client sharing the policy
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    // Only timeouts and transport failures, e.g. connection resets or server errors without a
+    // fault, are retried. Faults and rejected credentials would be answered the same.
+    pub(super) async fn run<T, F, Fut>(&self, mut call: F) -> Result<T, OnvifError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, OnvifError>>,
+    {
+        let mut delay = self.base_delay;
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Err(e) if attempt < self.max_attempts && is_retryable(&e) => {
+                    let wait = delay.mul_f64(1.0 - self.jitter * random_fraction());
+                    info!(
+                        attempt,
+                        wait_ms = wait.as_millis() as u64,
+                        "ONVIF request failed, retrying: {}",
+                        e
+                    );
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(wait).await;
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+// Good enough for spreading retries, not for anything else
+fn random_fraction() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    f64::from(nanos % 1000) / 1000.0
 }
 
 pub struct OnvifHelper {
     onvif_url: Url,
+    device_path: String,
     creds: Option<Credentials>,
+    auth_mode: AuthMode,
+    // What `AuthMode::Auto` settled on
+    detected_auth: Option<AuthMode>,
+    // Device clock minus the local one, `None` until synced
+    time_gap: Option<TimeDelta>,
+    rewrite_hosts: bool,
+    tls: Option<TlsConfig>,
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
     services_url: HashMap<String, Url>,
 }
 
 impl OnvifHelper {
+    // `ip_address` may carry a port, e.g. "192.168.1.10:8000", port 80 is used otherwise
     pub fn new(ip_address: &str) -> Result<Self, OnvifError> {
-        let onvif_url = Url::parse(&format!("http://{}:{}", ip_address, ONVIF_PORT))
-            .map_err(|_| OnvifError::UrlParseError)?;
+        let url = format!("http://{}", ip_address);
+        let onvif_url = Url::parse(&url).map_err(|_| OnvifError::UrlParseError(url))?;
         Ok(Self {
             onvif_url,
+            device_path: DEVICE_MGMT_PATH.to_string(),
+            creds: None,
+            auth_mode: AuthMode::Auto,
+            detected_auth: None,
+            time_gap: None,
+            rewrite_hosts: true,
+            tls: None,
+            timeout: None,
+            retry: RetryPolicy::default(),
+            services_url: HashMap::new(),
+        })
+    }
+
+    // The device service url as is, e.g. `https://cam7.internal:8443/onvif/device_service`. The
+    // path defaults to `/onvif/device_service` when the url has none.
+    pub fn from_url(url: Url) -> Result<Self, OnvifError> {
+        let invalid = |reason| OnvifError::InvalidDeviceUrl {
+            url: url.to_string(),
+            reason,
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(invalid("only http and https are supported"));
+        }
+        if url.host_str().is_none() {
+            return Err(invalid("url has no host"));
+        }
+        let device_path = match url.path() {
+            "" | "/" => DEVICE_MGMT_PATH.to_string(),
+            path => path.to_string(),
+        };
+        Ok(Self {
+            onvif_url: url,
+            device_path,
             creds: None,
+            auth_mode: AuthMode::Auto,
+            detected_auth: None,
+            time_gap: None,
+            rewrite_hosts: true,
+            tls: None,
+            timeout: None,
+            retry: RetryPolicy::default(),
             services_url: HashMap::new(),
         })
     }
 
+    // For when the port is unknown: asks every port and path for the device clock at once, which
+    // needs no credentials, and keeps the first one to answer. The attempts still running are
+    // dropped. `ip_address` is taken as in `new`, see `DEFAULT_PROBE_PORTS`.
+    pub async fn probe(
+        ip_address: &str,
+        creds: Option<(&str, &str)>,
+        ports: &[u16],
+    ) -> Result<Self, OnvifError> {
+        let mut attempts = FuturesUnordered::new();
+        for port in ports {
+            for path in PROBE_PATHS {
+                let helper = Self::new(ip_address)?
+                    .with_port(*port)
+                    .with_device_path(path);
+                attempts.push(async move {
+                    let url = helper.get_mgmt_url()?;
+                    let mgmt = helper.management()?;
+                    let clock = mgmt.get_system_date_and_time();
+                    let res = match tokio::time::timeout(PROBE_TIMEOUT, clock).await {
+                        Ok(res) => res.map(|_| ()),
+                        Err(_) => Err(OnvifError::Timeout {
+                            operation: "GetSystemDateAndTime",
+                            endpoint: url.to_string(),
+                        }),
+                    };
+                    Ok::<_, OnvifError>((helper, url, res))
+                });
+            }
+        }
+
+        let mut failed = Vec::new();
+        while let Some(attempt) = attempts.next().await {
+            let (helper, url, res) = attempt?;
+            match res {
+                // A fault still comes from an ONVIF device service, e.g. one refusing to tell
+                // its clock without credentials
+                Ok(())
+                | Err(OnvifError::Fault { .. })
+                | Err(OnvifError::Unauthorized(_))
+                | Err(OnvifError::OperationNotSupported(_)) => {
+                    debug!(%url, "Found the device service");
+                    return Ok(match creds {
+                        Some((user, pass)) => helper.with_credentials(user, pass),
+                        None => helper,
+                    });
+                }
+                Err(e) => failed.push((url, e)),
+            }
+        }
+        Err(OnvifError::ProbeFailed(failed))
+    }
+
+    // Overrides the port given to `new`
+    pub fn with_port(mut self, port: u16) -> Self {
+        // Only fails for urls that can't have a port, never the case for http ones
+        let _ = self.onvif_url.set_port(Some(port));
+        self
+    }
+
+    // For devices serving the device management service somewhere else than
+    // `/onvif/device_service`
+    pub fn with_device_path(mut self, path: &str) -> Self {
+        self.device_path = path.to_string();
+        self
+    }
+
+    // Service urls advertised with another host than the one connected to are pointed at the
+    // connected host and port by default, disable for devices that really serve them elsewhere
+    pub fn with_host_rewrite(mut self, rewrite: bool) -> Self {
+        self.rewrite_hosts = rewrite;
+        self
+    }
+
+    // Switches the device url to https, the port stays unless it was left to the scheme default
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        // Only fails for non special schemes, never the case for http ones
+        let _ = self.onvif_url.set_scheme("https");
+        self.tls = Some(tls);
+        self
+    }
+
+    // Bounds every request to the device, so one that is down fails fast with `Timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Applies to the clients created from now on, reads are tried once by default. Keep a clone
+    // to read `RetryPolicy::retries`.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
     pub fn with_credentials(mut self, user: &str, pass: &str) -> Self {
         self.creds = Some(Credentials {
             username: user.to_string(),
@@ -42,41 +511,114 @@ impl OnvifHelper {
         self
     }
 
+    // Applies to the clients created from now on
+    pub fn with_auth_mode(mut self, mode: AuthMode) -> Self {
+        self.auth_mode = mode;
+        self.detected_auth = None;
+        self
+    }
+
+    // What the clients use, `Auto` until the services were fetched with credentials
+    pub fn auth_mode(&self) -> AuthMode {
+        self.detected_auth.unwrap_or(self.auth_mode)
+    }
+
     pub async fn update_services_url(&mut self, force: bool) -> Result<(), OnvifError> {
         if !self.services_url.is_empty() && !force {
             return Ok(());
         }
 
         let mgmt_url = self.get_mgmt_url()?;
+        // Tokens are only checked against the device clock when there are credentials
+        if self.creds.is_some() && self.time_gap.is_none() {
+            if let Err(e) = self.sync_clock().await {
+                warn!(
+                    "Failed to read the device clock, assuming it is in sync: {}",
+                    e
+                );
+            }
+        }
 
-        let mgmt_cli = ManagementClient::connect(&mgmt_url, self.creds.clone());
+        let services = self.fetch_services_authenticated(&mgmt_url).await?;
         self.services_url
             .entry("management".to_string())
             .or_insert(mgmt_url);
-
-        let resp = mgmt_cli.get_capabilities().await?;
-        macro_rules! add_to_hashmap {
-            ($field_name:ident) => {
-                if resp.capabilities.$field_name.len() > 0 {
-                    let v = Url::parse(&resp.capabilities.$field_name[0].x_addr.to_string())
-                        .map_err(|_| OnvifError::UrlParseError)?;
-                    self.services_url
-                        .entry(stringify!($field_name).to_string())
-                        .and_modify(|e| *e = v.clone())
-                        .or_insert(v);
-                }
-            };
+        for (name, x_addr) in services {
+            let mut v = Url::parse(&x_addr).map_err(|_| OnvifError::UrlParseError(x_addr))?;
+            if v.scheme() == "http" && self.tls.as_ref().is_some_and(TlsConfig::upgrade_http) {
+                let _ = v.set_scheme("https");
+            }
+            if self.rewrite_hosts && v.host_str() != self.onvif_url.host_str() {
+                // Advertised hosts that differ from ours are usually internal addresses of a
+                // camera behind NAT, its services are reachable where the device service was
+                debug!(service = %name, advertised = %v, "Rewriting the host of a service url");
+                v.set_host(self.onvif_url.host_str())
+                    .map_err(|_| OnvifError::UrlParseError(v.to_string()))?;
+                let _ = v.set_port(self.onvif_url.port());
+            }
+            self.services_url
+                .entry(name)
+                .and_modify(|e| *e = v.clone())
+                .or_insert(v);
         }
-        add_to_hashmap!(analytics);
-        add_to_hashmap!(device);
-        add_to_hashmap!(events);
-        add_to_hashmap!(imaging);
-        add_to_hashmap!(media);
-        add_to_hashmap!(ptz);
 
         Ok(())
     }
 
+    // Straight to the device service without fetching the capabilities first, so it works with no
+    // or wrong credentials, e.g. as a connectivity probe
+    pub fn management(&self) -> Result<ManagementClient, OnvifError> {
+        Ok(ManagementClient::connect(
+            &self.get_mgmt_url()?,
+            &self.connection()?,
+        ))
+    }
+
+    // Changes the password and level of a user. When it is the user the helper authenticates as,
+    // the clients created from now on use the new password.
+    pub async fn set_user(
+        &mut self,
+        username: &str,
+        new_password: &str,
+        level: UserLevel,
+    ) -> Result<(), OnvifError> {
+        self.management()?
+            .set_user(username, new_password, level)
+            .await?;
+        if let Some(creds) = self.creds.as_mut().filter(|c| c.username == username) {
+            creds.password = new_password.to_string();
+        }
+        Ok(())
+    }
+
+    // Reads the device clock without credentials and signs the tokens of the clients created
+    // from now on with it, for cameras rejecting tokens whose timestamp is off their clock.
+    // Returns the offset in seconds, positive when the device is ahead. Done by
+    // `update_services_url` when needed, so only worth calling for long lived helpers.
+    pub async fn sync_clock(&mut self) -> Result<i64, OnvifError> {
+        let conn = Connection {
+            auth: None,
+            time_gap: None,
+            ..self.connection()?
+        };
+        let clock = ManagementClient::connect(&self.get_mgmt_url()?, &conn)
+            .get_system_date_and_time()
+            .await?;
+        if clock.offset_secs.abs() >= CLOCK_SKEW_TOLERANCE_SECS {
+            warn!(
+                offset_secs = clock.offset_secs,
+                "Device clock is off the local one"
+            );
+        }
+        self.time_gap = Some(TimeDelta::seconds(clock.offset_secs));
+        Ok(clock.offset_secs)
+    }
+
+    // `None` until the device clock was read
+    pub fn clock_offset(&self) -> Option<i64> {
+        self.time_gap.map(|gap| gap.num_seconds())
+    }
+
     pub fn get_service_urls(&self) -> &HashMap<String, Url> {
         &self.services_url
     }
@@ -84,15 +626,442 @@ impl OnvifHelper {
     pub async fn get_service<T: ClientWrapper>(&mut self) -> Result<T, OnvifError> {
         self.update_services_url(false).await?;
         let services = self.get_service_urls();
-        let service_name = services
-            .get(&T::get_service_name())
-            .ok_or(OnvifError::ServiceNotFound(T::get_service_name()))?;
-        Ok(T::connect(service_name, self.creds.clone()))
+        let service_name =
+            services
+                .get(&T::get_service_name())
+                .ok_or_else(|| OnvifError::ServiceNotFound {
+                    service: T::get_service_name(),
+                    available: services.keys().cloned().collect(),
+                })?;
+        Ok(T::connect(service_name, &self.connection()?))
+    }
+
+    // Media2 when the device advertises it, the ver10 media service otherwise
+    pub async fn get_media(&mut self) -> Result<Box<dyn MediaOps>, OnvifError> {
+        self.update_services_url(false).await?;
+        if self
+            .services_url
+            .contains_key(&Media2Client::get_service_name())
+        {
+            return Ok(Box::new(self.get_service::<Media2Client>().await?));
+        }
+        Ok(Box::new(self.get_service::<MediaClient>().await?))
+    }
+
+    // Devices without the analytics service fail with `OperationNotSupported` rather than
+    // `ServiceNotFound`
+    pub async fn get_analytics(&mut self) -> Result<AnalyticsClient, OnvifError> {
+        match self.get_service::<AnalyticsClient>().await {
+            Err(OnvifError::ServiceNotFound { .. }) => Err(OnvifError::OperationNotSupported(
+                "the analytics service".to_string(),
+            )),
+            res => res,
+        }
+    }
+
+    // Goes through the auth modes on `Auto` until the device accepts one
+    async fn fetch_services_authenticated(
+        &mut self,
+        mgmt_url: &Url,
+    ) -> Result<Vec<(String, String)>, OnvifError> {
+        let modes = if self.auth_mode == AuthMode::Auto
+            && self.detected_auth.is_none()
+            && self.creds.is_some()
+        {
+            vec![AuthMode::WsUsernameToken, AuthMode::HttpDigest]
+        } else {
+            vec![self.auth_mode()]
+        };
+        let mut rejected = None;
+        for &mode in &modes {
+            self.detected_auth = Some(mode);
+            match self.fetch_services_synced(mgmt_url).await {
+                Err(e) if is_auth_error(&e) || matches!(e, OnvifError::ClockSkew { .. }) => {
+                    debug!(%mode, "Device rejected the credentials: {}", e);
+                    rejected.get_or_insert(e);
+                }
+                res => {
+                    if modes.len() > 1 {
+                        info!(%mode, "Device accepted the credentials");
+                    }
+                    return res;
+                }
+            }
+        }
+        self.detected_auth = None;
+        match rejected {
+            // A token off the device clock says more than the rejected digest
+            Some(e @ OnvifError::ClockSkew { .. }) => Err(e),
+            Some(OnvifError::Unauthorized(op)) if modes.len() > 1 => {
+                let tried: Vec<String> = modes.iter().map(|m| m.to_string()).collect();
+                Err(OnvifError::Unauthorized(format!(
+                    "{} with {}",
+                    op,
+                    tried.join(" or ")
+                )))
+            }
+            Some(e) => Err(e),
+            None => Err(OnvifError::Unauthorized("GetServices".to_string())),
+        }
+    }
+
+    async fn fetch_services_synced(
+        &mut self,
+        mgmt_url: &Url,
+    ) -> Result<Vec<(String, String)>, OnvifError> {
+        match self.fetch_services(mgmt_url).await {
+            Err(e) if self.creds.is_some() && is_auth_error(&e) => {
+                // The clock may have drifted or been reset since the last sync
+                debug!("Device rejected the credentials, syncing its clock and retrying");
+                let offset_secs = self.sync_clock().await.map_err(|_| e)?;
+                match self.fetch_services(mgmt_url).await {
+                    Err(e)
+                        if is_auth_error(&e) && offset_secs.abs() >= CLOCK_SKEW_TOLERANCE_SECS =>
+                    {
+                        Err(OnvifError::ClockSkew { offset_secs })
+                    }
+                    res => res,
+                }
+            }
+            res => res,
+        }
+    }
+
+    // Service names and urls from `GetServices`, or from the deprecated `GetCapabilities` for
+    // devices that don't implement it
+    async fn fetch_services(&self, mgmt_url: &Url) -> Result<Vec<(String, String)>, OnvifError> {
+        let mgmt_cli = ManagementClient::connect(mgmt_url, &self.connection()?);
+        match mgmt_cli.get_services().await {
+            Ok(services) => return Ok(services),
+            // Would fail the same way
+            Err(e) if is_auth_error(&e) => return Err(e),
+            Err(e) => debug!("GetServices failed, falling back to GetCapabilities: {}", e),
+        }
+
+        let resp = mgmt_cli.get_capabilities().await?;
+        let mut services = Vec::new();
+        macro_rules! add_service {
+            ($field_name:ident) => {
+                if let Some(service) = resp.capabilities.$field_name.first() {
+                    services.push((
+                        stringify!($field_name).to_string(),
+                        service.x_addr.to_string(),
+                    ));
+                }
+            };
+        }
+        add_service!(analytics);
+        add_service!(device);
+        add_service!(events);
+        add_service!(imaging);
+        add_service!(media);
+        add_service!(ptz);
+        Ok(services)
+    }
+
+    fn connection(&self) -> Result<Connection, OnvifError> {
+        Ok(Connection {
+            auth: self.creds.clone(),
+            auth_mode: self.auth_mode(),
+            time_gap: self.time_gap,
+            http: self
+                .tls
+                .as_ref()
+                .map(|tls| tls.http_client(self.timeout))
+                .transpose()?,
+            timeout: self.timeout,
+            retry: self.retry.clone(),
+        })
     }
 
     fn get_mgmt_url(&self) -> Result<Url, OnvifError> {
         self.onvif_url
-            .join(DEVICE_MGMT_PATH)
-            .map_err(|_| OnvifError::UrlParseError)
+            .join(&self.device_path)
+            .map_err(|_| OnvifError::UrlParseError(self.device_path.clone()))
+    }
+}
+
+// What cameras answer for an expired token timestamp just like for wrong credentials
+fn is_auth_error(e: &OnvifError) -> bool {
+    matches!(e, OnvifError::Unauthorized(_))
+}
+
+// Sorts the errors of the onvif crate, which reports timeouts, failed handshakes, rejected
+// credentials and faults alike, into their variants
+pub(super) fn transport_error(
+    operation: &'static str,
+    endpoint: &Url,
+) -> impl FnOnce(transport::Error) -> OnvifError {
+    let endpoint = endpoint.to_string();
+    move |e| {
+        let reason = e.to_string();
+        let lower = reason.to_lowercase();
+        if lower.contains("timed out") || lower.contains("timeout") {
+            OnvifError::Timeout {
+                operation,
+                endpoint,
+            }
+        } else if is_tls_failure(&reason) {
+            OnvifError::TlsError(reason)
+        } else if matches!(e, transport::Error::Authorization(_))
+            || lower.contains("notauthorized")
+            || lower.contains("not authorized")
+        {
+            OnvifError::Unauthorized(operation.to_string())
+        } else if let Some((code, reason)) = parse_fault(&reason) {
+            OnvifError::Fault {
+                operation,
+                code,
+                reason,
+            }
+        } else {
+            OnvifError::TransportError {
+                operation,
+                endpoint,
+                source: e,
+            }
+        }
+    }
+}
+
+// Code and reason of a SOAP 1.2 fault, when the error carries the fault document
+fn parse_fault(text: &str) -> Option<(String, String)> {
+    let fault = elements(text, "Fault").into_iter().next()?;
+    let code = elements(fault.body, "Code").into_iter().next()?;
+    // Subcodes are nested in the code, the innermost one is the most specific
+    let mut values = elements(code.body, "Value");
+    let mut subcode = elements(code.body, "Subcode").into_iter().next();
+    while let Some(s) = subcode {
+        values = elements(s.body, "Value");
+        subcode = elements(s.body, "Subcode").into_iter().next();
+    }
+    let code = values.first().map(|v| unescape(v.body.trim()))?;
+    let reason = elements(fault.body, "Text")
+        .first()
+        .map(|t| unescape(t.body.trim()))
+        .unwrap_or_default();
+    Some((code, reason))
+}
+
+pub(super) fn is_tls_failure(reason: &str) -> bool {
+    let reason = reason.to_lowercase();
+    ["certificate", "handshake", "tls", "ssl"]
+        .iter()
+        .any(|s| reason.contains(s))
+}
+
+fn is_retryable(e: &OnvifError) -> bool {
+    matches!(
+        e,
+        OnvifError::Timeout { .. }
+            | OnvifError::TransportError { .. }
+            | OnvifError::HttpError {
+                status: Some(500..=599),
+                ..
+            }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, time::Instant};
+
+    use super::*;
+
+    const FAULT: &str = r#"<s:Envelope><s:Body><s:Fault>
+        <s:Code><s:Value>s:Sender</s:Value>
+            <s:Subcode><s:Value>ter:InvalidArgVal</s:Value>
+                <s:Subcode><s:Value>ter:NoProfile</s:Value></s:Subcode>
+            </s:Subcode>
+        </s:Code>
+        <s:Reason><s:Text xml:lang="en">Profile &quot;main&quot; does not exist</s:Text></s:Reason>
+    </s:Fault></s:Body></s:Envelope>"#;
+
+    fn endpoint() -> Url {
+        Url::parse("http://192.0.2.7/onvif/media_service").unwrap()
+    }
+
+    fn failed(e: transport::Error) -> OnvifError {
+        transport_error("GetProfiles", &endpoint())(e)
+    }
+
+    #[test]
+    fn transport_errors_name_the_call() {
+        let e = failed(transport::Error::Protocol(
+            "connection reset by peer".into(),
+        ));
+        match &e {
+            OnvifError::TransportError {
+                operation,
+                endpoint,
+                ..
+            } => {
+                assert_eq!(*operation, "GetProfiles");
+                assert_eq!(endpoint, "http://192.0.2.7/onvif/media_service");
+            }
+            e => panic!("expected TransportError, got {:?}", e),
+        }
+        assert!(e.source().is_some());
+        let message = e.to_string();
+        assert!(
+            message.starts_with("GetProfiles to http://192.0.2.7/onvif/media_service failed: "),
+            "{}",
+            message
+        );
+        assert!(message.contains("connection reset by peer"), "{}", message);
+    }
+
+    #[test]
+    fn transport_errors_are_sorted() {
+        assert!(matches!(
+            failed(transport::Error::Protocol("operation timed out".into())),
+            OnvifError::Timeout {
+                operation: "GetProfiles",
+                ref endpoint,
+            } if endpoint.ends_with("/onvif/media_service")
+        ));
+        assert!(matches!(
+            failed(transport::Error::Authorization("401".into())),
+            OnvifError::Unauthorized(ref op) if op == "GetProfiles"
+        ));
+        assert!(matches!(
+            failed(transport::Error::Protocol(
+                "invalid peer certificate".into()
+            )),
+            OnvifError::TlsError(_)
+        ));
+        match failed(transport::Error::Protocol(FAULT.into())) {
+            OnvifError::Fault {
+                operation,
+                code,
+                reason,
+            } => {
+                assert_eq!(operation, "GetProfiles");
+                // The innermost subcode
+                assert_eq!(code, "ter:NoProfile");
+                assert_eq!(reason, "Profile \"main\" does not exist");
+            }
+            e => panic!("expected Fault, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn url_errors_carry_the_url() {
+        match OnvifHelper::new("[::1") {
+            Err(OnvifError::UrlParseError(url)) => assert_eq!(url, "http://[::1"),
+            other => panic!("expected UrlParseError, got {:?}", other.err()),
+        }
+        let ftp = Url::parse("ftp://192.0.2.7/").unwrap();
+        match OnvifHelper::from_url(ftp) {
+            Err(OnvifError::InvalidDeviceUrl { url, .. }) => assert_eq!(url, "ftp://192.0.2.7/"),
+            other => panic!("expected InvalidDeviceUrl, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_service_lists_the_others() {
+        let mut helper = OnvifHelper::new("192.0.2.7").unwrap();
+        // Taken as already fetched, nothing goes out to the device
+        for name in ["management", "ptz"] {
+            helper.services_url.insert(name.to_string(), endpoint());
+        }
+        match helper.get_service::<MediaClient>().await {
+            Err(OnvifError::ServiceNotFound {
+                service,
+                mut available,
+            }) => {
+                assert_eq!(service, "media");
+                available.sort();
+                assert_eq!(available, ["management", "ptz"]);
+            }
+            Err(e) => panic!("expected ServiceNotFound, got {:?}", e),
+            Ok(_) => panic!("expected ServiceNotFound"),
+        }
+    }
+
+    fn reset() -> OnvifError {
+        failed(transport::Error::Protocol(
+            "connection reset by peer".into(),
+        ))
+    }
+
+    // Stands in for a flaky device, `errors` are answered in order before it succeeds. Returns the
+    // result and how many requests were made.
+    async fn flaky(
+        policy: &RetryPolicy,
+        mut errors: Vec<OnvifError>,
+    ) -> (Result<&'static str, OnvifError>, usize) {
+        errors.reverse();
+        let mut calls = 0;
+        let res = policy
+            .run(|| {
+                calls += 1;
+                let answer = errors.pop().map_or(Ok("profiles"), Err);
+                async move { answer }
+            })
+            .await;
+        (res, calls)
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_device_answers() {
+        let policy = RetryPolicy::new(4, Duration::from_millis(1));
+        let errors = vec![
+            reset(),
+            failed(transport::Error::Protocol("operation timed out".into())),
+            OnvifError::HttpError {
+                status: Some(503),
+                reason: "busy".into(),
+            },
+        ];
+        let (res, calls) = flaky(&policy, errors).await;
+        assert_eq!(res.ok(), Some("profiles"));
+        assert_eq!(calls, 4);
+        assert_eq!(policy.retries(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(1));
+        let (res, calls) = flaky(&policy, (0..5).map(|_| reset()).collect()).await;
+        assert!(matches!(res, Err(OnvifError::TransportError { .. })));
+        assert_eq!(calls, 3);
+        // Clones count along
+        assert_eq!(policy.clone().retries(), 2);
+
+        let (res, calls) = flaky(&RetryPolicy::default(), vec![reset()]).await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn answers_from_the_device_are_not_retried() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(1));
+        let answers = [
+            OnvifError::Unauthorized("GetProfiles".into()),
+            failed(transport::Error::Protocol(FAULT.into())),
+            OnvifError::HttpError {
+                status: Some(404),
+                reason: "not found".into(),
+            },
+        ];
+        for e in answers {
+            let (res, calls) = flaky(&policy, vec![e]).await;
+            assert!(res.is_err());
+            assert_eq!(calls, 1);
+        }
+        assert_eq!(policy.retries(), 0);
+    }
+
+    #[tokio::test]
+    async fn delay_doubles_up_to_the_max() {
+        let policy = RetryPolicy::new(4, Duration::from_millis(20))
+            .with_max_delay(Duration::from_millis(30));
+        let started = Instant::now();
+        let (res, _) = flaky(&policy, (0..3).map(|_| reset()).collect()).await;
+        assert!(res.is_ok());
+        // 20, then 30 twice
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(80), "{:?}", elapsed);
     }
 }
@@ -1,37 +1,433 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{NaiveDate, TimeDelta};
 use onvif::{
     schema::{
         self,
-        onvif::{Profile, ReferenceToken, StreamType, Transport, TransportProtocol},
+        onvif::{
+            self as tt, FloatRange, H264Configuration, ImagingSettings20, Profile, Ptzpreset,
+            Ptzspeed, Ptzvector, ReferenceToken, StreamType, Transport, TransportProtocol,
+            Vector1D, Vector2D, VideoEncoderConfiguration, VideoEncoderConfigurationOptions,
+            VideoEncoding, VideoResolution,
+        },
+        transport,
     },
-    soap::client::{Client, ClientBuilder, Credentials},
+    soap::client::{AuthType, Client, ClientBuilder, Credentials},
 };
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use tracing::info;
 use url::Url;
 
-use super::OnvifError;
+use super::{
+    is_tls_failure,
+    media2::MediaOps,
+    transport_error,
+    xml::{elements, escape, unescape, Element},
+    AuthMode, OnvifError, RetryPolicy,
+};
+use crate::camera::rtsp_session::utils::rewrite_url;
 
 pub trait ClientWrapper {
-    fn connect(endpoint: &Url, auth: Option<Credentials>) -> Self;
+    fn connect(endpoint: &Url, conn: &Connection) -> Self;
     fn get_service_name() -> String;
 }
 
+// How the clients reach the device, the same for all of them
+#[derive(Clone, Default)]
+pub struct Connection {
+    pub auth: Option<Credentials>,
+    pub auth_mode: AuthMode,
+    // Added to the local clock for the WS-Security timestamps, see `OnvifHelper::sync_clock`
+    pub time_gap: Option<TimeDelta>,
+    // Carries the TLS settings, the onvif crate's own client is used when unset
+    pub http: Option<reqwest::Client>,
+    // Of every request, the OS connect timeout applies when unset
+    pub timeout: Option<Duration>,
+    pub retry: RetryPolicy,
+}
+
+impl Connection {
+    pub(super) fn soap_client(&self, endpoint: &Url) -> Client {
+        let mut builder = ClientBuilder::new(endpoint)
+            .credentials(self.auth.clone())
+            .auth_type(match self.auth_mode {
+                AuthMode::WsUsernameToken => AuthType::UsernameToken,
+                AuthMode::HttpDigest => AuthType::Digest,
+                // Left to the device's challenge until `Auto` settled
+                AuthMode::Auto | AuthMode::Both => AuthType::Any,
+            })
+            .fix_time_gap(self.time_gap);
+        if let Some(http) = &self.http {
+            builder = builder.http_client(http.clone());
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder.build()
+    }
+}
+
 #[derive(Clone)]
 pub struct ManagementClient {
     inner: Client,
+    // Named in the errors
+    endpoint: Url,
+    retry: RetryPolicy,
+    // The maintenance calls are refused up front without credentials
+    authenticated: bool,
 }
 
 impl ManagementClient {
     pub async fn get_capabilities(
         &self,
     ) -> Result<schema::devicemgmt::GetCapabilitiesResponse, OnvifError> {
-        schema::devicemgmt::get_capabilities(&self.inner, &Default::default())
+        self.retry
+            .run(|| async move {
+                schema::devicemgmt::get_capabilities(&self.inner, &Default::default())
+                    .await
+                    .map_err(transport_error("GetCapabilities", &self.endpoint))
+            })
+            .await
+    }
+
+    // Name and url of the services there is a client for, named like
+    // `ClientWrapper::get_service_name`. Other services are left out.
+    pub async fn get_services(&self) -> Result<Vec<(String, String)>, OnvifError> {
+        let req = &schema::devicemgmt::GetServices {
+            include_capability: false,
+        };
+        let resp = self
+            .retry
+            .run(|| async move {
+                schema::devicemgmt::get_services(&self.inner, req)
+                    .await
+                    .map_err(transport_error("GetServices", &self.endpoint))
+            })
+            .await?;
+        Ok(resp
+            .service
+            .into_iter()
+            .filter_map(|s| {
+                Some((
+                    service_name(&s.namespace)?.to_string(),
+                    s.x_addr.to_string(),
+                ))
+            })
+            .collect())
+    }
+}
+
+fn service_name(namespace: &str) -> Option<&'static str> {
+    match namespace.trim_end_matches('/') {
+        "http://www.onvif.org/ver10/device/wsdl" => Some("device"),
+        "http://www.onvif.org/ver10/media/wsdl" => Some("media"),
+        "http://www.onvif.org/ver20/media/wsdl" => Some("media2"),
+        "http://www.onvif.org/ver10/events/wsdl" => Some("events"),
+        "http://www.onvif.org/ver20/imaging/wsdl" => Some("imaging"),
+        "http://www.onvif.org/ver20/ptz/wsdl" => Some("ptz"),
+        "http://www.onvif.org/ver20/analytics/wsdl" => Some("analytics"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInformation {
+    pub manufacturer: String,
+    pub model: String,
+    pub firmware_version: String,
+    pub serial_number: String,
+    pub hardware_id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceClock {
+    pub utc: SystemTime,
+    // Device clock minus the local one, positive when the device is ahead. Rounded to the second,
+    // which is all the device reports.
+    pub offset_secs: i64,
+}
+
+impl ManagementClient {
+    // Most cameras answer these two without credentials
+    pub async fn get_device_information(&self) -> Result<DeviceInformation, OnvifError> {
+        let info = self
+            .retry
+            .run(|| async move {
+                schema::devicemgmt::get_device_information(&self.inner, &Default::default())
+                    .await
+                    .map_err(transport_error("GetDeviceInformation", &self.endpoint))
+            })
+            .await?;
+        Ok(DeviceInformation {
+            manufacturer: info.manufacturer,
+            model: info.model,
+            firmware_version: info.firmware_version,
+            serial_number: info.serial_number,
+            hardware_id: info.hardware_id,
+        })
+    }
+
+    pub async fn get_system_date_and_time(&self) -> Result<DeviceClock, OnvifError> {
+        let sent = SystemTime::now();
+        let resp = schema::devicemgmt::get_system_date_and_time(&self.inner, &Default::default())
             .await
-            .map_err(|e| OnvifError::TransportError(e))
+            .map_err(transport_error("GetSystemDateAndTime", &self.endpoint))?;
+        // Compared against the middle of the round trip
+        let local = sent + SystemTime::now().duration_since(sent).unwrap_or_default() / 2;
+        let utc = resp
+            .system_date_time
+            .utc_date_time
+            .ok_or(OnvifError::IncompleteResponse("a utc date and time"))?;
+        let device = NaiveDate::from_ymd_opt(
+            utc.date.year as i32,
+            utc.date.month as u32,
+            utc.date.day as u32,
+        )
+        .and_then(|date| {
+            date.and_hms_opt(
+                utc.time.hour as u32,
+                utc.time.minute as u32,
+                utc.time.second as u32,
+            )
+        })
+        .ok_or(OnvifError::IncompleteResponse("a valid utc date and time"))?
+        .and_utc();
+        let local_secs = local
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        Ok(DeviceClock {
+            utc: device.into(),
+            offset_secs: (device.timestamp() as f64 - local_secs).round() as i64,
+        })
     }
 }
+
+impl ManagementClient {
+    // Returns the message the camera answers with, usually how long it will take. Never retried.
+    pub async fn system_reboot(&self) -> Result<String, OnvifError> {
+        self.check_authenticated("SystemReboot")?;
+        schema::devicemgmt::system_reboot(&self.inner, &Default::default())
+            .await
+            .map_err(transport_error("SystemReboot", &self.endpoint))
+            .map(|r| r.message)
+    }
+
+    // Reboots and waits until the device answers `GetSystemDateAndTime` again, e.g. for a
+    // watchdog to reconnect the session afterwards. Fails with `Timeout` if it isn't back within
+    // `timeout`.
+    pub async fn reboot_and_wait(&self, timeout: Duration) -> Result<String, OnvifError> {
+        const POLL_INTERVAL: Duration = Duration::from_secs(2);
+        // Devices rebooting before the first poll are never seen down, answering this long after
+        // the reboot counts as being back
+        const MAX_SHUTDOWN: Duration = Duration::from_secs(60);
+
+        let message = self.system_reboot().await?;
+        info!(%message, "Device is rebooting");
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut went_down = false;
+        let started = tokio::time::Instant::now();
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if tokio::time::Instant::now() >= deadline {
+                return Err(OnvifError::Timeout {
+                    operation: "SystemReboot",
+                    endpoint: self.endpoint.to_string(),
+                });
+            }
+            let answered = matches!(
+                tokio::time::timeout(POLL_INTERVAL, self.get_system_date_and_time()).await,
+                Ok(Ok(_))
+            );
+            match (answered, went_down) {
+                (false, _) => went_down = true,
+                (true, true) => break,
+                (true, false) if started.elapsed() >= MAX_SHUTDOWN => break,
+                (true, false) => {}
+            }
+        }
+        info!("Device is back after the reboot");
+        Ok(message)
+    }
+
+    // A soft reset keeps the network settings, after a hard one the device may come back at
+    // another address. Does nothing but fail with `NotConfirmed` unless `confirmed` is set.
+    pub async fn set_system_factory_default(
+        &self,
+        soft: bool,
+        confirmed: bool,
+    ) -> Result<(), OnvifError> {
+        if !confirmed {
+            return Err(OnvifError::NotConfirmed("SetSystemFactoryDefault"));
+        }
+        self.check_authenticated("SetSystemFactoryDefault")?;
+        let req = schema::devicemgmt::SetSystemFactoryDefault {
+            factory_default: if soft {
+                tt::FactoryDefaultType::Soft
+            } else {
+                tt::FactoryDefaultType::Hard
+            },
+        };
+        schema::devicemgmt::set_system_factory_default(&self.inner, &req)
+            .await
+            .map_err(transport_error("SetSystemFactoryDefault", &self.endpoint))
+            .map(|_| ())
+    }
+
+    fn check_authenticated(&self, operation: &str) -> Result<(), OnvifError> {
+        if self.authenticated {
+            Ok(())
+        } else {
+            Err(OnvifError::Unauthorized(operation.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserLevel {
+    Administrator,
+    Operator,
+    User,
+}
+
+impl UserLevel {
+    fn to_raw(self) -> tt::UserLevel {
+        match self {
+            Self::Administrator => tt::UserLevel::Administrator,
+            Self::Operator => tt::UserLevel::Operator,
+            Self::User => tt::UserLevel::User,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnvifUser {
+    pub username: String,
+    // `None` for the anonymous and vendor specific levels
+    pub level: Option<UserLevel>,
+}
+
+impl ManagementClient {
+    // Passwords are never listed
+    pub async fn get_users(&self) -> Result<Vec<OnvifUser>, OnvifError> {
+        let resp = self
+            .retry
+            .run(|| async move {
+                schema::devicemgmt::get_users(&self.inner, &Default::default())
+                    .await
+                    .map_err(transport_error("GetUsers", &self.endpoint))
+            })
+            .await?;
+        Ok(resp
+            .user
+            .into_iter()
+            .map(|u| OnvifUser {
+                level: match u.user_level {
+                    tt::UserLevel::Administrator => Some(UserLevel::Administrator),
+                    tt::UserLevel::Operator => Some(UserLevel::Operator),
+                    tt::UserLevel::User => Some(UserLevel::User),
+                    _ => None,
+                },
+                username: u.username,
+            })
+            .collect())
+    }
+
+    pub async fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+        level: UserLevel,
+    ) -> Result<(), OnvifError> {
+        self.check_authenticated("CreateUsers")?;
+        let req = schema::devicemgmt::CreateUsers {
+            user: vec![raw_user(username, password, level)],
+        };
+        schema::devicemgmt::create_users(&self.inner, &req)
+            .await
+            .map_err(user_error("CreateUsers", &self.endpoint, username))
+            .map(|_| ())
+    }
+
+    pub async fn delete_user(&self, username: &str) -> Result<(), OnvifError> {
+        self.check_authenticated("DeleteUsers")?;
+        let req = schema::devicemgmt::DeleteUsers {
+            username: vec![username.to_string()],
+        };
+        schema::devicemgmt::delete_users(&self.inner, &req)
+            .await
+            .map_err(user_error("DeleteUsers", &self.endpoint, username))
+            .map(|_| ())
+    }
+
+    // Clients already connected with the old password keep using it, see
+    // `OnvifHelper::set_user` to keep the helper's credentials in sync
+    pub async fn set_user(
+        &self,
+        username: &str,
+        new_password: &str,
+        level: UserLevel,
+    ) -> Result<(), OnvifError> {
+        self.check_authenticated("SetUser")?;
+        let req = schema::devicemgmt::SetUser {
+            user: vec![raw_user(username, new_password, level)],
+        };
+        schema::devicemgmt::set_user(&self.inner, &req)
+            .await
+            .map_err(user_error("SetUser", &self.endpoint, username))
+            .map(|_| ())
+    }
+}
+
+fn raw_user(username: &str, password: &str, level: UserLevel) -> tt::User {
+    tt::User {
+        username: username.to_string(),
+        password: Some(password.to_string()),
+        user_level: level.to_raw(),
+        ..Default::default()
+    }
+}
+
+// The user management faults that have a variant of their own
+fn user_error(
+    operation: &'static str,
+    endpoint: &Url,
+    username: &str,
+) -> impl FnOnce(transport::Error) -> OnvifError {
+    let username = username.to_string();
+    let other = transport_error(operation, endpoint);
+    move |e| {
+        let fault = e.to_string();
+        if fault.contains("TooManyUsers") {
+            OnvifError::TooManyUsers
+        } else if fault.contains("UsernameClash") {
+            OnvifError::UsernameExists(username)
+        } else if fault.contains("UsernameMissing") {
+            OnvifError::UnknownUser(username)
+        } else {
+            other(e)
+        }
+    }
+}
+
 impl ClientWrapper for ManagementClient {
-    fn connect(endpoint: &Url, auth: Option<Credentials>) -> Self {
-        let cli = ClientBuilder::new(&endpoint).credentials(auth).build();
-        Self { inner: cli }
+    fn connect(endpoint: &Url, conn: &Connection) -> Self {
+        let cli = conn.soap_client(endpoint);
+        Self {
+            inner: cli,
+            endpoint: endpoint.clone(),
+            retry: conn.retry.clone(),
+            authenticated: conn.auth.is_some(),
+        }
     }
     fn get_service_name() -> String {
         "management".to_string()
@@ -40,7 +436,139 @@ impl ClientWrapper for ManagementClient {
 
 pub struct MediaClient {
     inner: Client,
+    endpoint: Url,
     profile_token: Option<ReferenceToken>,
+    // See `with_video_source`, cleared when the profile changes
+    video_source_token: Option<String>,
+    // Kept for the snapshot fetch, which is plain HTTP rather than SOAP
+    conn: Connection,
+    snapshot_host: Option<String>,
+    // Shared with the clones, see `get_service_capabilities`
+    capabilities: Arc<Mutex<Option<MediaCapabilities>>>,
+}
+
+// What the media service says it supports, attributes it leaves out count as unsupported
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MediaCapabilities {
+    pub snapshot_uri: bool,
+    pub rotation: bool,
+    pub osd: bool,
+    pub max_profiles: Option<u32>,
+    pub streaming: StreamingCapabilities,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamingCapabilities {
+    pub rtp_multicast: bool,
+    // RTP over plain TCP
+    pub rtp_tcp: bool,
+    // RTP interleaved in the RTSP connection
+    pub rtp_rtsp_tcp: bool,
+    pub non_aggregate_control: bool,
+    pub no_rtsp_streaming: bool,
+}
+
+impl MediaCapabilities {
+    fn parse(resp: &str) -> Option<Self> {
+        let caps = elements(resp, "Capabilities").into_iter().next()?;
+        let flag = |e: &Element<'_>, name: &str| {
+            e.attribute(name)
+                .is_some_and(|v| v.trim() == "true" || v.trim() == "1")
+        };
+        let streaming = elements(caps.body, "StreamingCapabilities")
+            .first()
+            .map(|s| StreamingCapabilities {
+                rtp_multicast: flag(s, "RTPMulticast"),
+                rtp_tcp: flag(s, "RTP_TCP"),
+                rtp_rtsp_tcp: flag(s, "RTP_RTSP_TCP"),
+                non_aggregate_control: flag(s, "NonAggregateControl"),
+                no_rtsp_streaming: flag(s, "NoRTSPStreaming"),
+            })
+            .unwrap_or_default();
+        Some(Self {
+            snapshot_uri: flag(&caps, "SnapshotUri"),
+            rotation: flag(&caps, "Rotation"),
+            osd: flag(&caps, "OSD"),
+            max_profiles: elements(caps.body, "ProfileCapabilities")
+                .first()
+                .and_then(|p| p.attribute("MaximumNumberOfProfiles"))
+                .and_then(|m| m.trim().parse().ok()),
+            streaming,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoSourceInfo {
+    pub token: String,
+    pub framerate: f64,
+    pub resolution: (u32, u32),
+}
+
+// What a profile streams, as listed in `OnvifError::NoMatchingProfile`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileSummary {
+    pub name: String,
+    pub token: String,
+    // `None` for profiles without a video encoder, or with one ver10 has no name for, e.g. H.265
+    // profiles of `Media2Client`
+    pub encoding: Option<VideoEncoding>,
+    pub resolution: Option<(u32, u32)>,
+}
+
+impl ProfileSummary {
+    fn of(profile: &Profile) -> Self {
+        let encoder = profile.video_encoder_configuration.as_ref();
+        Self {
+            name: profile.name.0.clone(),
+            token: profile.token.0.clone(),
+            encoding: encoder.map(|c| c.encoding.clone()),
+            resolution: encoder.map(|c| (c.resolution.width as u32, c.resolution.height as u32)),
+        }
+    }
+}
+
+impl fmt::Display for ProfileSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.token)?;
+        if let Some(encoding) = &self.encoding {
+            write!(f, " {:?}", encoding)?;
+        }
+        if let Some((width, height)) = self.resolution {
+            write!(f, " {}x{}", width, height)?;
+        }
+        Ok(())
+    }
+}
+
+// Matchers for `MediaClient::with_profile_matching`, combine them in a closure
+pub fn by_encoding(encoding: VideoEncoding) -> impl Fn(&Profile) -> bool {
+    move |p| {
+        p.video_encoder_configuration
+            .as_ref()
+            .is_some_and(|c| c.encoding == encoding)
+    }
+}
+
+pub fn by_max_resolution(width: u32, height: u32) -> impl Fn(&Profile) -> bool {
+    move |p| {
+        p.video_encoder_configuration.as_ref().is_some_and(|c| {
+            c.resolution.width as u32 <= width && c.resolution.height as u32 <= height
+        })
+    }
+}
+
+// Case insensitive
+pub fn by_name_contains(name: &str) -> impl Fn(&Profile) -> bool {
+    let name = name.to_lowercase();
+    move |p| p.name.0.to_lowercase().contains(&name)
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub data: Vec<u8>,
+    // Usually `image/jpeg`
+    pub content_type: Option<String>,
 }
 
 impl MediaClient {
@@ -59,16 +587,148 @@ impl MediaClient {
 
     pub fn with_token(mut self, token: ReferenceToken) -> Self {
         self.profile_token = Some(token);
+        self.video_source_token = None;
+        self
+    }
+
+    // For the calls keyed by video source rather than profile, e.g. those of `ImagingClient`.
+    // Pick one of `get_video_sources` on multi-sensor devices.
+    pub fn with_video_source(mut self, token: &str) -> Self {
+        self.video_source_token = Some(token.to_string());
+        self
+    }
+
+    // Stores the source of the profile set with `with_token`, or the only source of the device
+    // when no profile is set. Fails with the list of sources when there are several to choose
+    // from, see `with_video_source`.
+    pub async fn with_resolved_video_source(self) -> Result<Self, OnvifError> {
+        let token = match self.profile_token {
+            Some(_) => self.get_profile_video_source().await?,
+            None => {
+                let mut sources = self.get_video_sources().await?;
+                match sources.len() {
+                    0 => return Err(OnvifError::IncompleteResponse("any video source")),
+                    1 => sources.remove(0).token,
+                    _ => {
+                        return Err(OnvifError::AmbiguousVideoSource(
+                            sources.into_iter().map(|s| s.token).collect(),
+                        ))
+                    }
+                }
+            }
+        };
+        Ok(self.with_video_source(&token))
+    }
+
+    pub fn video_source_token(&self) -> Option<&str> {
+        self.video_source_token.as_deref()
+    }
+
+    // Uses the first profile `predicate` accepts, returned along with what it streams. Fails with
+    // the list of profiles when none matches.
+    pub async fn with_profile_matching(
+        self,
+        predicate: impl Fn(&Profile) -> bool,
+    ) -> Result<(Self, ProfileSummary), OnvifError> {
+        let profiles = self.get_profiles().await?;
+        match profiles.iter().find(|p| predicate(p)) {
+            Some(profile) => {
+                let summary = ProfileSummary::of(profile);
+                let token = ReferenceToken {
+                    0: summary.token.clone(),
+                };
+                Ok((self.with_token(token), summary))
+            }
+            None => Err(OnvifError::NoMatchingProfile(
+                profiles.iter().map(ProfileSummary::of).collect(),
+            )),
+        }
+    }
+
+    // Replaces the host, and the port if given as `host:port`, of the snapshot uri the camera
+    // reports. For cameras behind NAT that report their internal address.
+    pub fn with_snapshot_host(mut self, host: &str) -> Self {
+        self.snapshot_host = Some(host.to_string());
         self
     }
 
     pub async fn get_profiles(&self) -> Result<Vec<Profile>, OnvifError> {
-        schema::media::get_profiles(&self.inner, &Default::default())
+        self.conn
+            .retry
+            .run(|| async move {
+                schema::media::get_profiles(&self.inner, &Default::default())
+                    .await
+                    .map_err(transport_error("GetProfiles", &self.endpoint))
+            })
             .await
-            .map_err(|e| OnvifError::TransportError(e))
             .map(|r| r.profiles)
     }
 
+    // The device's video sources, several on multi-sensor devices
+    pub async fn get_video_sources(&self) -> Result<Vec<VideoSourceInfo>, OnvifError> {
+        self.conn
+            .retry
+            .run(|| async move {
+                schema::media::get_video_sources(&self.inner, &Default::default())
+                    .await
+                    .map_err(transport_error("GetVideoSources", &self.endpoint))
+            })
+            .await
+            .map(|r| {
+                r.video_sources
+                    .into_iter()
+                    .map(|v| VideoSourceInfo {
+                        token: v.token.0,
+                        framerate: v.framerate,
+                        resolution: (v.resolution.width as u32, v.resolution.height as u32),
+                    })
+                    .collect()
+            })
+    }
+
+    // Video analytics configuration of the profile set with `with_token`, what `AnalyticsClient`
+    // calls are scoped to
+    pub async fn get_analytics_configuration_token(&self) -> Result<String, OnvifError> {
+        let token = &self.profile_token.as_ref().ok_or(OnvifError::UnsetToken)?.0;
+        self.get_profiles()
+            .await?
+            .into_iter()
+            .find(|p| p.token.0 == *token)
+            .and_then(|p| p.video_analytics_configuration)
+            .map(|c| c.token.0)
+            .ok_or_else(|| {
+                OnvifError::OperationNotSupported(format!("video analytics on profile {}", token))
+            })
+    }
+
+    // Video source of the profile set with `with_token`
+    pub async fn get_profile_video_source(&self) -> Result<String, OnvifError> {
+        self.profile_video_source_configuration()
+            .await
+            .map(|c| c.source_token.0)
+    }
+
+    // Video source configuration of the profile set with `with_token`, what OSDs belong to
+    pub async fn get_profile_video_source_configuration(&self) -> Result<String, OnvifError> {
+        self.profile_video_source_configuration()
+            .await
+            .map(|c| c.token.0)
+    }
+
+    async fn profile_video_source_configuration(
+        &self,
+    ) -> Result<tt::VideoSourceConfiguration, OnvifError> {
+        let token = &self.profile_token.as_ref().ok_or(OnvifError::UnsetToken)?.0;
+        self.get_profiles()
+            .await?
+            .into_iter()
+            .find(|p| p.token.0 == *token)
+            .and_then(|p| p.video_source_configuration)
+            .ok_or(OnvifError::IncompleteResponse(
+                "a video source for the profile",
+            ))
+    }
+
     pub async fn sync_iframe(&self) -> Result<(), OnvifError> {
         let req = schema::media::SetSynchronizationPoint {
             profile_token: ReferenceToken {
@@ -82,16 +742,32 @@ impl MediaClient {
         };
         schema::media::set_synchronization_point(&self.inner, &req)
             .await
-            .map_err(|e| OnvifError::TransportError(e))
+            .map_err(fault_error("SetSynchronizationPoint", &self.endpoint))
             .map(|_| ())
     }
 
+    // Unicast RTP over RTSP, see `get_stream_uri_with` for the other setups
     pub async fn get_stream_uri(&self) -> Result<Url, OnvifError> {
-        let req = schema::media::GetStreamUri {
+        self.get_stream_uri_with(StreamSetupOptions::default())
+            .await
+    }
+
+    pub async fn get_stream_uri_with(
+        &self,
+        options: StreamSetupOptions,
+    ) -> Result<Url, OnvifError> {
+        if matches!(options.stream_type, StreamType::RtpMulticast)
+            && self.supports(|c| c.streaming.rtp_multicast).await == Some(false)
+        {
+            return Err(OnvifError::OperationNotSupported(
+                "RTP multicast".to_string(),
+            ));
+        }
+        let req = &schema::media::GetStreamUri {
             stream_setup: schema::onvif::StreamSetup {
-                stream: StreamType::RtpUnicast,
+                stream: options.stream_type,
                 transport: Transport {
-                    protocol: TransportProtocol::Rtsp,
+                    protocol: options.protocol,
                     tunnel: Vec::new(),
                 },
             },
@@ -105,10 +781,655 @@ impl MediaClient {
             },
         };
 
-        schema::media::get_stream_uri(&self.inner, &req)
+        let uri = self
+            .conn
+            .retry
+            .run(|| async move {
+                schema::media::get_stream_uri(&self.inner, req)
+                    .await
+                    .map_err(transport_error("GetStreamUri", &self.endpoint))
+            })
+            .await?
+            .media_uri
+            .uri;
+        let url = Url::parse(&uri).map_err(|_| OnvifError::UrlParseError(uri))?;
+        let credentials = options
+            .credentials
+            .as_ref()
+            .map(|(user, pass)| (user.as_str(), pass.as_str()));
+        rewrite_url(&url, options.host.as_deref(), credentials)
+            .map_err(|_| OnvifError::UrlParseError(url.to_string()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamSetupOptions {
+    // `RtpMulticast` needs multicast to be configured on the profile
+    pub stream_type: StreamType,
+    // `Rtsp` for RTP over RTSP, `Http` for RTSP tunneled over HTTP, `Udp` for plain RTP
+    pub protocol: TransportProtocol,
+    // Replaces the host, and the port if given as `host:port`, of the returned uri
+    pub host: Option<String>,
+    // Put in the userinfo of the returned uri, for players that don't take them separately
+    pub credentials: Option<(String, String)>,
+}
+
+impl Default for StreamSetupOptions {
+    fn default() -> Self {
+        Self {
+            stream_type: StreamType::RtpUnicast,
+            protocol: TransportProtocol::Rtsp,
+            host: None,
+            credentials: None,
+        }
+    }
+}
+
+impl StreamSetupOptions {
+    pub fn new(stream_type: StreamType, protocol: TransportProtocol) -> Self {
+        Self {
+            stream_type,
+            protocol,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    pub fn with_credentials(mut self, user: &str, pass: &str) -> Self {
+        self.credentials = Some((user.to_string(), pass.to_string()));
+        self
+    }
+}
+
+impl MediaClient {
+    // Fetched once per client and its clones, see `refresh_service_capabilities`
+    pub async fn get_service_capabilities(&self) -> Result<MediaCapabilities, OnvifError> {
+        let cached = self
+            .capabilities
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        match cached {
+            Some(caps) => Ok(caps),
+            None => self.refresh_service_capabilities().await,
+        }
+    }
+
+    // E.g. after a firmware update or a change of the camera settings
+    pub async fn refresh_service_capabilities(&self) -> Result<MediaCapabilities, OnvifError> {
+        let req =
+            r#"<trt:GetServiceCapabilities xmlns:trt="http://www.onvif.org/ver10/media/wsdl"/>"#;
+        let resp = self
+            .conn
+            .retry
+            .run(|| async move {
+                self.inner
+                    .request(req)
+                    .await
+                    .map_err(fault_error("GetServiceCapabilities", &self.endpoint))
+            })
+            .await?;
+        let caps = MediaCapabilities::parse(&resp).ok_or(OnvifError::IncompleteResponse(
+            "the media service capabilities",
+        ))?;
+        *self.capabilities.lock().unwrap_or_else(|e| e.into_inner()) = Some(caps.clone());
+        Ok(caps)
+    }
+
+    // None when the camera doesn't answer GetServiceCapabilities, older ones predate it, in which
+    // case the operation is just tried
+    async fn supports(&self, check: impl FnOnce(&MediaCapabilities) -> bool) -> Option<bool> {
+        self.get_service_capabilities()
+            .await
+            .ok()
+            .map(|c| check(&c))
+    }
+
+    pub async fn get_snapshot_uri(&self) -> Result<Url, OnvifError> {
+        let req = &schema::media::GetSnapshotUri {
+            profile_token: ReferenceToken {
+                0: self
+                    .profile_token
+                    .as_ref()
+                    .ok_or(OnvifError::UnsetToken)?
+                    .0
+                    .to_string(),
+            },
+        };
+        let uri = self
+            .conn
+            .retry
+            .run(|| async move {
+                schema::media::get_snapshot_uri(&self.inner, req)
+                    .await
+                    .map_err(fault_error("GetSnapshotUri", &self.endpoint))
+            })
+            .await?
+            .media_uri
+            .uri;
+        let url = Url::parse(&uri).map_err(|_| OnvifError::UrlParseError(uri))?;
+        rewrite_url(&url, self.snapshot_host.as_deref(), None)
+            .map_err(|_| OnvifError::UrlParseError(url.to_string()))
+    }
+
+    // Fetches the snapshot with the client's credentials, answering a digest challenge if the
+    // camera sends one and falling back to basic auth otherwise
+    pub async fn fetch_snapshot(&self) -> Result<Snapshot, OnvifError> {
+        if self.supports(|c| c.snapshot_uri).await == Some(false) {
+            return Err(OnvifError::OperationNotSupported(
+                "snapshot uri".to_string(),
+            ));
+        }
+        let url = self.get_snapshot_uri().await?;
+        let http = self.conn.http.clone().unwrap_or_default();
+        let get = |url: Url| match self.conn.timeout {
+            Some(timeout) => http.get(url).timeout(timeout),
+            None => http.get(url),
+        };
+        let mut resp = get(url.clone()).send().await.map_err(http_error)?;
+        if let (StatusCode::UNAUTHORIZED, Some(creds)) = (resp.status(), &self.conn.auth) {
+            let challenge = resp
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or_default();
+            let authorization = if challenge.to_ascii_lowercase().starts_with("digest") {
+                let mut prompt =
+                    digest_auth::parse(challenge).map_err(|e| OnvifError::HttpError {
+                        status: Some(StatusCode::UNAUTHORIZED.as_u16()),
+                        reason: format!("unusable digest challenge: {}", e),
+                    })?;
+                let path = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                let context = digest_auth::AuthContext::new(
+                    creds.username.as_str(),
+                    creds.password.as_str(),
+                    path,
+                );
+                prompt
+                    .respond(&context)
+                    .map_err(|e| OnvifError::HttpError {
+                        status: Some(StatusCode::UNAUTHORIZED.as_u16()),
+                        reason: format!("unusable digest challenge: {}", e),
+                    })?
+                    .to_header_string()
+            } else {
+                let pair = format!("{}:{}", creds.username, creds.password);
+                format!("Basic {}", STANDARD.encode(pair))
+            };
+            resp = get(url)
+                .header(AUTHORIZATION, authorization)
+                .send()
+                .await
+                .map_err(http_error)?;
+        }
+        if !resp.status().is_success() {
+            return Err(OnvifError::HttpError {
+                status: Some(resp.status().as_u16()),
+                reason: "snapshot request was refused".to_string(),
+            });
+        }
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+        let data = resp.bytes().await.map_err(http_error)?.to_vec();
+        Ok(Snapshot { data, content_type })
+    }
+}
+
+const MEDIA_NS: &str = "http://www.onvif.org/ver10/media/wsdl";
+const SCHEMA_NS: &str = "http://www.onvif.org/ver10/schema";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OsdPosition {
+    UpperLeft,
+    UpperRight,
+    LowerLeft,
+    LowerRight,
+    // Normalized to -1..1, from the left and from the bottom
+    Custom { x: f32, y: f32 },
+}
+
+// Date and time formats are the camera's, e.g. `yyyy-MM-dd` and `HH:mm:ss`
+#[derive(Debug, Clone, PartialEq)]
+pub enum OsdContent {
+    Plain(String),
+    Date(String),
+    Time(String),
+    DateAndTime { date: String, time: String },
+    // Image OSDs and other kinds, only read back. Holds the type the camera gave.
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Osd {
+    // Empty until the camera assigned one, see `create_osd`
+    pub token: String,
+    pub video_source_configuration: String,
+    pub position: OsdPosition,
+    pub content: OsdContent,
+    pub font_size: Option<u32>,
+}
+
+impl Osd {
+    pub fn text(
+        video_source_configuration: &str,
+        position: OsdPosition,
+        content: OsdContent,
+    ) -> Self {
+        Self {
+            token: String::new(),
+            video_source_configuration: video_source_configuration.to_string(),
+            position,
+            content,
+            font_size: None,
+        }
+    }
+
+    pub fn with_font_size(mut self, size: u32) -> Self {
+        self.font_size = Some(size);
+        self
+    }
+
+    fn parse(osd: &Element<'_>) -> Self {
+        let text = |xml: &str, local: &str| {
+            elements(xml, local)
+                .first()
+                .map(|e| unescape(e.body.trim()))
+        };
+        let position = elements(osd.body, "Position").into_iter().next();
+        let position = match position.and_then(|p| text(p.body, "Type")).as_deref() {
+            Some("UpperRight") => OsdPosition::UpperRight,
+            Some("LowerLeft") => OsdPosition::LowerLeft,
+            Some("LowerRight") => OsdPosition::LowerRight,
+            Some("Custom") => {
+                let pos = position.and_then(|p| elements(p.body, "Pos").into_iter().next());
+                let coordinate = |name: &str| {
+                    pos.and_then(|p| p.attribute(name))
+                        .and_then(|v| v.trim().parse().ok())
+                        .unwrap_or(0.0)
+                };
+                OsdPosition::Custom {
+                    x: coordinate("x"),
+                    y: coordinate("y"),
+                }
+            }
+            _ => OsdPosition::UpperLeft,
+        };
+        let kind = text(osd.body, "Type").unwrap_or_default();
+        let string = elements(osd.body, "TextString").into_iter().next();
+        let field = |local: &str| string.and_then(|s| text(s.body, local)).unwrap_or_default();
+        let content = match (kind.as_str(), string) {
+            ("Text", Some(s)) => match text(s.body, "Type").as_deref() {
+                Some("Date") => OsdContent::Date(field("DateFormat")),
+                Some("Time") => OsdContent::Time(field("TimeFormat")),
+                Some("DateAndTime") => OsdContent::DateAndTime {
+                    date: field("DateFormat"),
+                    time: field("TimeFormat"),
+                },
+                _ => OsdContent::Plain(field("PlainText")),
+            },
+            _ => OsdContent::Other(kind),
+        };
+        Self {
+            token: osd.attribute("token").unwrap_or_default(),
+            video_source_configuration: text(osd.body, "VideoSourceConfigurationToken")
+                .unwrap_or_default(),
+            position,
+            content,
+            font_size: string
+                .and_then(|s| text(s.body, "FontSize"))
+                .and_then(|s| s.parse().ok()),
+        }
+    }
+
+    // The body of the `OSD` element of CreateOSD and SetOSD
+    fn to_xml(&self) -> String {
+        let position = match self.position {
+            OsdPosition::UpperLeft => "<tt:Type>UpperLeft</tt:Type>".to_string(),
+            OsdPosition::UpperRight => "<tt:Type>UpperRight</tt:Type>".to_string(),
+            OsdPosition::LowerLeft => "<tt:Type>LowerLeft</tt:Type>".to_string(),
+            OsdPosition::LowerRight => "<tt:Type>LowerRight</tt:Type>".to_string(),
+            OsdPosition::Custom { x, y } => format!(
+                r#"<tt:Type>Custom</tt:Type><tt:Pos x="{}" y="{}"/>"#,
+                x.clamp(-1.0, 1.0),
+                y.clamp(-1.0, 1.0)
+            ),
+        };
+        let element =
+            |local: &str, value: &str| format!("<tt:{0}>{1}</tt:{0}>", local, escape(value));
+        let (kind, formats, plain) = match &self.content {
+            OsdContent::Plain(text) => ("Plain", String::new(), element("PlainText", text)),
+            OsdContent::Date(date) => ("Date", element("DateFormat", date), String::new()),
+            OsdContent::Time(time) => ("Time", element("TimeFormat", time), String::new()),
+            OsdContent::DateAndTime { date, time } => (
+                "DateAndTime",
+                element("DateFormat", date) + &element("TimeFormat", time),
+                String::new(),
+            ),
+            // Sent back as a plain text OSD, the other kinds can't be written here
+            OsdContent::Other(_) => ("Plain", String::new(), String::new()),
+        };
+        let font_size = self
+            .font_size
+            .map(|s| element("FontSize", &s.to_string()))
+            .unwrap_or_default();
+        format!(
+            concat!(
+                r#"<trt:OSD token="{}" xmlns:tt="{}">"#,
+                "{}",
+                "<tt:Type>Text</tt:Type>",
+                "<tt:Position>{}</tt:Position>",
+                "<tt:TextString><tt:Type>{}</tt:Type>{}{}{}</tt:TextString>",
+                "</trt:OSD>",
+            ),
+            escape(&self.token),
+            SCHEMA_NS,
+            element(
+                "VideoSourceConfigurationToken",
+                &self.video_source_configuration
+            ),
+            position,
+            kind,
+            formats,
+            font_size,
+            plain
+        )
+    }
+}
+
+impl MediaClient {
+    // OSDs of a video source configuration, see `get_profile_video_source_configuration`
+    pub async fn get_osds(&self, configuration_token: &str) -> Result<Vec<Osd>, OnvifError> {
+        let req = format!(
+            concat!(
+                r#"<trt:GetOSDs xmlns:trt="{}">"#,
+                "<trt:ConfigurationToken>{}</trt:ConfigurationToken>",
+                "</trt:GetOSDs>",
+            ),
+            MEDIA_NS,
+            escape(configuration_token)
+        );
+        let req = req.as_str();
+        let resp = self
+            .conn
+            .retry
+            .run(|| async move {
+                self.inner
+                    .request(req)
+                    .await
+                    .map_err(fault_error("GetOSDs", &self.endpoint))
+            })
+            .await?;
+        Ok(elements(&resp, "OSDs").iter().map(Osd::parse).collect())
+    }
+
+    // Returns the token the camera gave the OSD. Never retried, see `RetryPolicy`.
+    pub async fn create_osd(&self, osd: &Osd) -> Result<String, OnvifError> {
+        let req = format!(
+            r#"<trt:CreateOSD xmlns:trt="{}">{}</trt:CreateOSD>"#,
+            MEDIA_NS,
+            osd.to_xml()
+        );
+        let resp = self
+            .inner
+            .request(&req)
+            .await
+            .map_err(osd_error("CreateOSD", &self.endpoint))?;
+        elements(&resp, "OSDToken")
+            .first()
+            .map(|t| unescape(t.body.trim()))
+            .ok_or(OnvifError::IncompleteResponse("the token of the new OSD"))
+    }
+
+    // Replaces the OSD with the token of `osd`
+    pub async fn set_osd(&self, osd: &Osd) -> Result<(), OnvifError> {
+        let req = format!(
+            r#"<trt:SetOSD xmlns:trt="{}">{}</trt:SetOSD>"#,
+            MEDIA_NS,
+            osd.to_xml()
+        );
+        self.inner
+            .request(&req)
+            .await
+            .map_err(osd_error("SetOSD", &self.endpoint))
+            .map(|_| ())
+    }
+
+    pub async fn delete_osd(&self, token: &str) -> Result<(), OnvifError> {
+        let req = format!(
+            r#"<trt:DeleteOSD xmlns:trt="{}"><trt:OSDToken>{}</trt:OSDToken></trt:DeleteOSD>"#,
+            MEDIA_NS,
+            escape(token)
+        );
+        self.inner
+            .request(&req)
             .await
-            .map_err(|e| OnvifError::TransportError(e))
-            .map(|u| Url::parse(&u.media_uri.uri).map_err(|_| OnvifError::UrlParseError))?
+            .map_err(fault_error("DeleteOSD", &self.endpoint))
+            .map(|_| ())
+    }
+
+    // Leaves exactly one plain text OSD showing `text` on the profile's video source, at
+    // `position` and `font_size`: an existing one is updated if needed and any duplicates are
+    // deleted, otherwise one is created. Returns its token.
+    pub async fn ensure_text_osd(
+        &self,
+        text: &str,
+        position: OsdPosition,
+        font_size: Option<u32>,
+    ) -> Result<String, OnvifError> {
+        let configuration = self.get_profile_video_source_configuration().await?;
+        let mut wanted = Osd::text(
+            &configuration,
+            position,
+            OsdContent::Plain(text.to_string()),
+        );
+        wanted.font_size = font_size;
+
+        let mut matching = self
+            .get_osds(&configuration)
+            .await?
+            .into_iter()
+            .filter(|o| o.content == wanted.content);
+        let Some(existing) = matching.next() else {
+            return self.create_osd(&wanted).await;
+        };
+        for duplicate in matching {
+            info!(token = %duplicate.token, text, "Deleting a duplicate OSD");
+            self.delete_osd(&duplicate.token).await?;
+        }
+        wanted.token = existing.token.clone();
+        // Cameras leave the font size out when it is their default
+        if existing.position != wanted.position
+            || (wanted.font_size.is_some() && existing.font_size != wanted.font_size)
+        {
+            self.set_osd(&wanted).await?;
+        }
+        Ok(wanted.token)
+    }
+}
+
+// Cameras refuse OSDs past the number their video source supports with `ter:MaxOSDs`
+fn osd_error(
+    operation: &'static str,
+    endpoint: &Url,
+) -> impl FnOnce(transport::Error) -> OnvifError {
+    let other = fault_error(operation, endpoint);
+    move |e| {
+        if e.to_string().contains("MaxOSDs") {
+            OnvifError::MaxOsdsReached
+        } else {
+            other(e)
+        }
+    }
+}
+
+impl MediaClient {
+    pub async fn get_video_encoder_configurations(
+        &self,
+    ) -> Result<Vec<VideoEncoderConfiguration>, OnvifError> {
+        self.conn
+            .retry
+            .run(|| async move {
+                schema::media::get_video_encoder_configurations(&self.inner, &Default::default())
+                    .await
+                    .map_err(transport_error(
+                        "GetVideoEncoderConfigurations",
+                        &self.endpoint,
+                    ))
+            })
+            .await
+            .map(|r| r.configurations)
+    }
+
+    // Cameras drop combinations they can't encode without a fault, so the configuration is read
+    // back and compared. Fails with `ConfigurationRejected` if it didn't stick.
+    pub async fn set_video_encoder_configuration(
+        &self,
+        config: VideoEncoderConfiguration,
+    ) -> Result<(), OnvifError> {
+        let token = config.token.0.clone();
+        let expected = (
+            config.encoding.clone(),
+            (config.resolution.width, config.resolution.height),
+            config.rate_control.as_ref().map(|r| r.frame_rate_limit),
+        );
+        let req = schema::media::SetVideoEncoderConfiguration {
+            configuration: config,
+            force_persistence: true,
+        };
+        schema::media::set_video_encoder_configuration(&self.inner, &req)
+            .await
+            .map_err(fault_error("SetVideoEncoderConfiguration", &self.endpoint))?;
+
+        let applied = self
+            .get_video_encoder_configurations()
+            .await?
+            .into_iter()
+            .find(|c| c.token.0 == token)
+            .ok_or_else(|| OnvifError::ConfigurationRejected(token.clone()))?;
+        let actual = (
+            applied.encoding,
+            (applied.resolution.width, applied.resolution.height),
+            applied.rate_control.map(|r| r.frame_rate_limit),
+        );
+        if actual != expected {
+            return Err(OnvifError::ConfigurationRejected(token));
+        }
+        Ok(())
+    }
+
+    // Valid resolutions, frame rates and bitrates for the encoder of the profile set with
+    // `with_token`
+    pub async fn get_video_encoder_configuration_options(
+        &self,
+    ) -> Result<VideoEncoderConfigurationOptions, OnvifError> {
+        let req = schema::media::GetVideoEncoderConfigurationOptions {
+            configuration_token: None,
+            profile_token: Some(ReferenceToken {
+                0: self
+                    .profile_token
+                    .as_ref()
+                    .ok_or(OnvifError::UnsetToken)?
+                    .0
+                    .to_string(),
+            }),
+        };
+        schema::media::get_video_encoder_configuration_options(&self.inner, &req)
+            .await
+            .map_err(transport_error(
+                "GetVideoEncoderConfigurationOptions",
+                &self.endpoint,
+            ))
+            .map(|r| r.options)
+    }
+
+    // Switches the encoder of the profile to H.264 at the largest resolution that fits in
+    // `max_resolution`, unless it already is. Returns the configuration in use afterwards.
+    pub async fn ensure_h264(
+        &self,
+        max_resolution: (u32, u32),
+    ) -> Result<VideoEncoderConfiguration, OnvifError> {
+        let token = &self.profile_token.as_ref().ok_or(OnvifError::UnsetToken)?.0;
+        let mut config = self
+            .get_profiles()
+            .await?
+            .into_iter()
+            .find(|p| p.token.0 == *token)
+            .and_then(|p| p.video_encoder_configuration)
+            .ok_or(OnvifError::IncompleteResponse("an encoder for the profile"))?;
+        let fits = |r: &VideoResolution| {
+            r.width as u32 <= max_resolution.0 && r.height as u32 <= max_resolution.1
+        };
+        if config.encoding == VideoEncoding::H264 && fits(&config.resolution) {
+            return Ok(config);
+        }
+
+        let h264 = self
+            .get_video_encoder_configuration_options()
+            .await?
+            .h264
+            .ok_or_else(|| OnvifError::OperationNotSupported("H.264 encoding".to_string()))?;
+        let resolution = h264
+            .resolutions_available
+            .iter()
+            .filter(|r| fits(r))
+            .max_by_key(|r| r.width as i64 * r.height as i64)
+            .ok_or_else(|| OnvifError::UnsupportedMode {
+                setting: "H.264 resolution",
+                mode: format!("at most {}x{}", max_resolution.0, max_resolution.1),
+            })?;
+        config.encoding = VideoEncoding::H264;
+        config.resolution = VideoResolution {
+            width: resolution.width,
+            height: resolution.height,
+        };
+        if config.h264.is_none() {
+            config.h264 = Some(H264Configuration {
+                gov_length: h264
+                    .gov_length_range
+                    .max
+                    .min(50)
+                    .max(h264.gov_length_range.min),
+                h264_profile: h264
+                    .h264_profiles_supported
+                    .first()
+                    .cloned()
+                    .unwrap_or_default(),
+            });
+        }
+        info!(
+            token = %config.token.0,
+            width = resolution.width,
+            height = resolution.height,
+            "Switching the profile encoder to H.264"
+        );
+        self.set_video_encoder_configuration(config.clone()).await?;
+        Ok(config)
+    }
+}
+
+fn http_error(e: reqwest::Error) -> OnvifError {
+    if e.is_timeout() {
+        return OnvifError::Timeout {
+            operation: "snapshot download",
+            endpoint: e.url().map(Url::to_string).unwrap_or_default(),
+        };
+    }
+    if e.is_connect() && is_tls_failure(&e.to_string()) {
+        return OnvifError::TlsError(e.to_string());
+    }
+    OnvifError::HttpError {
+        status: e.status().map(|s| s.as_u16()),
+        reason: e.to_string(),
     }
 }
 
@@ -120,20 +1441,464 @@ impl Clone for MediaClient {
 
         Self {
             inner: self.inner.clone(),
+            endpoint: self.endpoint.clone(),
             profile_token: cloned_token,
+            video_source_token: self.video_source_token.clone(),
+            conn: self.conn.clone(),
+            snapshot_host: self.snapshot_host.clone(),
+            capabilities: Arc::clone(&self.capabilities),
         }
     }
 }
 
+#[async_trait]
+impl MediaOps for MediaClient {
+    async fn get_profile_summaries(&self) -> Result<Vec<ProfileSummary>, OnvifError> {
+        Ok(self
+            .get_profiles()
+            .await?
+            .iter()
+            .map(ProfileSummary::of)
+            .collect())
+    }
+
+    fn select_profile(&mut self, token: &str) {
+        self.profile_token = Some(ReferenceToken(token.to_string()));
+        self.video_source_token = None;
+    }
+
+    async fn get_stream_uri(&self) -> Result<Url, OnvifError> {
+        MediaClient::get_stream_uri(self).await
+    }
+
+    async fn sync_iframe(&self) -> Result<(), OnvifError> {
+        MediaClient::sync_iframe(self).await
+    }
+}
+
 impl ClientWrapper for MediaClient {
-    fn connect(endpoint: &Url, auth: Option<Credentials>) -> Self {
-        let cli = ClientBuilder::new(&endpoint).credentials(auth).build();
+    fn connect(endpoint: &Url, conn: &Connection) -> Self {
         Self {
-            inner: cli,
+            inner: conn.soap_client(endpoint),
+            endpoint: endpoint.clone(),
             profile_token: None,
+            video_source_token: None,
+            conn: conn.clone(),
+            snapshot_host: None,
+            capabilities: Arc::new(Mutex::new(None)),
         }
     }
     fn get_service_name() -> String {
         "media".to_string()
     }
 }
+
+// Moves are in the device's default coordinate spaces, normalized to -1..1 for pan/tilt and 0..1
+// for zoom on most cameras
+pub struct PtzClient {
+    inner: Client,
+    endpoint: Url,
+    profile_token: Option<ReferenceToken>,
+}
+
+impl PtzClient {
+    // Token of a profile with a PTZ configuration, see `MediaClient::get_profiles`
+    pub fn with_token(mut self, token: ReferenceToken) -> Self {
+        self.profile_token = Some(token);
+        self
+    }
+
+    pub async fn absolute_move(&self, pan: f64, tilt: f64, zoom: f64) -> Result<(), OnvifError> {
+        let req = schema::ptz::AbsoluteMove {
+            profile_token: self.token()?,
+            position: ptz_vector(pan, tilt, zoom),
+            speed: None,
+        };
+        schema::ptz::absolute_move(&self.inner, &req)
+            .await
+            .map_err(fault_error("AbsoluteMove", &self.endpoint))
+            .map(|_| ())
+    }
+
+    pub async fn relative_move(&self, pan: f64, tilt: f64, zoom: f64) -> Result<(), OnvifError> {
+        let req = schema::ptz::RelativeMove {
+            profile_token: self.token()?,
+            translation: ptz_vector(pan, tilt, zoom),
+            speed: None,
+        };
+        schema::ptz::relative_move(&self.inner, &req)
+            .await
+            .map_err(fault_error("RelativeMove", &self.endpoint))
+            .map(|_| ())
+    }
+
+    // Keeps moving until `stop`
+    pub async fn continuous_move(&self, vx: f64, vy: f64, vzoom: f64) -> Result<(), OnvifError> {
+        let velocity = ptz_vector(vx, vy, vzoom);
+        let req = schema::ptz::ContinuousMove {
+            profile_token: self.token()?,
+            velocity: Ptzspeed {
+                pan_tilt: velocity.pan_tilt,
+                zoom: velocity.zoom,
+            },
+            timeout: None,
+        };
+        schema::ptz::continuous_move(&self.inner, &req)
+            .await
+            .map_err(fault_error("ContinuousMove", &self.endpoint))
+            .map(|_| ())
+    }
+
+    pub async fn stop(&self) -> Result<(), OnvifError> {
+        let req = schema::ptz::Stop {
+            profile_token: self.token()?,
+            pan_tilt: Some(true),
+            zoom: Some(true),
+        };
+        schema::ptz::stop(&self.inner, &req)
+            .await
+            .map_err(fault_error("Stop", &self.endpoint))
+            .map(|_| ())
+    }
+
+    pub async fn goto_preset(&self, token: &str) -> Result<(), OnvifError> {
+        let req = schema::ptz::GotoPreset {
+            profile_token: self.token()?,
+            preset_token: ReferenceToken {
+                0: token.to_string(),
+            },
+            speed: None,
+        };
+        schema::ptz::goto_preset(&self.inner, &req)
+            .await
+            .map_err(fault_error("GotoPreset", &self.endpoint))
+            .map(|_| ())
+    }
+
+    // Saves the current position as a new preset, returns its token
+    pub async fn set_preset(&self, name: &str) -> Result<String, OnvifError> {
+        let req = schema::ptz::SetPreset {
+            profile_token: self.token()?,
+            preset_name: Some(name.to_string()),
+            preset_token: None,
+        };
+        schema::ptz::set_preset(&self.inner, &req)
+            .await
+            .map_err(fault_error("SetPreset", &self.endpoint))
+            .map(|r| r.preset_token.0)
+    }
+
+    pub async fn get_presets(&self) -> Result<Vec<Ptzpreset>, OnvifError> {
+        let req = schema::ptz::GetPresets {
+            profile_token: self.token()?,
+        };
+        schema::ptz::get_presets(&self.inner, &req)
+            .await
+            .map_err(fault_error("GetPresets", &self.endpoint))
+            .map(|r| r.preset)
+    }
+
+    fn token(&self) -> Result<ReferenceToken, OnvifError> {
+        Ok(ReferenceToken {
+            0: self
+                .profile_token
+                .as_ref()
+                .ok_or(OnvifError::UnsetToken)?
+                .0
+                .to_string(),
+        })
+    }
+}
+
+impl Clone for PtzClient {
+    fn clone(&self) -> Self {
+        let cloned_token = self.profile_token.as_ref().map(|p_token| ReferenceToken {
+            0: p_token.0.to_string(),
+        });
+
+        Self {
+            inner: self.inner.clone(),
+            endpoint: self.endpoint.clone(),
+            profile_token: cloned_token,
+        }
+    }
+}
+
+impl ClientWrapper for PtzClient {
+    fn connect(endpoint: &Url, conn: &Connection) -> Self {
+        let cli = conn.soap_client(endpoint);
+        Self {
+            inner: cli,
+            endpoint: endpoint.clone(),
+            profile_token: None,
+        }
+    }
+    fn get_service_name() -> String {
+        "ptz".to_string()
+    }
+}
+
+fn ptz_vector(pan: f64, tilt: f64, zoom: f64) -> Ptzvector {
+    Ptzvector {
+        pan_tilt: Some(Vector2D {
+            x: pan,
+            y: tilt,
+            space: None,
+        }),
+        zoom: Some(Vector1D {
+            x: zoom,
+            space: None,
+        }),
+    }
+}
+
+// Cameras without support for an operation answer with a SOAP fault, e.g. PTZ calls on a fixed
+// camera, rather than leaving the service out of their capabilities
+pub(super) fn fault_error(
+    operation: &'static str,
+    endpoint: &Url,
+) -> impl FnOnce(transport::Error) -> OnvifError {
+    let other = transport_error(operation, endpoint);
+    move |e| {
+        let fault = e.to_string();
+        if [
+            "ActionNotSupported",
+            "NoPTZProfile",
+            "NotSupported",
+            "NoSuchService",
+        ]
+        .iter()
+        .any(|code| fault.contains(code))
+        {
+            OnvifError::OperationNotSupported(operation.to_string())
+        } else {
+            other(e)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExposureMode {
+    Auto,
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrCutFilterMode {
+    // Filter in, day mode
+    On,
+    // Filter out, night mode
+    Off,
+    Auto,
+}
+
+// `None` fields are left as the camera has them when setting
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImagingSettings {
+    pub brightness: Option<f64>,
+    pub contrast: Option<f64>,
+    pub saturation: Option<f64>,
+    pub exposure_mode: Option<ExposureMode>,
+    pub ir_cut_filter: Option<IrCutFilterMode>,
+}
+
+// Ranges are `(min, max)`, `None` where the camera doesn't advertise one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImagingOptions {
+    pub brightness: Option<(f64, f64)>,
+    pub contrast: Option<(f64, f64)>,
+    pub saturation: Option<(f64, f64)>,
+    pub exposure_modes: Vec<ExposureMode>,
+    pub ir_cut_filter_modes: Vec<IrCutFilterMode>,
+}
+
+impl ImagingOptions {
+    pub fn clamp(&self, settings: &ImagingSettings) -> ImagingSettings {
+        let clamp = |v: Option<f64>, range: Option<(f64, f64)>| match (v, range) {
+            (Some(v), Some((min, max))) => Some(v.clamp(min, max.max(min))),
+            (v, _) => v,
+        };
+        ImagingSettings {
+            brightness: clamp(settings.brightness, self.brightness),
+            contrast: clamp(settings.contrast, self.contrast),
+            saturation: clamp(settings.saturation, self.saturation),
+            ..settings.clone()
+        }
+    }
+
+    // Fails on the first value outside the advertised ranges or modes
+    pub fn check(&self, settings: &ImagingSettings) -> Result<(), OnvifError> {
+        let ranges = [
+            ("brightness", settings.brightness, self.brightness),
+            ("contrast", settings.contrast, self.contrast),
+            ("saturation", settings.saturation, self.saturation),
+        ];
+        for (setting, value, range) in ranges {
+            if let (Some(value), Some((min, max))) = (value, range) {
+                if value < min || value > max {
+                    return Err(OnvifError::OutOfRange {
+                        setting,
+                        value,
+                        min,
+                        max,
+                    });
+                }
+            }
+        }
+        if let Some(mode) = settings.exposure_mode {
+            if !self.exposure_modes.is_empty() && !self.exposure_modes.contains(&mode) {
+                return Err(OnvifError::UnsupportedMode {
+                    setting: "exposure",
+                    mode: format!("{:?}", mode),
+                });
+            }
+        }
+        if let Some(mode) = settings.ir_cut_filter {
+            if !self.ir_cut_filter_modes.is_empty() && !self.ir_cut_filter_modes.contains(&mode) {
+                return Err(OnvifError::UnsupportedMode {
+                    setting: "ir cut filter",
+                    mode: format!("{:?}", mode),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Scoped to a video source rather than a profile, see `MediaClient::get_video_sources`
+#[derive(Clone)]
+pub struct ImagingClient {
+    inner: Client,
+    endpoint: Url,
+}
+
+impl ImagingClient {
+    pub async fn get_imaging_settings(
+        &self,
+        video_source: &str,
+    ) -> Result<ImagingSettings, OnvifError> {
+        let raw = self.raw_settings(video_source).await?;
+        Ok(ImagingSettings {
+            brightness: raw.brightness,
+            contrast: raw.contrast,
+            saturation: raw.color_saturation,
+            exposure_mode: raw.exposure.and_then(|e| match e.mode {
+                tt::ExposureMode::Auto => Some(ExposureMode::Auto),
+                tt::ExposureMode::Manual => Some(ExposureMode::Manual),
+                _ => None,
+            }),
+            ir_cut_filter: raw.ir_cut_filter.and_then(ir_cut_from_raw),
+        })
+    }
+
+    // Checked against the camera's options first when it advertises them. The current settings
+    // are read back and only the fields set in `settings` are changed.
+    pub async fn set_imaging_settings(
+        &self,
+        video_source: &str,
+        settings: &ImagingSettings,
+    ) -> Result<(), OnvifError> {
+        if let Ok(options) = self.get_options(video_source).await {
+            options.check(settings)?;
+        }
+        let mut raw = self.raw_settings(video_source).await?;
+        raw.brightness = settings.brightness.or(raw.brightness);
+        raw.contrast = settings.contrast.or(raw.contrast);
+        raw.color_saturation = settings.saturation.or(raw.color_saturation);
+        if let Some(mode) = settings.exposure_mode {
+            raw.exposure.get_or_insert_with(Default::default).mode = match mode {
+                ExposureMode::Auto => tt::ExposureMode::Auto,
+                ExposureMode::Manual => tt::ExposureMode::Manual,
+            };
+        }
+        if let Some(mode) = settings.ir_cut_filter {
+            raw.ir_cut_filter = Some(match mode {
+                IrCutFilterMode::On => tt::IrCutFilterMode::On,
+                IrCutFilterMode::Off => tt::IrCutFilterMode::Off,
+                IrCutFilterMode::Auto => tt::IrCutFilterMode::Auto,
+            });
+        }
+
+        let req = schema::imaging::SetImagingSettings {
+            video_source_token: ReferenceToken {
+                0: video_source.to_string(),
+            },
+            imaging_settings: raw,
+            force_persistence: None,
+        };
+        schema::imaging::set_imaging_settings(&self.inner, &req)
+            .await
+            .map_err(transport_error("SetImagingSettings", &self.endpoint))
+            .map(|_| ())
+    }
+
+    pub async fn get_options(&self, video_source: &str) -> Result<ImagingOptions, OnvifError> {
+        let req = schema::imaging::GetOptions {
+            video_source_token: ReferenceToken {
+                0: video_source.to_string(),
+            },
+        };
+        let options = schema::imaging::get_options(&self.inner, &req)
+            .await
+            .map_err(transport_error("GetOptions", &self.endpoint))?
+            .imaging_options;
+        let range = |r: Option<FloatRange>| r.map(|r| (r.min, r.max));
+        Ok(ImagingOptions {
+            brightness: range(options.brightness),
+            contrast: range(options.contrast),
+            saturation: range(options.color_saturation),
+            exposure_modes: options
+                .exposure
+                .map(|e| {
+                    e.mode
+                        .into_iter()
+                        .filter_map(|m| match m {
+                            tt::ExposureMode::Auto => Some(ExposureMode::Auto),
+                            tt::ExposureMode::Manual => Some(ExposureMode::Manual),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ir_cut_filter_modes: options
+                .ir_cut_filter_modes
+                .into_iter()
+                .filter_map(ir_cut_from_raw)
+                .collect(),
+        })
+    }
+
+    async fn raw_settings(&self, video_source: &str) -> Result<ImagingSettings20, OnvifError> {
+        let req = schema::imaging::GetImagingSettings {
+            video_source_token: ReferenceToken {
+                0: video_source.to_string(),
+            },
+        };
+        schema::imaging::get_imaging_settings(&self.inner, &req)
+            .await
+            .map_err(transport_error("GetImagingSettings", &self.endpoint))
+            .map(|r| r.imaging_settings)
+    }
+}
+
+impl ClientWrapper for ImagingClient {
+    fn connect(endpoint: &Url, conn: &Connection) -> Self {
+        let cli = conn.soap_client(endpoint);
+        Self {
+            inner: cli,
+            endpoint: endpoint.clone(),
+        }
+    }
+    fn get_service_name() -> String {
+        "imaging".to_string()
+    }
+}
+
+fn ir_cut_from_raw(mode: tt::IrCutFilterMode) -> Option<IrCutFilterMode> {
+    match mode {
+        tt::IrCutFilterMode::On => Some(IrCutFilterMode::On),
+        tt::IrCutFilterMode::Off => Some(IrCutFilterMode::Off),
+        tt::IrCutFilterMode::Auto => Some(IrCutFilterMode::Auto),
+        _ => None,
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use reqwest::Certificate;
+
+use super::OnvifError;
+
+// TLS settings of every request made to the device, SOAP and snapshot fetches alike
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    accept_invalid_certs: bool,
+    ca_certs: Vec<Certificate>,
+    upgrade_http: bool,
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // For the self-signed certificates cameras ship with. The traffic is still encrypted but
+    // anyone on the path can pose as the camera.
+    pub fn with_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    // PEM, may hold several certificates. They are trusted on top of the bundled roots.
+    pub fn with_ca_bundle(mut self, pem: &[u8]) -> Result<Self, OnvifError> {
+        let certs = Certificate::from_pem_bundle(pem)
+            .map_err(|e| OnvifError::TlsError(format!("invalid ca bundle: {}", e)))?;
+        self.ca_certs.extend(certs);
+        Ok(self)
+    }
+
+    // Switches service urls the device advertises as http to https, for devices serving every
+    // service over both but only listing the http ones
+    pub fn with_https_upgrade(mut self, upgrade: bool) -> Self {
+        self.upgrade_http = upgrade;
+        self
+    }
+
+    pub(super) fn upgrade_http(&self) -> bool {
+        self.upgrade_http
+    }
+
+    pub(super) fn http_client(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<reqwest::Client, OnvifError> {
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        for cert in &self.ca_certs {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        builder
+            .build()
+            .map_err(|e| OnvifError::TlsError(e.to_string()))
+    }
+}
@@ -0,0 +1,80 @@
+// Cheap cameras get namespaces and escaping wrong often enough that a full XML parser would reject
+// answers that are perfectly usable, so elements are matched by local name only
+
+#[derive(Clone, Copy)]
+pub(super) struct Element<'a> {
+    // Everything between `<` and `>` of the opening tag, name and attributes
+    pub(super) tag: &'a str,
+    // Empty for self-closing elements
+    pub(super) body: &'a str,
+}
+
+impl Element<'_> {
+    pub(super) fn attribute(&self, local: &str) -> Option<String> {
+        let mut rest = self.tag;
+        while let Some(eq) = rest.find('=') {
+            let name = rest[..eq].split_whitespace().last().unwrap_or_default();
+            let value = rest[eq + 1..].trim_start();
+            let quote = value.chars().next()?;
+            let end = value[1..].find(quote)?;
+            if name.rsplit(':').next() == Some(local) {
+                return Some(unescape(&value[1..end + 1]));
+            }
+            rest = &value[end + 2..];
+        }
+        None
+    }
+}
+
+// Every `local` element, whatever its namespace prefix. Nested elements of the same name are
+// part of the outer one's body.
+pub(super) fn elements<'a>(xml: &'a str, local: &str) -> Vec<Element<'a>> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        if name.rsplit(':').next() != Some(local) {
+            continue;
+        }
+        if let Some(tag) = tag.strip_suffix('/') {
+            found.push(Element { tag, body: "" });
+            rest = &rest[end + 1..];
+            continue;
+        }
+        let body = &rest[end + 1..];
+        let close = format!("</{}>", name);
+        let Some(stop) = body.find(&close) else {
+            break;
+        };
+        found.push(Element {
+            tag,
+            body: &body[..stop],
+        });
+        rest = &body[stop + close.len()..];
+    }
+    found
+}
+
+pub(super) fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+pub(super) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,76 @@
+use bytes::Bytes;
+
+use super::worker::RawFrame;
+use crate::decoders::NalType;
+
+// Puts access units back together for cameras whose slices retina hands out as separate frames,
+// see `SessionConfig::aggregate_slices`. A frame starts a new picture when its timestamp differs
+// from the one being assembled, when it holds an AUD or parameter sets, or when its first slice
+// starts at macroblock 0. So the last picture is only complete once the next one begins.
+#[derive(Default)]
+pub(super) struct AccessUnitAssembler {
+    pending: Option<RawFrame>,
+    // Frames merged into `pending`, past the first
+    merged: u64,
+}
+
+impl AccessUnitAssembler {
+    // The access unit completed by `frame`, if any, and the slices merged into it
+    pub(super) fn push(&mut self, frame: RawFrame) -> Option<(RawFrame, u64)> {
+        let Some(mut pending) = self.pending.take() else {
+            self.pending = Some(frame);
+            return None;
+        };
+        if pending.timestamp.timestamp() != frame.timestamp.timestamp() || starts_picture(&frame) {
+            self.pending = Some(frame);
+            return Some((pending, std::mem::take(&mut self.merged)));
+        }
+
+        let mut data = Vec::with_capacity(pending.data.len() + frame.data.len());
+        data.extend_from_slice(&pending.data);
+        data.extend_from_slice(&frame.data);
+        pending.data = Bytes::from(data);
+        pending.random_access |= frame.random_access;
+        pending.loss = pending.loss.saturating_add(frame.loss);
+        self.pending = Some(pending);
+        self.merged += 1;
+        None
+    }
+
+    // Drops the picture being assembled, its remaining slices are gone with the connection
+    pub(super) fn clear(&mut self) {
+        self.pending = None;
+        self.merged = 0;
+    }
+}
+
+// Looks at the NALs of an AVCC frame with 4 byte lengths up to its first slice
+fn starts_picture(frame: &RawFrame) -> bool {
+    let data = &frame.data;
+    let mut index = 0;
+    while index + 4 <= data.len() {
+        let len = u32::from_be_bytes([
+            data[index],
+            data[index + 1],
+            data[index + 2],
+            data[index + 3],
+        ]) as usize;
+        index += 4;
+        let Some(nal) = data.get(index..index + len) else {
+            break;
+        };
+        index += len;
+        let Some(header) = nal.first() else {
+            continue;
+        };
+        match NalType::from_header(*header) {
+            NalType::Aud | NalType::Sps | NalType::Pps => return true,
+            // `first_mb_in_slice` is the first field of the slice header, an exp-Golomb 0 is a
+            // single set bit
+            NalType::Slice | NalType::Idr => return nal.get(1).is_some_and(|b| b & 0x80 != 0),
+            _ => {}
+        }
+    }
+    // Nothing to tell by, taken as a frame of its own like without aggregation
+    true
+}
@@ -0,0 +1,270 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use mp4::{AvcConfig, MediaConfig, Mp4Config, Mp4Sample, Mp4Writer, TrackConfig, TrackType};
+use tokio::sync::oneshot;
+
+use super::{streams::avcc_parameter_sets, SessionError};
+use crate::decoders::NalType;
+
+// Asks the decode worker for the buffered GOPs covering the last `duration`
+pub(super) struct ClipRequest {
+    pub(super) duration: Duration,
+    // avcC record of the stream, for when the iframes don't carry SPS/PPS in-band
+    pub(super) parameters: Option<Vec<u8>>,
+    pub(super) resolution: Option<(u32, u32)>,
+    pub(super) reply: oneshot::Sender<Result<Clip, SessionError>>,
+}
+
+pub(super) struct ClipSample {
+    // AVCC, which is how MP4 stores H.264 samples. The buffered frame's own bytes.
+    pub(super) data: Bytes,
+    // RTP timestamp, in `clock_rate` units. Samples are in decode order, so with B-frames these
+    // aren't increasing.
+    pub(super) ts: i64,
+    pub(super) random_access: bool,
+}
+
+pub(super) struct Clip {
+    pub(super) samples: Vec<ClipSample>,
+    pub(super) clock_rate: u32,
+    pub(super) parameters: Option<Vec<u8>>,
+    pub(super) resolution: Option<(u32, u32)>,
+}
+
+impl Clip {
+    // In-band parameter sets of the first iframe win over the SDP ones, they are what the camera
+    // actually encoded with
+    fn parameter_sets(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (mut sps, mut pps) = (None, None);
+        if let Some(iframe) = self.samples.first() {
+            let mut index = 0;
+            while index + 4 <= iframe.data.len() {
+                let len = u32::from_be_bytes([
+                    iframe.data[index],
+                    iframe.data[index + 1],
+                    iframe.data[index + 2],
+                    iframe.data[index + 3],
+                ]) as usize;
+                index += 4;
+                let Some(nal) = iframe.data.get(index..index + len) else {
+                    break;
+                };
+                match nal.first().map(|h| NalType::from_header(*h)) {
+                    Some(NalType::Sps) => sps = sps.or(Some(nal.to_vec())),
+                    Some(NalType::Pps) => pps = pps.or(Some(nal.to_vec())),
+                    _ => {}
+                }
+                index += len;
+            }
+        }
+        if let Some(avcc) = &self.parameters {
+            let (sdp_sps, sdp_pps) = avcc_parameter_sets(avcc);
+            sps = sps.or(sdp_sps.into_iter().next());
+            pps = pps.or(sdp_pps.into_iter().next());
+        }
+        sps.zip(pps)
+    }
+
+    // Muxes the samples as they are, without re-encoding. Returns the duration of the clip. The
+    // file only appears at `path` once complete, a failed or interrupted write leaves nothing
+    // behind there.
+    pub(super) fn write_mp4(self, path: &Path) -> Result<Duration, SessionError> {
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let res = self
+            .write_mp4_to(&tmp)
+            .and_then(|duration| fs::rename(&tmp, path).map(|_| duration).map_err(failed));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res
+    }
+
+    fn write_mp4_to(self, path: &Path) -> Result<Duration, SessionError> {
+        let (seq_param_set, pic_param_set) = self
+            .parameter_sets()
+            .ok_or_else(|| failed("no SPS/PPS in the stream parameters or the iframe"))?;
+        let (width, height) = self.resolution.unwrap_or_default();
+
+        let file = File::create(path).map_err(failed)?;
+        let config = Mp4Config {
+            major_brand: "isom".parse().map_err(failed)?,
+            minor_version: 512,
+            compatible_brands: ["isom", "iso2", "avc1", "mp41"]
+                .iter()
+                .filter_map(|b| b.parse().ok())
+                .collect(),
+            timescale: 1000,
+        };
+        let mut writer = Mp4Writer::write_start(BufWriter::new(file), &config).map_err(failed)?;
+        writer
+            .add_track(&TrackConfig {
+                track_type: TrackType::Video,
+                timescale: self.clock_rate,
+                language: "und".to_string(),
+                media_conf: MediaConfig::AvcConfig(AvcConfig {
+                    width: width as u16,
+                    height: height as u16,
+                    seq_param_set,
+                    pic_param_set,
+                }),
+            })
+            .map_err(failed)?;
+
+        // RTP only carries presentation timestamps. Sorted, they stand in for the decode ones and
+        // the difference goes in the rendering offset. The last frame has nothing after it, it
+        // lasts as long as the one before.
+        let mut decode_ts: Vec<i64> = self.samples.iter().map(|s| s.ts).collect();
+        decode_ts.sort_unstable();
+        let first_ts = decode_ts.first().copied().unwrap_or_default();
+        let mut last_duration = self.clock_rate / 25;
+        let mut total = 0u64;
+        for (i, sample) in self.samples.into_iter().enumerate() {
+            let dts = decode_ts[i];
+            let duration = match decode_ts.get(i + 1) {
+                Some(next) => (next - dts).max(1) as u32,
+                None => last_duration,
+            };
+            last_duration = duration;
+            total = (dts - first_ts) as u64 + duration as u64;
+            writer
+                .write_sample(
+                    1,
+                    &Mp4Sample {
+                        start_time: (dts - first_ts) as u64,
+                        duration,
+                        rendering_offset: (sample.ts - dts) as i32,
+                        is_sync: sample.random_access,
+                        bytes: sample.data,
+                    },
+                )
+                .map_err(failed)?;
+        }
+        writer.write_end().map_err(failed)?;
+        Ok(Duration::from_secs_f64(
+            total as f64 / self.clock_rate as f64,
+        ))
+    }
+}
+
+fn failed(e: impl fmt::Display) -> SessionError {
+    SessionError::ClipFailed(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use mp4::Mp4Reader;
+
+    use super::*;
+    use crate::camera::rtsp_session::fixtures;
+
+    fn sample(data: Vec<u8>, ts: i64) -> ClipSample {
+        ClipSample {
+            data: Bytes::from(data),
+            ts,
+            random_access: ts == 0,
+        }
+    }
+
+    fn clip(iframe: Vec<u8>, parameters: Option<Vec<u8>>) -> Clip {
+        Clip {
+            samples: vec![
+                sample(iframe, 0),
+                sample(fixtures::pframe(1), 3000),
+                sample(fixtures::pframe(2), 6000),
+            ],
+            clock_rate: 90_000,
+            parameters,
+            resolution: Some((fixtures::WIDTH as u32, fixtures::HEIGHT as u32)),
+        }
+    }
+
+    // NALs of an AVCC access unit, without their lengths
+    fn nals(mut au: &[u8]) -> Vec<Vec<u8>> {
+        let mut nals = Vec::new();
+        while au.len() >= 4 {
+            let len = u32::from_be_bytes([au[0], au[1], au[2], au[3]]) as usize;
+            nals.push(au[4..4 + len].to_vec());
+            au = &au[4 + len..];
+        }
+        nals
+    }
+
+    fn read(path: &Path) -> Mp4Reader<BufReader<File>> {
+        let file = File::open(path).unwrap();
+        let size = file.metadata().unwrap().len();
+        Mp4Reader::read_header(BufReader::new(file), size).unwrap()
+    }
+
+    #[test]
+    fn clip_boxes_describe_the_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.mp4");
+        let iframe = fixtures::iframe(10);
+        let duration = clip(iframe.clone(), None).write_mp4(&path).unwrap();
+        assert_eq!(duration, Duration::from_millis(100));
+
+        let mut mp4 = read(&path);
+        assert_eq!(mp4.ftyp.major_brand.to_string(), "isom");
+        assert_eq!(mp4.tracks().len(), 1);
+        let track = &mp4.tracks()[&1];
+        assert_eq!(track.track_type().unwrap(), TrackType::Video);
+        assert_eq!(track.timescale(), 90_000);
+        assert_eq!(
+            (track.width(), track.height()),
+            (fixtures::WIDTH as u16, fixtures::HEIGHT as u16)
+        );
+        // The in-band parameter sets of the iframe
+        let in_band = nals(&iframe);
+        assert_eq!(track.sequence_parameter_set().unwrap(), &in_band[0][..]);
+        assert_eq!(track.picture_parameter_set().unwrap(), &in_band[1][..]);
+        assert_eq!(track.sample_count(), 3);
+
+        let expected = [iframe, fixtures::pframe(1), fixtures::pframe(2)];
+        for (i, data) in expected.iter().enumerate() {
+            let sample = mp4.read_sample(1, i as u32 + 1).unwrap().unwrap();
+            assert_eq!(sample.start_time, i as u64 * 3000);
+            assert_eq!(sample.duration, 3000);
+            assert_eq!(sample.rendering_offset, 0);
+            assert_eq!(sample.is_sync, i == 0);
+            // Muxed as they were buffered
+            assert_eq!(&sample.bytes[..], &data[..]);
+        }
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn sdp_parameter_sets_stand_in_for_missing_in_band_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.mp4");
+        clip(fixtures::bare_iframe(10), Some(fixtures::parameters()))
+            .write_mp4(&path)
+            .unwrap();
+        let mp4 = read(&path);
+        let in_band = nals(&fixtures::iframe(10));
+        let track = &mp4.tracks()[&1];
+        assert_eq!(track.sequence_parameter_set().unwrap(), &in_band[0][..]);
+        assert_eq!(track.picture_parameter_set().unwrap(), &in_band[1][..]);
+    }
+
+    #[test]
+    fn failed_clip_leaves_no_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.mp4");
+        assert!(matches!(
+            clip(fixtures::bare_iframe(10), None).write_mp4(&path),
+            Err(SessionError::ClipFailed(_))
+        ));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
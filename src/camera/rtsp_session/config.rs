@@ -0,0 +1,485 @@
+use std::time::Duration;
+
+use retina::client::{InitialSequenceNumberPolicy, InitialTimestampPolicy};
+
+use super::ConfigError;
+
+// Anything shorter expires frames before a request can reach them
+const MIN_FRAME_LIFETIME: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TransportPreference {
+    Tcp,
+    Udp,
+    // Try UDP first and fall back to TCP if the server refuses the UDP setup
+    Auto,
+}
+
+// Which video stream of the DESCRIBE response gets played
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StreamSelector {
+    FirstVideo,
+    // Encoding name as in the SDP, e.g. `h264` or `jpeg`, compared case-insensitively
+    Codec(String),
+    // Zero based, only counting video streams
+    NthVideo(usize),
+    // SDP control attribute
+    Control(String),
+}
+
+// How PLAY treats the rtptime of the RTP-Info header, retina's `InitialTimestampPolicy`. The
+// first frame's timestamp is only checked against the camera's announcement with `Require`,
+// cameras that leave it out then fail to play. The others take whatever timestamp arrives
+// first, which a buggy or restarted camera may have jump around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TimestampPolicy {
+    // retina's choice, `Permissive` for a single stream
+    Default,
+    Require,
+    Ignore,
+    // Uses rtptime when it is there
+    Permissive,
+}
+
+impl TimestampPolicy {
+    pub(super) fn to_retina(self) -> InitialTimestampPolicy {
+        match self {
+            Self::Default => InitialTimestampPolicy::Default,
+            Self::Require => InitialTimestampPolicy::Require,
+            Self::Ignore => InitialTimestampPolicy::Ignore,
+            Self::Permissive => InitialTimestampPolicy::Permissive,
+        }
+    }
+}
+
+// How PLAY treats the seq of the RTP-Info header, retina's `InitialSequenceNumberPolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SequencePolicy {
+    Default,
+    Respect,
+    // Ignores the values some cameras send that don't match the first packet
+    IgnoreSuspicious,
+    Ignore,
+}
+
+impl SequencePolicy {
+    pub(super) fn to_retina(self) -> InitialSequenceNumberPolicy {
+        match self {
+            Self::Default => InitialSequenceNumberPolicy::Default,
+            Self::Respect => InitialSequenceNumberPolicy::Respect,
+            Self::IgnoreSuspicious => InitialSequenceNumberPolicy::IgnoreSuspiciousValues,
+            Self::Ignore => InitialSequenceNumberPolicy::Ignore,
+        }
+    }
+}
+
+// What happens to a new P-frame once the current GOP holds `buf_size` frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BufferPolicy {
+    DropNewest,
+    // Evicts the oldest P-frame after the iframe. Indices shift down, see
+    // `FrameResponse::index_offset`.
+    DropOldest,
+    // Only iframes are ever buffered
+    KeyframeOnly,
+}
+
+// Which P-frames become requestable, iframes always are. Skipped frames are still buffered and
+// fed to the decoder, since the frames after them reference them, but their pictures aren't
+// kept and they don't count towards `buf_size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Decimation {
+    // Every nth frame counting from the iframe
+    EveryNth(usize),
+    // Frames at least `1 / fps` apart by presentation timestamp
+    MaxFps(f32),
+}
+
+// Low-rate capture modes, on top of `buffer_policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CaptureMode {
+    Continuous,
+    // Only iframes are buffered, each is decoded as it arrives and requests get the newest one
+    // whatever their index, however old. With an interval and a resync hook, a synchronization
+    // point is requested that often for iframes between the camera's own.
+    KeyframeOnly {
+        #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+        request_interval: Option<Duration>,
+    },
+}
+
+// Prefer `SessionConfig::builder`, which validates the values. With the `serde` feature missing
+// fields take their default and durations are written like `500ms` or `1m 30s`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SessionConfig {
+    // Max frames buffered per GOP, iframe included. Frames past it are dropped until the next
+    // iframe
+    pub buf_size: usize,
+    pub buffer_policy: BufferPolicy,
+    pub capture_mode: CaptureMode,
+    // `None` makes every frame requestable
+    pub decimation: Option<Decimation>,
+    // GOPs kept around, newest included, so requests tagged with the generation of a previous GOP
+    // can still be served after the next iframe arrives
+    pub gop_history: usize,
+    // Cap on the raw bytes buffered across all GOPs. Oldest GOPs are evicted first, then frames
+    // of the current GOP are dropped until the next iframe
+    pub max_buffered_bytes: usize,
+    // Cap on the raw bytes of a single GOP, iframe included. Once a P-frame doesn't fit the GOP
+    // takes no more frames, as past `buf_size`. `None` leaves only `max_buffered_bytes`.
+    pub max_gop_bytes: Option<usize>,
+    // Frames above it are refused before anything parses them, a guard against corrupt length
+    // fields. The frames referencing a refused one are dropped until the next iframe.
+    pub max_frame_bytes: usize,
+    // Decoded frame buffers reused instead of allocated anew, see `BufferPool`. Frames decoded
+    // while all of them are in use get a plain allocation, 0 disables pooling.
+    pub buffer_pool_size: usize,
+    // Frames a broadcast receiver can fall behind before it gets `Lagged`
+    pub broadcast_capacity: usize,
+    // How long a buffered iframe can be served before requesters get `OldFrame`
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub frame_lifetime: Duration,
+    // Requests without a buffer generation are served against whatever is buffered. Kept on
+    // while callers migrate to generation tagged requests.
+    pub allow_untagged_requests: bool,
+    // How long requests wait for a fresh iframe after the resync hook fired before they get
+    // `OldFrame`. Unused without a hook.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub resync_timeout: Duration,
+    // Upper bound on `request_image` for requests without their own timeout, `None` waits forever
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub request_timeout: Option<Duration>,
+    // Pending `request_instance` calls before callers start waiting
+    pub instance_request_queue: usize,
+    // Live instances past which `request_instance` is refused, `None` for no limit
+    pub max_instances: Option<usize>,
+    // While no instance is alive only iframes are buffered and nothing is decoded, streams and
+    // broadcasts don't count as instances. Requests of the next instance get `Warmup` until the
+    // following iframe.
+    pub idle_mode: bool,
+    // Pause the session, as `SessionInstanceManager::pause` does, once it was idle this long.
+    // The next `request_instance` resumes it. `None` keeps the stream running.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub idle_pause_after: Option<Duration>,
+    // Frame requests across all instances not answered yet, queued for the decoder or waiting for
+    // their frame. Once full, requests either wait or fail with `Busy` depending on their
+    // `Backpressure`
+    pub frame_request_queue: usize,
+    pub transport: TransportPreference,
+    pub stream: StreamSelector,
+    pub initial_timestamp: TimestampPolicy,
+    pub initial_seq: SequencePolicy,
+    // Play again with `TimestampPolicy::Permissive` once when the camera leaves rtptime out under
+    // `Require`, reported with `SessionEvent::TimestampPolicyFallback`. The frame timestamps are
+    // then only as trustworthy as the camera's first packet.
+    pub timestamp_fallback: bool,
+    // Serve frames decoded after RTP packet loss instead of failing them with `CorruptGop` until
+    // the next iframe. They may show smearing or artifacts.
+    pub tolerate_loss: bool,
+    // Redirects DESCRIBE follows before failing with `Redirected`
+    pub max_redirects: usize,
+    // Time the stages of every served frame, see `FrameResponse::latency` and
+    // `SessionStats::latency`
+    pub measure_latency: bool,
+    // Merge frames carrying the further slices of a picture into one access unit, for cameras
+    // whose slices arrive as separate frames. A picture is buffered once the next one starts.
+    pub aggregate_slices: bool,
+    // Also set up the camera's ONVIF metadata stream, or any other `application` stream, and
+    // forward its messages to `SessionInstanceManager::metadata_stream`. Cameras without one
+    // play video only.
+    pub metadata_stream: bool,
+
+    // Backoff between reconnect attempts once the stream drops, doubling up to the max delay
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub reconnect_initial_delay: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub reconnect_max_delay: Duration,
+    // Restart the stream when no video frame arrives for this long, `None` disables the watchdog
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub stall_timeout: Option<Duration>,
+    // Consecutive failed reconnects before the session gives up, `None` retries forever
+    pub max_retries: Option<u32>,
+    // How long shutdown and reconnects wait for the previous session's TEARDOWN. Keepalives are
+    // sent by retina on its own schedule, which it doesn't let us change.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub teardown_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            buf_size: 60,
+            buffer_policy: BufferPolicy::DropNewest,
+            capture_mode: CaptureMode::Continuous,
+            decimation: None,
+            gop_history: 2,
+            max_buffered_bytes: 64 * 1024 * 1024,
+            max_gop_bytes: None,
+            max_frame_bytes: 16 * 1024 * 1024,
+            buffer_pool_size: 32,
+            broadcast_capacity: 16,
+            frame_lifetime: Duration::from_millis(500),
+            allow_untagged_requests: true,
+            resync_timeout: Duration::from_secs(2),
+            request_timeout: Some(Duration::from_secs(5)),
+            instance_request_queue: 24,
+            max_instances: None,
+            idle_mode: false,
+            idle_pause_after: None,
+            frame_request_queue: 32,
+            transport: TransportPreference::Tcp,
+            stream: StreamSelector::FirstVideo,
+            initial_timestamp: TimestampPolicy::Require,
+            initial_seq: SequencePolicy::Respect,
+            timestamp_fallback: true,
+            tolerate_loss: false,
+            max_redirects: 1,
+            measure_latency: false,
+            aggregate_slices: false,
+            metadata_stream: false,
+            reconnect_initial_delay: Duration::from_millis(500),
+            reconnect_max_delay: Duration::from_secs(30),
+            stall_timeout: Some(Duration::from_secs(10)),
+            max_retries: Some(10),
+            teardown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn builder() -> SessionConfigBuilder {
+        SessionConfigBuilder::default()
+    }
+
+    // For configs built by hand or deserialized, the builder runs it on `build`
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason| Err(ConfigError::Invalid { field, reason });
+        let zero = Duration::ZERO;
+        if self.buf_size == 0 {
+            return invalid("buf_size", "must be at least 1");
+        }
+        match self.decimation {
+            Some(Decimation::EveryNth(0)) => {
+                return invalid("decimation", "nth must be at least 1");
+            }
+            Some(Decimation::MaxFps(fps)) if !(fps.is_finite() && fps > 0.0) => {
+                return invalid("decimation", "fps must be above zero");
+            }
+            _ => {}
+        }
+        if self.gop_history == 0 {
+            return invalid("gop_history", "must be at least 1");
+        }
+        if self.max_buffered_bytes == 0 {
+            return invalid("max_buffered_bytes", "must be at least 1");
+        }
+        if self.max_gop_bytes == Some(0) {
+            return invalid("max_gop_bytes", "must be at least 1, or unset for no limit");
+        }
+        if self.max_frame_bytes == 0 {
+            return invalid("max_frame_bytes", "must be at least 1");
+        }
+        if self.broadcast_capacity == 0 {
+            return invalid("broadcast_capacity", "must be at least 1");
+        }
+        if self.frame_lifetime < MIN_FRAME_LIFETIME {
+            return invalid("frame_lifetime", "must be at least 10ms");
+        }
+        if self.instance_request_queue == 0 {
+            return invalid("instance_request_queue", "must be at least 1");
+        }
+        if self.max_instances == Some(0) {
+            return invalid("max_instances", "must be at least 1, or unset for no limit");
+        }
+        if self.frame_request_queue == 0 {
+            return invalid("frame_request_queue", "must be at least 1");
+        }
+        if self.request_timeout == Some(zero) {
+            return invalid(
+                "request_timeout",
+                "must be above zero, or unset to wait forever",
+            );
+        }
+        if self.stall_timeout == Some(zero) {
+            return invalid(
+                "stall_timeout",
+                "must be above zero, or unset to disable it",
+            );
+        }
+        if self.reconnect_initial_delay > self.reconnect_max_delay {
+            return invalid(
+                "reconnect_initial_delay",
+                "must not be above reconnect_max_delay",
+            );
+        }
+        if self.max_retries == Some(0) {
+            return invalid(
+                "max_retries",
+                "must be at least 1, or unset to retry forever",
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct SessionConfigBuilder {
+    config: SessionConfig,
+}
+
+impl SessionConfigBuilder {
+    pub fn with_buf_size(mut self, val: usize) -> Self {
+        self.config.buf_size = val;
+        self
+    }
+    pub fn with_buffer_policy(mut self, val: BufferPolicy) -> Self {
+        self.config.buffer_policy = val;
+        self
+    }
+    pub fn with_capture_mode(mut self, val: CaptureMode) -> Self {
+        self.config.capture_mode = val;
+        self
+    }
+    pub fn with_decimation(mut self, val: Option<Decimation>) -> Self {
+        self.config.decimation = val;
+        self
+    }
+    pub fn with_gop_history(mut self, val: usize) -> Self {
+        self.config.gop_history = val;
+        self
+    }
+    pub fn with_max_buffered_bytes(mut self, val: usize) -> Self {
+        self.config.max_buffered_bytes = val;
+        self
+    }
+    pub fn with_max_gop_bytes(mut self, val: Option<usize>) -> Self {
+        self.config.max_gop_bytes = val;
+        self
+    }
+    pub fn with_max_frame_bytes(mut self, val: usize) -> Self {
+        self.config.max_frame_bytes = val;
+        self
+    }
+    pub fn with_buffer_pool_size(mut self, val: usize) -> Self {
+        self.config.buffer_pool_size = val;
+        self
+    }
+    pub fn with_broadcast_capacity(mut self, val: usize) -> Self {
+        self.config.broadcast_capacity = val;
+        self
+    }
+    pub fn with_frame_lifetime(mut self, val: Duration) -> Self {
+        self.config.frame_lifetime = val;
+        self
+    }
+    pub fn with_allow_untagged_requests(mut self, val: bool) -> Self {
+        self.config.allow_untagged_requests = val;
+        self
+    }
+    pub fn with_resync_timeout(mut self, val: Duration) -> Self {
+        self.config.resync_timeout = val;
+        self
+    }
+    pub fn with_request_timeout(mut self, val: Option<Duration>) -> Self {
+        self.config.request_timeout = val;
+        self
+    }
+    pub fn with_instance_request_queue(mut self, val: usize) -> Self {
+        self.config.instance_request_queue = val;
+        self
+    }
+    pub fn with_max_instances(mut self, val: Option<usize>) -> Self {
+        self.config.max_instances = val;
+        self
+    }
+    pub fn with_idle_mode(mut self, val: bool) -> Self {
+        self.config.idle_mode = val;
+        self
+    }
+    pub fn with_idle_pause_after(mut self, val: Option<Duration>) -> Self {
+        self.config.idle_pause_after = val;
+        self
+    }
+    pub fn with_frame_request_queue(mut self, val: usize) -> Self {
+        self.config.frame_request_queue = val;
+        self
+    }
+    pub fn with_transport(mut self, val: TransportPreference) -> Self {
+        self.config.transport = val;
+        self
+    }
+    pub fn with_stream(mut self, val: StreamSelector) -> Self {
+        self.config.stream = val;
+        self
+    }
+    pub fn with_initial_timestamp(mut self, val: TimestampPolicy) -> Self {
+        self.config.initial_timestamp = val;
+        self
+    }
+    pub fn with_initial_seq(mut self, val: SequencePolicy) -> Self {
+        self.config.initial_seq = val;
+        self
+    }
+    pub fn with_timestamp_fallback(mut self, val: bool) -> Self {
+        self.config.timestamp_fallback = val;
+        self
+    }
+    pub fn with_tolerate_loss(mut self, val: bool) -> Self {
+        self.config.tolerate_loss = val;
+        self
+    }
+    pub fn with_max_redirects(mut self, val: usize) -> Self {
+        self.config.max_redirects = val;
+        self
+    }
+    pub fn with_measure_latency(mut self, val: bool) -> Self {
+        self.config.measure_latency = val;
+        self
+    }
+    pub fn with_aggregate_slices(mut self, val: bool) -> Self {
+        self.config.aggregate_slices = val;
+        self
+    }
+    pub fn with_metadata_stream(mut self, val: bool) -> Self {
+        self.config.metadata_stream = val;
+        self
+    }
+    pub fn with_reconnect_delay(mut self, initial: Duration, max: Duration) -> Self {
+        self.config.reconnect_initial_delay = initial;
+        self.config.reconnect_max_delay = max;
+        self
+    }
+    pub fn with_stall_timeout(mut self, val: Option<Duration>) -> Self {
+        self.config.stall_timeout = val;
+        self
+    }
+    pub fn with_max_retries(mut self, val: Option<u32>) -> Self {
+        self.config.max_retries = val;
+        self
+    }
+    pub fn with_teardown_timeout(mut self, val: Duration) -> Self {
+        self.config.teardown_timeout = val;
+        self
+    }
+
+    pub fn build(self) -> Result<SessionConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
@@ -0,0 +1,109 @@
+#[cfg(feature = "image")]
+use image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+#[cfg(feature = "ndarray")]
+use ndarray::{Array3, ArrayView3};
+
+use super::{FrameError, FrameResponse};
+#[cfg(feature = "image")]
+use crate::decoders::PixelFormat;
+
+fn size_mismatch(f: &FrameResponse, bpp: usize) -> FrameError {
+    FrameError::SizeMismatch {
+        expected: f.width() * f.height() * bpp,
+        actual: f.frame().len(),
+    }
+}
+
+// Bytes per pixel of a packed frame, once its length is checked against its size. The `image`
+// and `ndarray` constructors would otherwise panic or silently keep a partial picture.
+fn packed_bpp(f: &FrameResponse) -> Result<usize, FrameError> {
+    let bpp = f
+        .pixel_format()
+        .bytes_per_pixel()
+        .ok_or(FrameError::UnsupportedFormat(f.pixel_format()))?;
+    if f.frame().len() != f.width() * f.height() * bpp {
+        return Err(size_mismatch(f, bpp));
+    }
+    Ok(bpp)
+}
+
+// Copies the frame, putting red first for BGR and BGRA frames
+#[cfg(feature = "image")]
+fn to_rgb_order(f: &FrameResponse, bpp: usize) -> Vec<u8> {
+    let mut data = f.frame().to_vec();
+    if matches!(f.pixel_format(), PixelFormat::Bgr8 | PixelFormat::Bgra8) {
+        data.chunks_exact_mut(bpp).for_each(|px| px.swap(0, 2));
+    }
+    data
+}
+
+#[cfg(feature = "image")]
+impl TryFrom<&FrameResponse> for RgbImage {
+    type Error = FrameError;
+
+    // RGB8 and BGR8 frames only
+    fn try_from(f: &FrameResponse) -> Result<Self, Self::Error> {
+        if !matches!(f.pixel_format(), PixelFormat::Rgb8 | PixelFormat::Bgr8) {
+            return Err(FrameError::UnsupportedFormat(f.pixel_format()));
+        }
+        let bpp = packed_bpp(f)?;
+        RgbImage::from_raw(f.width() as u32, f.height() as u32, to_rgb_order(f, bpp))
+            .ok_or_else(|| size_mismatch(f, bpp))
+    }
+}
+
+#[cfg(feature = "image")]
+impl FrameResponse {
+    // Any of the packed formats, BGR ones are converted to RGB
+    pub fn to_dynamic_image(&self) -> Result<DynamicImage, FrameError> {
+        let bpp = packed_bpp(self)?;
+        let (width, height) = (self.width() as u32, self.height() as u32);
+        let data = to_rgb_order(self, bpp);
+        let image = match self.pixel_format() {
+            PixelFormat::Gray8 => GrayImage::from_raw(width, height, data).map(DynamicImage::from),
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => {
+                RgbImage::from_raw(width, height, data).map(DynamicImage::from)
+            }
+            _ => RgbaImage::from_raw(width, height, data).map(DynamicImage::from),
+        };
+        image.ok_or_else(|| size_mismatch(self, bpp))
+    }
+}
+
+#[cfg(feature = "ndarray")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayLayout {
+    // (height, width, channels), the layout of the frame itself
+    Hwc,
+    // (channels, height, width), what most inference runtimes expect
+    Chw,
+}
+
+#[cfg(feature = "ndarray")]
+impl FrameResponse {
+    // (height, width, channels) view over the frame buffer, without copying. Packed formats only.
+    pub fn as_array(&self) -> Result<ArrayView3<'_, u8>, FrameError> {
+        let bpp = packed_bpp(self)?;
+        let row_bytes = self.width() * bpp;
+        if self.stride() != row_bytes {
+            return Err(FrameError::NonContiguous {
+                stride: self.stride(),
+                row_bytes,
+            });
+        }
+        ArrayView3::from_shape((self.height(), self.width(), bpp), self.frame())
+            .map_err(|_| size_mismatch(self, bpp))
+    }
+
+    // `Chw` permutes the channels out, which has to copy either way
+    pub fn to_array(&self, layout: ArrayLayout) -> Result<Array3<u8>, FrameError> {
+        let view = self.as_array()?;
+        Ok(match layout {
+            ArrayLayout::Hwc => view.to_owned(),
+            ArrayLayout::Chw => view
+                .permuted_axes([2, 0, 1])
+                .as_standard_layout()
+                .into_owned(),
+        })
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+};
+
+use bytes::Bytes;
+use tracing::{debug, warn};
+
+use super::streams::avcc_parameter_sets;
+use crate::decoders::avcc_to_annex_b;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpStage {
+    // Length prefixed NALs as they came off the wire
+    Avcc,
+    // With start codes, what `AVCCDecoder` hands the H.264 decoder. Playable with ffprobe/ffplay
+    // as a raw `.h264` file.
+    AnnexB,
+}
+
+// Appends every access unit the session receives to a file, for feeding camera output that the
+// decoder rejects to other tools
+#[derive(Debug, Clone)]
+pub struct BitstreamDump {
+    path: PathBuf,
+    stage: DumpStage,
+    // The file is moved to `<path>.1` once it grows past this, replacing the previous one
+    max_bytes: Option<u64>,
+}
+
+impl BitstreamDump {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            stage: DumpStage::AnnexB,
+            max_bytes: None,
+        }
+    }
+
+    pub fn with_stage(mut self, stage: DumpStage) -> Self {
+        self.stage = stage;
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+}
+
+enum DumpMessage {
+    Frame(Bytes),
+    // The avcC record of the stream, sent on every (re)connect
+    Parameters(Vec<u8>),
+}
+
+// Handle held by the session loop, the writing happens on a thread of its own so a slow disk never
+// holds up packet ingestion. Dropping it flushes and closes the file.
+pub(super) struct DumpTap {
+    tx: mpsc::Sender<DumpMessage>,
+}
+
+impl DumpTap {
+    pub(super) fn spawn(dump: BitstreamDump, parameters: Option<Vec<u8>>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        if let Some(parameters) = parameters {
+            let _ = tx.send(DumpMessage::Parameters(parameters));
+        }
+        let path = dump.path.clone();
+        let spawned = thread::Builder::new()
+            .name("rtsp-dump".to_string())
+            .spawn(move || {
+                if let Err(e) = DumpWriter::new(dump).run(rx) {
+                    warn!("Bitstream dump stopped: {}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            warn!(
+                "Failed to spawn the dump thread for {}: {}",
+                path.display(),
+                e
+            );
+        }
+        Self { tx }
+    }
+
+    // Shares the buffered frame's bytes, only the Annex B stage copies
+    pub(super) fn frame(&self, data: Bytes) {
+        let _ = self.tx.send(DumpMessage::Frame(data));
+    }
+
+    pub(super) fn parameters(&self, avcc: Vec<u8>) {
+        let _ = self.tx.send(DumpMessage::Parameters(avcc));
+    }
+}
+
+struct DumpWriter {
+    dump: BitstreamDump,
+    file: Option<BufWriter<File>>,
+    written: u64,
+    // Parameter sets in the dump's format, repeated at the start of every file
+    header: Vec<u8>,
+}
+
+impl DumpWriter {
+    fn new(dump: BitstreamDump) -> Self {
+        Self {
+            dump,
+            file: None,
+            written: 0,
+            header: Vec::new(),
+        }
+    }
+
+    fn run(mut self, rx: mpsc::Receiver<DumpMessage>) -> io::Result<()> {
+        while let Ok(msg) = rx.recv() {
+            match msg {
+                DumpMessage::Parameters(avcc) => {
+                    self.header = parameter_sets(&avcc, self.dump.stage);
+                    // A reconnect may have changed them, the rest of the file needs the new ones
+                    if self.file.is_some() {
+                        let header = self.header.clone();
+                        self.write(&header)?;
+                    }
+                }
+                DumpMessage::Frame(data) => {
+                    let data = match self.dump.stage {
+                        DumpStage::Avcc => data,
+                        DumpStage::AnnexB => match avcc_to_annex_b(&data, 4) {
+                            Ok(converted) => Bytes::from(converted),
+                            // Kept as is, the malformed frame is likely what's being debugged
+                            Err(e) => {
+                                debug!("Dumping a frame that failed to convert: {}", e);
+                                data
+                            }
+                        },
+                    };
+                    if self.dump.max_bytes.is_some_and(|max| self.written >= max) {
+                        self.rollover()?;
+                    }
+                    self.write(&data)?;
+                }
+            }
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.dump.path)?;
+            let mut file = BufWriter::new(file);
+            file.write_all(&self.header)?;
+            self.written = self.header.len() as u64;
+            self.file = Some(file);
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(data)?;
+            self.written += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rollover(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        let mut previous = self.dump.path.clone().into_os_string();
+        previous.push(".1");
+        fs::rename(&self.dump.path, previous)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+// SPS and PPS of the avcC record with the framing of `stage`, to start the file with
+fn parameter_sets(avcc: &[u8], stage: DumpStage) -> Vec<u8> {
+    let (sps, pps) = avcc_parameter_sets(avcc);
+    let mut out = Vec::new();
+    for nal in sps.iter().chain(pps.iter()) {
+        match stage {
+            DumpStage::Avcc => out.extend_from_slice(&(nal.len() as u32).to_be_bytes()),
+            DumpStage::AnnexB => out.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]),
+        }
+        out.extend_from_slice(nal);
+    }
+    out
+}
@@ -0,0 +1,210 @@
+use std::{fmt, time::Duration};
+
+use retina::Error;
+use url::Url;
+
+use crate::decoders::{DecoderError, PixelFormat};
+
+// The reason of the `TlsError` an `rtsps://` url gets, the session has no TLS transport yet
+pub(super) const RTSPS_UNSUPPORTED: &str = "rtsps urls are not supported, retina only speaks \
+    plain RTSP; use an rtsp url, or a local TLS proxy such as stunnel in front of the camera";
+
+// TODO: Maybe I should split these into different sectors
+#[derive(Debug)]
+pub enum SessionError {
+    UrlParseError,
+    UnsetParameter(String),
+    InvalidPort(String),
+
+    ServerDropped,
+    BrokenPipeline,
+    Busy,
+    UnableToSubscribe(SubscribeError),
+    DecodingError(DecoderError),
+
+    OldFrame,
+    CorruptGop,
+    // Frames were evicted from a GOP the clip would include
+    TornGop,
+    ClipFailed(String),
+    FrameNotYetAvailable,
+    // The frame `FrameRequest::after` asked for is gone, this is the oldest one still buffered
+    FrameEvicted { oldest_available: u64 },
+    Timeout(Duration),
+    // Not even one frame decodes within `FrameRequest::with_deadline` and none is decoded yet
+    DeadlineExceeded(Duration),
+    Lagged(u64),
+    Reconnecting,
+    Paused,
+    Warmup,
+    // A decoder replacement is waiting for the next iframe, see
+    // `SessionInstanceManager::replace_decoder`
+    SwappingDecoder,
+    StreamStalled,
+    StreamError(Error),
+    BufferReset { expected: u64, actual: u64 },
+    UntaggedRequest,
+    // The server refused the credentials, or asked for some and there were none
+    Unauthorized(Error),
+    // The server redirected further than `SessionConfig::max_redirects` allows, to this url
+    Redirected(Url),
+    TlsError(String),
+    FailedToDescribeSession(Error),
+    NoVideoStreamFound,
+    NoMatchingStream { available: Vec<String> },
+    FailedToSetupStream(Error),
+    FailedToPlayStream(Error),
+    FailedToDemuxStream(Error),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UrlParseError => write!(f, "failed to parse the session url"),
+            Self::UnsetParameter(msg) => write!(f, "{}", msg),
+            Self::InvalidPort(port) => write!(f, "invalid port {}", port),
+            Self::ServerDropped => write!(f, "session loop dropped the request"),
+            Self::BrokenPipeline => write!(f, "session loop is no longer running"),
+            Self::Busy => write!(f, "request queue is full, try again"),
+            Self::UnableToSubscribe(reason) => write!(f, "unable to subscribe: {}", reason),
+            Self::DecodingError(e) => write!(f, "failed to decode frame: {}", e),
+            Self::OldFrame => write!(f, "buffered frames expired, waiting for a new iframe"),
+            Self::CorruptGop => {
+                write!(f, "packets were lost in this GOP, waiting for a new iframe")
+            }
+            Self::TornGop => write!(
+                f,
+                "frames of a buffered GOP were evicted, it can't be saved"
+            ),
+            Self::ClipFailed(reason) => write!(f, "failed to write the clip: {}", reason),
+            Self::FrameNotYetAvailable => write!(f, "requested frame is not yet available"),
+            Self::FrameEvicted { oldest_available } => write!(
+                f,
+                "requested frame was evicted, the oldest buffered is {}",
+                oldest_available
+            ),
+            Self::Timeout(t) => write!(f, "no frame was served within {:?}", t),
+            Self::DeadlineExceeded(d) => {
+                write!(f, "no frame of the GOP can be decoded within {:?}", d)
+            }
+            Self::Lagged(n) => write!(f, "receiver fell behind and missed {} frames", n),
+            Self::Paused => write!(f, "session is paused"),
+            Self::Warmup => write!(f, "session is leaving idle mode, waiting for a new iframe"),
+            Self::SwappingDecoder => {
+                write!(f, "decoder is being replaced, waiting for a new iframe")
+            }
+            Self::Reconnecting => write!(f, "stream dropped, reconnecting"),
+            Self::StreamStalled => write!(f, "stream stalled, restarting the session"),
+            Self::StreamError(e) => write!(f, "stream failed, reconnecting: {}", e),
+            Self::BufferReset { expected, actual } => write!(
+                f,
+                "buffer was reset: requested generation {} but buffer is at {}",
+                expected, actual
+            ),
+            Self::UntaggedRequest => write!(f, "request is missing a buffer generation"),
+            Self::Unauthorized(e) => write!(f, "server rejected the credentials: {}", e),
+            Self::Redirected(url) => write!(f, "server redirected to {}", url),
+            Self::TlsError(reason) => write!(f, "failed to establish TLS: {}", reason),
+            Self::FailedToDescribeSession(e) => write!(f, "DESCRIBE failed: {}", e),
+            Self::NoVideoStreamFound => write!(f, "no video stream found in the session"),
+            Self::NoMatchingStream { available } => write!(
+                f,
+                "no stream matches the selection, available: {}",
+                available.join(", ")
+            ),
+            Self::FailedToSetupStream(e) => write!(f, "SETUP failed: {}", e),
+            Self::FailedToPlayStream(e) => write!(f, "PLAY failed: {}", e),
+            Self::FailedToDemuxStream(e) => write!(f, "failed to demux the stream: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::DecodingError(e) => Some(e),
+            Self::StreamError(e)
+            | Self::Unauthorized(e)
+            | Self::FailedToDescribeSession(e)
+            | Self::FailedToSetupStream(e)
+            | Self::FailedToPlayStream(e)
+            | Self::FailedToDemuxStream(e) => Some(e),
+            Self::UnableToSubscribe(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<DecoderError> for SessionError {
+    fn from(e: DecoderError) -> Self {
+        Self::DecodingError(e)
+    }
+}
+
+// Why the session loop refused a `request_instance`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeError {
+    // `SessionConfig::max_instances` instances are alive already
+    MaxInstances(usize),
+    ShuttingDown,
+}
+
+impl fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaxInstances(max) => write!(f, "the session already has {} instances", max),
+            Self::ShuttingDown => write!(f, "the session is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for SubscribeError {}
+
+// A `SessionConfig` value that would leave the session unable to serve frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    Invalid {
+        field: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid { field, reason } => write!(f, "invalid {}: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Converting a `FrameResponse` into another image type failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    // The buffer doesn't hold `width * height` pixels of the reported format
+    SizeMismatch { expected: usize, actual: usize },
+    UnsupportedFormat(PixelFormat),
+    // Rows are padded, so the buffer can't be viewed as a contiguous array
+    NonContiguous { stride: usize, row_bytes: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SizeMismatch { expected, actual } => write!(
+                f,
+                "frame should be {} bytes for its size but is {} bytes",
+                expected, actual
+            ),
+            Self::UnsupportedFormat(format) => write!(f, "can't convert {:?} frames", format),
+            Self::NonContiguous { stride, row_bytes } => write!(
+                f,
+                "frame rows are {} bytes apart but only hold {} bytes",
+                stride, row_bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
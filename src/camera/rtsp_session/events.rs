@@ -0,0 +1,60 @@
+use retina::Timestamp;
+use url::Url;
+
+use super::{StreamParameters, TimestampPolicy};
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Connected {
+        url: Url,
+    },
+    // Sent again when the camera changes its parameters mid-stream
+    StreamSetup {
+        codec: String,
+        // `None` when the camera only sends its parameters in-band
+        resolution: Option<(u32, u32)>,
+        parameters: StreamParameters,
+    },
+    IFrameReceived {
+        ts: Timestamp,
+    },
+    // The decoder produced a frame of a different size than the one before it
+    ResolutionChanged {
+        from: (usize, usize),
+        to: (usize, usize),
+    },
+    // The current GOP hit `buf_size`, `max_gop_bytes` or `max_buffered_bytes`, reported once per
+    // GOP
+    BufferFull,
+    FrameExpired,
+    // The decoder failed on a frame and was reset, the GOP's frames from `index` on are refused
+    // with `CorruptGop` until the next iframe
+    DecodeFailed {
+        generation: u64,
+        index: usize,
+    },
+    Disconnected {
+        reason: String,
+    },
+    ReconnectAttempt {
+        n: u32,
+    },
+    // PLAY failed for the missing rtptime and went through with `to` instead
+    TimestampPolicyFallback {
+        from: TimestampPolicy,
+        to: TimestampPolicy,
+    },
+    // `SessionInstanceManager::replace_decoder` went through, GOP `generation` is the first one
+    // the new decoder sees
+    DecoderReplaced {
+        generation: u64,
+    },
+    // The last instance was dropped, see `SessionConfig::idle_mode`
+    Idle,
+    // An instance was handed out after `Idle`
+    Active,
+    // A frame handler panicked, see `SessionWrapper::on_frame`. It keeps getting frames.
+    HandlerPanicked {
+        message: String,
+    },
+}
@@ -0,0 +1,397 @@
+// Synthetic H.264 access units of a single 16x16 macroblock, baseline profile, in the AVCC form
+// retina hands out (4 byte lengths). `sized_iframe` makes larger pictures out of more of them.
+// Iframes carry the SPS and PPS in band, so decoders don't need the avcC record to start, except
+// for `bare_iframe`.
+
+pub const WIDTH: usize = 16;
+pub const HEIGHT: usize = 16;
+
+// IDR picture with every luma sample at `luma` and neutral chroma, stored uncompressed (I_PCM).
+// Back to back iframes need different `luma` values, the IDR id is taken from it.
+pub fn iframe(luma: u8) -> Vec<u8> {
+    sized_iframe(1, 1, luma)
+}
+
+// `iframe` of `width_mbs` by `height_mbs` macroblocks, its SPS announces that size. Level 1 caps
+// the picture at 99 macroblocks.
+pub fn sized_iframe(width_mbs: usize, height_mbs: usize, luma: u8) -> Vec<u8> {
+    let mut au = Vec::new();
+    push_nal(&mut au, 0x67, &sps(width_mbs, height_mbs));
+    push_nal(&mut au, 0x68, &pps());
+    push_nal(&mut au, 0x65, &idr_slice(luma, 0, width_mbs * height_mbs));
+    au
+}
+
+// `iframe` without the parameter sets, like cameras that only announce them in the SDP. Decodes
+// only once the ones of `parameters` went to the decoder.
+pub fn bare_iframe(luma: u8) -> Vec<u8> {
+    let mut au = Vec::new();
+    push_nal(&mut au, 0x65, &idr_slice(luma, 0, 1));
+    au
+}
+
+// P picture repeating the one before it. `frame_num` counts the frames since the iframe, the
+// iframe included, so the first P-frame after it is 1.
+pub fn pframe(frame_num: usize) -> Vec<u8> {
+    let mut au = Vec::new();
+    push_nal(&mut au, 0x41, &p_slice(frame_num, 0));
+    au
+}
+
+// 32x16 iframe of one slice per macroblock, each slice in an access unit of its own like the
+// cameras of `SessionConfig::aggregate_slices`. All slices of a picture share its timestamp.
+pub fn sliced_iframe(luma: u8) -> [Vec<u8>; 2] {
+    let mut first = Vec::new();
+    push_nal(&mut first, 0x67, &sps(2, 1));
+    push_nal(&mut first, 0x68, &pps());
+    push_nal(&mut first, 0x65, &idr_slice(luma, 0, 1));
+    let mut second = Vec::new();
+    push_nal(&mut second, 0x65, &idr_slice(luma, 1, 1));
+    [first, second]
+}
+
+// P-frame following `sliced_iframe`, split the same way
+pub fn sliced_pframe(frame_num: usize) -> [Vec<u8>; 2] {
+    [0, 1].map(|first_mb| {
+        let mut au = Vec::new();
+        push_nal(&mut au, 0x41, &p_slice(frame_num, first_mb));
+        au
+    })
+}
+
+// P-frame with a slice header of nothing but zero bits, longer than any exp-Golomb code may
+// start with. Decoders fail on it instead of concealing it.
+pub fn corrupt_pframe() -> Vec<u8> {
+    let mut au = Vec::new();
+    push_nal(&mut au, 0x41, &[0, 0, 0, 0, 0, 0, 0x80]);
+    au
+}
+
+// H.265 main profile counterpart of `iframe`, in HVCC with its VPS, SPS and PPS. The one 16x16
+// coding unit is stored as PCM, which leaves two CABAC bins to code by hand.
+pub fn hevc_iframe(luma: u8) -> Vec<u8> {
+    let mut au = Vec::new();
+    push_hevc_nal(&mut au, 32, &hevc_vps());
+    push_hevc_nal(&mut au, 33, &hevc_sps());
+    push_hevc_nal(&mut au, 34, &hevc_pps());
+    push_hevc_nal(&mut au, 19, &hevc_idr_slice(luma));
+    au
+}
+
+// avcC record matching the parameter sets of `iframe`
+pub fn parameters() -> Vec<u8> {
+    let (sps, pps) = (nal(0x67, &sps(1, 1)), nal(0x68, &pps()));
+    let mut record = vec![1, 66, 0xc0, 10, 0xff, 0xe1];
+    record.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    record.extend_from_slice(&sps);
+    record.push(1);
+    record.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    record.extend_from_slice(&pps);
+    record
+}
+
+fn sps(width_mbs: usize, height_mbs: usize) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    // Baseline, constrained, level 1
+    w.bits(66, 8);
+    w.bits(0xc0, 8);
+    w.bits(10, 8);
+    w.ue(0); // seq_parameter_set_id
+    w.ue(0); // log2_max_frame_num_minus4
+    w.ue(2); // pic_order_cnt_type, output order follows frame_num
+    w.ue(1); // max_num_ref_frames
+    w.bit(false); // gaps_in_frame_num_value_allowed_flag
+    w.ue(width_mbs as u32 - 1); // pic_width_in_mbs_minus1
+    w.ue(height_mbs as u32 - 1); // pic_height_in_map_units_minus1
+    w.bit(true); // frame_mbs_only_flag
+    w.bit(true); // direct_8x8_inference_flag
+    w.bit(false); // frame_cropping_flag
+    w.bit(false); // vui_parameters_present_flag
+    w.finish()
+}
+
+fn pps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(0); // pic_parameter_set_id
+    w.ue(0); // seq_parameter_set_id
+    w.bit(false); // entropy_coding_mode_flag, CAVLC
+    w.bit(false); // bottom_field_pic_order_in_frame_present_flag
+    w.ue(0); // num_slice_groups_minus1
+    w.ue(0); // num_ref_idx_l0_default_active_minus1
+    w.ue(0); // num_ref_idx_l1_default_active_minus1
+    w.bit(false); // weighted_pred_flag
+    w.bits(0, 2); // weighted_bipred_idc
+    w.se(0); // pic_init_qp_minus26
+    w.se(0); // pic_init_qs_minus26
+    w.se(0); // chroma_qp_index_offset
+    w.bit(true); // deblocking_filter_control_present_flag
+    w.bit(false); // constrained_intra_pred_flag
+    w.bit(false); // redundant_pic_cnt_present_flag
+    w.finish()
+}
+
+fn idr_slice(luma: u8, first_mb: u32, macroblocks: usize) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(first_mb); // first_mb_in_slice
+    w.ue(7); // slice_type, I
+    w.ue(0); // pic_parameter_set_id
+    w.bits(0, 4); // frame_num
+    w.ue(u32::from(luma)); // idr_pic_id
+    w.bit(false); // no_output_of_prior_pics_flag
+    w.bit(false); // long_term_reference_flag
+    w.se(0); // slice_qp_delta
+    w.ue(1); // disable_deblocking_filter_idc
+    for _ in 0..macroblocks {
+        w.ue(25); // mb_type, I_PCM
+        w.align();
+        // Zero samples aren't allowed in baseline PCM
+        for _ in 0..WIDTH * HEIGHT {
+            w.bits(u32::from(luma.max(1)), 8);
+        }
+        for _ in 0..WIDTH * HEIGHT / 2 {
+            w.bits(128, 8);
+        }
+    }
+    w.finish()
+}
+
+fn p_slice(frame_num: usize, first_mb: u32) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(first_mb); // first_mb_in_slice
+    w.ue(5); // slice_type, P
+    w.ue(0); // pic_parameter_set_id
+    w.bits((frame_num % 16) as u32, 4); // frame_num
+    w.bit(false); // num_ref_idx_active_override_flag
+    w.bit(false); // ref_pic_list_modification_flag_l0
+    w.bit(false); // adaptive_ref_pic_marking_mode_flag
+    w.se(0); // slice_qp_delta
+    w.ue(1); // disable_deblocking_filter_idc
+    w.ue(1); // mb_skip_run, the only macroblock of the slice
+    w.finish()
+}
+
+// Main profile, main tier, level 1, without sub-layers
+fn profile_tier_level(w: &mut BitWriter) {
+    w.bits(0, 2); // general_profile_space
+    w.bit(false); // general_tier_flag
+    w.bits(1, 5); // general_profile_idc
+    w.bits(0x6000_0000, 32); // general_profile_compatibility_flag, main and main 10
+    w.bit(true); // general_progressive_source_flag
+    w.bit(false); // general_interlaced_source_flag
+    w.bit(false); // general_non_packed_constraint_flag
+    w.bit(true); // general_frame_only_constraint_flag
+    w.bits(0, 32); // general_reserved_zero_43bits and general_inbld_flag
+    w.bits(0, 12);
+    w.bits(30, 8); // general_level_idc
+}
+
+fn hevc_vps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.bits(0, 4); // vps_video_parameter_set_id
+    w.bit(true); // vps_base_layer_internal_flag
+    w.bit(true); // vps_base_layer_available_flag
+    w.bits(0, 6); // vps_max_layers_minus1
+    w.bits(0, 3); // vps_max_sub_layers_minus1
+    w.bit(true); // vps_temporal_id_nesting_flag
+    w.bits(0xffff, 16); // vps_reserved_0xffff_16bits
+    profile_tier_level(&mut w);
+    w.bit(true); // vps_sub_layer_ordering_info_present_flag
+    w.ue(0); // vps_max_dec_pic_buffering_minus1
+    w.ue(0); // vps_max_num_reorder_pics
+    w.ue(0); // vps_max_latency_increase_plus1
+    w.bits(0, 6); // vps_max_layer_id
+    w.ue(0); // vps_num_layer_sets_minus1
+    w.bit(false); // vps_timing_info_present_flag
+    w.bit(false); // vps_extension_flag
+    w.finish()
+}
+
+fn hevc_sps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.bits(0, 4); // sps_video_parameter_set_id
+    w.bits(0, 3); // sps_max_sub_layers_minus1
+    w.bit(true); // sps_temporal_id_nesting_flag
+    profile_tier_level(&mut w);
+    w.ue(0); // sps_seq_parameter_set_id
+    w.ue(1); // chroma_format_idc, 4:2:0
+    w.ue(WIDTH as u32); // pic_width_in_luma_samples
+    w.ue(HEIGHT as u32); // pic_height_in_luma_samples
+    w.bit(false); // conformance_window_flag
+    w.ue(0); // bit_depth_luma_minus8
+    w.ue(0); // bit_depth_chroma_minus8
+    w.ue(0); // log2_max_pic_order_cnt_lsb_minus4
+    w.bit(true); // sps_sub_layer_ordering_info_present_flag
+    w.ue(0); // sps_max_dec_pic_buffering_minus1
+    w.ue(0); // sps_max_num_reorder_pics
+    w.ue(0); // sps_max_latency_increase_plus1
+             // 8x8 to 16x16 coding blocks, so the one CTB is a single coding unit
+    w.ue(0); // log2_min_luma_coding_block_size_minus3
+    w.ue(1); // log2_diff_max_min_luma_coding_block_size
+    w.ue(0); // log2_min_luma_transform_block_size_minus2
+    w.ue(2); // log2_diff_max_min_luma_transform_block_size
+    w.ue(0); // max_transform_hierarchy_depth_inter
+    w.ue(0); // max_transform_hierarchy_depth_intra
+    w.bit(false); // scaling_list_enabled_flag
+    w.bit(false); // amp_enabled_flag
+    w.bit(false); // sample_adaptive_offset_enabled_flag
+    w.bit(true); // pcm_enabled_flag
+    w.bits(7, 4); // pcm_sample_bit_depth_luma_minus1
+    w.bits(7, 4); // pcm_sample_bit_depth_chroma_minus1
+    w.ue(0); // log2_min_pcm_luma_coding_block_size_minus3
+    w.ue(1); // log2_diff_max_min_pcm_luma_coding_block_size
+    w.bit(true); // pcm_loop_filter_disabled_flag
+    w.ue(0); // num_short_term_ref_pic_sets
+    w.bit(false); // long_term_ref_pics_present_flag
+    w.bit(false); // sps_temporal_mvp_enabled_flag
+    w.bit(false); // strong_intra_smoothing_enabled_flag
+    w.bit(false); // vui_parameters_present_flag
+    w.bit(false); // sps_extension_present_flag
+    w.finish()
+}
+
+fn hevc_pps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(0); // pps_pic_parameter_set_id
+    w.ue(0); // pps_seq_parameter_set_id
+    w.bit(false); // dependent_slice_segments_enabled_flag
+    w.bit(false); // output_flag_present_flag
+    w.bits(0, 3); // num_extra_slice_header_bits
+    w.bit(false); // sign_data_hiding_enabled_flag
+    w.bit(false); // cabac_init_present_flag
+    w.ue(0); // num_ref_idx_l0_default_active_minus1
+    w.ue(0); // num_ref_idx_l1_default_active_minus1
+    w.se(0); // init_qp_minus26
+    w.bit(false); // constrained_intra_pred_flag
+    w.bit(false); // transform_skip_enabled_flag
+    w.bit(false); // cu_qp_delta_enabled_flag
+    w.se(0); // pps_cb_qp_offset
+    w.se(0); // pps_cr_qp_offset
+    w.bit(false); // pps_slice_chroma_qp_offsets_present_flag
+    w.bit(false); // weighted_pred_flag
+    w.bit(false); // weighted_bipred_flag
+    w.bit(false); // transquant_bypass_enabled_flag
+    w.bit(false); // tiles_enabled_flag
+    w.bit(false); // entropy_coding_sync_enabled_flag
+    w.bit(false); // pps_loop_filter_across_slices_enabled_flag
+    w.bit(true); // deblocking_filter_control_present_flag
+    w.bit(false); // deblocking_filter_override_enabled_flag
+    w.bit(true); // pps_deblocking_filter_disabled_flag
+    w.bit(false); // pps_scaling_list_data_present_flag
+    w.bit(false); // lists_modification_present_flag
+    w.ue(0); // log2_parallel_merge_level_minus2
+    w.bit(false); // slice_segment_header_extension_present_flag
+    w.bit(false); // pps_extension_present_flag
+    w.finish()
+}
+
+fn hevc_idr_slice(luma: u8) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.bit(true); // first_slice_segment_in_pic_flag
+    w.bit(false); // no_output_of_prior_pics_flag
+    w.ue(0); // slice_pic_parameter_set_id
+    w.ue(2); // slice_type, I
+    w.se(0); // slice_qp_delta
+    w.bit(true); // byte_alignment, alignment_bit_equal_to_one
+    w.align();
+    // split_cu_flag 0, the MPS of its initial state (rangeTabLPS 240 at the 510 starting range),
+    // then pcm_flag 1 as a terminating bin, flushed. Decodes to an offset of 269.
+    w.bits(0b1_0000_1101, 9);
+    w.align(); // pcm_alignment_zero_bit
+    for _ in 0..WIDTH * HEIGHT {
+        w.bits(u32::from(luma), 8);
+    }
+    for _ in 0..WIDTH * HEIGHT / 2 {
+        w.bits(128, 8);
+    }
+    // CABAC restarts after the samples, end_of_slice_segment_flag 1 flushed. Its last bit is the
+    // rbsp_stop_one_bit.
+    w.bits(0b1_1111_1101, 9);
+    w.align();
+    w.bytes
+}
+
+fn nal(header: u8, rbsp: &[u8]) -> Vec<u8> {
+    escaped(&[header], rbsp)
+}
+
+fn escaped(header: &[u8], rbsp: &[u8]) -> Vec<u8> {
+    // Emulation prevention, no `00 00 0x` with x <= 3 may appear in the payload
+    let mut nal = header.to_vec();
+    let mut zeros = 0;
+    for &b in rbsp {
+        if zeros >= 2 && b <= 3 {
+            nal.push(3);
+            zeros = 0;
+        }
+        nal.push(b);
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+    }
+    nal
+}
+
+fn push_nal(au: &mut Vec<u8>, header: u8, rbsp: &[u8]) {
+    push_length_prefixed(au, &nal(header, rbsp));
+}
+
+// H.265 headers are two bytes, the type then layer 0 and temporal id 0
+fn push_hevc_nal(au: &mut Vec<u8>, nal_type: u8, rbsp: &[u8]) {
+    push_length_prefixed(au, &escaped(&[nal_type << 1, 1], rbsp));
+}
+
+fn push_length_prefixed(au: &mut Vec<u8>, nal: &[u8]) {
+    au.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+    au.extend_from_slice(nal);
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits used of the last byte
+    used: u8,
+}
+
+impl BitWriter {
+    fn bit(&mut self, set: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if set {
+            if let Some(last) = self.bytes.last_mut() {
+                *last |= 0x80 >> self.used;
+            }
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn bits(&mut self, value: u32, count: u8) {
+        for i in (0..count).rev() {
+            self.bit((value >> i) & 1 == 1);
+        }
+    }
+
+    // Exp-Golomb
+    fn ue(&mut self, value: u32) {
+        let coded = value + 1;
+        let len = 32 - coded.leading_zeros() as u8;
+        self.bits(0, len - 1);
+        self.bits(coded, len);
+    }
+
+    fn se(&mut self, value: i32) {
+        let mapped = if value > 0 { 2 * value - 1 } else { -2 * value };
+        self.ue(mapped as u32);
+    }
+
+    fn align(&mut self) {
+        while self.used != 0 {
+            self.bit(false);
+        }
+    }
+
+    // rbsp_trailing_bits
+    fn finish(mut self) -> Vec<u8> {
+        self.bit(true);
+        self.align();
+        self.bytes
+    }
+}
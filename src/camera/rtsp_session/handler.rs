@@ -0,0 +1,54 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    thread::{self, JoinHandle},
+};
+
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, error};
+
+use super::{stream::FrameSubscriber, FrameResponse, SessionEvent};
+
+pub type FrameHandler = Box<dyn FnMut(FrameResponse) + Send>;
+
+// Runs `handler` on a thread of its own, fed like a `FrameStream` of `capacity` frames: while the
+// queue is full new frames are dropped, the handler sees the oldest ones first. Stops once the
+// session ends, after the frames still queued. A panic is reported as `HandlerPanicked` and the
+// handler keeps getting frames.
+pub(super) fn spawn(
+    mut handler: FrameHandler,
+    capacity: usize,
+    events_tx: broadcast::Sender<SessionEvent>,
+) -> (FrameSubscriber, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::channel(capacity.max(1));
+    let thread = thread::Builder::new()
+        .name("rtsp-frame-handler".to_string())
+        .spawn(move || {
+            while let Some(item) = rx.blocking_recv() {
+                let frame = match item {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        debug!("Frame handler skipping a failed frame: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(frame))) {
+                    let message = panic_message(payload.as_ref());
+                    error!("Frame handler panicked: {}", message);
+                    let _ = events_tx.send(SessionEvent::HandlerPanicked { message });
+                }
+            }
+        })
+        .expect("Failed to spawn the frame handler thread");
+    (tx, thread)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
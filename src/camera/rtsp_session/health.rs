@@ -0,0 +1,35 @@
+use std::time::Duration;
+
+// Liveness of a session without going through the request queue, see
+// `SessionInstanceManager::health`
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    // The session loop hasn't exited, e.g. after running out of `max_retries`
+    pub running: bool,
+    pub connected: bool,
+    pub since_last_packet: Option<Duration>,
+    pub since_last_iframe: Option<Duration>,
+    // Consecutive failed reconnects, 0 while connected
+    pub reconnect_attempt: u32,
+    // Why the stream was last lost or the last reconnect failed, kept after reconnecting
+    pub last_error: Option<String>,
+}
+
+impl Health {
+    // Running, connected and a video frame arrived within `max_staleness`
+    pub fn is_healthy(&self, max_staleness: Duration) -> bool {
+        self.running
+            && self.connected
+            && self
+                .since_last_packet
+                .is_some_and(|since| since <= max_staleness)
+    }
+}
+
+// The part of `Health` only the session loop knows, published whenever it changes
+#[derive(Debug, Clone, Default)]
+pub(super) struct LoopHealth {
+    pub(super) connected: bool,
+    pub(super) reconnect_attempt: u32,
+    pub(super) last_error: Option<String>,
+}
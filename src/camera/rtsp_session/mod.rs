@@ -1,57 +1,280 @@
+mod access_unit;
+mod clip;
+mod config;
+#[cfg(any(feature = "image", feature = "ndarray"))]
+mod convert;
+mod describe;
+mod dump;
+mod error;
+mod events;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fixtures;
+mod handler;
+mod health;
+mod request;
+#[cfg(any(test, feature = "test-util"))]
+mod scripted;
+mod shutdown;
+mod stats;
+mod stream;
+mod streams;
+#[cfg(test)]
+mod tests;
 pub mod utils;
+mod wallclock;
+mod worker;
 
-use std::time::{Duration, Instant};
+pub use config::{
+    BufferPolicy, CaptureMode, Decimation, SequencePolicy, SessionConfig, SessionConfigBuilder,
+    StreamSelector, TimestampPolicy, TransportPreference,
+};
+#[cfg(feature = "ndarray")]
+pub use convert::ArrayLayout;
+pub use dump::{BitstreamDump, DumpStage};
+pub use error::{ConfigError, FrameError, SessionError, SubscribeError};
+pub use events::SessionEvent;
+pub use handler::FrameHandler;
+pub use health::Health;
+pub use request::{
+    Backpressure, BatchRequest, BatchResponse, BufferState, BufferStatus, FrameRequest,
+    FrameResponse, OutputFormat, Priority,
+};
+#[cfg(any(test, feature = "test-util"))]
+pub use scripted::FrameScript;
+pub use shutdown::Dependent;
+pub use stats::{LatencyBreakdown, LatencyPercentiles, LatencySummary, SessionStats};
+pub use stream::{FrameBroadcast, FrameStream, MetadataMessage, MetadataStream};
+pub use streams::{StreamInfo, StreamParameters};
+
+use std::{
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use futures::StreamExt;
+use futures::{
+    future::{self, BoxFuture},
+    StreamExt,
+};
+use percent_encoding::percent_decode_str;
 use retina::{
     client::{
-        Demuxed, InitialSequenceNumberPolicy, InitialTimestampPolicy, PlayOptions, Session,
-        SessionOptions, SetupOptions, TcpTransportOptions, Transport,
+        Credentials, Demuxed, Described, PlayOptions, Session, SessionGroup, SessionOptions,
+        SetupOptions, TcpTransportOptions, TeardownPolicy, Transport, UdpTransportOptions,
     },
-    codec::{CodecItem, VideoFrame},
+    codec::CodecItem,
     Error,
 };
 use tokio::{sync, task::JoinHandle};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
-use crate::decoders::{DecoderError, ImageDecoder};
-
-// TODO: Maybe I should split these into different sectors
-#[derive(Debug)]
-pub enum SessionError {
-    UrlParseError,
-    UnsetParameter(String),
-
-    ServerDropped,
-    BrokenPipeline,
-    UnableToSubscribe(String),
-    DecodingError(DecoderError),
-
-    OldFrame,
-    FailedToDescribeSession(Error),
-    NoVideoStreamFound,
-    FailedToSetupStream(Error),
-    FailedToPlayStream(Error),
-    FailedToDemuxStream(Error),
+use crate::{
+    camera::onvif::{media2::MediaOps, services::MediaClient},
+    capture::ClockAnchor,
+    decoders::{AsyncImageDecoder, BufferPool, DecoderPool, ImageDecoder, SyncDecoder},
+};
+use access_unit::AccessUnitAssembler;
+use clip::ClipRequest;
+use dump::DumpTap;
+use health::LoopHealth;
+use stats::StatsCollector;
+use stream::FrameSubscriber;
+use wallclock::WallClock;
+use worker::{BlockingDecoder, DecodeWorker, RawFrame, WorkerMessage};
+
+// Asks the camera for a new synchronization point, called when the buffered frames expire
+pub type ResyncHook = Box<
+    dyn Fn() -> BoxFuture<'static, Result<(), Box<dyn std::error::Error + Send + Sync>>>
+        + Send
+        + Sync,
+>;
+
+// Fetches the stream url anew, e.g. from ONVIF, for when the camera rejects the credentials of the
+// current one. Tried once per connect.
+pub type UrlRefreshHook = Arc<
+    dyn Fn() -> BoxFuture<'static, Result<Url, Box<dyn std::error::Error + Send + Sync>>>
+        + Send
+        + Sync,
+>;
+
+// Holds one of the `SessionConfig::frame_request_queue` slots until the request is answered or
+// dropped, wherever it is waiting by then
+struct Requester<T> {
+    tx: sync::oneshot::Sender<Result<T, SessionError>>,
+    _slot: sync::OwnedSemaphorePermit,
+}
+
+impl<T> Requester<T> {
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+type FrameRequestTx = sync::mpsc::Sender<DataRequest>;
+
+enum DataRequest {
+    Frame(FrameRequest, Requester<FrameResponse>),
+    Batch(BatchRequest, Requester<BatchResponse>),
+}
+
+impl DataRequest {
+    // The requester timed out or went away
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Frame(_, sender) => sender.is_closed(),
+            Self::Batch(_, sender) => sender.is_closed(),
+        }
+    }
+
+    fn priority(&self) -> Priority {
+        match self {
+            Self::Frame(req, _) => req.priority(),
+            Self::Batch(req, _) => req.priority(),
+        }
+        .unwrap_or_default()
+    }
+
+    fn fail(self, e: SessionError) {
+        match self {
+            Self::Frame(_, sender) => respond(sender, Err(e)),
+            Self::Batch(_, sender) => respond(sender, Err(e)),
+        }
+    }
+}
+
+fn respond<T>(sender: Requester<T>, res: Result<T, SessionError>) {
+    if sender.tx.send(res).is_err() {
+        debug!("Channel was closed by requester");
+    }
+}
+
+// Counts the live instances, dropping the last one wakes the session loop
+struct InstanceGuard {
+    count: Arc<sync::watch::Sender<usize>>,
+}
+
+impl InstanceGuard {
+    fn new(count: Arc<sync::watch::Sender<usize>>) -> Self {
+        count.send_modify(|n| *n += 1);
+        Self { count }
+    }
+}
+
+impl Drop for InstanceGuard {
+    fn drop(&mut self) {
+        self.count.send_modify(|n| *n = n.saturating_sub(1));
+    }
 }
 
 pub struct SessionInstance {
-    data_req_tx: RequesterTx<Result<Vec<u8>, SessionError>>,
+    data_req_tx: FrameRequestTx,
+    request_slots: Arc<sync::Semaphore>,
+    _guard: InstanceGuard,
+    buffer_state_rx: sync::watch::Receiver<BufferState>,
+    buffer_status_rx: sync::watch::Receiver<BufferStatus>,
+    default_timeout: Option<Duration>,
+    default_priority: Option<Priority>,
 }
 
 impl SessionInstance {
-    fn new(data_req_tx: RequesterTx<Result<Vec<u8>, SessionError>>) -> Self {
-        Self { data_req_tx }
+    fn new(
+        data_req_tx: FrameRequestTx,
+        request_slots: Arc<sync::Semaphore>,
+        buffer_state_rx: sync::watch::Receiver<BufferState>,
+        buffer_status_rx: sync::watch::Receiver<BufferStatus>,
+        default_timeout: Option<Duration>,
+        guard: InstanceGuard,
+    ) -> Self {
+        Self {
+            data_req_tx,
+            request_slots,
+            _guard: guard,
+            buffer_state_rx,
+            buffer_status_rx,
+            default_timeout,
+            default_priority: None,
+        }
+    }
+
+    // For the requests of this instance that don't set their own
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.default_priority = Some(priority);
+        self
     }
 
-    pub async fn request_image(&self) -> Result<Vec<u8>, SessionError> {
-        let (req_tx, req_rx) = sync::oneshot::channel();
-        self.data_req_tx
-            .send(req_tx)
+    // Bounded by the request's own timeout, falling back to `SessionConfig::request_timeout`
+    pub async fn request_image(
+        &self,
+        mut req: FrameRequest,
+    ) -> Result<FrameResponse, SessionError> {
+        req.issued_at = Some(Instant::now());
+        req.priority = req.priority.or(self.default_priority);
+        let (timeout, backpressure) = (req.timeout(), req.backpressure());
+        self.send_request(timeout, backpressure, |tx| DataRequest::Frame(req, tx))
             .await
-            .map_err(|_| SessionError::BrokenPipeline)?;
+    }
 
-        req_rx.await.map_err(|_| SessionError::ServerDropped)?
+    // Frames that decoded before a failure are still returned alongside the error
+    pub async fn request_batch(
+        &self,
+        mut req: BatchRequest,
+    ) -> Result<BatchResponse, SessionError> {
+        req.issued_at = Some(Instant::now());
+        req.priority = req.priority.or(self.default_priority);
+        let (timeout, backpressure) = (req.timeout(), req.backpressure());
+        self.send_request(timeout, backpressure, |tx| DataRequest::Batch(req, tx))
+            .await
+    }
+
+    async fn send_request<T>(
+        &self,
+        timeout: Option<Duration>,
+        backpressure: Backpressure,
+        make: impl FnOnce(Requester<T>) -> DataRequest,
+    ) -> Result<T, SessionError> {
+        let request = async {
+            let slots = Arc::clone(&self.request_slots);
+            let slot = match backpressure {
+                Backpressure::Wait => slots
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| SessionError::BrokenPipeline)?,
+                Backpressure::FailFast => slots.try_acquire_owned().map_err(|e| match e {
+                    sync::TryAcquireError::NoPermits => SessionError::Busy,
+                    sync::TryAcquireError::Closed => SessionError::BrokenPipeline,
+                })?,
+            };
+            let (req_tx, req_rx) = sync::oneshot::channel();
+            let requester = Requester {
+                tx: req_tx,
+                _slot: slot,
+            };
+            // Never full, it holds no more requests than there are slots
+            self.data_req_tx
+                .send(make(requester))
+                .await
+                .map_err(|_| SessionError::BrokenPipeline)?;
+
+            req_rx.await.map_err(|_| SessionError::ServerDropped)?
+        };
+        match timeout.or(self.default_timeout) {
+            Some(timeout) => tokio::time::timeout(timeout, request)
+                .await
+                .map_err(|_| SessionError::Timeout(timeout))?,
+            None => request.await,
+        }
+    }
+
+    pub fn buffer_state(&self) -> BufferState {
+        *self.buffer_state_rx.borrow()
+    }
+
+    // Updated by the decode worker after every frame and request, reading it doesn't go through
+    // the request queue
+    pub fn buffer_status(&self) -> BufferStatus {
+        *self.buffer_status_rx.borrow()
     }
 }
 
@@ -59,21 +282,132 @@ type RequesterTx<T> = sync::mpsc::Sender<sync::oneshot::Sender<T>>;
 type RequesterRx<T> = sync::mpsc::Receiver<sync::oneshot::Sender<T>>;
 
 pub struct SessionInstanceManager {
-    subscriber_request_tx: RequesterTx<Option<SessionInstance>>,
-    task_handle: JoinHandle<()>,
+    subscriber_request_tx: RequesterTx<Result<SessionInstance, SubscribeError>>,
+    stream_request_tx: sync::mpsc::Sender<FrameSubscriber>,
+    control_tx: sync::mpsc::Sender<Control>,
+    stream_capacity: usize,
+    // Never read, only resubscribed so the manager doesn't keep the channel open on its own
+    broadcast_rx: sync::broadcast::Receiver<FrameResponse>,
+    stats: Arc<StatsCollector>,
+    // Only resubscribed, same as `broadcast_rx`
+    events_rx: sync::broadcast::Receiver<SessionEvent>,
+    // `None` without `SessionConfig::metadata_stream`
+    metadata_rx: Option<sync::broadcast::Receiver<MetadataMessage>>,
+    transport_rx: sync::watch::Receiver<Option<TransportPreference>>,
+    stream_rx: sync::watch::Receiver<Option<StreamParameters>>,
+    instances_rx: sync::watch::Receiver<usize>,
+    health_rx: sync::watch::Receiver<LoopHealth>,
+    buffer_state_rx: sync::watch::Receiver<BufferState>,
+    clock: ClockAnchor,
+    shutdown_tx: Option<sync::oneshot::Sender<()>>,
+    task_handle: Option<JoinHandle<()>>,
 }
 
 impl SessionInstanceManager {
     fn new(
-        subscriber_request_tx: RequesterTx<Option<SessionInstance>>,
+        subscriber_request_tx: RequesterTx<Result<SessionInstance, SubscribeError>>,
+        stream_request_tx: sync::mpsc::Sender<FrameSubscriber>,
+        control_tx: sync::mpsc::Sender<Control>,
+        stream_capacity: usize,
+        broadcast_rx: sync::broadcast::Receiver<FrameResponse>,
+        stats: Arc<StatsCollector>,
+        events_rx: sync::broadcast::Receiver<SessionEvent>,
+        metadata_rx: Option<sync::broadcast::Receiver<MetadataMessage>>,
+        transport_rx: sync::watch::Receiver<Option<TransportPreference>>,
+        stream_rx: sync::watch::Receiver<Option<StreamParameters>>,
+        instances_rx: sync::watch::Receiver<usize>,
+        health_rx: sync::watch::Receiver<LoopHealth>,
+        buffer_state_rx: sync::watch::Receiver<BufferState>,
+        shutdown_tx: sync::oneshot::Sender<()>,
         task_handle: JoinHandle<()>,
     ) -> Self {
         Self {
             subscriber_request_tx,
-            task_handle,
+            stream_request_tx,
+            control_tx,
+            stream_capacity,
+            broadcast_rx,
+            stats,
+            events_rx,
+            metadata_rx,
+            transport_rx,
+            stream_rx,
+            instances_rx,
+            health_rx,
+            buffer_state_rx,
+            clock: ClockAnchor::now(),
+            shutdown_tx: Some(shutdown_tx),
+            task_handle: Some(task_handle),
+        }
+    }
+
+    // Read straight from shared counters, doesn't go through the request queue
+    pub fn stats(&self) -> SessionStats {
+        self.stats.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    // Events emitted from now on. A receiver that falls behind loses the oldest events.
+    pub fn events(&self) -> sync::broadcast::Receiver<SessionEvent> {
+        self.events_rx.resubscribe()
+    }
+
+    // False once the session loop exited, e.g. after running out of `max_retries`
+    pub fn is_running(&self) -> bool {
+        self.task_handle.as_ref().is_some_and(|h| !h.is_finished())
+    }
+
+    // Transport negotiated for the current connection, `None` while not connected
+    pub fn transport(&self) -> Option<TransportPreference> {
+        *self.transport_rx.borrow()
+    }
+
+    // Taken when the session started, maps `FrameResponse::received_at` to wall-clock time
+    pub fn clock(&self) -> ClockAnchor {
+        self.clock
+    }
+
+    // Codec parameters of the stream being played, `None` while not connected
+    pub fn stream_info(&self) -> Option<StreamParameters> {
+        self.stream_rx.borrow().clone()
+    }
+
+    // Lock-free, combines what the session loop last published with the packet counters
+    pub fn health(&self) -> Health {
+        let stats = self.stats.snapshot();
+        let health = self.health_rx.borrow().clone();
+        Health {
+            running: self.is_running(),
+            connected: health.connected,
+            since_last_packet: stats.last_packet.map(|at| at.elapsed()),
+            since_last_iframe: stats.last_iframe.map(|at| at.elapsed()),
+            reconnect_attempt: health.reconnect_attempt,
+            last_error: health.last_error,
         }
     }
 
+    // Resolves once an iframe is buffered, right away when one already is. Frames are only
+    // decoded when asked for, so the first request may still take a decode.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<(), SessionError> {
+        let mut buffer_state_rx = self.buffer_state_rx.clone();
+        tokio::time::timeout(
+            timeout,
+            buffer_state_rx.wait_for(|state| state.i_frame_ts.is_some()),
+        )
+        .await
+        .map_err(|_| SessionError::Timeout(timeout))?
+        .map(|_| ())
+        .map_err(|_| SessionError::ServerDropped)
+    }
+
+    // Instances handed out by `request_instance` that weren't dropped yet
+    pub fn instance_count(&self) -> usize {
+        *self.instances_rx.borrow()
+    }
+
     pub async fn request_instance(&mut self) -> Result<SessionInstance, SessionError> {
         let (inp, out) = sync::oneshot::channel();
         self.subscriber_request_tx
@@ -83,175 +417,1293 @@ impl SessionInstanceManager {
 
         out.await
             .map_err(|_| SessionError::ServerDropped)?
-            .ok_or(SessionError::UnableToSubscribe(String::new())) // TODO: Change from
-                                                                   // Option to Result as this
-                                                                   // allows a feedback return
+            .map_err(SessionError::UnableToSubscribe)
     }
 
-    pub fn close(self) {}
-}
-
-impl Drop for SessionInstanceManager {
-    fn drop(&mut self) {
-        self.task_handle.abort();
+    // Every frame buffered from now on is decoded and pushed to the stream. Works alongside
+    // `SessionInstance::request_image`.
+    pub async fn subscribe_stream(&self) -> Result<FrameStream, SessionError> {
+        let (tx, rx) = sync::mpsc::channel(self.stream_capacity.max(1));
+        self.stream_request_tx
+            .send(tx)
+            .await
+            .map_err(|_| SessionError::BrokenPipeline)?;
+        Ok(FrameStream::new(rx))
     }
-}
 
-struct FrameHolder {
-    inner_h264: Vec<u8>,
-    ts: Instant,
-    decoded_frame: Option<Vec<u8>>,
-}
+    // Like `subscribe_stream`, but every receiver shares the same decoded frames
+    pub fn subscribe_broadcast(&self) -> FrameBroadcast {
+        FrameBroadcast::new(self.broadcast_rx.resubscribe())
+    }
 
-impl FrameHolder {
-    fn new(h264: Vec<u8>) -> Self {
-        Self {
-            inner_h264: h264,
-            ts: Instant::now(),
-            decoded_frame: None,
+    // Messages of the camera's metadata stream from now on, receivers falling more than
+    // `broadcast_capacity` behind skip the oldest. Ends right away without
+    // `SessionConfig::metadata_stream`.
+    pub fn metadata_stream(&self) -> MetadataStream {
+        match &self.metadata_rx {
+            Some(rx) => MetadataStream::new(rx.resubscribe()),
+            None => MetadataStream::new(sync::broadcast::channel(1).1),
         }
     }
 
-    fn decode(&mut self, decoder: &mut dyn ImageDecoder) -> Result<&[u8], DecoderError> {
-        if self.decoded_frame.is_some() {
-            return self
-                .decoded_frame
-                .as_ref()
-                .map(|v| v.as_slice())
-                .ok_or(DecoderError::NoImageDecoded);
-        }
-        let decoded = decoder.decode(&self.inner_h264)?;
+    // Stops buffering while keeping the RTSP session alive, requests get `SessionError::Paused`
+    // until `resume`. retina can't PAUSE a playing session, so packets keep arriving and are
+    // discarded.
+    pub async fn pause(&self) -> Result<(), SessionError> {
+        self.control_tx
+            .send(Control::Pause)
+            .await
+            .map_err(|_| SessionError::BrokenPipeline)
+    }
 
-        self.decoded_frame = Some(decoded.to_vec());
-        self.decoded_frame
-            .as_ref()
-            .map(|v| v.as_slice())
-            .ok_or(DecoderError::NoImageDecoded)
+    // Frames are served again from the next iframe on
+    pub async fn resume(&self) -> Result<(), SessionError> {
+        self.control_tx
+            .send(Control::Resume)
+            .await
+            .map_err(|_| SessionError::BrokenPipeline)
     }
 
-    fn empty() -> Self {
-        Self {
-            inner_h264: vec![],
-            ts: Instant::now(),
-            decoded_frame: Some(vec![]),
+    // Saves the buffered GOPs covering the last `duration` as an MP4 at `path`, without
+    // re-encoding. Whole GOPs are kept, so the clip starts on the iframe at or before that point
+    // and may run longer. Returns the duration actually written.
+    pub async fn save_clip(
+        &self,
+        path: impl AsRef<Path>,
+        duration: Duration,
+    ) -> Result<Duration, SessionError> {
+        let (reply, rx) = sync::oneshot::channel();
+        self.control_tx
+            .send(Control::Clip(duration, reply))
+            .await
+            .map_err(|_| SessionError::BrokenPipeline)?;
+        let clip = rx.await.map_err(|_| SessionError::ServerDropped)??;
+        let path = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || clip.write_mp4(&path))
+            .await
+            .map_err(|_| SessionError::ServerDropped)?
+    }
+
+    // Decodes with `decoder` from the next iframe on, e.g. after the camera switched codecs or to
+    // shed load with a cheaper output format. Everything buffered is dropped at that point, and
+    // until then requests get `SessionError::SwappingDecoder` and streams go without frames.
+    // Resolves with the generation of the first GOP the new decoder sees, the resync hook is
+    // asked for an iframe if there is one. A replacement still waiting when another one comes in
+    // is dropped, its caller gets `SwappingDecoder`.
+    pub async fn replace_decoder(
+        &self,
+        decoder: Box<dyn ImageDecoder + Sync + Send>,
+    ) -> Result<u64, SessionError> {
+        let (reply, rx) = sync::oneshot::channel();
+        self.control_tx
+            .send(Control::ReplaceDecoder(
+                Box::new(SyncDecoder::from(decoder)),
+                reply,
+            ))
+            .await
+            .map_err(|_| SessionError::BrokenPipeline)?;
+        rx.await.map_err(|_| SessionError::ServerDropped)?
+    }
+
+    // Same as `SessionWrapper::on_frame`, for frames buffered from now on
+    pub async fn on_frame(
+        &self,
+        handler: impl FnMut(FrameResponse) + Send + 'static,
+    ) -> Result<(), SessionError> {
+        self.control_tx
+            .send(Control::OnFrame(Box::new(handler)))
+            .await
+            .map_err(|_| SessionError::BrokenPipeline)
+    }
+
+    // Starts, replaces or with `None` stops the bitstream dump of a running session
+    pub async fn set_bitstream_dump(
+        &self,
+        dump: Option<BitstreamDump>,
+    ) -> Result<(), SessionError> {
+        self.control_tx
+            .send(Control::SetDump(dump))
+            .await
+            .map_err(|_| SessionError::BrokenPipeline)
+    }
+
+    // Asks the session loop to tear the RTSP session down and resolves once it has exited
+    pub async fn close(mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Some(handle) = self.task_handle.take() {
+            if let Err(e) = handle.await {
+                error!("Session task failed while closing: {:?}", e);
+            }
         }
     }
 
-    fn is_empty(&self) -> bool {
-        if let Some(v) = self.decoded_frame.as_ref() {
-            v.is_empty()
-        } else {
-            false
+    // Stops `dependents` one after the other, in the order given, and the session last, all within
+    // `timeout`. A dependent still running at the deadline is dropped. Returns whether everything
+    // exited on its own, see `shutdown`.
+    pub async fn shutdown_with(
+        self,
+        dependents: Vec<Box<dyn Dependent>>,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut clean = true;
+        for dependent in dependents {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if tokio::time::timeout(left, dependent.shutdown())
+                .await
+                .is_err()
+            {
+                warn!(
+                    "A dependent of the session didn't stop within {:?}, dropping it",
+                    timeout
+                );
+                clean = false;
+            }
         }
+        self.shutdown(deadline.saturating_duration_since(Instant::now()))
+            .await
+            && clean
     }
 
-    fn elapsed(&self) -> Duration {
-        Instant::now().duration_since(self.ts)
+    // Like `close`, but aborts the session task if it hasn't exited within `timeout`. Returns
+    // whether it exited on its own, pending requests have been failed and the frame handlers
+    // have seen their last frame by then.
+    pub async fn shutdown(mut self, timeout: Duration) -> bool {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        let Some(mut handle) = self.task_handle.take() else {
+            return true;
+        };
+        match tokio::time::timeout(timeout, &mut handle).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                error!("Session task failed while shutting down: {:?}", e);
+                true
+            }
+            Err(_) => {
+                warn!("Session didn't shut down within {:?}, aborting it", timeout);
+                handle.abort();
+                false
+            }
+        }
+    }
+}
+
+// Only signals the session loop, which tears the session down and fails pending requests on its
+// own. `shutdown` waits for that.
+impl Drop for SessionInstanceManager {
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
     }
 }
 
 pub struct SessionWrapper {
     camera_url: Url,
-    frame_holder: FrameHolder,
-    decoder: Box<dyn ImageDecoder + Sync + Send>,
+    creds: Option<Credentials>,
+    config: SessionConfig,
+    decoder: Box<dyn AsyncImageDecoder + Sync + Send>,
+    resync_hook: Option<ResyncHook>,
+    url_refresh: Option<UrlRefreshHook>,
+    dump: Option<BitstreamDump>,
+    frame_handlers: Vec<FrameHandler>,
+    // Tracks the sessions opened to the camera until their TEARDOWN went through
+    group: Arc<SessionGroup>,
 }
 
 impl SessionWrapper {
-    pub fn new(camera_url: Url, decoder: Box<dyn ImageDecoder + Sync + Send>) -> Self {
+    pub fn new(camera_url: Url, decoder: Box<dyn AsyncImageDecoder + Sync + Send>) -> Self {
         Self {
             camera_url,
-            frame_holder: FrameHolder::empty(),
+            creds: None,
+            config: SessionConfig::default(),
             decoder,
+            resync_hook: None,
+            url_refresh: None,
+            dump: None,
+            frame_handlers: Vec::new(),
+            group: Arc::new(SessionGroup::default()),
         }
     }
 
-    pub async fn start(self) -> SessionInstanceManager {
-        let (subscriber_requester_tx, subscriber_requester_rx) = sync::mpsc::channel(24);
-        let handle = tokio::spawn(self.session_loop(subscriber_requester_rx));
-        SessionInstanceManager::new(subscriber_requester_tx, handle)
+    pub fn new_sync(camera_url: Url, decoder: Box<dyn ImageDecoder + Sync + Send>) -> Self {
+        Self::new(camera_url, Box::new(SyncDecoder::from(decoder)))
     }
 
-    async fn start_session(&self) -> Result<Demuxed, SessionError> {
-        let mut session = Session::describe(self.camera_url.clone(), SessionOptions::default())
-            .await
-            .map_err(|e| SessionError::FailedToDescribeSession(e))?;
+    // Decodes on the threads of `pool`, shared with the other sessions built from it
+    pub fn new_pooled(camera_url: Url, pool: &DecoderPool) -> Self {
+        Self::new(camera_url, Box::new(pool.decoder()))
+    }
 
-        let video_stream = session
-            .streams()
-            .iter()
-            .position(|s| s.media() == "video")
-            .ok_or(SessionError::NoVideoStreamFound)?;
+    pub fn with_config(mut self, config: SessionConfig) -> Self {
+        self.config = config;
+        self
+    }
 
-        session
-            .setup(
-                video_stream,
-                SetupOptions::default().transport(Transport::Tcp(TcpTransportOptions::default())),
-            )
-            .await
-            .map_err(|e| SessionError::FailedToSetupStream(e))?;
+    pub fn with_credentials(mut self, user: &str, pass: &str) -> Self {
+        self.creds = Some(Credentials {
+            username: user.to_string(),
+            password: pass.to_string(),
+        });
+        self
+    }
 
-        session
-            .play(
-                PlayOptions::default()
-                    .initial_seq(InitialSequenceNumberPolicy::Respect)
-                    .initial_timestamp(InitialTimestampPolicy::Require),
+    // With a hook set, requests hitting expired frames wait for the iframe it requests instead of
+    // getting `OldFrame` straight away
+    pub fn with_resync_hook(mut self, hook: ResyncHook) -> Self {
+        self.resync_hook = Some(hook);
+        self
+    }
+
+    pub fn with_bitstream_dump(mut self, dump: BitstreamDump) -> Self {
+        self.dump = Some(dump);
+        self
+    }
+
+    // Called with every frame the session buffers, decoded as for a `FrameStream` and after
+    // decimation. Each handler runs on a thread of its own behind a queue of `buf_size` frames,
+    // frames arriving while it is full are dropped. Panics are caught and reported as
+    // `SessionEvent::HandlerPanicked`. Like streams, handlers get nothing while `idle_mode` has
+    // the session idle.
+    pub fn on_frame(mut self, handler: impl FnMut(FrameResponse) + Send + 'static) -> Self {
+        self.frame_handlers.push(Box::new(handler));
+        self
+    }
+
+    pub fn with_url_refresh_hook(mut self, hook: UrlRefreshHook) -> Self {
+        self.url_refresh = Some(hook);
+        self
+    }
+
+    // Sets both hooks, iframes are requested and stream urls refetched through `media_cli`
+    pub fn with_media_client(self, media_cli: MediaClient) -> Self {
+        let refresh_cli = media_cli.clone();
+        self.with_url_refresh_hook(Arc::new(move || {
+            let media_cli = refresh_cli.clone();
+            Box::pin(async move { media_cli.get_stream_uri().await.map_err(|e| e.into()) })
+        }))
+        .with_resync_hook(Box::new(move || {
+            let media_cli = media_cli.clone();
+            Box::pin(async move { media_cli.sync_iframe_or_wait().await.map_err(|e| e.into()) })
+        }))
+    }
+
+    // Connects before spawning the session loop, so a bad url or unreachable camera surfaces
+    // here with the actual DESCRIBE/SETUP/PLAY error
+    pub async fn start(self) -> Result<SessionInstanceManager, SessionError> {
+        let target = self.session_target();
+        let connected = target.clone().connect().await?;
+        info!(url = %target.url, transport = ?connected.transport, "Session started");
+        Ok(self.spawn(target, connected))
+    }
+
+    // Plays `script` instead of connecting to the camera. Once the script ran out the session
+    // keeps what it buffered and waits, as if the camera went quiet, unless `FrameScript::then`
+    // gave it a script to restart on. Nothing is ever sent to the camera url.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn start_scripted(self, script: FrameScript) -> SessionInstanceManager {
+        let (connected, restarts) = script.into_connected();
+        let target = SessionTarget {
+            restarts: Some(restarts),
+            ..self.session_target()
+        };
+        self.spawn(target, connected)
+    }
+
+    fn spawn(self, target: SessionTarget, connected: Connected) -> SessionInstanceManager {
+        let (subscriber_requester_tx, subscriber_requester_rx) =
+            sync::mpsc::channel(self.config.instance_request_queue.max(1));
+        let (transport_tx, transport_rx) = sync::watch::channel(None);
+        let (stream_tx, stream_rx) = sync::watch::channel(None);
+        let (instances_tx, instances_rx) = sync::watch::channel(0);
+        let (health_tx, health_rx) = sync::watch::channel(LoopHealth::default());
+        let (shutdown_tx, shutdown_rx) = sync::oneshot::channel();
+        let (buffer_state_tx, buffer_state_rx) = sync::watch::channel(BufferState {
+            generation: 0,
+            i_frame_ts: None,
+        });
+        let (buffer_status_tx, buffer_status_rx) = sync::watch::channel(BufferStatus {
+            generation: 0,
+            buffered: 0,
+            decoded: 0,
+            i_frame_ts: None,
+            corrupt: false,
+            buf_size: self.config.buf_size,
+            frame_lifetime: self.config.frame_lifetime,
+        });
+
+        let (resync_tx, resync_rx) = sync::mpsc::unbounded_channel();
+        let (stream_request_tx, stream_request_rx) = sync::mpsc::channel(8);
+        let (control_tx, control_rx) = sync::mpsc::channel(8);
+        let stream_capacity = self.config.buf_size;
+        let (broadcast_tx, broadcast_rx) =
+            sync::broadcast::channel(self.config.broadcast_capacity.max(1));
+        let stats = Arc::new(StatsCollector::new());
+        let (events_tx, events_rx) = sync::broadcast::channel(64);
+        let (metadata_tx, metadata_rx) = if self.config.metadata_stream {
+            let (tx, rx) = sync::broadcast::channel(self.config.broadcast_capacity.max(1));
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+
+        let worker_tx = DecodeWorker::new(
+            self.config.clone(),
+            BlockingDecoder::new(self.decoder, BufferPool::new(self.config.buffer_pool_size)),
+            buffer_state_tx,
+            buffer_status_tx,
+            broadcast_tx,
+            Arc::clone(&stats),
+            events_tx.clone(),
+        )
+        .with_resync(self.resync_hook.is_some().then_some(resync_tx))
+        .spawn();
+        let mut handler_threads = Vec::new();
+        for handler in self.frame_handlers {
+            let (subscriber, thread) =
+                handler::spawn(handler, self.config.buf_size, events_tx.clone());
+            let _ = worker_tx.send(WorkerMessage::Subscribe(subscriber));
+            handler_threads.push(thread);
+        }
+        let session_loop = SessionLoop {
+            config: self.config,
+            target,
+            worker_tx,
+            buffer_state_rx: buffer_state_rx.clone(),
+            buffer_status_rx,
+            transport_tx,
+            stream_tx,
+            instances: Arc::new(instances_tx),
+            health_tx,
+            resync_hook: self.resync_hook,
+            stats: Arc::clone(&stats),
+            events_tx,
+            dump: self.dump.map(|d| DumpTap::spawn(d, None)),
+            parameters: None,
+            resolution: None,
+            assembler: AccessUnitAssembler::default(),
+            wall_clock: WallClock::default(),
+            metadata_tx,
+            handler_threads,
+        };
+        let handle = tokio::spawn(session_loop.run(
+            connected,
+            subscriber_requester_rx,
+            instances_rx,
+            stream_request_rx,
+            control_rx,
+            resync_rx,
+            shutdown_rx,
+        ));
+        SessionInstanceManager::new(
+            subscriber_requester_tx,
+            stream_request_tx,
+            control_tx,
+            stream_capacity,
+            broadcast_rx,
+            stats,
+            events_rx,
+            metadata_rx,
+            transport_rx,
+            stream_rx,
+            instances_rx.clone(),
+            health_rx,
+            buffer_state_rx,
+            shutdown_tx,
+            handle,
+        )
+    }
+
+    fn session_target(&self) -> SessionTarget {
+        SessionTarget {
+            url_refresh: self.url_refresh.clone(),
+            ..SessionTarget::new(
+                &self.camera_url,
+                self.creds.clone(),
+                &self.config,
+                Arc::clone(&self.group),
             )
-            .await
-            .map_err(|e| SessionError::FailedToPlayStream(e))?
-            .demuxed()
-            .map_err(|e| SessionError::FailedToDemuxStream(e))
+        }
     }
 
-    async fn session_loop(mut self, mut data_requester_rx: RequesterRx<Option<SessionInstance>>) {
-        let mut session = self
-            .start_session()
-            .await
-            .expect("Failed to start session stream");
-        type DataRequester = sync::oneshot::Sender<Result<Vec<u8>, SessionError>>;
+    // Streams the camera advertises, without setting anything up or playing
+    pub async fn probe_streams(&self) -> Result<Vec<StreamInfo>, SessionError> {
+        self.session_target().probe().await
+    }
+}
+
+// Runs DESCRIBE only and lists what `url` offers, e.g. to size a decoder before starting the
+// session
+pub async fn probe(url: Url, creds: Option<(&str, &str)>) -> Result<Vec<StreamInfo>, SessionError> {
+    let creds = creds.map(|(user, pass)| Credentials {
+        username: user.to_string(),
+        password: pass.to_string(),
+    });
+    let group = Arc::new(SessionGroup::default());
+    SessionTarget::new(&url, creds, &SessionConfig::default(), group)
+        .probe()
+        .await
+}
+
+// Drives the RTSP connection. Frames and requests are handed to the decode worker as they come
+// in, so the loop keeps pulling packets while a frame is being decoded.
+struct SessionLoop {
+    config: SessionConfig,
+    target: SessionTarget,
+    worker_tx: sync::mpsc::UnboundedSender<WorkerMessage>,
+    buffer_state_rx: sync::watch::Receiver<BufferState>,
+    buffer_status_rx: sync::watch::Receiver<BufferStatus>,
+    transport_tx: sync::watch::Sender<Option<TransportPreference>>,
+    stream_tx: sync::watch::Sender<Option<StreamParameters>>,
+    // Live instance count, see `InstanceGuard`
+    instances: Arc<sync::watch::Sender<usize>>,
+    health_tx: sync::watch::Sender<LoopHealth>,
+    resync_hook: Option<ResyncHook>,
+    stats: Arc<StatsCollector>,
+    events_tx: sync::broadcast::Sender<SessionEvent>,
+    dump: Option<DumpTap>,
+    // avcC record and size from the SDP of the current connection
+    parameters: Option<Vec<u8>>,
+    resolution: Option<(u32, u32)>,
+    // Only fed with `SessionConfig::aggregate_slices`
+    assembler: AccessUnitAssembler,
+    wall_clock: WallClock,
+    // Only with `SessionConfig::metadata_stream`
+    metadata_tx: Option<sync::broadcast::Sender<MetadataMessage>>,
+    // Joined on shutdown, once the worker dropped their queues
+    handler_threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl SessionLoop {
+    fn reconnect_delay(&self, attempt: u32) -> Duration {
+        self.config
+            .reconnect_initial_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.config.reconnect_max_delay)
+    }
+
+    // Drops whatever was buffered from the lost stream and schedules the first reconnect
+    fn begin_reconnect(
+        &mut self,
+        reason: &Disconnect,
+    ) -> BoxFuture<'static, Result<Connected, SessionError>> {
+        let _ = self.transport_tx.send(None);
+        let _ = self.stream_tx.send(None);
+        self.assembler.clear();
+        self.wall_clock.clear();
+        let _ = self.worker_tx.send(WorkerMessage::Drain(reason.clone()));
+        self.health_tx.send_modify(|h| {
+            h.connected = false;
+            h.last_error = Some(reason.to_error().to_string());
+        });
+        self.emit(SessionEvent::Disconnected {
+            reason: reason.to_error().to_string(),
+        });
+        self.schedule_reconnect(0)
+    }
+
+    fn schedule_reconnect(
+        &self,
+        attempt: u32,
+    ) -> BoxFuture<'static, Result<Connected, SessionError>> {
+        self.emit(SessionEvent::ReconnectAttempt { n: attempt + 1 });
+        self.target
+            .clone()
+            .connect_after(self.reconnect_delay(attempt))
+    }
+
+    fn on_connected(&mut self, connected: Connected) -> FrameSource {
+        let _ = self.transport_tx.send(Some(connected.transport));
+        self.health_tx.send_modify(|h| {
+            h.connected = true;
+            h.reconnect_attempt = 0;
+        });
+        if let (Some(tap), Some(parameters)) = (&self.dump, &connected.parameters) {
+            tap.parameters(parameters.clone());
+        }
+        self.emit(SessionEvent::Connected {
+            url: self.target.url.clone(),
+        });
+        if let (Some(from), Some(to)) = (
+            connected.timestamp_fallback_from,
+            connected.stream.timestamp_policy,
+        ) {
+            self.stats.record_timestamp_fallback();
+            self.emit(SessionEvent::TimestampPolicyFallback { from, to });
+        }
+        self.set_stream(connected.stream, connected.parameters);
+        connected.source
+    }
 
-        let (data_req_tx, mut data_req_rx) = sync::mpsc::channel::<DataRequester>(32);
+    fn on_stream_update(&mut self, mut update: StreamUpdate) {
+        info!(parameters = ?update.stream, "The camera changed the stream parameters");
+        update.stream.timestamp_policy = self
+            .stream_tx
+            .borrow()
+            .as_ref()
+            .and_then(|s| s.timestamp_policy);
+        if let (Some(tap), Some(parameters)) = (&self.dump, &update.parameters) {
+            tap.parameters(parameters.clone());
+        }
+        self.set_stream(update.stream, update.parameters);
+    }
+
+    // The decoder gets the new parameter sets, it drops frames decoded at another size on its own
+    fn set_stream(&mut self, stream: StreamParameters, parameters: Option<Vec<u8>>) {
+        let _ = self
+            .worker_tx
+            .send(WorkerMessage::Parameters(parameters.clone()));
+        self.parameters = parameters;
+        self.resolution = stream.dimensions;
+        let _ = self.stream_tx.send(Some(stream.clone()));
+        self.emit(SessionEvent::StreamSetup {
+            codec: stream.encoding.clone(),
+            resolution: stream.dimensions,
+            parameters: stream,
+        });
+    }
+
+    fn set_idle(&self, idle: bool) {
+        if idle {
+            debug!("Last instance dropped, the session is idle");
+            self.emit(SessionEvent::Idle);
+        } else {
+            debug!("Instance handed out, the session is active again");
+            self.emit(SessionEvent::Active);
+        }
+        if self.config.idle_mode {
+            let _ = self.worker_tx.send(WorkerMessage::SetIdle(idle));
+        }
+    }
+
+    // Broadcast never waits on receivers, a send only fails when nobody is listening
+    fn emit(&self, event: SessionEvent) {
+        let _ = self.events_tx.send(event);
+    }
+
+    fn resync(&self) {
+        let Some(hook) = &self.resync_hook else {
+            return;
+        };
+        let fut = hook();
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                warn!("Failed to request a synchronization point: {}", e);
+            }
+        });
+    }
+
+    async fn run(
+        mut self,
+        connected: Connected,
+        mut data_requester_rx: RequesterRx<Result<SessionInstance, SubscribeError>>,
+        mut instances_rx: sync::watch::Receiver<usize>,
+        mut stream_request_rx: sync::mpsc::Receiver<FrameSubscriber>,
+        mut control_rx: sync::mpsc::Receiver<Control>,
+        mut resync_rx: sync::mpsc::UnboundedReceiver<()>,
+        mut shutdown_rx: sync::oneshot::Receiver<()>,
+    ) {
+        let mut session = Some(self.on_connected(connected));
+        let mut reconnect: Option<BoxFuture<'static, Result<Connected, SessionError>>> = None;
+        let mut attempt = 0;
+        let mut last_video = Instant::now();
+        let mut disconnect = Disconnect::Ended;
+        let mut resync_deadline: Option<Instant> = None;
+        let mut paused = false;
+        // Set while no instance is alive
+        let mut idle = false;
+        let mut idle_deadline: Option<Instant> = None;
+        // Paused by `idle_pause_after` rather than `pause`, the next instance resumes it
+        let mut idle_paused = false;
+        // See `CaptureMode::KeyframeOnly`
+        let keyframe_interval = match self.config.capture_mode {
+            CaptureMode::KeyframeOnly { request_interval } if self.resync_hook.is_some() => {
+                request_interval.filter(|i| !i.is_zero())
+            }
+            _ => None,
+        };
+        let mut keyframe_deadline = keyframe_interval.map(|i| Instant::now() + i);
+
+        let (data_req_tx, mut data_req_rx) =
+            sync::mpsc::channel::<DataRequest>(self.config.frame_request_queue.max(1));
+        let request_slots = Arc::new(sync::Semaphore::new(self.config.frame_request_queue.max(1)));
         loop {
             tokio::select! {
-                Some(sender) = data_req_rx.recv(), if !self.frame_holder.is_empty() => {
-                    if self.frame_holder.elapsed() > Duration::from_millis(500) {
-                        self.frame_holder = FrameHolder::empty();
-                        match sender.send(Err(SessionError::OldFrame)) {
-                            Ok(_) => {},
-                            Err(e) => {
-                                println!("Channel was closed by requester: {:?}", e)
-                            }
-                        }
+                _ = &mut shutdown_rx => {
+                    info!("Shutting the session down");
+                    break;
+                },
+                Some(req) = data_req_rx.recv() => {
+                    if paused {
+                        req.fail(SessionError::Paused);
                         continue;
                     }
-                    let f = self.frame_holder.decode(&mut *self.decoder).map_or_else(|e| Err(SessionError::DecodingError(e)), |v| Ok(v.to_vec()));
-                    match sender.send(f) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            println!("Channel was closed by requester: {:?}", e)
+                    if reconnect.is_some() {
+                        req.fail(disconnect.to_error());
+                        continue;
+                    }
+                    if let Err(e) = self.worker_tx.send(WorkerMessage::Request(req)) {
+                        if let WorkerMessage::Request(req) = e.0 {
+                            req.fail(SessionError::BrokenPipeline);
                         }
                     }
-
                 },
                 Some(req) = data_requester_rx.recv() => {
-                    match req.send(Some(SessionInstance::new(data_req_tx.clone()))) {
-                        Ok(_) => {},
-                        Err(_) => {
-                            println!("Failed to send data back")
+                    // A shutdown that raced this request wins, the instance would be dead on arrival
+                    let shutting_down = !matches!(
+                        shutdown_rx.try_recv(),
+                        Err(sync::oneshot::error::TryRecvError::Empty)
+                    );
+                    if shutting_down {
+                        let _ = req.send(Err(SubscribeError::ShuttingDown));
+                        info!("Shutting the session down");
+                        break;
+                    }
+                    let live = *self.instances.borrow();
+                    let res = match self.config.max_instances {
+                        Some(max) if live >= max => {
+                            debug!(max, "Refusing an instance over the limit");
+                            Err(SubscribeError::MaxInstances(max))
                         }
+                        _ => {
+                            if idle {
+                                idle = false;
+                                idle_deadline = None;
+                                self.set_idle(false);
+                            }
+                            if idle_paused {
+                                info!("Instance requested, resuming the idle session");
+                                paused = false;
+                                idle_paused = false;
+                            }
+                            Ok(SessionInstance::new(
+                                data_req_tx.clone(),
+                                Arc::clone(&request_slots),
+                                self.buffer_state_rx.clone(),
+                                self.buffer_status_rx.clone(),
+                                self.config.request_timeout,
+                                InstanceGuard::new(Arc::clone(&self.instances)),
+                            ))
+                        }
+                    };
+                    // Dropping the refused or unreceived instance gives its slot back
+                    if req.send(res).is_err() {
+                        warn!("Instance requester dropped before receiving the instance")
                     }
                 },
-                Some(Ok(packet)) = session.next() => {
-                    match packet {
-                        CodecItem::VideoFrame(f) => {
-                            if !f.is_random_access_point() {
-                                continue;
+                Ok(()) = instances_rx.changed() => {
+                    if *instances_rx.borrow_and_update() == 0 && !idle {
+                        idle = true;
+                        idle_deadline = self.config.idle_pause_after.map(|d| Instant::now() + d);
+                        self.set_idle(true);
+                    }
+                },
+                _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now).into()), if idle_deadline.is_some() && !paused => {
+                    info!("Session idle for {:?}, pausing it", self.config.idle_pause_after.unwrap_or_default());
+                    idle_deadline = None;
+                    paused = true;
+                    idle_paused = true;
+                    resync_deadline = None;
+                    let _ = self.worker_tx.send(WorkerMessage::Drain(Disconnect::Paused));
+                },
+                Some(control) = control_rx.recv() => {
+                    match control {
+                        Control::Pause if !paused => {
+                            info!("Pausing the session");
+                            paused = true;
+                            resync_deadline = None;
+                            let _ = self.worker_tx.send(WorkerMessage::Drain(Disconnect::Paused));
+                        }
+                        Control::Resume if paused => {
+                            info!("Resuming the session, waiting for the next iframe");
+                            paused = false;
+                            idle_paused = false;
+                        }
+                        Control::Clip(duration, reply) => {
+                            let req = ClipRequest {
+                                duration,
+                                parameters: self.parameters.clone(),
+                                resolution: self.resolution,
+                                reply,
+                            };
+                            if let Err(e) = self.worker_tx.send(WorkerMessage::Clip(req)) {
+                                if let WorkerMessage::Clip(req) = e.0 {
+                                    let _ = req.reply.send(Err(SessionError::BrokenPipeline));
+                                }
+                            }
+                        }
+                        Control::ReplaceDecoder(decoder, reply) => {
+                            let msg = WorkerMessage::ReplaceDecoder { decoder, reply };
+                            if let Err(e) = self.worker_tx.send(msg) {
+                                if let WorkerMessage::ReplaceDecoder { reply, .. } = e.0 {
+                                    let _ = reply.send(Err(SessionError::BrokenPipeline));
+                                }
                             }
-                            self.frame_holder = FrameHolder::new(f.into_data())
+                        }
+                        Control::OnFrame(handler) => {
+                            let (subscriber, thread) = handler::spawn(
+                                handler,
+                                self.config.buf_size,
+                                self.events_tx.clone(),
+                            );
+                            let _ = self.worker_tx.send(WorkerMessage::Subscribe(subscriber));
+                            self.handler_threads.push(thread);
+                        }
+                        Control::SetDump(dump) => {
+                            info!(enabled = dump.is_some(), "Changing the bitstream dump");
+                            let parameters = self.parameters.clone();
+                            self.dump = dump.map(|d| DumpTap::spawn(d, parameters));
                         }
                         _ => {}
                     }
+                },
+                Some(subscriber) = stream_request_rx.recv() => {
+                    let _ = self.worker_tx.send(WorkerMessage::Subscribe(subscriber));
+                },
+                item = next_frame(&mut session, &mut self.wall_clock, self.metadata_tx.as_ref()) => {
+                    match item {
+                        Some(Ok(Some((f, update)))) => {
+                            if let Some(update) = update {
+                                self.on_stream_update(update);
+                            }
+                            last_video = Instant::now();
+                            let f = if self.config.aggregate_slices {
+                                let Some((f, merged)) = self.assembler.push(f) else {
+                                    continue;
+                                };
+                                self.stats.record_slices_aggregated(merged);
+                                f
+                            } else {
+                                f
+                            };
+                            self.stats.record_frame(f.data.len(), f.random_access);
+                            if f.loss > 0 {
+                                self.stats.record_loss(f.loss);
+                            }
+                            if let Some(tap) = &self.dump {
+                                tap.frame(f.data.clone());
+                            }
+                            if paused {
+                                continue;
+                            }
+                            if f.random_access {
+                                resync_deadline = None;
+                                self.emit(SessionEvent::IFrameReceived { ts: f.timestamp });
+                            }
+                            if self.worker_tx.send(WorkerMessage::Frame(f)).is_err() {
+                                error!("Decode worker is gone, stopping the session");
+                                break;
+                            }
+                        }
+                        Some(Ok(None)) => {}
+                        // retina can't resume a demuxed stream after it yielded an error, so any
+                        // error is treated as the end of the session
+                        Some(Err(e)) => {
+                            error!("Stream failed, reconnecting: {}", e);
+                            session = None;
+                            disconnect = Disconnect::Failed(e);
+                        }
+                        None => {
+                            warn!("Stream ended, reconnecting");
+                            session = None;
+                            disconnect = Disconnect::Ended;
+                        }
+                    }
+                    if session.is_none() {
+                        attempt = 0;
+                        reconnect = Some(self.begin_reconnect(&disconnect));
+                    }
+                },
+                _ = tokio::time::sleep_until((last_video + self.config.stall_timeout.unwrap_or_default()).into()), if session.as_ref().is_some_and(FrameSource::can_stall) && self.config.stall_timeout.is_some() => {
+                    warn!("No video received for {:?}, restarting the stream", last_video.elapsed());
+                    session = None;
+                    disconnect = Disconnect::Stalled;
+                    attempt = 0;
+                    reconnect = Some(self.begin_reconnect(&disconnect));
+                },
+                Some(()) = resync_rx.recv(), if self.resync_hook.is_some() => {
+                    if resync_deadline.is_none() {
+                        debug!("Requesting a synchronization point");
+                        self.resync();
+                        resync_deadline = Some(Instant::now() + self.config.resync_timeout);
+                    }
+                },
+                _ = tokio::time::sleep_until(resync_deadline.unwrap_or_else(Instant::now).into()), if resync_deadline.is_some() => {
+                    warn!("No iframe received within {:?} of the resync", self.config.resync_timeout);
+                    resync_deadline = None;
+                    let _ = self.worker_tx.send(WorkerMessage::ResyncExpired);
+                },
+                _ = tokio::time::sleep_until(keyframe_deadline.unwrap_or_else(Instant::now).into()), if keyframe_deadline.is_some() => {
+                    if session.is_some() && !paused {
+                        debug!("Requesting a synchronization point for the next keyframe");
+                        self.resync();
+                    }
+                    keyframe_deadline = keyframe_interval.map(|i| Instant::now() + i);
+                },
+                res = async { reconnect.as_mut().unwrap().await }, if reconnect.is_some() => {
+                    reconnect = None;
+                    match res {
+                        Ok(connected) => {
+                            info!(transport = ?connected.transport, attempt, "Reconnected");
+                            self.stats.record_reconnect();
+                            session = Some(self.on_connected(connected));
+                            last_video = Instant::now();
+                            attempt = 0;
+                        }
+                        Err(e) => {
+                            attempt += 1;
+                            self.health_tx.send_modify(|h| {
+                                h.reconnect_attempt = attempt;
+                                h.last_error = Some(e.to_string());
+                            });
+                            if self.config.max_retries.is_some_and(|max| attempt >= max) {
+                                error!("Giving up after {} reconnect attempts: {:?}", attempt, e);
+                                break;
+                            }
+                            warn!("Reconnect attempt {} failed: {:?}", attempt, e);
+                            reconnect = Some(self.schedule_reconnect(attempt));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Dropping the playing session sends the TEARDOWN (see `TeardownPolicy::Always`)
+        drop(session);
+        drop(reconnect);
+        let _ = self.transport_tx.send(None);
+        self.health_tx.send_modify(|h| h.connected = false);
+        self.target.await_teardown().await;
+
+        data_requester_rx.close();
+        while let Ok(req) = data_requester_rx.try_recv() {
+            let _ = req.send(Err(SubscribeError::ShuttingDown));
+        }
+
+        // Closing the worker channel stops the decoder thread once it has answered its backlog
+        drop(self.worker_tx);
+        data_req_rx.close();
+        while let Some(req) = data_req_rx.recv().await {
+            req.fail(SessionError::ServerDropped);
+        }
+
+        // The handlers stop once the worker is gone and they ran through the frames queued for
+        // them, a handler stuck on a frame isn't waited for past the teardown timeout
+        let threads = self.handler_threads;
+        if !threads.is_empty() {
+            let join = tokio::task::spawn_blocking(move || {
+                for thread in threads {
+                    let _ = thread.join();
+                }
+            });
+            if tokio::time::timeout(self.config.teardown_timeout, join)
+                .await
+                .is_err()
+            {
+                warn!(
+                    "Frame handlers didn't finish within {:?}",
+                    self.config.teardown_timeout
+                );
+            }
+        }
+    }
+}
+
+// Where the session loop pulls frames from
+enum FrameSource {
+    Rtsp(Demuxed),
+    #[cfg(any(test, feature = "test-util"))]
+    Scripted(scripted::ScriptPlayer),
+}
+
+impl FrameSource {
+    // Scripts hold frames back as long as they say and never end, the camera url is never
+    // connected to on their behalf
+    fn can_stall(&self) -> bool {
+        match self {
+            Self::Rtsp(_) => true,
+            #[cfg(any(test, feature = "test-util"))]
+            Self::Scripted(s) => s.stalls(),
+        }
+    }
+}
+
+// `Ok(None)` for anything that isn't a video frame, sender reports go to `wall_clock` and messages
+// of the metadata stream to `metadata`. Frames the camera sent new parameters with come along
+// with them.
+async fn next_frame(
+    session: &mut Option<FrameSource>,
+    wall_clock: &mut WallClock,
+    metadata: Option<&sync::broadcast::Sender<MetadataMessage>>,
+) -> Option<Result<Option<(RawFrame, Option<StreamUpdate>)>, Error>> {
+    match session {
+        Some(FrameSource::Rtsp(s)) => {
+            let item = s.next().await?;
+            Some(item.map(|item| match item {
+                CodecItem::VideoFrame(f) => {
+                    let update = f.has_new_parameters().then(|| {
+                        let stream = &s.streams()[f.stream_id()];
+                        StreamUpdate {
+                            parameters: streams::h264_extra_data(stream),
+                            stream: StreamParameters::of(stream),
+                        }
+                    });
+                    let mut f = RawFrame::from(f);
+                    f.captured_at = wall_clock.capture_time(f.timestamp, f.received);
+                    Some((f, update))
+                }
+                CodecItem::Rtcp(pkt) => {
+                    wall_clock.on_rtcp(&pkt);
+                    None
+                }
+                CodecItem::MessageFrame(m) => {
+                    if let Some(tx) = metadata {
+                        // No receivers is fine, nobody asked for the stream yet
+                        let _ = tx.send((m.timestamp(), m.data().to_vec()));
+                    }
+                    None
+                }
+                _ => None,
+            }))
+        }
+        #[cfg(any(test, feature = "test-util"))]
+        Some(FrameSource::Scripted(s)) => Some(Ok(match s.next().await {
+            scripted::Played::Frame(f) => Some((f, None)),
+            scripted::Played::Metadata(message) => {
+                if let Some(tx) = metadata {
+                    let _ = tx.send(message);
+                }
+                None
+            }
+        })),
+        None => future::pending().await,
+    }
+}
+
+// Parameters the camera changed to mid-stream
+struct StreamUpdate {
+    stream: StreamParameters,
+    parameters: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+enum Disconnect {
+    Ended,
+    Stalled,
+    Failed(Error),
+    // Not a disconnect, but buffered frames are dropped all the same
+    Paused,
+}
+
+impl Disconnect {
+    // What requesters get told while the session is reconnecting
+    fn to_error(&self) -> SessionError {
+        match self {
+            Self::Ended => SessionError::Reconnecting,
+            Self::Stalled => SessionError::StreamStalled,
+            Self::Failed(e) => SessionError::StreamError(e.clone()),
+            Self::Paused => SessionError::Paused,
+        }
+    }
+}
+
+enum Control {
+    Pause,
+    Resume,
+    SetDump(Option<BitstreamDump>),
+    OnFrame(FrameHandler),
+    Clip(
+        Duration,
+        sync::oneshot::Sender<Result<clip::Clip, SessionError>>,
+    ),
+    ReplaceDecoder(
+        Box<dyn AsyncImageDecoder + Sync + Send>,
+        sync::oneshot::Sender<Result<u64, SessionError>>,
+    ),
+}
+
+struct Connected {
+    source: FrameSource,
+    transport: TransportPreference,
+    stream: StreamParameters,
+    parameters: Option<Vec<u8>>,
+    // Set when `timestamp_fallback` kicked in, `stream.timestamp_policy` is the one played with
+    timestamp_fallback_from: Option<TimestampPolicy>,
+}
+
+#[derive(Clone)]
+struct SessionTarget {
+    url: Url,
+    creds: Option<Credentials>,
+    transport: TransportPreference,
+    stream: StreamSelector,
+    initial_timestamp: TimestampPolicy,
+    initial_seq: SequencePolicy,
+    timestamp_fallback: bool,
+    metadata_stream: bool,
+    max_redirects: usize,
+    url_refresh: Option<UrlRefreshHook>,
+    group: Arc<SessionGroup>,
+    teardown_timeout: Duration,
+    // Connected to in place of the camera by scripted sessions
+    #[cfg(any(test, feature = "test-util"))]
+    restarts: Option<scripted::Restarts>,
+}
+
+impl SessionTarget {
+    // retina refuses urls carrying userinfo, so credentials embedded in the url are moved into
+    // the session options. Explicit credentials take precedence.
+    fn new(
+        url: &Url,
+        creds: Option<Credentials>,
+        config: &SessionConfig,
+        group: Arc<SessionGroup>,
+    ) -> Self {
+        let mut target = Self {
+            url: url.clone(),
+            creds,
+            transport: config.transport,
+            stream: config.stream.clone(),
+            initial_timestamp: config.initial_timestamp,
+            initial_seq: config.initial_seq,
+            timestamp_fallback: config.timestamp_fallback,
+            metadata_stream: config.metadata_stream,
+            max_redirects: config.max_redirects,
+            url_refresh: None,
+            group,
+            teardown_timeout: config.teardown_timeout,
+            #[cfg(any(test, feature = "test-util"))]
+            restarts: None,
+        };
+        target.set_url(url.clone());
+        target
+    }
+
+    fn set_url(&mut self, mut url: Url) {
+        if !url.username().is_empty() {
+            if self.creds.is_none() {
+                let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().to_string();
+                self.creds = Some(Credentials {
+                    username: decode(url.username()),
+                    password: decode(url.password().unwrap_or_default()),
+                });
+            }
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+        }
+        self.url = url;
+    }
+
+    // Stale sessions are torn down first, cameras capping their sessions would refuse the new one
+    // otherwise
+    fn connect_after(self, delay: Duration) -> BoxFuture<'static, Result<Connected, SessionError>> {
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            self.await_teardown().await;
+            self.connect().await
+        })
+    }
+
+    // Waits for the TEARDOWN of the sessions dropped so far. Failures are only logged, the camera
+    // drops the session by itself once it times out.
+    async fn await_teardown(&self) {
+        match tokio::time::timeout(self.teardown_timeout, self.group.await_teardown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to tear down a previous session: {}", e),
+            Err(_) => warn!(
+                "Previous sessions weren't torn down within {:?}, continuing",
+                self.teardown_timeout
+            ),
+        }
+    }
+
+    // A PLAY refused over the missing rtptime is retried once on a fresh session, retina gives the
+    // session up with the error
+    async fn connect(self) -> Result<Connected, SessionError> {
+        #[cfg(any(test, feature = "test-util"))]
+        if let Some(restarts) = &self.restarts {
+            return Ok(restarts.connect().await);
+        }
+        let requested = self.initial_timestamp;
+        match self.clone().connect_transport().await {
+            Err(SessionError::FailedToPlayStream(e))
+                if self.timestamp_fallback
+                    && requested == TimestampPolicy::Require
+                    && is_missing_rtptime(&e) =>
+            {
+                warn!(
+                    "The camera sent no rtptime in RTP-Info, playing with the permissive timestamp policy: {}",
+                    e
+                );
+                let mut target = self;
+                target.initial_timestamp = TimestampPolicy::Permissive;
+                target.connect_transport().await.map(|connected| Connected {
+                    timestamp_fallback_from: Some(requested),
+                    ..connected
+                })
+            }
+            res => res,
+        }
+    }
+
+    async fn connect_transport(self) -> Result<Connected, SessionError> {
+        match self.transport {
+            TransportPreference::Auto => {
+                match self.clone().connect_with(TransportPreference::Udp).await {
+                    Err(SessionError::FailedToSetupStream(e)) => {
+                        warn!("UDP setup refused, falling back to TCP: {}", e);
+                        self.connect_with(TransportPreference::Tcp).await
+                    }
+                    res => res,
+                }
+            }
+            transport => self.connect_with(transport).await,
+        }
+    }
+
+    // Follows redirects up to `max_redirects`. Rejected credentials are retried once with the url
+    // of the refresh hook, the camera may have handed out a token that expired since.
+    async fn describe(mut self) -> Result<Session<Described>, SessionError> {
+        let mut redirects = 0;
+        let mut refreshed = false;
+        loop {
+            match self.describe_once().await {
+                Err(SessionError::Redirected(to)) if redirects < self.max_redirects => {
+                    info!(from = %self.url, %to, "Following RTSP redirect");
+                    redirects += 1;
+                    self.set_url(to);
+                }
+                Err(SessionError::Unauthorized(e)) if !refreshed && self.creds.is_some() => {
+                    let Some(refresh) = self.url_refresh.clone() else {
+                        return Err(SessionError::Unauthorized(e));
+                    };
+                    refreshed = true;
+                    match refresh().await {
+                        Ok(url) => {
+                            debug!(%url, "Credentials rejected, retrying with a refreshed url");
+                            self.set_url(url);
+                        }
+                        Err(refresh_err) => {
+                            warn!("Failed to refresh the stream url: {}", refresh_err);
+                            return Err(SessionError::Unauthorized(e));
+                        }
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // retina only speaks plain RTSP, so `rtsps://` urls fail before anything is sent in the
+    // clear. There is no skip-verify option either, nothing would honour it.
+    async fn describe_once(&self) -> Result<Session<Described>, SessionError> {
+        if self.url.scheme() == "rtsps" {
+            return Err(SessionError::TlsError(error::RTSPS_UNSUPPORTED.to_owned()));
+        }
+
+        let options = SessionOptions::default()
+            .creds(self.creds.clone())
+            .session_group(Arc::clone(&self.group))
+            .teardown(TeardownPolicy::Always);
+        let e = match Session::describe(self.url.clone(), options).await {
+            Ok(session) => return Ok(session),
+            Err(e) => e,
+        };
+        match describe::response_status(&e) {
+            Some(401) => Err(SessionError::Unauthorized(e)),
+            Some(status) if describe::is_redirect(status) => {
+                match describe::redirect_location(&self.url).await {
+                    Some(to) => Err(SessionError::Redirected(to)),
+                    None => Err(SessionError::FailedToDescribeSession(e)),
                 }
             }
+            _ => Err(SessionError::FailedToDescribeSession(e)),
+        }
+    }
+
+    async fn probe(self) -> Result<Vec<StreamInfo>, SessionError> {
+        let session = self.describe().await?;
+        Ok(session
+            .streams()
+            .iter()
+            .enumerate()
+            .map(|(index, s)| StreamInfo::new(index, s))
+            .collect())
+    }
+
+    async fn connect_with(self, transport: TransportPreference) -> Result<Connected, SessionError> {
+        let selector = self.stream.clone();
+        let mut session = self.describe().await?;
+        let video_stream = streams::select_stream(session.streams(), &selector)?;
+
+        session
+            .setup(video_stream, setup_options(transport))
+            .await
+            .map_err(|e| SessionError::FailedToSetupStream(e))?;
+        if self.metadata_stream {
+            self.setup_metadata(&mut session, transport).await;
+        }
+
+        let stream = &session.streams()[video_stream];
+        let parameters = streams::h264_extra_data(stream);
+        let stream = StreamParameters {
+            timestamp_policy: Some(self.initial_timestamp),
+            ..StreamParameters::of(stream)
+        };
+
+        session
+            .play(
+                PlayOptions::default()
+                    .initial_seq(self.initial_seq.to_retina())
+                    .initial_timestamp(self.initial_timestamp.to_retina()),
+            )
+            .await
+            .map_err(|e| SessionError::FailedToPlayStream(e))?
+            .demuxed()
+            .map_err(|e| SessionError::FailedToDemuxStream(e))
+            .map(|demuxed| Connected {
+                source: FrameSource::Rtsp(demuxed),
+                transport,
+                stream,
+                parameters,
+                timestamp_fallback_from: None,
+            })
+    }
+
+    // Best effort, the video plays on its own when the camera has no metadata stream or refuses
+    // to set it up
+    async fn setup_metadata(
+        &self,
+        session: &mut Session<Described>,
+        transport: TransportPreference,
+    ) {
+        let Some(index) = streams::select_metadata_stream(session.streams()) else {
+            debug!("The camera offers no metadata stream");
+            return;
+        };
+        let encoding = session.streams()[index].encoding_name().to_string();
+        match session.setup(index, setup_options(transport)).await {
+            Ok(()) => info!(%encoding, "Metadata stream set up"),
+            Err(e) => {
+                warn!(%encoding, "Failed to set up the metadata stream, playing video only: {}", e)
+            }
         }
     }
 }
+
+fn setup_options(transport: TransportPreference) -> SetupOptions {
+    let transport = match transport {
+        TransportPreference::Udp => Transport::Udp(UdpTransportOptions::default()),
+        _ => Transport::Tcp(TcpTransportOptions::default()),
+    };
+    SetupOptions::default().transport(transport)
+}
+
+// retina only has the text to go by, which names the missing rtptime
+fn is_missing_rtptime(e: &Error) -> bool {
+    e.to_string().to_ascii_lowercase().contains("rtptime")
+}
@@ -0,0 +1,465 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use retina::Timestamp;
+
+use super::{LatencyBreakdown, SessionError};
+use crate::decoders::{FrameFormat, PixelFormat, PooledBuffer};
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum FrameTarget {
+    Index(usize),
+    Latest,
+    At(Instant),
+    After(u64),
+}
+
+// What a request does when the session's request queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    #[default]
+    Wait,
+    // Fail with `SessionError::Busy` right away
+    FailFast,
+}
+
+// Which requests the decode worker serves first once several are queued. Background requests
+// still get a turn after every few interactive ones, so they are never held back for good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    Interactive,
+    #[default]
+    Background,
+}
+
+// What a frame request is answered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Decoded,
+    // The access unit as buffered, in Annex B and without going through the decoder. Its
+    // dimensions are zero, see `PixelFormat::H264AnnexB`.
+    RawAccessUnit,
+}
+
+pub struct FrameRequest {
+    target: FrameTarget,
+    generation: Option<u64>,
+    timeout: Option<Duration>,
+    backpressure: Backpressure,
+    // When the instance sent it, for `FrameResponse::latency`
+    pub(super) issued_at: Option<Instant>,
+    wait_for_frame: bool,
+    deadline: Option<Duration>,
+    // Falls back to the instance's, see `SessionInstance::with_priority`
+    pub(super) priority: Option<Priority>,
+    output: OutputFormat,
+}
+
+impl FrameRequest {
+    // Frame at `index` of the buffered GOP in presentation order, index 0 being the iframe
+    pub fn new(index: usize) -> Self {
+        Self {
+            target: FrameTarget::Index(index),
+            generation: None,
+            timeout: None,
+            backpressure: Backpressure::Wait,
+            issued_at: None,
+            wait_for_frame: false,
+            deadline: None,
+            priority: None,
+            output: OutputFormat::Decoded,
+        }
+    }
+
+    // Most recently buffered frame; the response carries the index that was served
+    pub fn latest() -> Self {
+        Self {
+            target: FrameTarget::Latest,
+            generation: None,
+            timeout: None,
+            backpressure: Backpressure::Wait,
+            issued_at: None,
+            wait_for_frame: false,
+            deadline: None,
+            priority: None,
+            output: OutputFormat::Decoded,
+        }
+    }
+
+    // Frames `start..end` of a single GOP, decoded in one go and answered through
+    // `SessionInstance::request_batch`
+    pub fn range(start: usize, end: usize) -> BatchRequest {
+        BatchRequest {
+            start,
+            end,
+            generation: None,
+            timeout: None,
+            backpressure: Backpressure::Wait,
+            issued_at: None,
+            priority: None,
+        }
+    }
+
+    // Buffered frame received closest to `at`. Check `FrameResponse::received_at` for how far
+    // off it is
+    pub fn at(at: Instant) -> Self {
+        Self {
+            target: FrameTarget::At(at),
+            generation: None,
+            timeout: None,
+            backpressure: Backpressure::Wait,
+            issued_at: None,
+            wait_for_frame: false,
+            deadline: None,
+            priority: None,
+            output: OutputFormat::Decoded,
+        }
+    }
+
+    // Frame with the sequence number following `seq`, see `FrameResponse::seq`. Fails with
+    // `FrameNotYetAvailable` until it arrives and with `SessionError::FrameEvicted` once it is
+    // gone, `after(oldest_available - 1)` then resumes from the oldest frame still buffered.
+    pub fn after(seq: u64) -> Self {
+        Self {
+            target: FrameTarget::After(seq),
+            generation: None,
+            timeout: None,
+            backpressure: Backpressure::Wait,
+            issued_at: None,
+            wait_for_frame: false,
+            deadline: None,
+            priority: None,
+            output: OutputFormat::Decoded,
+        }
+    }
+
+    // Only accept the frame if the buffer is still at `generation`, otherwise the session
+    // answers with `SessionError::BufferReset`
+    pub fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    // Overrides `SessionConfig::request_timeout` for this request
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    pub fn backpressure(&self) -> Backpressure {
+        self.backpressure
+    }
+
+    // A frame of the newest GOP that wasn't buffered yet, or the one `after` or `at` are still
+    // waiting for, is served once it arrives instead of failing. Bounded by the request timeout.
+    pub fn wait_for_frame(mut self, wait: bool) -> Self {
+        self.wait_for_frame = wait;
+        self
+    }
+
+    pub fn waits_for_frame(&self) -> bool {
+        self.wait_for_frame
+    }
+
+    // When decoding up to the frame would take longer than `deadline` going by the recent decode
+    // times, the newest frame of its GOP already decoded is served instead, or the iframe. See
+    // `FrameResponse::downgraded_from`.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn priority(&self) -> Option<Priority> {
+        self.priority
+    }
+
+    pub fn with_output(mut self, output: OutputFormat) -> Self {
+        self.output = output;
+        self
+    }
+
+    // Short for `with_output(OutputFormat::RawAccessUnit)`, e.g. for archiving the stream or
+    // decoding it elsewhere
+    pub fn raw(self) -> Self {
+        self.with_output(OutputFormat::RawAccessUnit)
+    }
+
+    pub fn output(&self) -> OutputFormat {
+        self.output
+    }
+
+    pub(super) fn target(&self) -> FrameTarget {
+        self.target
+    }
+}
+
+pub struct BatchRequest {
+    pub(super) start: usize,
+    pub(super) end: usize,
+    generation: Option<u64>,
+    timeout: Option<Duration>,
+    backpressure: Backpressure,
+    pub(super) issued_at: Option<Instant>,
+    pub(super) priority: Option<Priority>,
+}
+
+impl BatchRequest {
+    pub fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    pub fn backpressure(&self) -> Backpressure {
+        self.backpressure
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn priority(&self) -> Option<Priority> {
+        self.priority
+    }
+}
+
+#[derive(Clone)]
+pub struct FrameResponse {
+    pub(super) frame: Arc<PooledBuffer>,
+    pub(super) format: FrameFormat,
+    pub(super) index: usize,
+    pub(super) decode_index: usize,
+    pub(super) index_offset: usize,
+    pub(super) generation: u64,
+    pub(super) seq: u64,
+    pub(super) i_frame_ts: Instant,
+    pub(super) timestamp: Timestamp,
+    pub(super) random_access: bool,
+    pub(super) received_at: Instant,
+    pub(super) captured_at: Option<SystemTime>,
+    pub(super) latency: Option<LatencyBreakdown>,
+    pub(super) downgraded_from: Option<usize>,
+}
+
+impl FrameResponse {
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    // The buffer is shared with the session's decoded cache, no copy is made. It goes back to
+    // the session's pool once every handle to it is dropped.
+    pub fn into_frame(self) -> Arc<PooledBuffer> {
+        self.frame
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    pub fn width(&self) -> usize {
+        self.format.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.format.height
+    }
+
+    // Bytes per row of the first plane, frames are packed without padding
+    pub fn stride(&self) -> usize {
+        self.format.stride()
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.format.pixel_format
+    }
+
+    // RTP timestamp of this specific frame
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    // When the frame arrived from the camera
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    // When the camera took the frame, by its clock, from the RTCP sender reports. `None` before
+    // the first one came in and for cameras sending none or nonsense, see `FrameMetadata::new`
+    // for falling back to `received_at`.
+    pub fn captured_at(&self) -> Option<SystemTime> {
+        self.captured_at
+    }
+
+    pub fn is_random_access_point(&self) -> bool {
+        self.random_access
+    }
+
+    // Position in presentation order, the one requests address frames by
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    // Position in the order the frame arrived and was decoded in. Only differs from `index` for
+    // streams with B-frames.
+    pub fn decode_index(&self) -> usize {
+        self.decode_index
+    }
+
+    // P-frames evicted from this GOP under `BufferPolicy::DropOldest` when the frame was served.
+    // Frame `index` (past the iframe) is the `index + index_offset`th frame of the GOP.
+    pub fn index_offset(&self) -> usize {
+        self.index_offset
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Counts the requestable frames buffered over the whole session, reconnects included, from 1.
+    // Gaps mean frames were dropped or evicted before they were served.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn i_frame_ts(&self) -> Instant {
+        self.i_frame_ts
+    }
+
+    // The index asked for, when `FrameRequest::with_deadline` served an earlier frame instead
+    pub fn downgraded_from(&self) -> Option<usize> {
+        self.downgraded_from
+    }
+
+    // `None` unless `SessionConfig::measure_latency` is on
+    pub fn latency(&self) -> Option<LatencyBreakdown> {
+        self.latency
+    }
+}
+
+// Responses that never went through a session, for testing what consumes them
+#[cfg(any(test, feature = "test-util"))]
+impl FrameResponse {
+    // Frame `index` of the GOP `generation`, received now and timestamped a second past the
+    // iframe per 90 frames
+    pub fn synthetic(frame: Vec<u8>, format: FrameFormat, generation: u64, index: usize) -> Self {
+        let clock_rate = std::num::NonZeroU32::new(90_000).expect("non zero");
+        let pts = index as i64 * 1000;
+        let now = Instant::now();
+        Self {
+            frame: Arc::new(PooledBuffer::from(frame)),
+            format,
+            index,
+            decode_index: index,
+            index_offset: 0,
+            generation,
+            seq: index as u64 + 1,
+            i_frame_ts: now,
+            timestamp: Timestamp::new(pts, clock_rate, 0).expect("timestamp in range"),
+            random_access: index == 0,
+            received_at: now,
+            captured_at: None,
+            latency: None,
+            downgraded_from: None,
+        }
+    }
+}
+
+// Frames decoded before the first failure, followed by that failure if there was one
+pub struct BatchResponse {
+    pub(super) frames: Vec<FrameResponse>,
+    pub(super) error: Option<SessionError>,
+}
+
+impl BatchResponse {
+    pub fn frames(&self) -> &[FrameResponse] {
+        &self.frames
+    }
+
+    pub fn error(&self) -> Option<&SessionError> {
+        self.error.as_ref()
+    }
+
+    pub fn into_parts(self) -> (Vec<FrameResponse>, Option<SessionError>) {
+        (self.frames, self.error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BufferState {
+    pub generation: u64,
+    pub i_frame_ts: Option<Instant>,
+}
+
+// The newest GOP as the decode worker last left it, see `SessionInstance::buffer_status`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferStatus {
+    pub generation: u64,
+    // Frames of the GOP, iframe included, and how many of them were decoded already
+    pub buffered: usize,
+    pub decoded: usize,
+    pub i_frame_ts: Option<Instant>,
+    // Packet loss hit the GOP, frames from the loss on get `CorruptGop`
+    pub corrupt: bool,
+    pub buf_size: usize,
+    pub frame_lifetime: Duration,
+}
+
+impl BufferStatus {
+    pub fn i_frame_age(&self) -> Option<Duration> {
+        self.i_frame_ts.map(|ts| ts.elapsed())
+    }
+
+    // Requests would get `OldFrame`, or wait on a resync, until the next iframe
+    pub fn is_expired(&self) -> bool {
+        self.i_frame_age()
+            .is_some_and(|age| age > self.frame_lifetime)
+    }
+
+    // Index `buffered` is what the next frame to arrive will get, as long as there is room
+    pub fn is_full(&self) -> bool {
+        self.buffered >= self.buf_size
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    collections::VecDeque,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::future;
+use retina::Timestamp;
+
+use super::{
+    fixtures, worker::RawFrame, Connected, FrameSource, MetadataMessage, StreamParameters,
+    TransportPreference,
+};
+
+const CLOCK_RATE: u32 = 90_000;
+
+struct ScriptedFrame {
+    data: Vec<u8>,
+    // `None` for a message of the metadata stream
+    video: Option<Video>,
+    pts: i64,
+    // Held back this long after the previous frame
+    delay: Duration,
+}
+
+struct Video {
+    random_access: bool,
+    loss: u16,
+}
+
+// What the script hands the session loop next
+pub(super) enum Played {
+    Frame(RawFrame),
+    Metadata(MetadataMessage),
+}
+
+// Frames a session plays instead of a camera stream, see `SessionWrapper::start_scripted`.
+// Frames go out one interval apart with timestamps on a 90kHz clock, e.g.
+// `FrameScript::new(25.0).iframe(fixtures::iframe(64)).pframes(10)`.
+pub struct FrameScript {
+    frames: VecDeque<ScriptedFrame>,
+    interval: Duration,
+    // Presentation timestamp of the next frame
+    pts: i64,
+    // Added to the delay of the next frame
+    wait: Duration,
+    // Frames pushed since the last iframe, iframe included, which is the `frame_num` the next
+    // fixture P-frame needs
+    since_iframe: usize,
+    codec: String,
+    resolution: Option<(u32, u32)>,
+    parameters: Option<Vec<u8>>,
+    // Played in turn each time the session restarts the stream, see `then`
+    restarts: Vec<FrameScript>,
+}
+
+impl FrameScript {
+    pub fn new(fps: f32) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            interval: Duration::from_secs_f32(1.0 / fps.max(f32::EPSILON)),
+            pts: 0,
+            wait: Duration::ZERO,
+            since_iframe: 0,
+            codec: "h264".to_string(),
+            resolution: Some((fixtures::WIDTH as u32, fixtures::HEIGHT as u32)),
+            parameters: Some(fixtures::parameters()),
+            restarts: Vec::new(),
+        }
+    }
+
+    // What the SDP would have said, both describe the fixtures by default. The resolution shows in
+    // `SessionEvent::StreamSetup`.
+    pub fn with_resolution(mut self, resolution: Option<(u32, u32)>) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    // avcC record handed to clips and bitstream dumps, its parameter sets also go to the decoder
+    // ahead of iframes without their own, e.g. `fixtures::bare_iframe`
+    pub fn with_parameters(mut self, parameters: Option<Vec<u8>>) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    pub fn iframe(self, data: Vec<u8>) -> Self {
+        self.push(data, true, 0)
+    }
+
+    pub fn pframe(self, data: Vec<u8>) -> Self {
+        self.push(data, false, 0)
+    }
+
+    // `count` fixture P-frames continuing the current GOP
+    pub fn pframes(mut self, count: usize) -> Self {
+        for _ in 0..count {
+            let n = self.since_iframe;
+            self = self.pframe(fixtures::pframe(n));
+        }
+        self
+    }
+
+    // P-frame that arrives after `packets` RTP packets went missing
+    pub fn lost(self, packets: u16, data: Vec<u8>) -> Self {
+        self.push(data, false, packets.max(1))
+    }
+
+    // Further slice of the frame before it, going out right after it with the same timestamp, see
+    // `fixtures::sliced_iframe`
+    pub fn slice(mut self, data: Vec<u8>) -> Self {
+        let pts = self.frames.back().map_or(self.pts, |f| f.pts);
+        self.frames.push_back(ScriptedFrame {
+            data,
+            video: Some(Video {
+                random_access: false,
+                loss: 0,
+            }),
+            pts,
+            delay: self.wait,
+        });
+        self.wait = Duration::ZERO;
+        self
+    }
+
+    // Message of the metadata stream, e.g. ONVIF XML, going out right after the frame before it with
+    // the timestamp of the next one. Only forwarded with `SessionConfig::metadata_stream`.
+    pub fn metadata(mut self, data: Vec<u8>) -> Self {
+        self.frames.push_back(ScriptedFrame {
+            data,
+            video: None,
+            pts: self.pts,
+            delay: self.wait,
+        });
+        self.wait = Duration::ZERO;
+        self
+    }
+
+    // Holds the next frame back for `duration` on top of the interval, e.g. to let frames expire
+    pub fn wait(mut self, duration: Duration) -> Self {
+        self.wait += duration;
+        self
+    }
+
+    // Played once the session restarts the stream, which it only does for this script after
+    // `SessionConfig::stall_timeout` passed without a frame. A script with nothing to restart on
+    // never stalls, it waits for good once it ran out.
+    pub fn then(mut self, mut next: FrameScript) -> Self {
+        let later = std::mem::take(&mut next.restarts);
+        self.restarts.push(next);
+        self.restarts.extend(later);
+        self
+    }
+
+    fn push(mut self, data: Vec<u8>, random_access: bool, loss: u16) -> Self {
+        self.since_iframe = if random_access {
+            1
+        } else {
+            self.since_iframe + 1
+        };
+        // Metadata messages don't take a frame interval
+        let delay = if self.frames.iter().any(|f| f.video.is_some()) {
+            self.interval + self.wait
+        } else {
+            self.wait
+        };
+        self.frames.push_back(ScriptedFrame {
+            data,
+            video: Some(Video {
+                random_access,
+                loss,
+            }),
+            pts: self.pts,
+            delay,
+        });
+        self.pts += (self.interval.as_secs_f64() * CLOCK_RATE as f64).round() as i64;
+        self.wait = Duration::ZERO;
+        self
+    }
+
+    // The scripts to restart on go to the session target, which connects to them instead of the
+    // camera
+    pub(super) fn into_connected(mut self) -> (Connected, Restarts) {
+        let restarts = Restarts(Arc::new(Mutex::new(
+            std::mem::take(&mut self.restarts).into(),
+        )));
+        let stalls = !restarts.is_empty();
+        (self.into_source(stalls), restarts)
+    }
+
+    fn into_source(self, stalls: bool) -> Connected {
+        Connected {
+            transport: TransportPreference::Tcp,
+            stream: StreamParameters::new(&self.codec, self.resolution, self.parameters.as_deref()),
+            parameters: self.parameters,
+            timestamp_fallback_from: None,
+            source: FrameSource::Scripted(ScriptPlayer {
+                frames: self.frames,
+                next_at: None,
+                stalls,
+            }),
+        }
+    }
+}
+
+// Scripts left to restart on, shared by the clones of the session target
+#[derive(Clone)]
+pub(super) struct Restarts(Arc<Mutex<VecDeque<FrameScript>>>);
+
+impl Restarts {
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).is_empty()
+    }
+
+    // Never resolves once every script was played, the camera url isn't tried instead
+    pub(super) async fn connect(&self) -> Connected {
+        let next = {
+            let mut scripts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            scripts.pop_front().map(|s| (s, !scripts.is_empty()))
+        };
+        match next {
+            Some((script, stalls)) => script.into_source(stalls),
+            None => future::pending().await,
+        }
+    }
+}
+
+pub(super) struct ScriptPlayer {
+    frames: VecDeque<ScriptedFrame>,
+    // When the front frame goes out, set once it's first waited on
+    next_at: Option<Instant>,
+    // There is a script to restart on, see `FrameScript::then`
+    stalls: bool,
+}
+
+impl ScriptPlayer {
+    pub(super) fn stalls(&self) -> bool {
+        self.stalls
+    }
+
+    // Pending for good once the script ran out, the stream never ends. The session loop drops
+    // this future whenever another branch fires, so the frame is only taken off the script once
+    // its deadline passed.
+    pub(super) async fn next(&mut self) -> Played {
+        let Some(delay) = self.frames.front().map(|f| f.delay) else {
+            return future::pending().await;
+        };
+        let next_at = *self.next_at.get_or_insert_with(|| Instant::now() + delay);
+        tokio::time::sleep_until(next_at.into()).await;
+        self.next_at = None;
+        let frame = self
+            .frames
+            .pop_front()
+            .expect("the front frame was just waited on");
+        let clock_rate = NonZeroU32::new(CLOCK_RATE).expect("clock rate is not zero");
+        let timestamp =
+            Timestamp::new(frame.pts, clock_rate, 0).expect("scripted timestamps don't overflow");
+        let Some(video) = frame.video else {
+            return Played::Metadata((timestamp, frame.data));
+        };
+        Played::Frame(RawFrame {
+            data: Bytes::from(frame.data),
+            timestamp,
+            random_access: video.random_access,
+            received: Instant::now(),
+            captured_at: None,
+            loss: video.loss,
+            retained: true,
+            seq: 0,
+        })
+    }
+}
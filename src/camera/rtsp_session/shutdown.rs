@@ -0,0 +1,18 @@
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+
+// Something fed by a session that has to stop before the session does, so it neither waits on
+// requests the session can no longer answer nor gets cut off halfway through a write. See
+// `SessionInstanceManager::shutdown_with`.
+#[async_trait]
+pub trait Dependent: Send {
+    async fn shutdown(self: Box<Self>);
+}
+
+// Any task of the caller's, e.g. one draining a `FrameStream`, that ends once it resolves
+#[async_trait]
+impl Dependent for BoxFuture<'static, ()> {
+    async fn shutdown(self: Box<Self>) {
+        (*self).await
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Served frames the latency percentiles are taken over
+const LATENCY_WINDOW: usize = 256;
+
+// Where the time went for one served frame, see `SessionConfig::measure_latency`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyBreakdown {
+    // From the frame's arrival to the request for it, zero when the request was waiting for it
+    pub capture_to_request: Duration,
+    // From the later of the two to the decoder starting on it, behind the frames and requests
+    // queued before
+    pub queue_wait: Duration,
+    // Includes the frames it references when those weren't decoded yet
+    pub decode: Duration,
+    // From the frame's arrival to the response going out
+    pub total: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl LatencyPercentiles {
+    fn of(mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+        let at = |p: usize| samples[(samples.len() - 1) * p / 100];
+        Self {
+            p50: at(50),
+            p95: at(95),
+            max: at(100),
+        }
+    }
+}
+
+// Over the last `LATENCY_WINDOW` frames served
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub capture_to_request: LatencyPercentiles,
+    pub queue_wait: LatencyPercentiles,
+    pub decode: LatencyPercentiles,
+    pub total: LatencyPercentiles,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SessionStats {
+    // Time covered by these numbers, since the session started or the last reset
+    pub elapsed: Duration,
+    pub frames_received: u64,
+    pub iframes_received: u64,
+    pub bytes_received: u64,
+    // Frames that arrived before any iframe or past `buf_size`/`max_buffered_bytes`
+    pub frames_dropped: u64,
+    // Of those, the ones refused for `buf_size`, and for `max_gop_bytes` or `max_buffered_bytes`
+    pub frames_dropped_full: u64,
+    pub frames_dropped_bytes: u64,
+    // Frames over `SessionConfig::max_frame_bytes`, never buffered
+    pub frames_oversized: u64,
+    // Frames without any NAL data, skipped without touching the GOP
+    pub frames_empty: u64,
+    // Requestable frames buffered, and P-frames skipped by `SessionConfig::decimation`
+    pub frames_buffered: u64,
+    pub frames_decimated: u64,
+    // Frames merged into the access unit before them, see `SessionConfig::aggregate_slices`
+    pub slices_aggregated: u64,
+    // Frames that arrived after missing RTP packets, and how many packets went missing
+    pub loss_events: u64,
+    pub packets_lost: u64,
+    pub decode_count: u64,
+    pub decode_time_min: Option<Duration>,
+    pub decode_time_avg: Option<Duration>,
+    pub decode_time_max: Option<Duration>,
+    // Frames the decoder failed on, and later GOPs that decoded fine after one
+    pub decode_failures: u64,
+    pub decode_recoveries: u64,
+    pub reconnects: u64,
+    // Connects that only played with `TimestampPolicy::Permissive`, see
+    // `SessionConfig::timestamp_fallback`
+    pub timestamp_fallbacks: u64,
+    pub last_packet: Option<Instant>,
+    pub last_iframe: Option<Instant>,
+    // `None` unless `SessionConfig::measure_latency` is on and a frame was served
+    pub latency: Option<LatencySummary>,
+}
+
+impl SessionStats {
+    pub fn fps(&self) -> f64 {
+        self.frames_received as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    // Rate requestable frames were buffered at, below `fps` with decimation or a full buffer
+    pub fn buffered_fps(&self) -> f64 {
+        self.frames_buffered as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn bitrate_bps(&self) -> f64 {
+        (self.bytes_received * 8) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+// Shared between the session loop, the decode worker and the manager. Instants are stored as
+// nanoseconds past `base`, offset by one so zero can mean "never".
+pub(super) struct StatsCollector {
+    base: Instant,
+    since: AtomicU64,
+    frames_received: AtomicU64,
+    iframes_received: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_dropped: AtomicU64,
+    frames_dropped_full: AtomicU64,
+    frames_dropped_bytes: AtomicU64,
+    frames_oversized: AtomicU64,
+    frames_empty: AtomicU64,
+    frames_buffered: AtomicU64,
+    frames_decimated: AtomicU64,
+    slices_aggregated: AtomicU64,
+    loss_events: AtomicU64,
+    packets_lost: AtomicU64,
+    decode_count: AtomicU64,
+    decode_nanos_total: AtomicU64,
+    decode_nanos_min: AtomicU64,
+    decode_nanos_max: AtomicU64,
+    // Moving average weighing the last few decodes, 0 before the first
+    decode_nanos_recent: AtomicU64,
+    decode_failures: AtomicU64,
+    decode_recoveries: AtomicU64,
+    reconnects: AtomicU64,
+    timestamp_fallbacks: AtomicU64,
+    last_packet: AtomicU64,
+    last_iframe: AtomicU64,
+    latency: Mutex<VecDeque<LatencyBreakdown>>,
+}
+
+impl StatsCollector {
+    pub(super) fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            since: AtomicU64::new(0),
+            frames_received: AtomicU64::new(0),
+            iframes_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            frames_dropped_full: AtomicU64::new(0),
+            frames_dropped_bytes: AtomicU64::new(0),
+            frames_oversized: AtomicU64::new(0),
+            frames_empty: AtomicU64::new(0),
+            frames_buffered: AtomicU64::new(0),
+            frames_decimated: AtomicU64::new(0),
+            slices_aggregated: AtomicU64::new(0),
+            loss_events: AtomicU64::new(0),
+            packets_lost: AtomicU64::new(0),
+            decode_count: AtomicU64::new(0),
+            decode_nanos_total: AtomicU64::new(0),
+            decode_nanos_min: AtomicU64::new(u64::MAX),
+            decode_nanos_max: AtomicU64::new(0),
+            decode_nanos_recent: AtomicU64::new(0),
+            decode_failures: AtomicU64::new(0),
+            decode_recoveries: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            timestamp_fallbacks: AtomicU64::new(0),
+            last_packet: AtomicU64::new(0),
+            last_iframe: AtomicU64::new(0),
+            latency: Mutex::new(VecDeque::with_capacity(LATENCY_WINDOW)),
+        }
+    }
+
+    pub(super) fn record_frame(&self, bytes: usize, random_access: bool) {
+        let now = self.now();
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_packet.store(now, Ordering::Relaxed);
+        if random_access {
+            self.iframes_received.fetch_add(1, Ordering::Relaxed);
+            self.last_iframe.store(now, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn record_dropped(&self) {
+        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_dropped_full(&self) {
+        self.frames_dropped_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_dropped_bytes(&self) {
+        self.frames_dropped_bytes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_oversized(&self) {
+        self.frames_oversized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_empty(&self) {
+        self.frames_empty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_buffered(&self) {
+        self.frames_buffered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_decimated(&self) {
+        self.frames_decimated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_slices_aggregated(&self, slices: u64) {
+        self.slices_aggregated.fetch_add(slices, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_loss(&self, packets: u16) {
+        self.loss_events.fetch_add(1, Ordering::Relaxed);
+        self.packets_lost
+            .fetch_add(packets as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_decode(&self, took: Duration) {
+        let nanos = took.as_nanos() as u64;
+        self.decode_count.fetch_add(1, Ordering::Relaxed);
+        self.decode_nanos_total.fetch_add(nanos, Ordering::Relaxed);
+        self.decode_nanos_min.fetch_min(nanos, Ordering::Relaxed);
+        self.decode_nanos_max.fetch_max(nanos, Ordering::Relaxed);
+        // Only the decode worker records, the load and store don't race
+        let recent = self.decode_nanos_recent.load(Ordering::Relaxed);
+        let recent = if recent == 0 {
+            nanos
+        } else {
+            recent - recent / 8 + nanos / 8
+        };
+        self.decode_nanos_recent
+            .store(recent.max(1), Ordering::Relaxed);
+    }
+
+    // How long a frame took to decode lately, `None` before the first decode
+    pub(super) fn recent_decode_time(&self) -> Option<Duration> {
+        let recent = self.decode_nanos_recent.load(Ordering::Relaxed);
+        (recent > 0).then(|| Duration::from_nanos(recent))
+    }
+
+    pub(super) fn record_decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_decode_recovery(&self) {
+        self.decode_recoveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_latency(&self, latency: LatencyBreakdown) {
+        let mut window = self.latency.lock().unwrap_or_else(|e| e.into_inner());
+        if window.len() == LATENCY_WINDOW {
+            window.pop_front();
+        }
+        window.push_back(latency);
+    }
+
+    pub(super) fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_timestamp_fallback(&self) {
+        self.timestamp_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> SessionStats {
+        let decode_count = self.decode_count.load(Ordering::Relaxed);
+        let decoded = |nanos: u64| (decode_count > 0).then(|| Duration::from_nanos(nanos));
+        let since = self.since.load(Ordering::Relaxed);
+
+        SessionStats {
+            elapsed: self
+                .base
+                .elapsed()
+                .saturating_sub(Duration::from_nanos(since)),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            iframes_received: self.iframes_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            frames_dropped_full: self.frames_dropped_full.load(Ordering::Relaxed),
+            frames_dropped_bytes: self.frames_dropped_bytes.load(Ordering::Relaxed),
+            frames_oversized: self.frames_oversized.load(Ordering::Relaxed),
+            frames_empty: self.frames_empty.load(Ordering::Relaxed),
+            frames_buffered: self.frames_buffered.load(Ordering::Relaxed),
+            frames_decimated: self.frames_decimated.load(Ordering::Relaxed),
+            slices_aggregated: self.slices_aggregated.load(Ordering::Relaxed),
+            loss_events: self.loss_events.load(Ordering::Relaxed),
+            packets_lost: self.packets_lost.load(Ordering::Relaxed),
+            decode_count,
+            decode_time_min: decoded(self.decode_nanos_min.load(Ordering::Relaxed)),
+            decode_time_avg: decoded(
+                self.decode_nanos_total.load(Ordering::Relaxed) / decode_count.max(1),
+            ),
+            decode_time_max: decoded(self.decode_nanos_max.load(Ordering::Relaxed)),
+            decode_failures: self.decode_failures.load(Ordering::Relaxed),
+            decode_recoveries: self.decode_recoveries.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            timestamp_fallbacks: self.timestamp_fallbacks.load(Ordering::Relaxed),
+            last_packet: self.instant(self.last_packet.load(Ordering::Relaxed)),
+            last_iframe: self.instant(self.last_iframe.load(Ordering::Relaxed)),
+            latency: self.latency_summary(),
+        }
+    }
+
+    fn latency_summary(&self) -> Option<LatencySummary> {
+        let window = self.latency.lock().unwrap_or_else(|e| e.into_inner());
+        if window.is_empty() {
+            return None;
+        }
+        let stage = |f: fn(&LatencyBreakdown) -> Duration| {
+            LatencyPercentiles::of(window.iter().map(f).collect())
+        };
+        Some(LatencySummary {
+            samples: window.len(),
+            capture_to_request: stage(|l| l.capture_to_request),
+            queue_wait: stage(|l| l.queue_wait),
+            decode: stage(|l| l.decode),
+            total: stage(|l| l.total),
+        })
+    }
+
+    // Counters start over, the last packet and iframe instants are kept
+    pub(super) fn reset(&self) {
+        self.since
+            .store(self.base.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.frames_received.store(0, Ordering::Relaxed);
+        self.iframes_received.store(0, Ordering::Relaxed);
+        self.bytes_received.store(0, Ordering::Relaxed);
+        self.frames_dropped.store(0, Ordering::Relaxed);
+        self.frames_dropped_full.store(0, Ordering::Relaxed);
+        self.frames_dropped_bytes.store(0, Ordering::Relaxed);
+        self.frames_oversized.store(0, Ordering::Relaxed);
+        self.frames_empty.store(0, Ordering::Relaxed);
+        self.frames_buffered.store(0, Ordering::Relaxed);
+        self.frames_decimated.store(0, Ordering::Relaxed);
+        self.slices_aggregated.store(0, Ordering::Relaxed);
+        self.loss_events.store(0, Ordering::Relaxed);
+        self.packets_lost.store(0, Ordering::Relaxed);
+        self.decode_count.store(0, Ordering::Relaxed);
+        self.decode_nanos_total.store(0, Ordering::Relaxed);
+        self.decode_nanos_min.store(u64::MAX, Ordering::Relaxed);
+        self.decode_nanos_max.store(0, Ordering::Relaxed);
+        self.decode_failures.store(0, Ordering::Relaxed);
+        self.decode_recoveries.store(0, Ordering::Relaxed);
+        self.reconnects.store(0, Ordering::Relaxed);
+        self.timestamp_fallbacks.store(0, Ordering::Relaxed);
+        self.latency
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    fn now(&self) -> u64 {
+        self.base.elapsed().as_nanos() as u64 + 1
+    }
+
+    fn instant(&self, stored: u64) -> Option<Instant> {
+        (stored > 0).then(|| self.base + Duration::from_nanos(stored - 1))
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Stream;
+use retina::Timestamp;
+use tokio::sync::{broadcast, mpsc};
+use tracing::warn;
+
+use super::{FrameResponse, SessionError};
+
+pub(super) type FrameSubscriber = mpsc::Sender<Result<FrameResponse, SessionError>>;
+
+// Yields every frame the session buffers, decoded as it arrives. Frames are dropped for this
+// subscriber instead of stalling the session when it falls `buf_size` frames behind. Dropping the
+// stream unsubscribes it.
+pub struct FrameStream {
+    rx: mpsc::Receiver<Result<FrameResponse, SessionError>>,
+}
+
+impl FrameStream {
+    pub(super) fn new(rx: mpsc::Receiver<Result<FrameResponse, SessionError>>) -> Self {
+        Self { rx }
+    }
+}
+
+impl Stream for FrameStream {
+    type Item = Result<FrameResponse, SessionError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+// Receives every frame the session buffers, decoded once and shared with all receivers. A
+// receiver that falls more than `broadcast_capacity` frames behind gets `SessionError::Lagged`
+// and resumes from the oldest frame still in the channel.
+pub struct FrameBroadcast {
+    rx: broadcast::Receiver<FrameResponse>,
+}
+
+impl FrameBroadcast {
+    pub(super) fn new(rx: broadcast::Receiver<FrameResponse>) -> Self {
+        Self { rx }
+    }
+
+    pub async fn recv(&mut self) -> Result<FrameResponse, SessionError> {
+        self.rx.recv().await.map_err(|e| match e {
+            broadcast::error::RecvError::Lagged(n) => SessionError::Lagged(n),
+            broadcast::error::RecvError::Closed => SessionError::BrokenPipeline,
+        })
+    }
+}
+
+// A message of the metadata stream, e.g. the XML of an ONVIF `tt:MetadataStream`, with its RTP
+// timestamp on the metadata stream's own clock
+pub type MetadataMessage = (Timestamp, Vec<u8>);
+
+// Messages of the metadata stream from now on, see `SessionConfig::metadata_stream`. Stays empty
+// while the camera has no metadata stream and ends with the session, or right away when the
+// option is off.
+pub struct MetadataStream {
+    rx: broadcast::Receiver<MetadataMessage>,
+}
+
+impl MetadataStream {
+    pub(super) fn new(rx: broadcast::Receiver<MetadataMessage>) -> Self {
+        Self { rx }
+    }
+
+    // `None` once the stream ended. Messages a slow receiver missed are skipped.
+    pub async fn recv(&mut self) -> Option<MetadataMessage> {
+        loop {
+            match self.rx.recv().await {
+                Ok(message) => return Some(message),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!(
+                        missed = n,
+                        "Metadata receiver fell behind, skipping messages"
+                    );
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = MetadataMessage> {
+        futures::stream::unfold(self, |mut s| async move { s.recv().await.map(|m| (m, s)) })
+    }
+}
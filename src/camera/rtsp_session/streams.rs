@@ -0,0 +1,227 @@
+use retina::{client::Stream, codec::ParametersRef};
+
+use super::{SessionError, StreamSelector, TimestampPolicy};
+use crate::decoders::NalType;
+
+// A stream as advertised in the DESCRIBE response
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    // Position in the DESCRIBE response, across all media
+    pub index: usize,
+    pub media: String,
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub control: Option<String>,
+    // Parsed from the SDP parameters, `None` when the camera only sends them in-band
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl StreamInfo {
+    pub(super) fn new(index: usize, s: &Stream) -> Self {
+        Self {
+            index,
+            media: s.media().to_string(),
+            encoding: s.encoding_name().to_string(),
+            clock_rate: s.clock_rate_hz(),
+            control: s.control().map(|c| c.to_string()),
+            dimensions: video_dimensions(s),
+        }
+    }
+}
+
+// Codec parameters of the stream being played, from the SDP and updated when the camera sends
+// new ones in-band
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamParameters {
+    // Encoding name from the SDP, e.g. `H264`
+    pub encoding: String,
+    // RFC 6381 codec string, e.g. `avc1.4D401F`
+    pub codec: Option<String>,
+    pub dimensions: Option<(u32, u32)>,
+    // From the SPS timing info, when the camera sends it
+    pub frame_rate: Option<f64>,
+    // H.264 `profile_idc` and `level_idc`, e.g. 100 and 40 for High at level 4.0
+    pub profile: Option<u8>,
+    pub level: Option<u8>,
+    // The one the stream was played with, see `SessionConfig::timestamp_fallback`
+    pub timestamp_policy: Option<TimestampPolicy>,
+}
+
+impl StreamParameters {
+    // What can be read off the avcC record alone, the frame rate needs the parsed SPS
+    pub(super) fn new(encoding: &str, dimensions: Option<(u32, u32)>, avcc: Option<&[u8]>) -> Self {
+        // profile, compatibility and level follow the version byte
+        let header = avcc.and_then(|a| a.get(1..4));
+        Self {
+            encoding: encoding.to_string(),
+            codec: header.map(|h| format!("avc1.{:02X}{:02X}{:02X}", h[0], h[1], h[2])),
+            dimensions,
+            frame_rate: None,
+            profile: header.map(|h| h[0]),
+            level: header.map(|h| h[2]),
+            timestamp_policy: None,
+        }
+    }
+
+    pub(super) fn of(s: &Stream) -> Self {
+        let avcc = h264_extra_data(s);
+        let mut parameters = Self::new(s.encoding_name(), video_dimensions(s), avcc.as_deref());
+        if let Some(ParametersRef::Video(v)) = s.parameters() {
+            parameters.codec = Some(v.rfc6381_codec().to_string());
+            // retina has the frame duration, in seconds as a fraction
+            parameters.frame_rate = v
+                .frame_rate()
+                .filter(|(num, _)| *num > 0)
+                .map(|(num, den)| den as f64 / num as f64);
+        }
+        parameters
+    }
+}
+
+pub(super) fn video_dimensions(s: &Stream) -> Option<(u32, u32)> {
+    match s.parameters() {
+        Some(ParametersRef::Video(v)) => Some(v.pixel_dimensions()),
+        _ => None,
+    }
+}
+
+// The avcC record of an H.264 stream, holding the SPS and PPS from the SDP
+pub(super) fn h264_extra_data(s: &Stream) -> Option<Vec<u8>> {
+    if !s.encoding_name().eq_ignore_ascii_case("h264") {
+        return None;
+    }
+    match s.parameters() {
+        Some(ParametersRef::Video(v)) => Some(v.extra_data().to_vec()),
+        _ => None,
+    }
+}
+
+// SPS and PPS out of an AVCDecoderConfigurationRecord. Whatever doesn't parse is left out,
+// cameras that send them in-band still work without.
+pub(super) fn avcc_parameter_sets(avcc: &[u8]) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let mut sets = [Vec::new(), Vec::new()];
+    // version, profile, compatibility, level and length size come first
+    let mut index = 5;
+    for (found, count_mask) in sets.iter_mut().zip([0x1F, 0xFF]) {
+        let Some(count) = avcc.get(index) else {
+            break;
+        };
+        index += 1;
+        for _ in 0..(count & count_mask) {
+            let Some(len) = avcc.get(index..index + 2) else {
+                break;
+            };
+            let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+            index += 2;
+            let Some(nal) = avcc.get(index..index + len) else {
+                break;
+            };
+            found.push(nal.to_vec());
+            index += len;
+        }
+    }
+    let [sps, pps] = sets;
+    (sps, pps)
+}
+
+// The parameter sets of an avcC record framed like the frames retina hands out, with 4 byte
+// lengths, to go in front of an iframe. `None` when the record holds no SPS or no PPS.
+pub(super) fn avcc_parameter_prefix(avcc: &[u8]) -> Option<Vec<u8>> {
+    let (sps, pps) = avcc_parameter_sets(avcc);
+    if sps.is_empty() || pps.is_empty() {
+        return None;
+    }
+    let mut prefix = Vec::new();
+    for nal in sps.iter().chain(&pps) {
+        prefix.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        prefix.extend_from_slice(nal);
+    }
+    Some(prefix)
+}
+
+// Whether an AVCC frame has both an SPS and a PPS in band. Frames that don't parse as AVCC with
+// 4 byte lengths count as having them, nothing gets put in front of them.
+pub(super) fn carries_parameter_sets(frame: &[u8]) -> bool {
+    let (mut sps, mut pps) = (false, false);
+    let mut index = 0;
+    while index < frame.len() {
+        let Some(len) = frame.get(index..index + 4) else {
+            return true;
+        };
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        index += 4;
+        let Some(header) = frame.get(index..index + len).and_then(|nal| nal.first()) else {
+            return true;
+        };
+        match NalType::from_header(*header) {
+            NalType::Sps => sps = true,
+            NalType::Pps => pps = true,
+            _ => {}
+        }
+        index += len;
+    }
+    sps && pps
+}
+
+// Whether an AVCC frame with 4 byte lengths has no NAL data at all: empty, or only zero length
+// NALs. Frames that don't parse that way count as having data, the decoder reports on those.
+pub(super) fn is_empty_access_unit(frame: &[u8]) -> bool {
+    let mut index = 0;
+    while index < frame.len() {
+        let Some(len) = frame.get(index..index + 4) else {
+            return false;
+        };
+        if len != [0, 0, 0, 0] {
+            return false;
+        }
+        index += 4;
+    }
+    true
+}
+
+// The stream `SessionConfig::metadata_stream` sets up, ONVIF metadata first
+pub(super) fn select_metadata_stream(streams: &[Stream]) -> Option<usize> {
+    let mut applications = streams
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.media() == "application");
+    let first = applications.clone().next();
+    applications
+        .find(|(_, s)| s.encoding_name().eq_ignore_ascii_case("vnd.onvif.metadata"))
+        .or(first)
+        .map(|(i, _)| i)
+}
+
+pub(super) fn select_stream(
+    streams: &[Stream],
+    selector: &StreamSelector,
+) -> Result<usize, SessionError> {
+    let mut videos = streams
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.media() == "video");
+
+    let found = match selector {
+        StreamSelector::FirstVideo => videos.next(),
+        StreamSelector::Codec(codec) => {
+            videos.find(|(_, s)| s.encoding_name().eq_ignore_ascii_case(codec))
+        }
+        StreamSelector::NthVideo(n) => videos.nth(*n),
+        // Matches either the full control url or just its trailing part, e.g. `trackID=1`
+        StreamSelector::Control(control) => videos.find(|(_, s)| {
+            s.control().is_some_and(|c| {
+                c.as_str() == control.as_str() || c.as_str().ends_with(control.as_str())
+            })
+        }),
+    };
+
+    found.map(|(i, _)| i).ok_or_else(|| match selector {
+        StreamSelector::FirstVideo => SessionError::NoVideoStreamFound,
+        _ => SessionError::NoMatchingStream {
+            available: streams
+                .iter()
+                .map(|s| format!("{}/{}", s.media(), s.encoding_name()))
+                .collect(),
+        },
+    })
+}
@@ -0,0 +1,890 @@
+// Sessions driven end to end by a `FrameScript`, from the session loop through the decode worker
+// to the requesters
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::broadcast;
+
+use super::*;
+use crate::capture::{FrameSink, TimeLapse, TimeLapseEvent};
+use crate::decoders::{
+    avcc_to_annex_b,
+    testing::{self, MockDecoder},
+    AVCCDecoder, Chain, DecoderError, H264GrayDecoder, PixelFormat,
+};
+
+fn camera_url() -> Url {
+    Url::parse("rtsp://camera.invalid/stream").unwrap()
+}
+
+fn session(config: SessionConfig, script: FrameScript) -> SessionInstanceManager {
+    SessionWrapper::new(camera_url(), Box::new(MockDecoder::new(1)))
+        .with_config(config)
+        .start_scripted(script)
+}
+
+// Decodes the fixtures for real, a picture is the luma of the fixture throughout
+fn h264_session(script: FrameScript) -> SessionInstanceManager {
+    let decoder = AVCCDecoder::new().chain(H264GrayDecoder::new(false).unwrap());
+    SessionWrapper::new_sync(camera_url(), Box::new(decoder)).start_scripted(script)
+}
+
+// The receiver the manager took when the session was spawned, which holds the events sent before
+// the test got to subscribe
+fn events_from_start(manager: &mut SessionInstanceManager) -> broadcast::Receiver<SessionEvent> {
+    let later = manager.events_rx.resubscribe();
+    std::mem::replace(&mut manager.events_rx, later)
+}
+
+// Events up to and including the first one `until` matches
+async fn events_until(
+    events: &mut broadcast::Receiver<SessionEvent>,
+    until: impl Fn(&SessionEvent) -> bool,
+) -> Vec<SessionEvent> {
+    let mut seen = Vec::new();
+    let wait = async {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let done = until(&event);
+                    seen.push(event);
+                    if done {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(e) => panic!("event channel closed: {}", e),
+            }
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .is_err()
+    {
+        panic!("event didn't arrive, saw {:?}", seen);
+    }
+    seen
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stalled_stream_is_restarted() {
+    let config = SessionConfig::builder()
+        .with_stall_timeout(Some(Duration::from_millis(100)))
+        .with_reconnect_delay(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0)
+        .iframe(fixtures::iframe(10))
+        .pframes(2)
+        .then(
+            FrameScript::new(50.0)
+                .iframe(fixtures::iframe(20))
+                .pframes(2),
+        );
+    let mut manager = session(config, script);
+    let mut events = events_from_start(&mut manager);
+
+    let seen = events_until(&mut events, |e| {
+        matches!(e, SessionEvent::Disconnected { .. })
+    })
+    .await;
+    assert!(matches!(seen[0], SessionEvent::Connected { .. }));
+    assert!(seen
+        .iter()
+        .any(|e| matches!(e, SessionEvent::IFrameReceived { .. })));
+    match seen.last() {
+        Some(SessionEvent::Disconnected { reason }) => {
+            assert_eq!(*reason, SessionError::StreamStalled.to_string());
+        }
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+
+    let seen = events_until(&mut events, |e| {
+        matches!(e, SessionEvent::IFrameReceived { .. })
+    })
+    .await;
+    assert!(matches!(seen[0], SessionEvent::ReconnectAttempt { n: 1 }));
+    assert!(seen
+        .iter()
+        .any(|e| matches!(e, SessionEvent::Connected { .. })));
+    assert_eq!(manager.stats().reconnects, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn script_without_restart_never_stalls() {
+    let config = SessionConfig::builder()
+        .with_stall_timeout(Some(Duration::from_millis(20)))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0).iframe(fixtures::iframe(10));
+    let mut manager = session(config, script);
+    let mut events = events_from_start(&mut manager);
+
+    events_until(&mut events, |e| {
+        matches!(e, SessionEvent::IFrameReceived { .. })
+    })
+    .await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let later: Vec<SessionEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+    assert!(
+        !later
+            .iter()
+            .any(|e| matches!(e, SessionEvent::Disconnected { .. })),
+        "{:?}",
+        later
+    );
+    assert!(manager.health().connected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connected_comes_before_the_first_iframe() {
+    let script = FrameScript::new(50.0)
+        .iframe(fixtures::iframe(10))
+        .pframes(2);
+    let mut manager = session(SessionConfig::default(), script);
+    let mut events = events_from_start(&mut manager);
+
+    let seen = events_until(&mut events, |e| {
+        matches!(e, SessionEvent::IFrameReceived { .. })
+    })
+    .await;
+    assert_eq!(seen.len(), 3, "{:?}", seen);
+    assert!(matches!(&seen[0], SessionEvent::Connected { url } if *url == camera_url()));
+    match &seen[1] {
+        SessionEvent::StreamSetup {
+            codec, resolution, ..
+        } => {
+            assert_eq!(codec, "h264");
+            assert_eq!(*resolution, Some((16, 16)));
+        }
+        e => panic!("expected StreamSetup, got {:?}", e),
+    }
+}
+
+// Current-thread, so the loop only runs once the test waits on the reply
+#[tokio::test]
+async fn instances_are_refused_while_shutting_down() {
+    let script = FrameScript::new(50.0).iframe(fixtures::iframe(10));
+    let mut manager = session(SessionConfig::default(), script);
+
+    let _ = manager.shutdown_tx.take().unwrap().send(());
+    let res = manager.request_instance().await;
+    assert!(
+        matches!(
+            res,
+            Err(SessionError::UnableToSubscribe(
+                SubscribeError::ShuttingDown
+            ))
+        ),
+        "{:?}",
+        res.err()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn instances_over_the_limit_are_refused() {
+    let config = SessionConfig::builder()
+        .with_max_instances(Some(1))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0).iframe(fixtures::iframe(10));
+    let mut manager = session(config, script);
+
+    let _instance = manager.request_instance().await.ok().unwrap();
+    match manager.request_instance().await {
+        Err(e @ SessionError::UnableToSubscribe(SubscribeError::MaxInstances(1))) => {
+            assert_eq!(
+                e.to_string(),
+                "unable to subscribe: the session already has 1 instances"
+            );
+        }
+        res => panic!("expected a refusal, got {:?}", res.err()),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropped_instances_free_their_slot() {
+    const MAX: usize = 3;
+    let config = SessionConfig::builder()
+        .with_max_instances(Some(MAX))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0).iframe(fixtures::iframe(10));
+    let mut manager = session(config, script);
+    let mut events = events_from_start(&mut manager);
+
+    let mut instances = Vec::new();
+    for _ in 0..MAX {
+        instances.push(manager.request_instance().await.ok().unwrap());
+    }
+    assert_eq!(manager.instance_count(), MAX);
+    assert!(matches!(
+        manager.request_instance().await,
+        Err(SessionError::UnableToSubscribe(
+            SubscribeError::MaxInstances(MAX)
+        ))
+    ));
+    // Refusals aren't counted
+    assert_eq!(manager.instance_count(), MAX);
+
+    instances.pop();
+    assert_eq!(manager.instance_count(), MAX - 1);
+    instances.push(manager.request_instance().await.ok().unwrap());
+    assert_eq!(manager.instance_count(), MAX);
+
+    instances.clear();
+    assert_eq!(manager.instance_count(), 0);
+    events_until(&mut events, |e| matches!(e, SessionEvent::Idle)).await;
+}
+
+// Queued like `SessionInstance::request_image` does, but the test keeps the reply channel to tell
+// an error reply from a dropped request
+fn queue(
+    instance: &SessionInstance,
+    req: FrameRequest,
+) -> sync::oneshot::Receiver<Result<FrameResponse, SessionError>> {
+    let (tx, rx) = sync::oneshot::channel();
+    let slot = Arc::clone(&instance.request_slots)
+        .try_acquire_owned()
+        .unwrap();
+    let req = DataRequest::Frame(req, Requester { tx, _slot: slot });
+    assert!(instance.data_req_tx.try_send(req).is_ok());
+    rx
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_answers_pending_requests() {
+    let script = FrameScript::new(50.0).iframe(fixtures::iframe(10));
+    let mut manager = session(SessionConfig::default(), script);
+    let mut events = events_from_start(&mut manager);
+    let instance = manager.request_instance().await.ok().unwrap();
+    events_until(&mut events, |e| {
+        matches!(e, SessionEvent::IFrameReceived { .. })
+    })
+    .await;
+
+    // Frames that never come, the worker holds on to the requests
+    let replies: Vec<_> = (1..4)
+        .map(|index| queue(&instance, FrameRequest::new(index).wait_for_frame(true)))
+        .collect();
+    assert!(manager.shutdown(Duration::from_secs(5)).await);
+    for reply in replies {
+        match reply.await {
+            Ok(Err(SessionError::ServerDropped)) => {}
+            Ok(res) => panic!("expected ServerDropped, got {:?}", res.err()),
+            Err(_) => panic!("the request was dropped without a reply"),
+        }
+    }
+}
+
+// Waits for the worker to have `frames` frames of the GOP buffered
+async fn buffered(instance: &SessionInstance, frames: usize) {
+    let wait = async {
+        while instance.buffer_status().buffered < frames {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .is_err()
+    {
+        panic!("only {} frames arrived", instance.buffer_status().buffered);
+    }
+}
+
+// Which decoder produced the picture out of which frame, see `MockDecoder`
+fn picture(res: Result<FrameResponse, SessionError>) -> (u8, u8) {
+    match res {
+        Ok(frame) => (frame.frame()[0], frame.frame()[1]),
+        Err(e) => panic!("expected a frame, got {}", e),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_are_served_from_the_scripted_frames() {
+    let config = SessionConfig::builder()
+        .with_frame_lifetime(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0)
+        .iframe(testing::frame(10))
+        .pframe(testing::frame(11))
+        .pframe(testing::frame(12));
+    let mut manager = session(config, script);
+    let instance = manager.request_instance().await.ok().unwrap();
+    buffered(&instance, 3).await;
+
+    let generation = instance.buffer_state().generation;
+    for index in [2, 0, 1] {
+        let frame = instance.request_image(FrameRequest::new(index)).await;
+        let frame = frame.ok().unwrap();
+        assert_eq!((frame.index(), frame.generation()), (index, generation));
+        assert_eq!(picture(Ok(frame)), (1, 10 + index as u8));
+    }
+    assert_eq!(
+        picture(instance.request_image(FrameRequest::latest()).await),
+        (1, 12)
+    );
+    assert!(matches!(
+        instance.request_image(FrameRequest::new(3)).await,
+        Err(SessionError::DecodingError(DecoderError::IndexOutOfBounds))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn expired_gop_is_refused() {
+    let config = SessionConfig::builder()
+        .with_frame_lifetime(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0).iframe(testing::frame(10));
+    let mut manager = session(config, script);
+    let mut events = events_from_start(&mut manager);
+    let instance = manager.request_instance().await.ok().unwrap();
+    buffered(&instance, 1).await;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(matches!(
+        instance.request_image(FrameRequest::new(0)).await,
+        Err(SessionError::OldFrame)
+    ));
+    events_until(&mut events, |e| matches!(e, SessionEvent::FrameExpired)).await;
+    // Nothing is left to serve until the next iframe
+    assert!(matches!(
+        instance
+            .request_image(FrameRequest::new(0).with_timeout(Duration::from_millis(50)))
+            .await,
+        Err(SessionError::Timeout(_))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_for_an_older_moment_than_the_buffer_are_refused() {
+    let config = SessionConfig::builder()
+        .with_frame_lifetime(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0).iframe(testing::frame(10));
+    let before = Instant::now();
+    let mut manager = session(config, script);
+    let instance = manager.request_instance().await.ok().unwrap();
+    buffered(&instance, 1).await;
+
+    assert!(matches!(
+        instance.request_image(FrameRequest::at(before)).await,
+        Err(SessionError::OldFrame)
+    ));
+    assert_eq!(
+        picture(
+            instance
+                .request_image(FrameRequest::at(Instant::now()))
+                .await
+        ),
+        (1, 10)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_buffer_keeps_the_start_of_the_gop() {
+    let config = SessionConfig::builder()
+        .with_buf_size(2)
+        .with_frame_lifetime(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0)
+        .iframe(testing::frame(10))
+        .pframe(testing::frame(11))
+        .pframe(testing::frame(12))
+        .pframe(testing::frame(13));
+    let mut manager = session(config, script);
+    let mut events = events_from_start(&mut manager);
+    let instance = manager.request_instance().await.ok().unwrap();
+
+    events_until(&mut events, |e| matches!(e, SessionEvent::BufferFull)).await;
+    // The last frame is refused too, but reported once per GOP
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!std::iter::from_fn(|| events.try_recv().ok())
+        .any(|e| matches!(e, SessionEvent::BufferFull)));
+    assert_eq!(manager.stats().frames_dropped_full, 2);
+    assert_eq!(instance.buffer_status().buffered, 2);
+    for index in 0..2 {
+        assert_eq!(
+            picture(instance.request_image(FrameRequest::new(index)).await),
+            (1, 10 + index as u8)
+        );
+    }
+    assert!(matches!(
+        instance.request_image(FrameRequest::new(2)).await,
+        Err(SessionError::DecodingError(DecoderError::IndexOutOfBounds))
+    ));
+}
+
+// Of the newest GOP's iframe
+async fn luma(instance: &SessionInstance) -> Result<u8, SessionError> {
+    let frame = instance.request_image(FrameRequest::new(0)).await?;
+    assert_eq!(
+        (frame.width(), frame.height()),
+        (fixtures::WIDTH, fixtures::HEIGHT)
+    );
+    assert!(frame.frame().iter().all(|p| *p == frame.frame()[0]));
+    Ok(frame.frame()[0])
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn iframes_without_parameter_sets_need_the_sdp_ones() {
+    for parameters in [Some(fixtures::parameters()), None] {
+        let with_sdp = parameters.is_some();
+        let script = FrameScript::new(50.0)
+            .with_parameters(parameters)
+            .iframe(fixtures::bare_iframe(90));
+        let mut manager = h264_session(script);
+        let instance = manager.request_instance().await.ok().unwrap();
+        buffered(&instance, 1).await;
+
+        match luma(&instance).await {
+            Ok(luma) => {
+                assert!(with_sdp);
+                assert_eq!(luma, 90);
+            }
+            Err(e) => assert!(!with_sdp, "{}", e),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sdp_parameter_sets_go_again_to_a_reset_decoder() {
+    // The loss resets the decoder at the next iframe, the in-band parameter sets aren't doubled
+    let script = FrameScript::new(50.0)
+        .iframe(fixtures::iframe(60))
+        .lost(1, fixtures::pframe(1))
+        .wait(Duration::from_millis(200))
+        .iframe(fixtures::bare_iframe(120));
+    let mut manager = h264_session(script);
+    let mut events = events_from_start(&mut manager);
+    let instance = manager.request_instance().await.ok().unwrap();
+
+    events_until(&mut events, |e| {
+        matches!(e, SessionEvent::IFrameReceived { .. })
+    })
+    .await;
+    assert_eq!(luma(&instance).await.ok(), Some(60));
+    events_until(&mut events, |e| {
+        matches!(e, SessionEvent::IFrameReceived { .. })
+    })
+    .await;
+    assert_eq!(luma(&instance).await.ok(), Some(120));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keyframe_only_serves_the_newest_iframe() {
+    let config = SessionConfig::builder()
+        .with_capture_mode(CaptureMode::KeyframeOnly {
+            request_interval: Some(Duration::from_millis(30)),
+        })
+        .with_frame_lifetime(Duration::from_millis(10))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0)
+        .iframe(testing::frame(10))
+        .pframe(testing::frame(11))
+        .pframe(testing::frame(12))
+        .wait(Duration::from_millis(200))
+        .iframe(testing::frame(20))
+        .pframe(testing::frame(21));
+    let decoder = MockDecoder::new(1);
+    let log = decoder.log();
+    let resyncs = Arc::new(AtomicUsize::new(0));
+    let hook_resyncs = Arc::clone(&resyncs);
+    let mut manager = SessionWrapper::new(camera_url(), Box::new(decoder))
+        .with_config(config)
+        .with_resync_hook(Box::new(move || {
+            hook_resyncs.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Ok(()) })
+        }))
+        .start_scripted(script);
+    let mut events = events_from_start(&mut manager);
+    let instance = manager.request_instance().await.ok().unwrap();
+
+    let mut iframe_ts = Vec::new();
+    for id in [10, 20] {
+        let seen = events_until(&mut events, |e| {
+            matches!(e, SessionEvent::IFrameReceived { .. })
+        })
+        .await;
+        if let Some(SessionEvent::IFrameReceived { ts }) = seen.last() {
+            iframe_ts.push(ts.timestamp());
+        }
+        // Past its lifetime, and the delta frames after it never count
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        let frame = instance
+            .request_image(FrameRequest::new(2))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!((frame.index(), frame.frame()[1]), (0, id));
+        assert_eq!(
+            Some(frame.timestamp().timestamp()),
+            iframe_ts.last().copied()
+        );
+        assert_eq!(instance.buffer_status().buffered, 1);
+    }
+    // Decoded on arrival, the requests didn't feed the decoder any further
+    assert_eq!(log.decoded(), [10, 20]);
+    assert!(resyncs.load(Ordering::Relaxed) >= 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrupt_frame_taints_its_gop_until_the_resync() {
+    let config = SessionConfig::builder()
+        .with_frame_lifetime(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let script = FrameScript::new(50.0)
+        .iframe(fixtures::iframe(60))
+        .pframes(1)
+        .pframe(fixtures::corrupt_pframe())
+        .pframes(1)
+        .wait(Duration::from_millis(300))
+        .iframe(fixtures::iframe(90));
+    let resyncs = Arc::new(AtomicUsize::new(0));
+    let hook_resyncs = Arc::clone(&resyncs);
+    let decoder = AVCCDecoder::new().chain(H264GrayDecoder::new(false).unwrap());
+    let mut manager = SessionWrapper::new_sync(camera_url(), Box::new(decoder))
+        .with_config(config)
+        .with_resync_hook(Box::new(move || {
+            hook_resyncs.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Ok(()) })
+        }))
+        .start_scripted(script);
+    let mut events = events_from_start(&mut manager);
+    let instance = manager.request_instance().await.ok().unwrap();
+    buffered(&instance, 4).await;
+
+    let res = instance.request_image(FrameRequest::new(3)).await;
+    assert!(
+        matches!(
+            res,
+            Err(SessionError::DecodingError(DecoderError::DecodeFail { .. }))
+        ),
+        "{:?}",
+        res.err()
+    );
+    let seen = events_until(&mut events, |e| {
+        matches!(e, SessionEvent::DecodeFailed { .. })
+    })
+    .await;
+    assert!(matches!(
+        seen.last(),
+        Some(SessionEvent::DecodeFailed { index: 2, .. })
+    ));
+    for index in 2..4 {
+        assert!(matches!(
+            instance.request_image(FrameRequest::new(index)).await,
+            Err(SessionError::CorruptGop)
+        ));
+    }
+    assert_eq!(luma(&instance).await.ok(), Some(60));
+    // Asked for right away rather than at the camera's next iframe
+    let resynced = async {
+        while resyncs.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+    assert!(tokio::time::timeout(Duration::from_millis(200), resynced)
+        .await
+        .is_ok());
+    assert_eq!(resyncs.load(Ordering::Relaxed), 1);
+    assert_eq!(manager.stats().decode_failures, 1);
+
+    events_until(&mut events, |e| {
+        matches!(e, SessionEvent::IFrameReceived { .. })
+    })
+    .await;
+    assert_eq!(luma(&instance).await.ok(), Some(90));
+    let stats = manager.stats();
+    assert_eq!((stats.decode_failures, stats.decode_recoveries), (1, 1));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_info_matches_the_fixture_sps() {
+    let config = SessionConfig::builder()
+        .with_stall_timeout(Some(Duration::from_millis(100)))
+        .with_reconnect_delay(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    // The restart comes back at another resolution, announced in band only
+    let script = FrameScript::new(50.0).iframe(fixtures::iframe(60)).then(
+        FrameScript::new(50.0)
+            .with_resolution(Some((32, 16)))
+            .with_parameters(None)
+            .iframe(fixtures::sized_iframe(2, 1, 90)),
+    );
+    let decoder = AVCCDecoder::new().chain(H264GrayDecoder::new(false).unwrap());
+    let mut manager = SessionWrapper::new_sync(camera_url(), Box::new(decoder))
+        .with_config(config)
+        .start_scripted(script);
+    let mut events = events_from_start(&mut manager);
+    let instance = manager.request_instance().await.ok().unwrap();
+
+    for (width, height) in [(fixtures::WIDTH, fixtures::HEIGHT), (32, 16)] {
+        let seen = events_until(&mut events, |e| {
+            matches!(e, SessionEvent::IFrameReceived { .. })
+        })
+        .await;
+        let Some(SessionEvent::StreamSetup { resolution, .. }) = seen
+            .iter()
+            .find(|e| matches!(e, SessionEvent::StreamSetup { .. }))
+        else {
+            panic!("no StreamSetup in {:?}", seen);
+        };
+        let info = manager.stream_info().unwrap();
+        assert_eq!(info.dimensions, *resolution);
+
+        // What the decoder read off the SPS
+        let frame = instance
+            .request_image(FrameRequest::new(0))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!((frame.width(), frame.height()), (width, height));
+        assert_eq!(info.dimensions, Some((width as u32, height as u32)));
+        if width == fixtures::WIDTH {
+            assert_eq!(info.codec.as_deref(), Some("avc1.42C00A"));
+            assert_eq!((info.profile, info.level), (Some(66), Some(10)));
+        } else {
+            assert_eq!(info.codec, None);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interactive_request_overtakes_a_queued_batch() {
+    let config = SessionConfig::builder()
+        .with_frame_lifetime(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let mut script = FrameScript::new(100.0).iframe(testing::frame(10));
+    for id in 11..20 {
+        script = script.pframe(testing::frame(id));
+    }
+    let decoder = MockDecoder::new(1).with_delay(Duration::from_millis(30));
+    let mut manager = SessionWrapper::new(camera_url(), Box::new(decoder))
+        .with_config(config)
+        .start_scripted(script);
+    let background = manager.request_instance().await.ok().unwrap();
+    let interactive = manager
+        .request_instance()
+        .await
+        .ok()
+        .unwrap()
+        .with_priority(Priority::Interactive);
+    buffered(&background, 10).await;
+
+    let order = std::sync::Mutex::new(Vec::new());
+    let done = |label| order.lock().unwrap().push(label);
+    tokio::join!(
+        async {
+            // Keeps the worker busy for 150ms
+            let batch = background.request_batch(FrameRequest::range(0, 5)).await;
+            assert!(batch.ok().unwrap().error.is_none());
+            done("running");
+        },
+        async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let batch = background.request_batch(FrameRequest::range(5, 10)).await;
+            assert!(batch.ok().unwrap().error.is_none());
+            done("queued");
+        },
+        async {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            let frame = interactive.request_image(FrameRequest::new(1)).await;
+            assert_eq!(picture(frame), (1, 11));
+            done("interactive");
+        },
+    );
+    assert_eq!(*order.lock().unwrap(), ["running", "interactive", "queued"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slices_are_merged_into_whole_pictures() {
+    for aggregate in [true, false] {
+        let [i0, i1] = fixtures::sliced_iframe(70);
+        let mut script = FrameScript::new(50.0).iframe(i0).slice(i1);
+        for frame_num in 1..=2 {
+            let [p0, p1] = fixtures::sliced_pframe(frame_num);
+            script = script.pframe(p0).slice(p1);
+        }
+        let config = SessionConfig::builder()
+            .with_frame_lifetime(Duration::from_secs(30))
+            .with_aggregate_slices(aggregate)
+            .build()
+            .unwrap();
+        let decoder = AVCCDecoder::new().chain(H264GrayDecoder::new(false).unwrap());
+        let mut manager = SessionWrapper::new_sync(camera_url(), Box::new(decoder))
+            .with_config(config)
+            .start_scripted(script);
+        let instance = manager.request_instance().await.ok().unwrap();
+
+        if !aggregate {
+            // Every slice taken for a frame
+            buffered(&instance, 6).await;
+            assert_eq!(manager.stats().slices_aggregated, 0);
+            continue;
+        }
+        // The last picture waits for the next one to begin
+        buffered(&instance, 2).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(instance.buffer_status().buffered, 2);
+        assert_eq!(manager.stats().slices_aggregated, 2);
+        for index in 0..2 {
+            let frame = instance
+                .request_image(FrameRequest::new(index))
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(
+                (frame.width(), frame.height()),
+                (2 * fixtures::WIDTH, fixtures::HEIGHT)
+            );
+            assert!(frame.frame().iter().all(|p| *p == 70));
+        }
+        assert!(matches!(
+            instance.request_image(FrameRequest::new(2)).await,
+            Err(SessionError::DecodingError(DecoderError::IndexOutOfBounds))
+        ));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn raw_requests_return_the_fixture_bytes() {
+    let config = SessionConfig::builder()
+        .with_frame_lifetime(Duration::from_millis(300))
+        .build()
+        .unwrap();
+    let sent = [fixtures::iframe(60), fixtures::pframe(1)];
+    let script = FrameScript::new(50.0)
+        .iframe(sent[0].clone())
+        .pframe(sent[1].clone());
+    let decoder = MockDecoder::new(1);
+    let log = decoder.log();
+    let mut manager = SessionWrapper::new(camera_url(), Box::new(decoder))
+        .with_config(config)
+        .start_scripted(script);
+    let instance = manager.request_instance().await.ok().unwrap();
+    buffered(&instance, 2).await;
+
+    for (index, fixture) in sent.iter().enumerate() {
+        let frame = instance
+            .request_image(FrameRequest::new(index).raw())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(frame.frame(), avcc_to_annex_b(fixture, 4).unwrap());
+        assert_eq!(frame.pixel_format(), PixelFormat::H264AnnexB);
+        assert_eq!(frame.is_random_access_point(), index == 0);
+        assert_eq!(frame.timestamp().timestamp(), index as i64 * 1800);
+    }
+    assert!(log.calls().is_empty());
+
+    // Expires like decoded frames do
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(matches!(
+        instance.request_image(FrameRequest::new(0).raw()).await,
+        Err(SessionError::OldFrame)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn metadata_and_video_are_delivered_side_by_side() {
+    for enabled in [true, false] {
+        let config = SessionConfig::builder()
+            .with_frame_lifetime(Duration::from_secs(30))
+            .with_metadata_stream(enabled)
+            .build()
+            .unwrap();
+        let script = FrameScript::new(50.0)
+            .iframe(testing::frame(10))
+            .metadata(b"<first/>".to_vec())
+            .pframe(testing::frame(11))
+            .metadata(b"<second/>".to_vec())
+            .pframe(testing::frame(12));
+        let mut manager = session(config, script);
+        // The receiver taken when the session was spawned, the first message goes out right away
+        let mut metadata = match manager.metadata_rx.take() {
+            Some(rx) => MetadataStream::new(rx),
+            None => manager.metadata_stream(),
+        };
+        let instance = manager.request_instance().await.ok().unwrap();
+
+        if enabled {
+            for (pts, xml) in [(1800, &b"<first/>"[..]), (3600, b"<second/>")] {
+                let (timestamp, message) = metadata.recv().await.unwrap();
+                assert_eq!((timestamp.timestamp(), &message[..]), (pts, xml));
+            }
+        } else {
+            assert!(metadata.recv().await.is_none());
+        }
+        buffered(&instance, 3).await;
+        for index in 0..3 {
+            assert_eq!(
+                picture(instance.request_image(FrameRequest::new(index)).await),
+                (1, 10 + index as u8)
+            );
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn time_lapse_stops_before_the_session_without_partial_files() {
+    let config = SessionConfig::builder()
+        .with_frame_lifetime(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let mut script = FrameScript::new(50.0).iframe(testing::frame(10));
+    for id in 11..30 {
+        script = script.pframe(testing::frame(id));
+    }
+    let mut manager = session(config, script);
+    let instance = manager.request_instance().await.ok().unwrap();
+    buffered(&instance, 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let sink = FrameSink::new(dir.path()).with_template("{ts}.{ext}");
+    let time_lapse = TimeLapse::new(instance, Duration::from_millis(20)).start(sink);
+    let mut events = time_lapse.events();
+    let mut captured = Vec::new();
+    while captured.len() < 3 {
+        match tokio::time::timeout(Duration::from_secs(5), events.recv()).await {
+            Ok(Ok(TimeLapseEvent::Captured { path, .. })) => captured.extend(path),
+            Ok(Ok(_)) => {}
+            res => panic!("time-lapse stopped capturing: {:?}", res),
+        }
+    }
+
+    // Stopped while writes are under way, the session goes last
+    let clean = manager
+        .shutdown_with(vec![Box::new(time_lapse)], Duration::from_secs(2))
+        .await;
+    assert!(clean);
+    while let Ok(event) = events.try_recv() {
+        if let TimeLapseEvent::Captured { path, .. } = event {
+            captured.extend(path);
+        }
+    }
+    let mut written: Vec<PathBuf> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    written.sort();
+    captured.sort();
+    captured.dedup();
+    assert_eq!(written, captured);
+    for path in written {
+        let data = fs::read(&path).unwrap();
+        assert_eq!((data.len(), data[0]), (2, 1), "{}", path.display());
+    }
+}
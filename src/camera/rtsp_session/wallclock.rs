@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use retina::{rtcp::ReceivedCompoundPacket, Timestamp};
+use tracing::{debug, warn};
+
+// Seconds from the NTP epoch, 1900, to the Unix one
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+// Cameras that never got their clock set report times from around 1970, anything before 2001 is
+// taken for such a clock
+const MIN_PLAUSIBLE_UNIX: u64 = 978_307_200;
+// How far RTP time may run apart from arrival time before the camera is deemed to have restarted
+// its RTP clock. Covers network jitter and frames buffered on the camera.
+const MAX_DRIFT: Duration = Duration::from_secs(10);
+
+// RTP time to wall-clock time, from the last usable RTCP sender report
+struct Mapping {
+    rtp: Timestamp,
+    ntp: SystemTime,
+    received: Instant,
+}
+
+// Capture times of frames from the sender reports of the connection, reset on every reconnect.
+// None until the first usable report, cameras usually send one every 5s.
+#[derive(Default)]
+pub(super) struct WallClock {
+    mapping: Option<Mapping>,
+}
+
+impl WallClock {
+    pub(super) fn on_rtcp(&mut self, pkt: &ReceivedCompoundPacket) {
+        let Some(rtp) = pkt.rtp_timestamp() else {
+            return;
+        };
+        for sr in pkt.pkts() {
+            let Ok(Some(sr)) = sr.as_sender_report() else {
+                continue;
+            };
+            let Some(ntp) = ntp_to_system_time(sr.ntp_timestamp().0) else {
+                debug!("Ignoring a sender report without a plausible NTP time");
+                continue;
+            };
+            if let Some(predicted) = self.predict(rtp) {
+                let off = match ntp.duration_since(predicted) {
+                    Ok(d) => d,
+                    Err(e) => e.duration(),
+                };
+                if off > MAX_DRIFT {
+                    warn!(off = ?off, "Sender report disagrees with the previous one, resetting the wall clock mapping");
+                }
+            }
+            self.mapping = Some(Mapping {
+                rtp,
+                ntp,
+                received: Instant::now(),
+            });
+        }
+    }
+
+    // `None` without a mapping, or once RTP time jumped away from arrival time
+    pub(super) fn capture_time(&mut self, ts: Timestamp, received: Instant) -> Option<SystemTime> {
+        let mapping = self.mapping.as_ref()?;
+        let rtp_elapsed = seconds_between(mapping.rtp, ts)?;
+        let arrival_elapsed = if received >= mapping.received {
+            (received - mapping.received).as_secs_f64()
+        } else {
+            -(mapping.received - received).as_secs_f64()
+        };
+        if (rtp_elapsed - arrival_elapsed).abs() > MAX_DRIFT.as_secs_f64() {
+            warn!(
+                rtp_elapsed,
+                arrival_elapsed,
+                "RTP clock jumped, dropping the wall clock mapping until the next sender report"
+            );
+            self.mapping = None;
+            return None;
+        }
+        self.predict(ts)
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.mapping = None;
+    }
+
+    fn predict(&self, ts: Timestamp) -> Option<SystemTime> {
+        let mapping = self.mapping.as_ref()?;
+        let elapsed = seconds_between(mapping.rtp, ts)?;
+        let offset = Duration::try_from_secs_f64(elapsed.abs()).ok()?;
+        if elapsed >= 0.0 {
+            mapping.ntp.checked_add(offset)
+        } else {
+            mapping.ntp.checked_sub(offset)
+        }
+    }
+}
+
+fn seconds_between(from: Timestamp, to: Timestamp) -> Option<f64> {
+    let rate = from.clock_rate();
+    (rate == to.clock_rate())
+        .then(|| (to.timestamp() - from.timestamp()) as f64 / f64::from(rate.get()))
+}
+
+// 32.32 fixed point seconds since 1900
+fn ntp_to_system_time(ntp: u64) -> Option<SystemTime> {
+    let secs = (ntp >> 32).checked_sub(NTP_UNIX_OFFSET)?;
+    if secs < MIN_PLAUSIBLE_UNIX {
+        return None;
+    }
+    let nanos = ((ntp & 0xffff_ffff) * 1_000_000_000) >> 32;
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos as u32))
+}
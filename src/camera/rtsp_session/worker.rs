@@ -0,0 +1,2483 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use retina::{codec::VideoFrame, Timestamp};
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast, mpsc, oneshot, watch},
+};
+use tracing::{debug, warn};
+
+use super::{
+    clip::{Clip, ClipRequest, ClipSample},
+    events::SessionEvent,
+    request::FrameTarget,
+    respond,
+    stats::StatsCollector,
+    stream::FrameSubscriber,
+    streams, BatchRequest, BatchResponse, BufferPolicy, BufferState, BufferStatus, CaptureMode,
+    DataRequest, Decimation, Disconnect, FrameRequest, FrameResponse, LatencyBreakdown,
+    OutputFormat, Priority, SessionConfig, SessionError,
+};
+use crate::decoders::{
+    AVCCDecoder, AsyncImageDecoder, BufferPool, DecoderError, FrameFormat, ImageDecoder,
+    OwnedFrame, PixelFormat, PooledBuffer,
+};
+
+pub(super) struct RawFrame {
+    // Shared with the bitstream dump and clips, buffering a frame never copies it
+    pub(super) data: Bytes,
+    pub(super) timestamp: Timestamp,
+    pub(super) random_access: bool,
+    pub(super) received: Instant,
+    // From the RTCP sender reports, see `WallClock`
+    pub(super) captured_at: Option<SystemTime>,
+    // RTP packets missing right before this frame
+    pub(super) loss: u16,
+    // Unset for frames skipped by `SessionConfig::decimation`, which are only kept as references
+    pub(super) retained: bool,
+    // See `FrameResponse::seq`, 0 until buffered
+    pub(super) seq: u64,
+}
+
+impl From<VideoFrame> for RawFrame {
+    fn from(f: VideoFrame) -> Self {
+        Self {
+            timestamp: f.timestamp(),
+            random_access: f.is_random_access_point(),
+            received: Instant::now(),
+            captured_at: None,
+            loss: f.loss(),
+            retained: true,
+            seq: 0,
+            data: Bytes::from(f.into_data()),
+        }
+    }
+}
+
+struct CachedFrame {
+    // Shared with every response serving this frame, back in the pool once all are dropped
+    data: Arc<PooledBuffer>,
+    format: FrameFormat,
+}
+
+// The decoder thread has no runtime of its own, async decoders are driven on the session's
+pub(super) struct BlockingDecoder {
+    inner: Box<dyn AsyncImageDecoder>,
+    runtime: Handle,
+    pool: BufferPool,
+    // SPS and PPS from the SDP, see `streams::avcc_parameter_prefix`. Fed with the first frame
+    // after a reset, unless that one carries its own, for cameras that only announce them there.
+    parameter_sets: Option<Vec<u8>>,
+    primed: bool,
+}
+
+impl BlockingDecoder {
+    // Must be called from inside the runtime
+    pub(super) fn new(inner: Box<dyn AsyncImageDecoder>, pool: BufferPool) -> Self {
+        Self {
+            inner,
+            runtime: Handle::current(),
+            pool,
+            parameter_sets: None,
+            primed: false,
+        }
+    }
+
+    // The avcC record of the stream, on every connect
+    fn set_parameters(&mut self, avcc: Option<&[u8]>) {
+        let parameter_sets = avcc.and_then(streams::avcc_parameter_prefix);
+        if parameter_sets != self.parameter_sets {
+            self.parameter_sets = parameter_sets;
+            self.primed = false;
+        }
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<OwnedFrame, DecoderError> {
+        let primed = std::mem::replace(&mut self.primed, true);
+        let prefixed;
+        let data = match &self.parameter_sets {
+            Some(sets) if !primed && !streams::carries_parameter_sets(data) => {
+                prefixed = [sets.as_slice(), data].concat();
+                &prefixed
+            }
+            _ => data,
+        };
+        let res = self
+            .runtime
+            .block_on(self.inner.decode_pooled(data, &self.pool));
+        // The parameter sets never reached the decoder
+        if res.as_ref().is_err_and(turned_away) {
+            self.primed = primed;
+        }
+        res
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.primed = false;
+    }
+
+    // Keeps the buffer pool and the SDP parameter sets, the new decoder gets the latter with its
+    // first frame
+    fn replace(&mut self, inner: Box<dyn AsyncImageDecoder>) {
+        self.inner = inner;
+        self.primed = false;
+    }
+}
+
+// Raw frames are kept in arrival order, which is the order the decoder needs them in. Decoded
+// frames and request indices are in presentation order, the two only differ for streams with
+// B-frames. Decoded frames cover every raw frame, request indices only the retained ones.
+struct Gop {
+    generation: u64,
+    raw_frames: Vec<RawFrame>,
+    // `None` for pictures of frames that weren't retained
+    decoded_frames: Vec<Option<CachedFrame>>,
+    // Raw frames passed to the decoder so far. Runs ahead of `decoded_frames` while the decoder
+    // holds back reordered pictures.
+    fed: usize,
+    raw_bytes: usize,
+    ts: Instant,
+    // Retained P-frames evicted under `BufferPolicy::DropOldest`
+    offset: usize,
+    // Every frame evicted, retained or not. Once any is gone the GOP can't be replayed, so it
+    // only decodes further while the decoder is still positioned on it.
+    evicted: usize,
+    // Index of the first frame that followed packet loss. It and every frame after it reference
+    // data that never arrived, so they are refused with `CorruptGop` instead of decoded.
+    corrupt_from: Option<usize>,
+    // Index of the frame that failed to decode. The decoder state past it is unknown, so later
+    // requests for it or the frames after it fail the same way instead of decoding garbage.
+    failed_at: Option<usize>,
+    // Set once a frame was refused, the frames after it would reference it
+    closed: bool,
+}
+
+impl Gop {
+    fn new(generation: u64, iframe: RawFrame) -> Self {
+        Self {
+            generation,
+            raw_bytes: iframe.data.len(),
+            raw_frames: vec![iframe],
+            decoded_frames: Vec::new(),
+            fed: 0,
+            ts: Instant::now(),
+            offset: 0,
+            evicted: 0,
+            corrupt_from: None,
+            failed_at: None,
+            closed: false,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.raw_frames.len()
+    }
+
+    // After a decode failure at `index`, its frames from there on are refused with `CorruptGop`
+    fn taint(&mut self, index: usize) {
+        self.failed_at = Some(index);
+        self.corrupt_from = Some(self.corrupt_from.map_or(index, |c| c.min(index)));
+    }
+
+    // Frames requests can address
+    fn retained(&self) -> usize {
+        self.raw_frames.iter().filter(|f| f.retained).count()
+    }
+
+    fn retained_decoded(&self) -> usize {
+        self.decoded_frames.iter().filter(|f| f.is_some()).count()
+    }
+
+    fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(self.ts)
+    }
+
+    // Arrival indices sorted by presentation timestamp, frames with the same one keep their
+    // arrival order
+    fn presentation_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        order.sort_by_key(|i| self.raw_frames[*i].timestamp.timestamp());
+        order
+    }
+
+    // Position among the decoded pictures and arrival index of the retained frame at
+    // presentation position `index`
+    fn slot(&self, index: usize) -> Option<(usize, usize)> {
+        self.presentation_order()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, i)| self.raw_frames[*i].retained)
+            .nth(index)
+    }
+
+    fn arrival_index(&self, index: usize) -> Option<usize> {
+        self.slot(index).map(|(_, i)| i)
+    }
+
+    // Roughly how many frames go through the decoder before the picture at `index` is out,
+    // counting the replay when the decoder last worked on another GOP
+    fn frames_to_decode(&self, index: usize, decoder_here: bool) -> Option<usize> {
+        let (slot, _) = self.slot(index)?;
+        let ahead = (slot + 1).saturating_sub(self.decoded_frames.len());
+        if ahead == 0 || decoder_here {
+            Some(ahead)
+        } else {
+            Some(self.fed + ahead)
+        }
+    }
+}
+
+struct FrameHolder {
+    // Oldest first, the back is the GOP currently being filled
+    gops: VecDeque<Gop>,
+    raw_bytes: usize,
+    // GOP whose last fed frame is what the decoder saw last
+    decoder_at: Option<u64>,
+    // The decoder still holds back pictures of that GOP
+    decoder_pending: bool,
+    // Failure not yet reported by the worker, as the GOP and the arrival index of the frame
+    failure: Option<(u64, usize)>,
+    // GOP the decoder last failed on, cleared once a later one decodes
+    failed_generation: Option<u64>,
+}
+
+impl FrameHolder {
+    fn empty() -> Self {
+        Self {
+            gops: VecDeque::new(),
+            raw_bytes: 0,
+            decoder_at: None,
+            decoder_pending: false,
+            failure: None,
+            failed_generation: None,
+        }
+    }
+
+    fn push_gop(&mut self, gop: Gop, history: usize, max_bytes: usize) {
+        self.raw_bytes += gop.raw_bytes;
+        self.gops.push_back(gop);
+        while self.gops.len() > history.max(1) {
+            self.evict_oldest();
+        }
+        self.evict_over(max_bytes, 0);
+    }
+
+    // Frames past `buf_size` or a byte cap are dropped until the next iframe. Frames that
+    // aren't retained only count towards the byte caps.
+    fn add_image(&mut self, frame: RawFrame, config: &SessionConfig) -> Result<(), Refusal> {
+        let len = frame.data.len();
+        self.evict_over(config.max_buffered_bytes, len);
+        let over_total = self.raw_bytes + len > config.max_buffered_bytes;
+        let Some(gop) = self.gops.back_mut() else {
+            return Err(Refusal::NoGop);
+        };
+        let refusal = if gop.closed {
+            Some(Refusal::Closed)
+        } else if frame.retained && gop.retained() >= config.buf_size {
+            Some(Refusal::Full)
+        } else if over_total
+            || config
+                .max_gop_bytes
+                .is_some_and(|max| gop.raw_bytes + len > max)
+        {
+            Some(Refusal::Bytes)
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            gop.closed = true;
+            return Err(refusal);
+        }
+        gop.raw_bytes += len;
+        self.raw_bytes += len;
+        gop.raw_frames.push(frame);
+        Ok(())
+    }
+
+    // Frames referencing a refused one can't be decoded
+    fn close_latest(&mut self) {
+        if let Some(gop) = self.gops.back_mut() {
+            gop.closed = true;
+        }
+    }
+
+    // The current GOP is never evicted to make room
+    fn evict_over(&mut self, max_bytes: usize, incoming: usize) {
+        while self.gops.len() > 1 && self.raw_bytes + incoming > max_bytes {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(gop) = self.gops.pop_front() {
+            self.raw_bytes -= gop.raw_bytes;
+        }
+    }
+
+    fn latest(&self) -> Option<&Gop> {
+        self.gops.back()
+    }
+
+    fn find(&self, generation: u64) -> Option<&Gop> {
+        self.gops.iter().find(|g| g.generation == generation)
+    }
+
+    // Frames reference the ones that arrived before them, so raw frames go through the decoder
+    // exactly once and in arrival order until the picture at presentation position `index` comes
+    // out. Pictures come out in presentation order, every retained one is cached. When the
+    // decoder last worked on another GOP, the frames fed for this one are replayed first to
+    // rebuild its reference state.
+    fn decode(
+        &mut self,
+        decoder: &mut BlockingDecoder,
+        stats: &StatsCollector,
+        generation: u64,
+        index: usize,
+    ) -> Result<&CachedFrame, DecoderError> {
+        let (slot, _) = self
+            .find(generation)
+            .and_then(|g| g.slot(index))
+            .ok_or(DecoderError::IndexOutOfBounds)?;
+        self.decode_slot(decoder, stats, generation, slot)?;
+        self.find(generation)
+            .and_then(|g| g.decoded_frames[slot].as_ref())
+            .ok_or(DecoderError::IndexOutOfBounds)
+    }
+
+    // Decodes until the picture at position `slot` of every frame's presentation order is out
+    fn decode_slot(
+        &mut self,
+        decoder: &mut BlockingDecoder,
+        stats: &StatsCollector,
+        generation: u64,
+        slot: usize,
+    ) -> Result<(), DecoderError> {
+        let decoder_at = self.decoder_at;
+        let Some(gop) = self.gops.iter_mut().find(|g| g.generation == generation) else {
+            return Err(DecoderError::IndexOutOfBounds);
+        };
+        if slot >= gop.raw_frames.len() {
+            return Err(DecoderError::IndexOutOfBounds);
+        }
+        if slot < gop.decoded_frames.len() {
+            return Ok(());
+        }
+
+        if decoder_at != Some(generation) && gop.evicted > 0 {
+            return Err(DecoderError::MissingReference);
+        }
+
+        self.decoder_at = None;
+        if decoder_at != Some(generation) {
+            // Pictures held back for the other GOP would otherwise come out as this one's
+            if self.decoder_pending {
+                decoder.reset();
+            }
+            let mut failed = None;
+            for (i, raw) in gop.raw_frames[..gop.fed].iter().enumerate() {
+                let start = Instant::now();
+                match decoder.decode(&raw.data) {
+                    Ok(_) | Err(DecoderError::NoImageDecoded) => {}
+                    // The replay starts over next time, from a reset decoder
+                    Err(e) if turned_away(&e) => {
+                        self.decoder_pending = true;
+                        return Err(e);
+                    }
+                    Err(e) => {
+                        failed = Some((i, e));
+                        break;
+                    }
+                }
+                stats.record_decode(start.elapsed());
+            }
+            if let Some((i, e)) = failed {
+                gop.taint(i);
+                decoder.reset();
+                stats.record_decode_failure();
+                self.failure = Some((generation, i));
+                self.failed_generation = Some(generation);
+                return Err(e);
+            }
+        }
+        let order = gop.presentation_order();
+        while gop.decoded_frames.len() <= slot {
+            let next = gop.fed;
+            if let Some(failed) = gop.failed_at.filter(|f| next >= *f) {
+                self.decoder_at = Some(generation);
+                self.decoder_pending = gop.fed > gop.decoded_frames.len();
+                return Err(DecoderError::FailedReference { index: failed });
+            }
+            // The picture comes out once the frames it was reordered with have arrived
+            if next == gop.raw_frames.len() {
+                self.decoder_at = Some(generation);
+                self.decoder_pending = true;
+                return Err(DecoderError::NoImageDecoded);
+            }
+            let start = Instant::now();
+            match decoder.decode(&gop.raw_frames[next].data) {
+                Ok(frame) => {
+                    if self.failed_generation.is_some_and(|g| generation > g) {
+                        stats.record_decode_recovery();
+                        self.failed_generation = None;
+                    }
+                    let retained = gop.raw_frames[order[gop.decoded_frames.len()]].retained;
+                    gop.decoded_frames.push(retained.then(|| CachedFrame {
+                        data: frame.data,
+                        format: frame.format,
+                    }));
+                }
+                // Reordered, its picture comes out with a later frame
+                Err(DecoderError::NoImageDecoded) => {}
+                // The frame is fed again by the next request for it
+                Err(e) if turned_away(&e) => {
+                    self.decoder_at = Some(generation);
+                    self.decoder_pending = gop.fed > gop.decoded_frames.len();
+                    return Err(e);
+                }
+                // The decoder state is unknown past here, it starts over with the next GOP decoded
+                Err(e) => {
+                    gop.taint(next);
+                    decoder.reset();
+                    stats.record_decode_failure();
+                    self.decoder_pending = false;
+                    self.failure = Some((generation, next));
+                    self.failed_generation = Some(generation);
+                    return Err(e);
+                }
+            }
+            stats.record_decode(start.elapsed());
+            gop.fed += 1;
+        }
+        self.decoder_at = Some(generation);
+        self.decoder_pending = gop.fed > gop.decoded_frames.len();
+        Ok(())
+    }
+
+    // Makes room in the newest GOP by dropping the oldest retained frame after the iframe, along
+    // with the skipped ones before it. Each frame is decoded first so the decoder still holds the
+    // references the frames after it need.
+    fn evict_oldest_frame(
+        &mut self,
+        decoder: &mut BlockingDecoder,
+        stats: &StatsCollector,
+    ) -> Result<(), DecoderError> {
+        loop {
+            let Some((generation, slot)) = self.gops.back().filter(|g| g.len() > 1).and_then(|g| {
+                let slot = g.presentation_order().iter().position(|i| *i == 1)?;
+                Some((g.generation, slot))
+            }) else {
+                return Ok(());
+            };
+            self.decode_slot(decoder, stats, generation, slot)?;
+
+            let Some(gop) = self.gops.back_mut() else {
+                return Ok(());
+            };
+            let frame = gop.raw_frames.remove(1);
+            gop.decoded_frames.remove(slot);
+            gop.fed -= 1;
+            gop.raw_bytes -= frame.data.len();
+            gop.evicted += 1;
+            // Frames past the evicted one move down, a GOP tainted from its iframe on stays so
+            for index in [gop.corrupt_from.as_mut(), gop.failed_at.as_mut()]
+                .into_iter()
+                .flatten()
+            {
+                if *index > 1 {
+                    *index -= 1;
+                }
+            }
+            self.raw_bytes -= frame.data.len();
+            if frame.retained {
+                gop.offset += 1;
+                return Ok(());
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.gops.is_empty()
+    }
+
+    // Cached frames of the other GOPs were decoded at the old resolution
+    fn drop_decoded_except(&mut self, generation: u64) {
+        for gop in self.gops.iter_mut().filter(|g| g.generation != generation) {
+            if gop.evicted == 0 {
+                gop.decoded_frames.clear();
+                gop.fed = 0;
+            }
+        }
+    }
+}
+
+// Refused by a `DecoderPool` before the frame reached the decoder, nothing about the decoder or
+// the GOP changed. Only the request fails, the frame decodes fine once the pool has room.
+fn turned_away(e: &DecoderError) -> bool {
+    matches!(
+        e,
+        DecoderError::PoolSaturated { .. } | DecoderError::PoolClosed
+    )
+}
+
+// Why `FrameHolder::add_image` refused a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refusal {
+    // No iframe arrived yet
+    NoGop,
+    // An earlier frame of the GOP was refused
+    Closed,
+    Full,
+    Bytes,
+}
+
+pub(super) enum WorkerMessage {
+    Frame(RawFrame),
+    // The stream was lost, everything buffered belongs to the old connection
+    Drain(Disconnect),
+    Request(DataRequest),
+    // No iframe followed the resync request in time
+    ResyncExpired,
+    Subscribe(FrameSubscriber),
+    Clip(ClipRequest),
+    // See `SessionConfig::idle_mode`
+    SetIdle(bool),
+    // avcC record of the stream just connected to
+    Parameters(Option<Vec<u8>>),
+    // Swapped in at the next iframe, the reply carries the generation it starts with
+    ReplaceDecoder {
+        decoder: Box<dyn AsyncImageDecoder + Sync + Send>,
+        reply: oneshot::Sender<Result<u64, SessionError>>,
+    },
+}
+
+struct DecoderSwap {
+    decoder: Box<dyn AsyncImageDecoder + Sync + Send>,
+    reply: oneshot::Sender<Result<u64, SessionError>>,
+}
+
+// Interactive requests served in a row before a waiting background one gets its turn
+const INTERACTIVE_BURST: usize = 4;
+
+// Requests taken off the channel but not served yet, see `Priority`
+#[derive(Default)]
+struct RequestQueue {
+    interactive: VecDeque<DataRequest>,
+    background: VecDeque<DataRequest>,
+    // Interactive requests served since a background one was, while some were waiting
+    streak: usize,
+}
+
+impl RequestQueue {
+    fn push(&mut self, req: DataRequest) {
+        match req.priority() {
+            Priority::Interactive => self.interactive.push_back(req),
+            Priority::Background => self.background.push_back(req),
+        }
+    }
+
+    fn pop(&mut self) -> Option<DataRequest> {
+        if self.interactive.is_empty() || self.streak >= INTERACTIVE_BURST {
+            self.streak = 0;
+            if let Some(req) = self.background.pop_front() {
+                return Some(req);
+            }
+        }
+        let req = self.interactive.pop_front()?;
+        if !self.background.is_empty() {
+            self.streak += 1;
+        }
+        Some(req)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = DataRequest> + '_ {
+        self.streak = 0;
+        self.interactive.drain(..).chain(self.background.drain(..))
+    }
+}
+
+// Owns the decoder and the buffered GOPs on a dedicated thread, so decoding never holds up packet
+// ingestion in the session loop. Messages other than requests are handled strictly in the order
+// they were sent, which keeps P-frames behind their references. Requests are queued by priority
+// and only served once nothing else is waiting, so they still see the frames that preceded them.
+pub(super) struct DecodeWorker {
+    config: SessionConfig,
+    decoder: BlockingDecoder,
+    frame_holder: FrameHolder,
+    generation: u64,
+    queued: RequestQueue,
+    // Requests that arrived while nothing was buffered, served once the next iframe lands
+    pending: VecDeque<DataRequest>,
+    // Requests for frames yet to arrive, see `FrameRequest::wait_for_frame`. Tried again after
+    // every buffered frame.
+    waiting: Vec<DataRequest>,
+    buffer_state_tx: watch::Sender<BufferState>,
+    buffer_status_tx: watch::Sender<BufferStatus>,
+    // Set when the session has a resync hook; expiry then parks requests instead of failing them
+    resync_tx: Option<mpsc::UnboundedSender<()>>,
+    // Stream subscribers, each new buffered frame is decoded once and pushed to all of them
+    subscribers: Vec<FrameSubscriber>,
+    broadcast_tx: broadcast::Sender<FrameResponse>,
+    stats: Arc<StatsCollector>,
+    events_tx: broadcast::Sender<SessionEvent>,
+    // Last generation `BufferFull` was reported for
+    full_reported: Option<u64>,
+    // Picture size of the last decoded frame
+    resolution: Option<(usize, usize)>,
+    // Set after a drain or a loss, the decoder is reset before the next iframe so it doesn't
+    // carry references from the frames that were thrown away
+    stale_decoder: bool,
+    // Only iframes are buffered and nothing is decoded
+    idle: bool,
+    // Left idle mode, requests get `Warmup` until the next iframe
+    warming_up: bool,
+    // Frames since the last retained one and its timestamp in seconds, for `decimation`
+    since_kept: usize,
+    kept_at: f64,
+    // Sequence number of the last retained frame buffered
+    seq: u64,
+    // Waiting for the next iframe, nothing is served meanwhile
+    swap: Option<DecoderSwap>,
+}
+
+impl DecodeWorker {
+    pub(super) fn new(
+        config: SessionConfig,
+        decoder: BlockingDecoder,
+        buffer_state_tx: watch::Sender<BufferState>,
+        buffer_status_tx: watch::Sender<BufferStatus>,
+        broadcast_tx: broadcast::Sender<FrameResponse>,
+        stats: Arc<StatsCollector>,
+        events_tx: broadcast::Sender<SessionEvent>,
+    ) -> Self {
+        Self {
+            config,
+            decoder,
+            frame_holder: FrameHolder::empty(),
+            generation: 0,
+            queued: RequestQueue::default(),
+            pending: VecDeque::new(),
+            waiting: Vec::new(),
+            buffer_state_tx,
+            buffer_status_tx,
+            resync_tx: None,
+            subscribers: Vec::new(),
+            broadcast_tx,
+            stats,
+            events_tx,
+            full_reported: None,
+            resolution: None,
+            stale_decoder: false,
+            idle: false,
+            warming_up: false,
+            since_kept: 0,
+            kept_at: 0.0,
+            seq: 0,
+            swap: None,
+        }
+    }
+
+    pub(super) fn with_resync(mut self, resync_tx: Option<mpsc::UnboundedSender<()>>) -> Self {
+        self.resync_tx = resync_tx;
+        self
+    }
+
+    pub(super) fn spawn(self) -> mpsc::UnboundedSender<WorkerMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("rtsp-decoder".to_string())
+            .spawn(move || self.run(rx))
+            .expect("Failed to spawn the decoder thread");
+        tx
+    }
+
+    // Everything already sent is taken in before the next queued request is served, so an
+    // interactive request overtakes the background ones queued ahead of it
+    fn run(mut self, mut rx: mpsc::UnboundedReceiver<WorkerMessage>) {
+        loop {
+            let msg = match rx.try_recv() {
+                Ok(msg) => msg,
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    if let Some(req) = self.queued.pop() {
+                        self.handle_request(req);
+                        self.report_decode_failure();
+                        self.publish_status();
+                        continue;
+                    }
+                    match rx.blocking_recv() {
+                        Some(msg) => msg,
+                        None => break,
+                    }
+                }
+                Err(mpsc::error::TryRecvError::Disconnected) => break,
+            };
+            self.handle(msg);
+            self.report_decode_failure();
+            self.publish_status();
+        }
+
+        let queued = self.queued.drain();
+        for req in self
+            .pending
+            .drain(..)
+            .chain(self.waiting.drain(..))
+            .chain(queued)
+        {
+            req.fail(SessionError::ServerDropped);
+        }
+    }
+
+    // The iframe that ends the tainted GOP is asked for right away, rather than waiting for the
+    // camera's own interval
+    fn report_decode_failure(&mut self) {
+        let Some((generation, index)) = self.frame_holder.failure.take() else {
+            return;
+        };
+        warn!(
+            "Decoding frame {} of GOP {} failed, refusing the rest of the GOP",
+            index, generation
+        );
+        let _ = self
+            .events_tx
+            .send(SessionEvent::DecodeFailed { generation, index });
+        if let Some(tx) = &self.resync_tx {
+            let _ = tx.send(());
+        }
+    }
+
+    fn handle_request(&mut self, req: DataRequest) {
+        // The requester timed out or went away while the request was queued
+        if req.is_closed() {
+            return;
+        }
+        if self.warming_up {
+            req.fail(SessionError::Warmup);
+            return;
+        }
+        if self.swap.is_some() {
+            req.fail(SessionError::SwappingDecoder);
+            return;
+        }
+        if self.expired() {
+            self.reset();
+            let _ = self.events_tx.send(SessionEvent::FrameExpired);
+            match &self.resync_tx {
+                Some(tx) => {
+                    let _ = tx.send(());
+                }
+                None => {
+                    req.fail(SessionError::OldFrame);
+                    return;
+                }
+            }
+        }
+        if self.frame_holder.is_empty() {
+            self.pending.push_back(req);
+            return;
+        }
+        self.answer(req);
+    }
+
+    fn handle(&mut self, msg: WorkerMessage) {
+        match msg {
+            WorkerMessage::Frame(frame) => self.push_frame(frame),
+            WorkerMessage::Drain(reason) => {
+                self.reset();
+                let queued = self.queued.drain();
+                for req in self
+                    .pending
+                    .drain(..)
+                    .chain(self.waiting.drain(..))
+                    .chain(queued)
+                {
+                    req.fail(reason.to_error());
+                }
+                self.publish(|| Err(reason.to_error()));
+            }
+            WorkerMessage::Request(req) => self.queued.push(req),
+            WorkerMessage::ResyncExpired => {
+                for req in self.pending.drain(..) {
+                    req.fail(SessionError::OldFrame);
+                }
+            }
+            WorkerMessage::Subscribe(subscriber) => self.subscribers.push(subscriber),
+            WorkerMessage::SetIdle(idle) => {
+                // The frames skipped while idle are gone, the GOP in progress can't be served
+                self.warming_up = self.idle && !idle;
+                self.idle = idle;
+            }
+            WorkerMessage::Parameters(parameters) => {
+                self.decoder.set_parameters(parameters.as_deref())
+            }
+            WorkerMessage::ReplaceDecoder { decoder, reply } => {
+                if let Some(previous) = self.swap.replace(DecoderSwap { decoder, reply }) {
+                    debug!("Decoder replacement superseded before it went through");
+                    let _ = previous.reply.send(Err(SessionError::SwappingDecoder));
+                }
+                let queued = self.queued.drain();
+                for req in self
+                    .pending
+                    .drain(..)
+                    .chain(self.waiting.drain(..))
+                    .chain(queued)
+                {
+                    req.fail(SessionError::SwappingDecoder);
+                }
+                if let Some(tx) = &self.resync_tx {
+                    let _ = tx.send(());
+                }
+            }
+            WorkerMessage::Clip(ClipRequest {
+                duration,
+                parameters,
+                resolution,
+                reply,
+            }) => {
+                // The SDP may not have the size, the decoder knows it once a frame went through
+                let resolution = resolution.or(self.resolution.map(|(w, h)| (w as u32, h as u32)));
+                let clip = self.clip(duration).map(|(samples, clock_rate)| Clip {
+                    samples,
+                    clock_rate,
+                    parameters,
+                    resolution,
+                });
+                let _ = reply.send(clip);
+            }
+        }
+    }
+
+    fn push_frame(&mut self, mut frame: RawFrame) {
+        // Nothing references a frame without data, the GOP goes on without it
+        if streams::is_empty_access_unit(&frame.data) {
+            debug!(
+                len = frame.data.len(),
+                random_access = frame.random_access,
+                "Skipping an access unit without NAL data"
+            );
+            self.stats.record_empty();
+            return;
+        }
+        if frame.data.len() > self.config.max_frame_bytes {
+            warn!(
+                len = frame.data.len(),
+                max = self.config.max_frame_bytes,
+                "Refusing an oversized frame, dropping the frames after it until the next iframe"
+            );
+            self.stats.record_oversized();
+            self.stats.record_dropped();
+            self.frame_holder.close_latest();
+            self.stale_decoder = true;
+            return;
+        }
+        if frame.random_access {
+            if let Some(swap) = self.swap.take() {
+                self.swap_decoder(swap);
+            }
+            self.warming_up = false;
+            self.since_kept = 0;
+            self.kept_at = frame.timestamp.elapsed_secs();
+            self.stats.record_buffered();
+            if self.stale_decoder || frame.loss > 0 {
+                self.stale_decoder = false;
+                self.decoder.reset();
+                self.frame_holder.decoder_at = None;
+                self.frame_holder.decoder_pending = false;
+            }
+            self.generation += 1;
+            self.seq += 1;
+            frame.seq = self.seq;
+            let gop = Gop::new(self.generation, frame);
+            self.frame_holder.push_gop(
+                gop,
+                self.config.gop_history,
+                self.config.max_buffered_bytes,
+            );
+            self.publish_state();
+            if self.keyframe_only() && !self.idle {
+                if let Err(e) =
+                    self.frame_holder
+                        .decode(&mut self.decoder, &self.stats, self.generation, 0)
+                {
+                    warn!("Failed to decode the keyframe: {}", e);
+                }
+            }
+            while let Some(req) = self.pending.pop_front() {
+                if !req.is_closed() {
+                    self.answer(req);
+                }
+            }
+            self.answer_waiting();
+            self.push_to_subscribers();
+            return;
+        }
+        if self.idle || self.warming_up || self.keyframe_only() {
+            return;
+        }
+        let frame_lost = frame.loss > 0;
+        let lost = frame_lost && !self.config.tolerate_loss;
+        frame.retained = self.decimate(&frame);
+        let retained = frame.retained;
+        if retained {
+            frame.seq = self.seq + 1;
+        }
+        let res = match self.config.buffer_policy {
+            BufferPolicy::KeyframeOnly => return,
+            BufferPolicy::DropNewest => self.frame_holder.add_image(frame, &self.config),
+            BufferPolicy::DropOldest => self.add_evicting_oldest(frame),
+        };
+        let full = match res {
+            Err(Refusal::Full) => {
+                self.stats.record_dropped_full();
+                true
+            }
+            Err(Refusal::Bytes) => {
+                self.stats.record_dropped_bytes();
+                true
+            }
+            _ => false,
+        };
+        let added = res.is_ok();
+        if !retained {
+            self.stats.record_decimated();
+        }
+        if lost {
+            self.taint_latest(added);
+        }
+        if frame_lost {
+            self.stale_decoder = true;
+        }
+        if added && retained {
+            self.seq += 1;
+            self.stats.record_buffered();
+            self.answer_waiting();
+            self.push_to_subscribers();
+        } else if !added {
+            self.stats.record_dropped();
+            if full && self.full_reported != Some(self.generation) {
+                self.full_reported = Some(self.generation);
+                let _ = self.events_tx.send(SessionEvent::BufferFull);
+            }
+        }
+    }
+
+    // Nothing decoded by the old decoder outlives it, the GOP about to start is the new one's
+    // first
+    fn swap_decoder(&mut self, swap: DecoderSwap) {
+        self.reset();
+        self.decoder.replace(swap.decoder);
+        self.stale_decoder = false;
+        self.resolution = None;
+        let generation = self.generation + 1;
+        debug!(generation, "Decoder replaced");
+        let _ = self
+            .events_tx
+            .send(SessionEvent::DecoderReplaced { generation });
+        let _ = swap.reply.send(Ok(generation));
+    }
+
+    fn decimate(&mut self, frame: &RawFrame) -> bool {
+        self.since_kept += 1;
+        let keep = match self.config.decimation {
+            None => true,
+            Some(Decimation::EveryNth(n)) => self.since_kept >= n,
+            Some(Decimation::MaxFps(fps)) => {
+                frame.timestamp.elapsed_secs() - self.kept_at >= 1.0 / f64::from(fps)
+            }
+        };
+        if keep {
+            self.since_kept = 0;
+            self.kept_at = frame.timestamp.elapsed_secs();
+        }
+        keep
+    }
+
+    // Frames buffered before the loss stay servable. A lossy frame that was dropped still taints
+    // the frames buffered after it, they would reference it.
+    fn taint_latest(&mut self, added: bool) {
+        if let Some(gop) = self.frame_holder.gops.back_mut() {
+            if gop.corrupt_from.is_none() {
+                debug!("Packet loss in GOP {}, refusing its frames", gop.generation);
+                gop.corrupt_from = Some(if added { gop.len() - 1 } else { gop.len() });
+            }
+        }
+    }
+
+    fn add_evicting_oldest(&mut self, frame: RawFrame) -> Result<(), Refusal> {
+        let len = frame.data.len();
+        let needs_room = self.frame_holder.latest().is_some_and(|g| {
+            (frame.retained && g.retained() >= self.config.buf_size)
+                || self.frame_holder.raw_bytes + len > self.config.max_buffered_bytes
+                || self
+                    .config
+                    .max_gop_bytes
+                    .is_some_and(|max| g.raw_bytes + len > max)
+        });
+        if needs_room {
+            if let Err(e) = self
+                .frame_holder
+                .evict_oldest_frame(&mut self.decoder, &self.stats)
+            {
+                warn!("Failed to decode the frame being evicted: {}", e);
+            }
+        }
+        self.frame_holder.add_image(frame, &self.config)
+    }
+
+    // Decodes the frame that was just buffered, only when someone is listening. The manager holds
+    // one broadcast receiver of its own, so it takes a second one to count as a listener.
+    fn push_to_subscribers(&mut self) {
+        self.subscribers.retain(|s| !s.is_closed());
+        if self.idle
+            || self.swap.is_some()
+            || (self.subscribers.is_empty() && self.broadcast_tx.receiver_count() <= 1)
+        {
+            return;
+        }
+        match self.serve(FrameRequest::latest().with_generation(self.generation)) {
+            Ok(frame) => {
+                let _ = self.broadcast_tx.send(frame.clone());
+                self.publish(|| Ok(frame.clone()));
+            }
+            // Resumes on its own with the next iframe
+            Err(SessionError::CorruptGop) => {}
+            // The decoder is still holding the first pictures of a reordered stream back
+            Err(SessionError::DecodingError(DecoderError::NoImageDecoded)) => {}
+            Err(e) => warn!("Failed to decode frame for stream subscribers: {}", e),
+        }
+    }
+
+    // Subscribers that are full miss this item rather than blocking the worker
+    fn publish(&mut self, item: impl Fn() -> Result<FrameResponse, SessionError>) {
+        self.subscribers.retain(|s| !s.is_closed());
+        for subscriber in &self.subscribers {
+            if let Err(mpsc::error::TrySendError::Full(_)) = subscriber.try_send(item()) {
+                debug!("Stream subscriber is lagging, dropping the frame");
+            }
+        }
+    }
+
+    fn expired(&self) -> bool {
+        !self.keyframe_only()
+            && self
+                .frame_holder
+                .latest()
+                .is_some_and(|g| g.elapsed() > self.config.frame_lifetime)
+    }
+
+    fn keyframe_only(&self) -> bool {
+        matches!(self.config.capture_mode, CaptureMode::KeyframeOnly { .. })
+    }
+
+    fn answer(&mut self, req: DataRequest) {
+        match req {
+            DataRequest::Frame(req, sender)
+                if req.waits_for_frame() && self.yet_to_arrive(&req) =>
+            {
+                self.waiting.push(DataRequest::Frame(req, sender));
+            }
+            DataRequest::Frame(req, sender) => {
+                let res = self.serve(req);
+                respond(sender, res);
+            }
+            DataRequest::Batch(req, sender) => {
+                let res = self.serve_batch(req);
+                respond(sender, res);
+            }
+        }
+    }
+
+    fn answer_waiting(&mut self) {
+        for req in std::mem::take(&mut self.waiting) {
+            if !req.is_closed() {
+                self.answer(req);
+            }
+        }
+    }
+
+    // Frames past `buf_size` never arrive, and GOPs other than the newest don't grow
+    fn yet_to_arrive(&self, req: &FrameRequest) -> bool {
+        if self.keyframe_only() {
+            return false;
+        }
+        match req.target() {
+            FrameTarget::Index(i) => {
+                i < self.config.buf_size
+                    && self
+                        .resolve_gop(req.generation())
+                        .is_ok_and(|g| g.generation == self.generation && i >= g.retained())
+            }
+            FrameTarget::At(_) | FrameTarget::After(_) => {
+                matches!(self.locate(req), Err(SessionError::FrameNotYetAvailable))
+            }
+            FrameTarget::Latest => false,
+        }
+    }
+
+    fn serve(&mut self, req: FrameRequest) -> Result<FrameResponse, SessionError> {
+        let (generation, index) = self.locate(&req)?;
+        if req.output() == OutputFormat::RawAccessUnit {
+            return self.serve_raw(generation, index);
+        }
+        let issued_at = req.issued_at.unwrap_or_else(Instant::now);
+        if let Some(deadline) = req.deadline() {
+            let served = self.within_deadline(generation, index, deadline)?;
+            if served != index {
+                return self
+                    .serve_frame(generation, served, issued_at)
+                    .map(|frame| FrameResponse {
+                        downgraded_from: Some(index),
+                        ..frame
+                    });
+            }
+        }
+        match self.serve_frame(generation, index, issued_at) {
+            // With B-frames the last pictures only come out once later frames arrive, the newest
+            // one decoded so far is served instead
+            Err(SessionError::DecodingError(DecoderError::NoImageDecoded))
+                if matches!(req.target(), FrameTarget::Latest) =>
+            {
+                let decoded = self
+                    .frame_holder
+                    .find(generation)
+                    .map_or(0, |g| g.retained_decoded());
+                match decoded.checked_sub(1) {
+                    Some(index) => self.serve_frame(generation, index, issued_at),
+                    None => Err(DecoderError::NoImageDecoded.into()),
+                }
+            }
+            res => res,
+        }
+    }
+
+    // The index to serve in place of `index` when the decoder can't get to it within `deadline`
+    fn within_deadline(
+        &self,
+        generation: u64,
+        index: usize,
+        deadline: Duration,
+    ) -> Result<usize, SessionError> {
+        // Nothing to go by before the first decode
+        let Some(per_frame) = self.stats.recent_decode_time() else {
+            return Ok(index);
+        };
+        let Some(gop) = self.frame_holder.find(generation) else {
+            return Ok(index);
+        };
+        let decoder_here = self.frame_holder.decoder_at == Some(generation);
+        let fits = |index| {
+            gop.frames_to_decode(index, decoder_here)
+                .map_or(true, |n| per_frame * n as u32 <= deadline)
+        };
+        if fits(index) {
+            return Ok(index);
+        }
+        match gop.retained_decoded().checked_sub(1) {
+            Some(decoded) => Ok(decoded.min(index)),
+            None if fits(0) => Ok(0),
+            None => Err(SessionError::DeadlineExceeded(deadline)),
+        }
+    }
+
+    // Frames are decoded in order and the batch stops at the first one that fails. Only a
+    // request that can't be matched to a GOP fails as a whole.
+    fn serve_batch(&mut self, req: BatchRequest) -> Result<BatchResponse, SessionError> {
+        let generation = self.resolve_gop(req.generation())?.generation;
+        let issued_at = req.issued_at.unwrap_or_else(Instant::now);
+        let mut frames = Vec::with_capacity(req.end.saturating_sub(req.start));
+        for index in req.start..req.end {
+            match self.serve_frame(generation, index, issued_at) {
+                Ok(frame) => frames.push(frame),
+                Err(e) => {
+                    return Ok(BatchResponse {
+                        frames,
+                        error: Some(e),
+                    })
+                }
+            }
+        }
+        Ok(BatchResponse {
+            frames,
+            error: None,
+        })
+    }
+
+    fn serve_frame(
+        &mut self,
+        generation: u64,
+        index: usize,
+        issued_at: Instant,
+    ) -> Result<FrameResponse, SessionError> {
+        let gop = self
+            .frame_holder
+            .find(generation)
+            .ok_or(DecoderError::IndexOutOfBounds)?;
+        let decode_index = gop
+            .arrival_index(index)
+            .ok_or(DecoderError::IndexOutOfBounds)?;
+        if gop.corrupt_from.is_some_and(|c| decode_index >= c) {
+            return Err(SessionError::CorruptGop);
+        }
+        let (i_frame_ts, index_offset) = (gop.ts, gop.offset);
+        let raw = &gop.raw_frames[decode_index];
+        let (timestamp, random_access, received_at, captured_at, seq) = (
+            raw.timestamp,
+            raw.random_access,
+            raw.received,
+            raw.captured_at,
+            raw.seq,
+        );
+
+        let decode_start = Instant::now();
+        let decoded =
+            self.frame_holder
+                .decode(&mut self.decoder, &self.stats, generation, index)?;
+        let decode = decode_start.elapsed();
+        let (frame, format) = (Arc::clone(&decoded.data), decoded.format);
+        self.track_resolution(generation, format);
+        let latency = self.config.measure_latency.then(|| {
+            let latency = LatencyBreakdown {
+                capture_to_request: issued_at.saturating_duration_since(received_at),
+                queue_wait: decode_start.saturating_duration_since(issued_at.max(received_at)),
+                decode,
+                total: received_at.elapsed(),
+            };
+            self.stats.record_latency(latency);
+            latency
+        });
+        Ok(FrameResponse {
+            frame,
+            format,
+            index,
+            decode_index,
+            index_offset,
+            generation,
+            seq,
+            i_frame_ts,
+            timestamp,
+            random_access,
+            received_at,
+            captured_at,
+            latency,
+            downgraded_from: None,
+        })
+    }
+
+    // The decoder isn't involved, so neither packet loss nor earlier decode failures keep the
+    // frame from being served. Iframes get the SDP parameter sets in front unless they carry
+    // their own, to be decodable on their own.
+    fn serve_raw(&self, generation: u64, index: usize) -> Result<FrameResponse, SessionError> {
+        let gop = self
+            .frame_holder
+            .find(generation)
+            .ok_or(DecoderError::IndexOutOfBounds)?;
+        let decode_index = gop
+            .arrival_index(index)
+            .ok_or(DecoderError::IndexOutOfBounds)?;
+        let raw = &gop.raw_frames[decode_index];
+        let prefixed;
+        let data: &[u8] = match &self.decoder.parameter_sets {
+            Some(sets) if raw.random_access && !streams::carries_parameter_sets(&raw.data) => {
+                prefixed = [sets.as_slice(), &raw.data].concat();
+                &prefixed
+            }
+            _ => &raw.data,
+        };
+        // Passes sources already handing out Annex B through as they are
+        let mut stage = AVCCDecoder::new();
+        let annex_b = stage.decode_frame(data)?.data.to_vec();
+        Ok(FrameResponse {
+            frame: Arc::new(PooledBuffer::from(annex_b)),
+            format: FrameFormat {
+                width: 0,
+                height: 0,
+                pixel_format: PixelFormat::H264AnnexB,
+            },
+            index,
+            decode_index,
+            index_offset: gop.offset,
+            generation,
+            seq: raw.seq,
+            i_frame_ts: gop.ts,
+            timestamp: raw.timestamp,
+            random_access: raw.random_access,
+            received_at: raw.received,
+            captured_at: raw.captured_at,
+            latency: None,
+            downgraded_from: None,
+        })
+    }
+
+    fn track_resolution(&mut self, generation: u64, format: FrameFormat) {
+        let to = (format.width, format.height);
+        if let Some(from) = self.resolution.filter(|r| *r != to) {
+            debug!("Resolution changed from {:?} to {:?}", from, to);
+            self.frame_holder.drop_decoded_except(generation);
+            let _ = self
+                .events_tx
+                .send(SessionEvent::ResolutionChanged { from, to });
+        }
+        self.resolution = Some(to);
+    }
+
+    // Resolves the request to the generation of a buffered GOP and an index inside it
+    fn locate(&self, req: &FrameRequest) -> Result<(u64, usize), SessionError> {
+        if self.keyframe_only() {
+            return Ok((self.resolve_gop(req.generation())?.generation, 0));
+        }
+        match req.target() {
+            FrameTarget::Index(i) => Ok((self.resolve_gop(req.generation())?.generation, i)),
+            FrameTarget::Latest => {
+                let gop = self.resolve_gop(req.generation())?;
+                Ok((gop.generation, gop.retained() - 1))
+            }
+            FrameTarget::At(at) => self.locate_at(req, at),
+            FrameTarget::After(seq) => self.locate_after(req, seq),
+        }
+    }
+
+    // Retained frame with the lowest sequence number past `seq`. Sequence numbers are global, so
+    // untagged requests are fine here even without `allow_untagged_requests`.
+    fn locate_after(&self, req: &FrameRequest, seq: u64) -> Result<(u64, usize), SessionError> {
+        let gops: Vec<&Gop> = match req.generation() {
+            Some(_) => vec![self.resolve_gop(req.generation())?],
+            None => self.frame_holder.gops.iter().collect(),
+        };
+        let (generation, index, next) = gops
+            .iter()
+            .flat_map(|g| {
+                g.presentation_order()
+                    .into_iter()
+                    .filter(|i| g.raw_frames[*i].retained)
+                    .enumerate()
+                    .map(move |(index, i)| (g.generation, index, g.raw_frames[i].seq))
+            })
+            .filter(|(_, _, s)| *s > seq)
+            .min_by_key(|(_, _, s)| *s)
+            .ok_or(SessionError::FrameNotYetAvailable)?;
+        if next > seq + 1 {
+            return Err(SessionError::FrameEvicted {
+                oldest_available: next,
+            });
+        }
+        Ok((generation, index))
+    }
+
+    // Frame received closest to `at`, as its presentation position. Tagged requests only search
+    // their own GOP, untagged ones the whole history.
+    fn locate_at(&self, req: &FrameRequest, at: Instant) -> Result<(u64, usize), SessionError> {
+        if at > Instant::now() {
+            return Err(SessionError::FrameNotYetAvailable);
+        }
+        let gops: Vec<&Gop> = match req.generation() {
+            Some(_) => vec![self.resolve_gop(req.generation())?],
+            None if !self.config.allow_untagged_requests => {
+                return Err(SessionError::UntaggedRequest)
+            }
+            None => self.frame_holder.gops.iter().collect(),
+        };
+        if gops.first().map_or(true, |g| at < g.raw_frames[0].received) {
+            return Err(SessionError::OldFrame);
+        }
+
+        gops.iter()
+            .flat_map(|g| {
+                g.presentation_order()
+                    .into_iter()
+                    .filter(|i| g.raw_frames[*i].retained)
+                    .enumerate()
+                    .map(move |(index, i)| (g.generation, index, g.raw_frames[i].received))
+            })
+            .min_by_key(|(_, _, received)| received.max(&at).duration_since(*received.min(&at)))
+            .map(|(generation, index, _)| (generation, index))
+            .ok_or(SessionError::OldFrame)
+    }
+
+    // Tagged requests are served from the GOP of their generation as long as it's still in the
+    // history, untagged ones from the newest GOP
+    fn resolve_gop(&self, generation: Option<u64>) -> Result<&Gop, SessionError> {
+        let reset = |expected| SessionError::BufferReset {
+            expected,
+            actual: self.generation,
+        };
+        match generation {
+            Some(expected) => self.frame_holder.find(expected).ok_or(reset(expected)),
+            None if !self.config.allow_untagged_requests => Err(SessionError::UntaggedRequest),
+            None => self.frame_holder.latest().ok_or(SessionError::OldFrame),
+        }
+    }
+
+    fn buffer_state(&self) -> BufferState {
+        BufferState {
+            generation: self.generation,
+            i_frame_ts: self.frame_holder.latest().map(|g| g.ts),
+        }
+    }
+
+    fn publish_state(&self) {
+        let _ = self.buffer_state_tx.send(self.buffer_state());
+    }
+
+    fn buffer_status(&self) -> BufferStatus {
+        let latest = self.frame_holder.latest();
+        BufferStatus {
+            generation: self.generation,
+            buffered: latest.map_or(0, |g| g.retained()),
+            decoded: latest.map_or(0, |g| g.retained_decoded()),
+            i_frame_ts: latest.map(|g| g.ts),
+            corrupt: latest.is_some_and(|g| g.corrupt_from.is_some()),
+            buf_size: self.config.buf_size,
+            frame_lifetime: self.config.frame_lifetime,
+        }
+    }
+
+    // Receivers are only woken when something changed
+    fn publish_status(&self) {
+        let status = self.buffer_status();
+        self.buffer_status_tx.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+    }
+
+    // Copies the whole GOPs covering the last `duration`, oldest first. The raw frames are kept,
+    // muxing happens off the worker thread.
+    fn clip(&self, duration: Duration) -> Result<(Vec<ClipSample>, u32), SessionError> {
+        let gops = &self.frame_holder.gops;
+        let last = gops
+            .back()
+            .and_then(|g| g.raw_frames.last())
+            .ok_or(SessionError::FrameNotYetAvailable)?;
+        let end = last.timestamp.elapsed_secs();
+        let start = gops
+            .iter()
+            .rposition(|g| end - g.raw_frames[0].timestamp.elapsed_secs() >= duration.as_secs_f64())
+            .unwrap_or(0);
+
+        let mut samples = Vec::new();
+        for gop in gops.iter().skip(start) {
+            if gop.corrupt_from.is_some() {
+                return Err(SessionError::CorruptGop);
+            }
+            if gop.evicted > 0 {
+                return Err(SessionError::TornGop);
+            }
+            samples.extend(gop.raw_frames.iter().map(|raw| ClipSample {
+                data: raw.data.clone(),
+                ts: raw.timestamp.timestamp(),
+                random_access: raw.random_access,
+            }));
+        }
+        Ok((samples, last.timestamp.clock_rate().get()))
+    }
+
+    // Dropping the buffered GOPs invalidates what requesters know about the buffer
+    fn reset(&mut self) {
+        self.frame_holder = FrameHolder::empty();
+        self.stale_decoder = true;
+        self.generation += 1;
+        self.publish_state();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        num::NonZeroU32,
+        sync::Mutex,
+    };
+
+    use tokio::{runtime::Runtime, sync::Semaphore};
+
+    use super::super::{fixtures, Requester};
+    use super::*;
+    use crate::decoders::{
+        testing::{self, Call, MockDecoder},
+        Chain, DecoderPool, DecoderPoolConfig, H264RGBDecoder, Saturation,
+    };
+
+    type Reply<T> = oneshot::Receiver<Result<T, SessionError>>;
+
+    // Drives the worker by hand, each call runs as the worker thread would run the message
+    struct Harness {
+        worker: DecodeWorker,
+        events: broadcast::Receiver<SessionEvent>,
+        pts: i64,
+        // The decoder blocks on it, it has to outlive the worker
+        _runtime: Runtime,
+    }
+
+    impl Harness {
+        fn new(config: SessionConfig, decoder: impl AsyncImageDecoder + 'static) -> Self {
+            let runtime = Runtime::new().unwrap();
+            let decoder = runtime
+                .block_on(async { BlockingDecoder::new(Box::new(decoder), BufferPool::new(4)) });
+            let (buffer_state_tx, _) = watch::channel(BufferState {
+                generation: 0,
+                i_frame_ts: None,
+            });
+            let (buffer_status_tx, _) = watch::channel(BufferStatus {
+                generation: 0,
+                buffered: 0,
+                decoded: 0,
+                i_frame_ts: None,
+                corrupt: false,
+                buf_size: config.buf_size,
+                frame_lifetime: config.frame_lifetime,
+            });
+            let (broadcast_tx, _) = broadcast::channel(4);
+            let (events_tx, events) = broadcast::channel(64);
+            let worker = DecodeWorker::new(
+                config,
+                decoder,
+                buffer_state_tx,
+                buffer_status_tx,
+                broadcast_tx,
+                Arc::new(StatsCollector::new()),
+                events_tx,
+            );
+            Self {
+                worker,
+                events,
+                pts: 0,
+                _runtime: runtime,
+            }
+        }
+
+        fn push(&mut self, data: Vec<u8>, random_access: bool) {
+            let f = raw(data, random_access, self.pts);
+            self.pts += 3000;
+            self.send(WorkerMessage::Frame(f));
+        }
+
+        fn iframe(&mut self, id: u8) {
+            self.push(testing::frame(id), true);
+        }
+
+        fn pframe(&mut self, id: u8) {
+            self.push(testing::frame(id), false);
+        }
+
+        fn send(&mut self, msg: WorkerMessage) {
+            self.worker.handle(msg);
+            self.worker.report_decode_failure();
+            self.worker.publish_status();
+        }
+
+        // Queued, not served before `serve`
+        fn enqueue(&mut self, req: FrameRequest) -> Reply<FrameResponse> {
+            let (req, reply) = frame_request(req);
+            self.send(WorkerMessage::Request(req));
+            reply
+        }
+
+        fn serve(&mut self) {
+            while let Some(req) = self.worker.queued.pop() {
+                self.worker.handle_request(req);
+                self.worker.report_decode_failure();
+                self.worker.publish_status();
+            }
+        }
+
+        fn request(&mut self, req: FrameRequest) -> Result<FrameResponse, SessionError> {
+            let mut reply = self.enqueue(req);
+            self.serve();
+            answer(&mut reply)
+        }
+
+        fn generation(&self) -> u64 {
+            self.worker.buffer_state().generation
+        }
+
+        // Sent since the last call
+        fn events(&mut self) -> Vec<SessionEvent> {
+            std::iter::from_fn(|| self.events.try_recv().ok()).collect()
+        }
+    }
+
+    fn raw(data: Vec<u8>, random_access: bool, pts: i64) -> RawFrame {
+        let clock_rate = NonZeroU32::new(90_000).unwrap();
+        RawFrame {
+            data: Bytes::from(data),
+            timestamp: Timestamp::new(pts, clock_rate, 0).unwrap(),
+            random_access,
+            received: Instant::now(),
+            captured_at: None,
+            loss: 0,
+            retained: true,
+            seq: 0,
+        }
+    }
+
+    fn requester<T>() -> (Requester<T>, Reply<T>) {
+        let (tx, rx) = oneshot::channel();
+        let slot = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        (Requester { tx, _slot: slot }, rx)
+    }
+
+    fn frame_request(req: FrameRequest) -> (DataRequest, Reply<FrameResponse>) {
+        let (sender, reply) = requester();
+        (DataRequest::Frame(req, sender), reply)
+    }
+
+    fn answer<T>(reply: &mut Reply<T>) -> Result<T, SessionError> {
+        reply.try_recv().expect("the request was answered")
+    }
+
+    fn error<T>(res: Result<T, SessionError>) -> SessionError {
+        match res {
+            Ok(_) => panic!("expected the request to fail"),
+            Err(e) => e,
+        }
+    }
+
+    // Tag of the decoder and id of the frame the picture was decoded from
+    fn picture(res: Result<FrameResponse, SessionError>) -> (u8, u8) {
+        match res {
+            Ok(frame) => (frame.frame()[0], frame.frame()[1]),
+            Err(e) => panic!("expected a frame, got {}", e),
+        }
+    }
+
+    fn config() -> SessionConfig {
+        SessionConfig::builder().build().unwrap()
+    }
+
+    #[test]
+    fn request_queued_across_a_keyframe_reset_is_refused() {
+        let config = SessionConfig::builder()
+            .with_gop_history(1)
+            .build()
+            .unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        h.iframe(10);
+        h.pframe(11);
+        let generation = h.generation();
+
+        let mut reply = h.enqueue(FrameRequest::new(1).with_generation(generation));
+        h.iframe(20);
+        h.pframe(21);
+        h.serve();
+
+        match error(answer(&mut reply)) {
+            SessionError::BufferReset { expected, actual } => {
+                assert_eq!(expected, generation);
+                assert_eq!(actual, h.generation());
+            }
+            e => panic!("expected BufferReset, got {}", e),
+        }
+    }
+
+    #[test]
+    fn request_queued_across_a_keyframe_reset_is_served_from_its_gop() {
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        h.iframe(10);
+        h.pframe(11);
+        let generation = h.generation();
+
+        let mut reply = h.enqueue(FrameRequest::new(1).with_generation(generation));
+        h.iframe(20);
+        h.pframe(21);
+        h.serve();
+
+        let frame = answer(&mut reply).ok().unwrap();
+        assert_eq!(frame.generation(), generation);
+        assert_eq!(frame.frame(), [1, 11]);
+    }
+
+    #[test]
+    fn untagged_requests_follow_the_newest_gop() {
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        h.iframe(10);
+        h.pframe(11);
+        let mut reply = h.enqueue(FrameRequest::new(1));
+        h.iframe(20);
+        h.pframe(21);
+        h.serve();
+        assert_eq!(picture(answer(&mut reply)), (1, 21));
+
+        let config = SessionConfig::builder()
+            .with_allow_untagged_requests(false)
+            .build()
+            .unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        h.iframe(10);
+        assert!(matches!(
+            error(h.request(FrameRequest::new(0))),
+            SessionError::UntaggedRequest
+        ));
+    }
+
+    #[test]
+    fn expiry_bumps_the_generation() {
+        let config = SessionConfig::builder()
+            .with_frame_lifetime(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        h.iframe(10);
+        let generation = h.generation();
+        thread::sleep(Duration::from_millis(20));
+
+        assert!(matches!(
+            error(h.request(FrameRequest::new(0).with_generation(generation))),
+            SessionError::OldFrame
+        ));
+        assert!(h.generation() > generation);
+        h.iframe(20);
+        assert!(matches!(
+            error(h.request(FrameRequest::new(0).with_generation(generation))),
+            SessionError::BufferReset { .. }
+        ));
+    }
+
+    #[test]
+    fn responses_share_the_cached_frame() {
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        h.iframe(10);
+        h.pframe(11);
+
+        let first = h.request(FrameRequest::new(1)).ok().unwrap();
+        let second = h.request(FrameRequest::new(1)).ok().unwrap();
+        assert!(Arc::ptr_eq(&first.frame, &second.frame));
+        // The decoded cache holds the third handle
+        assert_eq!(Arc::strong_count(&first.frame), 3);
+
+        let other = h.request(FrameRequest::new(0)).ok().unwrap();
+        assert!(!Arc::ptr_eq(&first.frame, &other.frame));
+        assert_eq!(first.frame(), [1, 11]);
+        assert_eq!(other.frame(), [1, 10]);
+    }
+
+    #[test]
+    fn buffer_full_is_reported_once_per_gop() {
+        let config = SessionConfig::builder().with_buf_size(2).build().unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        h.iframe(10);
+        h.pframe(11);
+        assert!(h.events().is_empty());
+        h.pframe(12);
+        h.pframe(13);
+        let events = h.events();
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(matches!(events[0], SessionEvent::BufferFull));
+
+        h.iframe(20);
+        h.pframe(21);
+        h.pframe(22);
+        assert!(matches!(h.events()[..], [SessionEvent::BufferFull]));
+    }
+
+    #[test]
+    fn expiry_is_reported() {
+        let config = SessionConfig::builder()
+            .with_frame_lifetime(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        h.iframe(10);
+        thread::sleep(Duration::from_millis(20));
+        let _ = h.request(FrameRequest::new(0));
+        assert!(matches!(h.events()[..], [SessionEvent::FrameExpired]));
+    }
+
+    fn with_policy(policy: BufferPolicy) -> Harness {
+        let config = SessionConfig::builder()
+            .with_buf_size(3)
+            .with_buffer_policy(policy)
+            .build()
+            .unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        h.iframe(10);
+        for id in 11..=14 {
+            h.pframe(id);
+        }
+        h
+    }
+
+    fn out_of_bounds(res: Result<FrameResponse, SessionError>) -> bool {
+        matches!(
+            error(res),
+            SessionError::DecodingError(DecoderError::IndexOutOfBounds)
+        )
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_start_of_the_gop() {
+        let mut h = with_policy(BufferPolicy::DropNewest);
+        assert_eq!(h.worker.buffer_status().buffered, 3);
+        for (index, id) in [(0, 10), (1, 11), (2, 12)] {
+            let frame = h.request(FrameRequest::new(index)).ok().unwrap();
+            assert_eq!(frame.frame(), [1, id]);
+            assert_eq!(frame.index_offset(), 0);
+        }
+        assert!(out_of_bounds(h.request(FrameRequest::new(3))));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_frames() {
+        let mut h = with_policy(BufferPolicy::DropOldest);
+        assert_eq!(h.worker.buffer_status().buffered, 3);
+        for (index, id) in [(0, 10), (1, 13), (2, 14)] {
+            let frame = h.request(FrameRequest::new(index)).ok().unwrap();
+            assert_eq!(frame.frame(), [1, id]);
+            assert_eq!(frame.index(), index);
+        }
+        let frame = h.request(FrameRequest::new(1)).ok().unwrap();
+        assert_eq!(frame.index_offset(), 2);
+        assert!(out_of_bounds(h.request(FrameRequest::new(3))));
+
+        // The next GOP starts without an offset
+        h.iframe(20);
+        let frame = h.request(FrameRequest::new(0)).ok().unwrap();
+        assert_eq!(frame.index_offset(), 0);
+    }
+
+    #[test]
+    fn keyframe_only_buffers_nothing_but_iframes() {
+        let mut h = with_policy(BufferPolicy::KeyframeOnly);
+        assert_eq!(h.worker.buffer_status().buffered, 1);
+        assert_eq!(
+            h.request(FrameRequest::new(0)).ok().unwrap().frame(),
+            [1, 10]
+        );
+        assert!(out_of_bounds(h.request(FrameRequest::new(1))));
+        assert!(h.events().is_empty());
+    }
+
+    #[test]
+    fn resolution_change_is_reported_and_drops_the_old_pictures() {
+        let decoder = AVCCDecoder::new().chain(H264RGBDecoder::new(false).unwrap());
+        let mut h = Harness::new(config(), decoder);
+        h.push(fixtures::iframe(60), true);
+        let small = h.request(FrameRequest::new(0)).ok().unwrap();
+        assert_eq!((small.width(), small.height()), (16, 16));
+        // Held by the decoded cache too
+        assert_eq!(Arc::strong_count(&small.frame), 2);
+        assert!(h.events().is_empty());
+
+        h.push(fixtures::sized_iframe(2, 1, 80), true);
+        let large = h.request(FrameRequest::new(0)).ok().unwrap();
+        assert_eq!((large.width(), large.height()), (32, 16));
+        assert_eq!(large.frame().len(), 32 * 16 * 3);
+        assert!(matches!(
+            h.events()[..],
+            [SessionEvent::ResolutionChanged {
+                from: (16, 16),
+                to: (32, 16)
+            }]
+        ));
+        assert_eq!(Arc::strong_count(&small.frame), 1);
+    }
+
+    #[test]
+    fn decoder_is_reset_after_a_drain_or_a_loss() {
+        let decoder = MockDecoder::new(1);
+        let log = decoder.log();
+        let mut h = Harness::new(config(), decoder);
+        h.iframe(10);
+        h.request(FrameRequest::new(0)).ok().unwrap();
+        // A clean GOP boundary keeps the decoder as it is
+        h.iframe(20);
+        h.request(FrameRequest::new(0)).ok().unwrap();
+
+        h.send(WorkerMessage::Drain(Disconnect::Ended));
+        h.iframe(30);
+        h.request(FrameRequest::new(0)).ok().unwrap();
+
+        let mut lossy = raw(testing::frame(40), true, h.pts);
+        lossy.loss = 2;
+        h.send(WorkerMessage::Frame(lossy));
+        h.request(FrameRequest::new(0)).ok().unwrap();
+
+        assert_eq!(
+            log.calls(),
+            [
+                Call::Decode(10),
+                Call::Decode(20),
+                Call::Reset,
+                Call::Decode(30),
+                Call::Reset,
+                Call::Decode(40),
+            ]
+        );
+    }
+
+    fn clip(h: &mut Harness, duration: Duration) -> Result<Clip, SessionError> {
+        let (reply, mut rx) = oneshot::channel();
+        h.send(WorkerMessage::Clip(ClipRequest {
+            duration,
+            parameters: None,
+            resolution: None,
+            reply,
+        }));
+        answer(&mut rx)
+    }
+
+    // Ids of the frames in the clip, see `testing::frame`
+    fn clip_ids(clip: Result<Clip, SessionError>) -> Vec<u8> {
+        match clip {
+            Ok(clip) => clip.samples.iter().map(|s| s.data[5]).collect(),
+            Err(e) => panic!("expected a clip, got {}", e),
+        }
+    }
+
+    #[test]
+    fn clip_takes_whole_gops() {
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        assert!(matches!(
+            clip(&mut h, Duration::ZERO),
+            Err(SessionError::FrameNotYetAvailable)
+        ));
+        h.iframe(10);
+        h.pframe(11);
+        h.iframe(20);
+        h.pframe(21);
+        assert_eq!(clip_ids(clip(&mut h, Duration::ZERO)), [20, 21]);
+        assert_eq!(
+            clip_ids(clip(&mut h, Duration::from_secs(60))),
+            [10, 11, 20, 21]
+        );
+    }
+
+    #[test]
+    fn clip_refuses_tainted_and_torn_gops() {
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        h.iframe(10);
+        let mut lossy = raw(testing::frame(11), false, h.pts);
+        lossy.loss = 1;
+        h.send(WorkerMessage::Frame(lossy));
+        assert!(matches!(
+            clip(&mut h, Duration::ZERO),
+            Err(SessionError::CorruptGop)
+        ));
+
+        let mut h = with_policy(BufferPolicy::DropOldest);
+        assert!(matches!(
+            clip(&mut h, Duration::ZERO),
+            Err(SessionError::TornGop)
+        ));
+    }
+
+    #[test]
+    fn every_frame_is_decoded_once_in_order() {
+        let decoder = MockDecoder::new(1);
+        let log = decoder.log();
+        let mut h = Harness::new(config(), decoder);
+        h.iframe(10);
+        for id in 11..15 {
+            h.pframe(id);
+        }
+
+        // Jumping ahead feeds everything before it first
+        assert_eq!(picture(h.request(FrameRequest::new(3))), (1, 13));
+        assert_eq!(log.decoded(), [10, 11, 12, 13]);
+        // Going back is served from what was decoded on the way
+        for index in [1, 0, 2] {
+            assert_eq!(
+                picture(h.request(FrameRequest::new(index))).1,
+                10 + index as u8
+            );
+        }
+        assert_eq!(picture(h.request(FrameRequest::new(4))), (1, 14));
+        assert_eq!(
+            log.calls(),
+            [
+                Call::Decode(10),
+                Call::Decode(11),
+                Call::Decode(12),
+                Call::Decode(13),
+                Call::Decode(14),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_frame_poisons_the_rest_of_its_gop() {
+        let decoder = MockDecoder::new(1).failing_on(12);
+        let log = decoder.log();
+        let mut h = Harness::new(config(), decoder);
+        h.iframe(10);
+        for id in 11..15 {
+            h.pframe(id);
+        }
+
+        assert!(matches!(
+            h.request(FrameRequest::new(3)),
+            Err(SessionError::DecodingError(
+                DecoderError::MalformedNal { .. }
+            ))
+        ));
+        let generation = h.generation();
+        assert!(matches!(
+            h.events()[..],
+            [SessionEvent::DecodeFailed { generation: g, index: 2 }] if g == generation
+        ));
+        // The same requests fail the same way every time, without feeding the decoder again
+        for _ in 0..2 {
+            for index in 2..5 {
+                assert!(matches!(
+                    h.request(FrameRequest::new(index)),
+                    Err(SessionError::CorruptGop)
+                ));
+            }
+        }
+        // The frames before the failure were fine
+        assert_eq!(picture(h.request(FrameRequest::new(0))).1, 10);
+        assert_eq!(picture(h.request(FrameRequest::new(1))).1, 11);
+        assert_eq!(
+            log.calls(),
+            [
+                Call::Decode(10),
+                Call::Decode(11),
+                Call::Decode(12),
+                Call::Reset,
+            ]
+        );
+
+        // The next GOP starts from the reset decoder
+        h.iframe(20);
+        assert_eq!(picture(h.request(FrameRequest::new(0))).1, 20);
+        assert_eq!(log.decoded(), [10, 11, 12, 20]);
+    }
+
+    #[test]
+    fn b_frames_are_addressed_in_presentation_order() {
+        let decoder = MockDecoder::new(1).with_reorder(1);
+        let log = decoder.log();
+        let mut h = Harness::new(config(), decoder);
+        // I P B B in decode order, the ids being the presentation order
+        for (id, random_access, pts) in [
+            (10, true, 0),
+            (13, false, 9000),
+            (11, false, 3000),
+            (12, false, 6000),
+        ] {
+            h.send(WorkerMessage::Frame(raw(
+                testing::frame(id),
+                random_access,
+                pts,
+            )));
+        }
+
+        let frame = h.request(FrameRequest::new(1)).ok().unwrap();
+        assert_eq!(frame.frame()[1], 11);
+        assert_eq!((frame.index(), frame.decode_index()), (1, 2));
+        assert_eq!(frame.timestamp().timestamp(), 3000);
+        assert_eq!(log.decoded(), [10, 13, 11]);
+
+        for (index, decode_index) in [(0, 0), (2, 3)] {
+            let frame = h.request(FrameRequest::new(index)).ok().unwrap();
+            assert_eq!(frame.frame()[1], 10 + index as u8);
+            assert_eq!(frame.decode_index(), decode_index);
+            assert_eq!(frame.timestamp().timestamp(), index as i64 * 3000);
+        }
+        // The decoder still holds the P-frame's picture back
+        assert!(matches!(
+            h.request(FrameRequest::new(3)),
+            Err(SessionError::DecodingError(DecoderError::NoImageDecoded))
+        ));
+        let latest = h.request(FrameRequest::latest()).ok().unwrap();
+        assert_eq!((latest.index(), latest.frame()[1]), (2, 12));
+
+        h.send(WorkerMessage::Frame(raw(testing::frame(14), false, 12000)));
+        let frame = h.request(FrameRequest::new(3)).ok().unwrap();
+        assert_eq!(frame.frame()[1], 13);
+        assert_eq!(
+            (frame.decode_index(), frame.timestamp().timestamp()),
+            (1, 9000)
+        );
+        assert_eq!(log.decoded(), [10, 13, 11, 12, 14]);
+    }
+
+    #[test]
+    fn pictures_reuse_the_buffers_of_evicted_gops() {
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        h.iframe(10);
+        let first = h.request(FrameRequest::new(0)).ok().unwrap();
+        assert!(first.frame.is_pooled());
+        let ptr = first.frame().as_ptr();
+        drop(first);
+
+        // The first GOP's picture stays cached until `gop_history` GOPs came after it
+        h.iframe(20);
+        assert_ne!(picture_ptr(&mut h), ptr);
+        h.iframe(30);
+        assert_eq!(picture_ptr(&mut h), ptr);
+    }
+
+    fn picture_ptr(h: &mut Harness) -> *const u8 {
+        h.request(FrameRequest::new(0))
+            .ok()
+            .unwrap()
+            .frame()
+            .as_ptr()
+    }
+
+    #[test]
+    fn deadline_downgrades_to_what_the_slow_decoder_has_done() {
+        let decoder = MockDecoder::new(1).with_delay(Duration::from_millis(20));
+        let log = decoder.log();
+        let mut h = Harness::new(config(), decoder);
+        h.iframe(10);
+        for id in 11..19 {
+            h.pframe(id);
+        }
+        // Gives the estimate its first decode time
+        assert_eq!(picture(h.request(FrameRequest::new(0))).1, 10);
+
+        let frame = h
+            .request(FrameRequest::new(7).with_deadline(Duration::from_millis(50)))
+            .ok()
+            .unwrap();
+        assert_eq!((frame.index(), frame.downgraded_from()), (0, Some(7)));
+        assert_eq!(log.decoded(), [10]);
+
+        // Close enough to make it
+        let frame = h
+            .request(FrameRequest::new(2).with_deadline(Duration::from_millis(100)))
+            .ok()
+            .unwrap();
+        assert_eq!((frame.index(), frame.downgraded_from()), (2, None));
+        assert_eq!(frame.frame()[1], 12);
+
+        // Nothing of the new GOP is decoded, and not even its iframe would make it
+        h.iframe(20);
+        h.pframe(21);
+        assert!(matches!(
+            h.request(FrameRequest::new(1).with_deadline(Duration::from_millis(5))),
+            Err(SessionError::DeadlineExceeded(d)) if d == Duration::from_millis(5)
+        ));
+        assert_eq!(log.decoded(), [10, 11, 12]);
+    }
+
+    #[test]
+    fn background_requests_get_a_turn_between_interactive_bursts() {
+        let (i, b) = (Priority::Interactive, Priority::Background);
+        let mut queue = RequestQueue::default();
+        for priority in [b, b].into_iter().chain([i; 6]) {
+            queue.push(frame_request(FrameRequest::new(0).with_priority(priority)).0);
+        }
+
+        let order: Vec<Priority> = std::iter::from_fn(|| queue.pop())
+            .map(|req| req.priority())
+            .collect();
+        assert_eq!(order, [i, i, i, i, b, i, i, b]);
+    }
+
+    // Counts the bytes each thread allocates, so the tests running alongside don't show up in
+    // `allocated_by`
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count(bytes: usize) {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() + bytes));
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocated_by<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATED.with(Cell::get);
+        let res = f();
+        (res, ALLOCATED.with(Cell::get) - before)
+    }
+
+    #[test]
+    fn buffered_frames_share_the_received_data() {
+        const FRAME: usize = 64 * 1024;
+        const FRAMES: usize = 10;
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        let frames: Vec<RawFrame> = (0..FRAMES)
+            .map(|i| raw(vec![i as u8; FRAME], i == 0, i as i64 * 3000))
+            .collect();
+        let data: Vec<*const u8> = frames.iter().map(|f| f.data.as_ptr()).collect();
+
+        // With a `Vec` per frame this was FRAMES * FRAME bytes, 640KiB, for the copies alone.
+        // What is left is bookkeeping.
+        let ((), allocated) = allocated_by(|| {
+            for frame in frames {
+                h.send(WorkerMessage::Frame(frame));
+            }
+        });
+        assert!(allocated < FRAME, "{} bytes allocated", allocated);
+        let gop = h.worker.frame_holder.latest().unwrap();
+        assert!(gop
+            .raw_frames
+            .iter()
+            .map(|f| f.data.as_ptr())
+            .eq(data.iter().copied()));
+
+        // Clips hand out the same buffers
+        let (clip, allocated) = allocated_by(|| clip(&mut h, Duration::ZERO));
+        assert!(allocated < FRAME, "{} bytes allocated", allocated);
+        let clip = clip.ok().unwrap();
+        assert!(clip.samples.iter().map(|s| s.data.as_ptr()).eq(data));
+    }
+
+    #[test]
+    fn frames_turned_away_by_the_pool_decode_later() {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let built = Arc::clone(&logs);
+        let config = DecoderPoolConfig::default()
+            .with_workers(1)
+            .with_queue_depth(1)
+            .with_saturation(Saturation::Reject);
+        // The first decoder built is the one keeping the worker busy
+        let pool = DecoderPool::new(config, move || {
+            let mut logs = built.lock().unwrap();
+            let delay = if logs.is_empty() {
+                Duration::from_millis(200)
+            } else {
+                Duration::ZERO
+            };
+            let decoder = MockDecoder::new(logs.len() as u8).with_delay(delay);
+            logs.push(decoder.log());
+            Ok(Box::new(decoder) as Box<dyn ImageDecoder + Sync + Send>)
+        });
+        let mut h = Harness::new(config(), pool.decoder());
+        h.iframe(10);
+        h.pframe(11);
+        h.pframe(12);
+        h.events();
+
+        let busy = {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let data = testing::frame(1);
+                Runtime::new()
+                    .unwrap()
+                    .block_on(pool.decode(u64::MAX, &data))
+            })
+        };
+        while pool.queued() != [1] {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(
+            h.request(FrameRequest::new(2)),
+            Err(SessionError::DecodingError(DecoderError::PoolSaturated {
+                worker: 0
+            }))
+        ));
+        busy.join().unwrap().unwrap();
+        while pool.queued() != [0] {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Neither the GOP nor the decoder were given up on
+        assert_eq!(picture(h.request(FrameRequest::new(2))), (1, 12));
+        assert!(!h
+            .events()
+            .iter()
+            .any(|e| matches!(e, SessionEvent::DecodeFailed { .. })));
+        assert_eq!(h.worker.stats.snapshot().decode_failures, 0);
+        assert_eq!(logs.lock().unwrap()[1].decoded(), [10, 11, 12]);
+    }
+
+    // Every `testing::frame` is 6 bytes
+    #[test]
+    fn byte_cap_evicts_old_gops_before_closing_the_current_one() {
+        let config = SessionConfig::builder()
+            .with_gop_history(2)
+            .with_max_buffered_bytes(18)
+            .build()
+            .unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        h.iframe(10);
+        h.pframe(11);
+        h.iframe(20);
+        assert_eq!(h.worker.frame_holder.gops.len(), 2);
+
+        h.pframe(21);
+        h.pframe(22);
+        let holder = &h.worker.frame_holder;
+        assert_eq!((holder.gops.len(), holder.raw_bytes), (1, 18));
+
+        // Nothing older to evict, the GOP ends here
+        h.pframe(23);
+        h.pframe(24);
+        assert!(h.worker.frame_holder.latest().unwrap().closed);
+        assert_eq!(h.worker.frame_holder.raw_bytes, 18);
+        let stats = h.worker.stats.snapshot();
+        assert_eq!(stats.frames_dropped_bytes, 1);
+        assert_eq!(picture(h.request(FrameRequest::new(2))), (1, 22));
+        assert!(matches!(
+            h.request(FrameRequest::new(3)),
+            Err(SessionError::DecodingError(DecoderError::IndexOutOfBounds))
+        ));
+
+        h.iframe(30);
+        let holder = &h.worker.frame_holder;
+        assert_eq!((holder.gops.len(), holder.raw_bytes), (1, 6));
+    }
+
+    #[test]
+    fn gop_byte_cap_applies_to_each_gop() {
+        let config = SessionConfig::builder()
+            .with_gop_history(2)
+            .with_max_gop_bytes(Some(12))
+            .build()
+            .unwrap();
+        let mut h = Harness::new(config, MockDecoder::new(1));
+        for iframe in [10, 20] {
+            h.iframe(iframe);
+            for id in 1..4 {
+                h.pframe(iframe + id);
+            }
+            assert_eq!(h.worker.frame_holder.latest().unwrap().raw_frames.len(), 2);
+        }
+        // The older GOP kept what it had
+        let holder = &h.worker.frame_holder;
+        assert_eq!((holder.gops.len(), holder.raw_bytes), (2, 24));
+        assert_eq!(h.worker.stats.snapshot().frames_dropped_bytes, 2);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_parsing() {
+        let config = SessionConfig::builder()
+            .with_max_frame_bytes(8)
+            .build()
+            .unwrap();
+        let decoder = MockDecoder::new(1);
+        let log = decoder.log();
+        let mut h = Harness::new(config, decoder);
+        h.iframe(10);
+        h.pframe(11);
+        h.push(vec![0; 9], false);
+        // References the refused frame
+        h.pframe(12);
+        let generation = h.generation();
+        h.push(vec![0; 9], true);
+
+        assert_eq!(h.generation(), generation);
+        assert_eq!(h.worker.frame_holder.latest().unwrap().raw_frames.len(), 2);
+        let stats = h.worker.stats.snapshot();
+        assert_eq!(stats.frames_oversized, 2);
+        assert!(matches!(
+            h.request(FrameRequest::new(2)),
+            Err(SessionError::DecodingError(DecoderError::IndexOutOfBounds))
+        ));
+        assert_eq!(picture(h.request(FrameRequest::new(1))), (1, 11));
+
+        // The decoder saw none of it and starts over at the next iframe
+        h.iframe(20);
+        assert_eq!(picture(h.request(FrameRequest::new(0))), (1, 20));
+        assert_eq!(
+            log.calls(),
+            [
+                Call::Decode(10),
+                Call::Decode(11),
+                Call::Reset,
+                Call::Decode(20)
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_access_units_are_skipped_without_ending_the_gop() {
+        let decoder = MockDecoder::new(1);
+        let log = decoder.log();
+        let mut h = Harness::new(config(), decoder);
+        h.iframe(10);
+        h.push(Vec::new(), false);
+        h.push(vec![0; 8], false);
+        h.pframe(11);
+
+        assert_eq!(h.worker.frame_holder.latest().unwrap().raw_frames.len(), 2);
+        assert_eq!(h.worker.stats.snapshot().frames_empty, 2);
+        assert_eq!(picture(h.request(FrameRequest::new(1))), (1, 11));
+        assert_eq!(log.decoded(), [10, 11]);
+    }
+
+    #[test]
+    fn replaced_decoder_takes_over_at_the_next_iframe() {
+        let (old, new) = (MockDecoder::new(1), MockDecoder::new(2));
+        let (old_log, new_log) = (old.log(), new.log());
+        let mut h = Harness::new(config(), old);
+        h.iframe(10);
+        h.pframe(11);
+        assert_eq!(picture(h.request(FrameRequest::new(1))), (1, 11));
+        let old_generation = h.generation();
+
+        let (reply, mut swapped) = oneshot::channel();
+        h.send(WorkerMessage::ReplaceDecoder {
+            decoder: Box::new(new),
+            reply,
+        });
+        // Nothing is served until the swap went through
+        h.pframe(12);
+        assert!(swapped.try_recv().is_err());
+        assert!(matches!(
+            h.request(FrameRequest::new(0)),
+            Err(SessionError::SwappingDecoder)
+        ));
+
+        h.events();
+        h.iframe(20);
+        h.pframe(21);
+        let generation = swapped.try_recv().unwrap().ok().unwrap();
+        assert_eq!(generation, h.generation());
+        assert!(h.events().iter().any(
+            |e| matches!(e, SessionEvent::DecoderReplaced { generation: g } if *g == generation)
+        ));
+        assert_eq!(picture(h.request(FrameRequest::new(1))), (2, 21));
+        assert_eq!(picture(h.request(FrameRequest::new(0))), (2, 20));
+        assert!(h
+            .request(FrameRequest::new(0).with_generation(old_generation))
+            .is_err());
+
+        assert_eq!(old_log.decoded(), [10, 11]);
+        assert_eq!(new_log.decoded(), [20, 21]);
+    }
+
+    #[test]
+    fn superseded_replacement_is_refused() {
+        let mut h = Harness::new(config(), MockDecoder::new(1));
+        h.iframe(10);
+        let mut replies: Vec<_> = (2..4)
+            .map(|tag| {
+                let (reply, rx) = oneshot::channel();
+                h.send(WorkerMessage::ReplaceDecoder {
+                    decoder: Box::new(MockDecoder::new(tag)),
+                    reply,
+                });
+                rx
+            })
+            .collect();
+        assert!(matches!(
+            replies[0].try_recv(),
+            Ok(Err(SessionError::SwappingDecoder))
+        ));
+
+        h.iframe(20);
+        assert!(matches!(replies[1].try_recv(), Ok(Ok(_))));
+        assert_eq!(picture(h.request(FrameRequest::new(0))), (3, 20));
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{camera::rtsp_session::FrameResponse, decoders::PixelFormat};
+
+// One reading of both clocks, to turn the `Instant`s frames carry into wall-clock time. See
+// `SessionInstanceManager::clock`, which is taken when the session starts.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockAnchor {
+    instant: Instant,
+    system: SystemTime,
+}
+
+impl ClockAnchor {
+    pub fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            system: SystemTime::now(),
+        }
+    }
+
+    // Unaffected by the system clock being changed after the anchor was taken
+    pub fn system_time(&self, at: Instant) -> SystemTime {
+        if at >= self.instant {
+            self.system + (at - self.instant)
+        } else {
+            self.system - (self.instant - at)
+        }
+    }
+}
+
+// What every frame of a sink has in common
+#[derive(Debug, Clone)]
+pub struct FrameContext {
+    pub camera_host: Option<String>,
+    pub profile_token: Option<String>,
+    pub clock: ClockAnchor,
+}
+
+impl FrameContext {
+    pub fn new(clock: ClockAnchor) -> Self {
+        Self {
+            camera_host: None,
+            profile_token: None,
+            clock,
+        }
+    }
+
+    pub fn with_camera_host(mut self, host: &str) -> Self {
+        self.camera_host = Some(host.to_string());
+        self
+    }
+
+    pub fn with_profile_token(mut self, token: &str) -> Self {
+        self.profile_token = Some(token.to_string());
+        self
+    }
+}
+
+// Where `FrameMetadata::captured_at` comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CaptureClock {
+    // The camera's clock, through the RTCP sender reports
+    SenderReport,
+    // Our clock when the frame arrived, for cameras without usable sender reports
+    #[default]
+    Arrival,
+}
+
+// Describes a saved frame, see `FrameSink::with_sidecar`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameMetadata {
+    // The image, relative to the sink directory. Set by `FrameSink`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub file: Option<String>,
+    pub camera_host: Option<String>,
+    pub profile_token: Option<String>,
+    // When the camera took the frame, or when it arrived, see `capture_clock`
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub captured_at: SystemTime,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capture_clock: CaptureClock,
+    // In `clock_rate` units
+    pub rtp_timestamp: i64,
+    pub clock_rate: u32,
+    pub generation: u64,
+    pub index: usize,
+    pub seq: u64,
+    pub width: usize,
+    pub height: usize,
+    pub pixel_format: PixelFormat,
+    // Only with `SessionConfig::measure_latency`
+    #[cfg_attr(feature = "serde", serde(default, with = "humantime_serde"))]
+    pub decode_time: Option<Duration>,
+}
+
+impl FrameMetadata {
+    pub fn new(frame: &FrameResponse, context: &FrameContext) -> Self {
+        let timestamp = frame.timestamp();
+        let (captured_at, capture_clock) = match frame.captured_at() {
+            Some(at) => (at, CaptureClock::SenderReport),
+            None => (
+                context.clock.system_time(frame.received_at()),
+                CaptureClock::Arrival,
+            ),
+        };
+        Self {
+            file: None,
+            camera_host: context.camera_host.clone(),
+            profile_token: context.profile_token.clone(),
+            captured_at,
+            capture_clock,
+            rtp_timestamp: timestamp.timestamp(),
+            clock_rate: timestamp.clock_rate().get(),
+            generation: frame.generation(),
+            index: frame.index(),
+            seq: frame.seq(),
+            width: frame.width(),
+            height: frame.height(),
+            pixel_format: frame.pixel_format(),
+            decode_time: frame.latency().map(|l| l.decode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchor_converts_instants_on_both_sides() {
+        let anchor = ClockAnchor::now();
+        let step = Duration::from_millis(1500);
+        assert_eq!(
+            anchor.system_time(anchor.instant + step),
+            anchor.system + step
+        );
+        assert_eq!(anchor.system_time(anchor.instant), anchor.system);
+        if let Some(before) = anchor.instant.checked_sub(step) {
+            assert_eq!(anchor.system_time(before), anchor.system - step);
+        }
+    }
+}
@@ -0,0 +1,631 @@
+mod metadata;
+mod timelapse;
+
+pub use metadata::{CaptureClock, ClockAnchor, FrameContext, FrameMetadata};
+pub use timelapse::{FrameCallback, TimeLapse, TimeLapseEvent, TimeLapseHandle};
+
+use std::{
+    collections::VecDeque,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use tracing::{debug, warn};
+
+use crate::{
+    camera::rtsp_session::{Dependent, FrameResponse},
+    decoders::{DecoderError, ImageDecoder, JpegEncoder, PixelFormat, PngEncoder},
+};
+
+#[derive(Debug)]
+pub enum SinkError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Encode(DecoderError),
+    // The template expands to an absolute path or one leaving the sink directory
+    InvalidPath(PathBuf),
+    #[cfg(feature = "serde")]
+    Metadata(serde_json::Error),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => {
+                write!(f, "failed to write {}: {}", path.display(), source)
+            }
+            Self::Encode(e) => write!(f, "failed to encode frame: {}", e),
+            Self::InvalidPath(path) => {
+                write!(f, "{} is outside the sink directory", path.display())
+            }
+            #[cfg(feature = "serde")]
+            Self::Metadata(e) => write!(f, "failed to serialize the frame metadata: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Encode(e) => Some(e),
+            Self::InvalidPath(_) => None,
+            #[cfg(feature = "serde")]
+            Self::Metadata(e) => Some(e),
+        }
+    }
+}
+
+impl From<DecoderError> for SinkError {
+    fn from(e: DecoderError) -> Self {
+        Self::Encode(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkEncoding {
+    // The frame bytes as the decoder chain produced them
+    Raw,
+    Png,
+    // Quality from 1 to 100
+    Jpeg(i32),
+}
+
+// File `FrameSink::with_sidecar` keeps the `FrameMetadata` of the frames in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sidecar {
+    // `<image>.json` next to each image, deleted along with it
+    Json,
+    // A line per image appended to `index.jsonl` in the image's directory
+    JsonLines,
+}
+
+#[cfg(feature = "serde")]
+const INDEX_FILE: &str = "index.jsonl";
+
+// Writes frames under a directory, one file each. Only the files this sink wrote count towards
+// the retention limits, the oldest are deleted first.
+pub struct FrameSink {
+    dir: PathBuf,
+    // Placeholders: {camera}, {date} (YYYY-MM-DD, UTC), {ts} (unix millis), {generation},
+    // {index} and {ext}
+    template: String,
+    camera: String,
+    encoding: SinkEncoding,
+    max_files: Option<usize>,
+    max_bytes: Option<u64>,
+    written: VecDeque<(PathBuf, u64)>,
+    written_bytes: u64,
+    // Temp file of a `write_async` that was dropped before its rename, see `shutdown`
+    pending: Option<PathBuf>,
+    #[cfg(feature = "serde")]
+    sidecar: Option<(Sidecar, FrameContext)>,
+}
+
+impl FrameSink {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            template: "{camera}/{date}/{ts}.{ext}".to_string(),
+            camera: "camera".to_string(),
+            encoding: SinkEncoding::Raw,
+            max_files: None,
+            max_bytes: None,
+            written: VecDeque::new(),
+            written_bytes: 0,
+            pending: None,
+            #[cfg(feature = "serde")]
+            sidecar: None,
+        }
+    }
+
+    // Sidecars are written after the image, a failed one fails the write but keeps the image
+    #[cfg(feature = "serde")]
+    pub fn with_sidecar(mut self, sidecar: Sidecar, context: FrameContext) -> Self {
+        self.sidecar = Some((sidecar, context));
+        self
+    }
+
+    pub fn with_template(mut self, template: &str) -> Self {
+        self.template = template.to_string();
+        self
+    }
+
+    pub fn with_camera(mut self, camera: &str) -> Self {
+        self.camera = camera.to_string();
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: SinkEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files.max(1));
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    // Returns where the frame was written
+    pub fn write(&mut self, frame: &FrameResponse) -> Result<PathBuf, SinkError> {
+        let (path, data) = self.prepare(frame)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error(parent))?;
+        }
+        write_replacing(&path, &data)?;
+        #[cfg(feature = "serde")]
+        self.write_sidecar(&path, frame)?;
+
+        for old in self.track(&path, data.len() as u64) {
+            if let Err(e) = fs::remove_file(&old) {
+                warn!("Failed to delete {}: {}", old.display(), e);
+            }
+        }
+        Ok(path)
+    }
+
+    // Encoding still runs on the calling task, only the file system calls are async
+    pub async fn write_async(&mut self, frame: &FrameResponse) -> Result<PathBuf, SinkError> {
+        let (path, data) = self.prepare(frame)?;
+        let tmp = temp_path(&path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(io_error(parent))?;
+        }
+        self.pending = Some(tmp.clone());
+        tokio::fs::write(&tmp, &data)
+            .await
+            .map_err(io_error(&tmp))?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .map_err(io_error(&path))?;
+        self.pending = None;
+        #[cfg(feature = "serde")]
+        self.write_sidecar_async(&path, frame).await?;
+
+        for old in self.track(&path, data.len() as u64) {
+            if let Err(e) = tokio::fs::remove_file(&old).await {
+                warn!("Failed to delete {}: {}", old.display(), e);
+            }
+        }
+        Ok(path)
+    }
+
+    // Deletes what a cancelled `write_async` left behind, so no partial file outlives the sink.
+    // Also done on drop, without blocking on it here.
+    pub async fn shutdown(&mut self) {
+        if let Some(tmp) = self.pending.take() {
+            debug!("Deleting the unfinished {}", tmp.display());
+            if let Err(e) = tokio::fs::remove_file(&tmp).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to delete {}: {}", tmp.display(), e);
+                }
+            }
+        }
+    }
+
+    fn prepare(&self, frame: &FrameResponse) -> Result<(PathBuf, Vec<u8>), SinkError> {
+        let size = (frame.width(), frame.height());
+        let (data, ext) = match self.encoding {
+            SinkEncoding::Raw => (frame.frame().to_vec(), raw_extension(frame.pixel_format())),
+            SinkEncoding::Png => (
+                PngEncoder::new(size)
+                    .with_input_format(frame.pixel_format())
+                    .decode_frame(frame.frame())?
+                    .data
+                    .to_vec(),
+                "png",
+            ),
+            SinkEncoding::Jpeg(quality) => (
+                JpegEncoder::new(size)
+                    .with_quality(quality)
+                    .with_input_format(frame.pixel_format())
+                    .decode_frame(frame.frame())?
+                    .data
+                    .to_vec(),
+                "jpg",
+            ),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let relative = self
+            .template
+            .replace("{camera}", &self.camera)
+            .replace("{date}", &date(now.as_secs()))
+            .replace("{ts}", &now.as_millis().to_string())
+            .replace("{generation}", &frame.generation().to_string())
+            .replace("{index}", &frame.index().to_string())
+            .replace("{ext}", ext);
+        let relative = PathBuf::from(relative);
+        let escapes = relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)));
+        if escapes {
+            return Err(SinkError::InvalidPath(relative));
+        }
+        Ok((self.dir.join(relative), data))
+    }
+
+    // Records the new file and returns the ones now over the limits
+    fn track(&mut self, path: &Path, len: u64) -> Vec<PathBuf> {
+        // Overwriting a file keeps a single entry for it
+        if let Some(at) = self.written.iter().position(|(p, _)| p == path) {
+            if let Some((_, old)) = self.written.remove(at) {
+                self.written_bytes -= old;
+            }
+        }
+        self.written.push_back((path.to_path_buf(), len));
+        self.written_bytes += len;
+
+        let mut expired = Vec::new();
+        while self.written.len() > 1 && self.over_limits() {
+            let Some((old, old_len)) = self.written.pop_front() else {
+                break;
+            };
+            debug!("Deleting {} to stay within the sink limits", old.display());
+            self.written_bytes -= old_len;
+            #[cfg(feature = "serde")]
+            if let Some((Sidecar::Json, _)) = self.sidecar {
+                expired.push(old.with_extension("json"));
+            }
+            expired.push(old);
+        }
+        expired
+    }
+
+    fn over_limits(&self) -> bool {
+        self.max_files.is_some_and(|max| self.written.len() > max)
+            || self.max_bytes.is_some_and(|max| self.written_bytes > max)
+    }
+}
+
+impl Drop for FrameSink {
+    fn drop(&mut self) {
+        if let Some(tmp) = self.pending.take() {
+            let _ = fs::remove_file(tmp);
+        }
+    }
+}
+
+#[async_trait]
+impl Dependent for FrameSink {
+    async fn shutdown(mut self: Box<Self>) {
+        FrameSink::shutdown(&mut self).await
+    }
+}
+
+#[cfg(feature = "serde")]
+impl FrameSink {
+    // Where the metadata of the image at `path` goes and the JSON to put there
+    fn sidecar_entry(
+        &self,
+        path: &Path,
+        frame: &FrameResponse,
+    ) -> Result<Option<(Sidecar, PathBuf, String)>, SinkError> {
+        let Some((sidecar, context)) = &self.sidecar else {
+            return Ok(None);
+        };
+        let mut metadata = FrameMetadata::new(frame, context);
+        metadata.file = path
+            .strip_prefix(&self.dir)
+            .ok()
+            .map(|p| p.to_string_lossy().into_owned());
+        let json = serde_json::to_string(&metadata).map_err(SinkError::Metadata)?;
+        let target = match sidecar {
+            Sidecar::Json => path.with_extension("json"),
+            Sidecar::JsonLines => path.with_file_name(INDEX_FILE),
+        };
+        Ok(Some((*sidecar, target, json)))
+    }
+
+    fn write_sidecar(&self, path: &Path, frame: &FrameResponse) -> Result<(), SinkError> {
+        use std::io::Write;
+
+        match self.sidecar_entry(path, frame)? {
+            Some((Sidecar::Json, target, json)) => write_replacing(&target, json.as_bytes()),
+            Some((Sidecar::JsonLines, target, json)) => fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&target)
+                .and_then(|mut file| writeln!(file, "{}", json))
+                .map_err(io_error(&target)),
+            None => Ok(()),
+        }
+    }
+
+    async fn write_sidecar_async(
+        &mut self,
+        path: &Path,
+        frame: &FrameResponse,
+    ) -> Result<(), SinkError> {
+        use tokio::io::AsyncWriteExt;
+
+        match self.sidecar_entry(path, frame)? {
+            Some((Sidecar::Json, target, json)) => {
+                let tmp = temp_path(&target);
+                self.pending = Some(tmp.clone());
+                tokio::fs::write(&tmp, json).await.map_err(io_error(&tmp))?;
+                tokio::fs::rename(&tmp, &target)
+                    .await
+                    .map_err(io_error(&target))?;
+                self.pending = None;
+                Ok(())
+            }
+            Some((Sidecar::JsonLines, target, json)) => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&target)
+                    .await
+                    .map_err(io_error(&target))?;
+                file.write_all(format!("{}\n", json).as_bytes())
+                    .await
+                    .map_err(io_error(&target))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> SinkError + '_ {
+    move |source| SinkError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+// Goes through a temp file renamed over `path`, which is deleted again if either step fails
+fn write_replacing(path: &Path, data: &[u8]) -> Result<(), SinkError> {
+    let tmp = temp_path(path);
+    let res = fs::write(&tmp, data)
+        .map_err(io_error(&tmp))
+        .and_then(|_| fs::rename(&tmp, path).map_err(io_error(path)));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+// Next to the final file so the rename never crosses file systems
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn raw_extension(format: PixelFormat) -> &'static str {
+    match format {
+        PixelFormat::Jpeg => "jpg",
+        PixelFormat::Png => "png",
+        PixelFormat::H264AnnexB => "h264",
+        _ => "raw",
+    }
+}
+
+// YYYY-MM-DD of a unix timestamp, in UTC
+fn date(secs: u64) -> String {
+    DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoders::FrameFormat;
+
+    fn frame(generation: u64, len: usize) -> FrameResponse {
+        let format = FrameFormat {
+            width: len,
+            height: 1,
+            pixel_format: PixelFormat::Gray8,
+        };
+        FrameResponse::synthetic(vec![generation as u8; len], format, generation, 0)
+    }
+
+    // Names of the files under `dir`, sorted
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn oldest_files_are_deleted_past_max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FrameSink::new(dir.path())
+            .with_template("{generation}.{ext}")
+            .with_max_files(3);
+        for generation in 1..=5 {
+            let path = sink.write(&frame(generation, 4)).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), [generation as u8; 4]);
+        }
+        assert_eq!(files(dir.path()), ["3.raw", "4.raw", "5.raw"]);
+    }
+
+    #[test]
+    fn oldest_files_are_deleted_past_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FrameSink::new(dir.path())
+            .with_template("{generation}.{ext}")
+            .with_max_bytes(25);
+        for generation in 1..=4 {
+            sink.write(&frame(generation, 10)).unwrap();
+        }
+        assert_eq!(files(dir.path()), ["3.raw", "4.raw"]);
+
+        // The newest file is kept even alone over the limit
+        sink.write(&frame(5, 30)).unwrap();
+        assert_eq!(files(dir.path()), ["5.raw"]);
+    }
+
+    #[test]
+    fn overwriting_a_file_counts_it_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FrameSink::new(dir.path())
+            .with_template("same.{ext}")
+            .with_max_files(2);
+        for generation in 1..=3 {
+            sink.write(&frame(generation, 4)).unwrap();
+        }
+        assert_eq!(files(dir.path()), ["same.raw"]);
+        assert_eq!(fs::read(dir.path().join("same.raw")).unwrap(), [3; 4]);
+    }
+
+    #[test]
+    fn default_template_creates_the_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FrameSink::new(dir.path()).with_camera("gate");
+        let path = sink.write(&frame(1, 4)).unwrap();
+        let relative = path.strip_prefix(dir.path()).unwrap();
+        let parts: Vec<_> = relative.iter().map(|p| p.to_string_lossy()).collect();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0], "gate");
+        assert_eq!(parts[1].len(), "YYYY-MM-DD".len());
+        assert!(parts[2].ends_with(".raw"));
+        // Nothing but the frame, the temp file was renamed
+        assert_eq!(files(path.parent().unwrap()).len(), 1);
+    }
+
+    #[test]
+    fn templates_leaving_the_directory_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        for template in ["../{ts}.{ext}", "/tmp/{ts}.{ext}"] {
+            let mut sink = FrameSink::new(dir.path()).with_template(template);
+            assert!(matches!(
+                sink.write(&frame(1, 4)),
+                Err(SinkError::InvalidPath(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn io_errors_name_the_path() {
+        let dir = tempfile::tempdir().unwrap();
+        // A file where the sink needs a directory
+        fs::write(dir.path().join("taken"), b"").unwrap();
+        let mut sink = FrameSink::new(dir.path()).with_template("taken/{ts}.{ext}");
+        match sink.write(&frame(1, 4)) {
+            Err(SinkError::Io { path, .. }) => assert_eq!(path, dir.path().join("taken")),
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn async_writes_rotate_too() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FrameSink::new(dir.path())
+            .with_template("{generation}.{ext}")
+            .with_max_files(2);
+        for generation in 1..=3 {
+            sink.write_async(&frame(generation, 4)).await.unwrap();
+        }
+        sink.shutdown().await;
+        assert_eq!(files(dir.path()), ["2.raw", "3.raw"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_sidecars_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = FrameContext::new(ClockAnchor::now())
+            .with_camera_host("10.0.0.7")
+            .with_profile_token("main");
+        let mut sink = FrameSink::new(dir.path())
+            .with_template("{generation}.{ext}")
+            .with_max_files(1)
+            .with_sidecar(Sidecar::Json, context.clone());
+        let written = frame(1, 4);
+        sink.write(&written).unwrap();
+
+        let json = fs::read_to_string(dir.path().join("1.json")).unwrap();
+        let mut expected = FrameMetadata::new(&written, &context);
+        expected.file = Some("1.raw".to_string());
+        assert_eq!(
+            serde_json::from_str::<FrameMetadata>(&json).unwrap(),
+            expected
+        );
+
+        // Rotated out along with its image
+        sink.write(&frame(2, 4)).unwrap();
+        assert_eq!(files(dir.path()), ["2.json", "2.raw"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn index_lines_follow_the_capture_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FrameSink::new(dir.path())
+            .with_template("{index}.{ext}")
+            .with_sidecar(Sidecar::JsonLines, FrameContext::new(ClockAnchor::now()));
+        let format = FrameFormat {
+            width: 4,
+            height: 1,
+            pixel_format: PixelFormat::Gray8,
+        };
+        for index in 0..4 {
+            sink.write(&FrameResponse::synthetic(vec![0; 4], format, 1, index))
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        let index = fs::read_to_string(dir.path().join(INDEX_FILE)).unwrap();
+        let lines: Vec<FrameMetadata> = index
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let names: Vec<_> = lines.iter().map(|m| m.file.as_deref()).collect();
+        assert_eq!(
+            names,
+            [Some("0.raw"), Some("1.raw"), Some("2.raw"), Some("3.raw")]
+        );
+        assert!(lines
+            .iter()
+            .all(|m| m.capture_clock == CaptureClock::Arrival));
+        assert!(lines.windows(2).all(|w| {
+            w[0].captured_at < w[1].captured_at
+                && w[0].rtp_timestamp < w[1].rtp_timestamp
+                && w[0].seq < w[1].seq
+        }));
+    }
+
+    #[test]
+    fn png_sink_keeps_the_colors_of_bgr_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FrameSink::new(dir.path())
+            .with_template("{generation}.{ext}")
+            .with_encoding(SinkEncoding::Png);
+        let format = FrameFormat {
+            width: 2,
+            height: 1,
+            pixel_format: PixelFormat::Bgr8,
+        };
+        let bgr = FrameResponse::synthetic(vec![1, 2, 3, 4, 5, 6], format, 1, 0);
+        let path = sink.write(&bgr).unwrap();
+        assert_eq!(files(dir.path()), ["1.png"]);
+
+        let file = fs::read(&path).unwrap();
+        let mut reader = png::Decoder::new(&file[..]).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(&pixels[..info.buffer_size()], [3, 2, 1, 6, 5, 4]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    error::Error,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{debug, error, warn};
+
+use super::FrameSink;
+use crate::camera::rtsp_session::{
+    Dependent, FrameRequest, FrameResponse, ResyncHook, SessionError, SessionInstance,
+};
+
+pub type FrameCallback =
+    Box<dyn FnMut(&FrameResponse) -> Result<(), Box<dyn Error + Send + Sync>> + Send>;
+
+#[derive(Debug, Clone)]
+pub enum TimeLapseEvent {
+    // `path` is where a `FrameSink` wrote it, `None` for callbacks
+    Captured {
+        index: usize,
+        generation: u64,
+        path: Option<PathBuf>,
+        elapsed: Duration,
+    },
+    // The previous tick ran past these many ticks, they were dropped instead of run back to back
+    Skipped(u64),
+    // No frame could be fetched this tick
+    RequestFailed(String),
+    // The frame was fetched but the sink refused it
+    SinkFailed(String),
+}
+
+enum Sink {
+    Files(FrameSink),
+    Callback(FrameCallback),
+}
+
+// Saves the freshest frame of a session every `interval`
+pub struct TimeLapse {
+    instance: SessionInstance,
+    interval: Duration,
+    resync_hook: Option<ResyncHook>,
+}
+
+impl TimeLapse {
+    pub fn new(instance: SessionInstance, interval: Duration) -> Self {
+        Self {
+            instance,
+            interval: interval.max(Duration::from_millis(1)),
+            resync_hook: None,
+        }
+    }
+
+    // Called when the buffered frames expired, usually the same hook as the session's. Sessions
+    // with a hook of their own already resync before a request can see `OldFrame`.
+    pub fn with_resync_hook(mut self, hook: ResyncHook) -> Self {
+        self.resync_hook = Some(hook);
+        self
+    }
+
+    pub fn start(self, sink: FrameSink) -> TimeLapseHandle {
+        self.spawn(Sink::Files(sink))
+    }
+
+    pub fn start_with(self, callback: FrameCallback) -> TimeLapseHandle {
+        self.spawn(Sink::Callback(callback))
+    }
+
+    fn spawn(self, sink: Sink) -> TimeLapseHandle {
+        let (events_tx, events_rx) = broadcast::channel(64);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(self.run(sink, events_tx, shutdown_rx));
+        TimeLapseHandle {
+            events_rx,
+            shutdown_tx: Some(shutdown_tx),
+            task: Some(task),
+        }
+    }
+
+    async fn run(
+        self,
+        mut sink: Sink,
+        events_tx: broadcast::Sender<TimeLapseEvent>,
+        mut shutdown_rx: oneshot::Receiver<()>,
+    ) {
+        let emit = |event| {
+            let _ = events_tx.send(event);
+        };
+        let mut ticker = time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut last_tick: Option<time::Instant> = None;
+
+        loop {
+            let tick = tokio::select! {
+                tick = ticker.tick() => tick,
+                _ = &mut shutdown_rx => break,
+            };
+            if let Some(last) = last_tick {
+                let missed = (tick - last).as_nanos() / self.interval.as_nanos();
+                if missed > 1 {
+                    debug!(
+                        missed = missed - 1,
+                        "Time-lapse capture ran long, skipping ticks"
+                    );
+                    emit(TimeLapseEvent::Skipped(missed as u64 - 1));
+                }
+            }
+            last_tick = Some(tick);
+
+            let b = Instant::now();
+            let frame = tokio::select! {
+                frame = self.latest() => frame,
+                _ = &mut shutdown_rx => break,
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(SessionError::OldFrame) => {
+                    self.resync();
+                    emit(TimeLapseEvent::RequestFailed(
+                        SessionError::OldFrame.to_string(),
+                    ));
+                    continue;
+                }
+                Err(e) => {
+                    emit(TimeLapseEvent::RequestFailed(e.to_string()));
+                    continue;
+                }
+            };
+
+            let saved = match &mut sink {
+                Sink::Files(sink) => sink
+                    .write_async(&frame)
+                    .await
+                    .map(Some)
+                    .map_err(|e| e.to_string()),
+                Sink::Callback(callback) => {
+                    callback(&frame).map(|_| None).map_err(|e| e.to_string())
+                }
+            };
+            match saved {
+                Ok(path) => emit(TimeLapseEvent::Captured {
+                    index: frame.index(),
+                    generation: frame.generation(),
+                    path,
+                    elapsed: b.elapsed(),
+                }),
+                Err(e) => {
+                    warn!("Time-lapse sink failed: {}", e);
+                    emit(TimeLapseEvent::SinkFailed(e));
+                }
+            }
+        }
+        if let Sink::Files(sink) = &mut sink {
+            sink.shutdown().await;
+        }
+    }
+
+    // A buffer reset between reading the generation and serving the request is retried once
+    // against the new generation
+    async fn latest(&self) -> Result<FrameResponse, SessionError> {
+        let generation = self.instance.buffer_state().generation;
+        match self
+            .instance
+            .request_image(FrameRequest::latest().with_generation(generation))
+            .await
+        {
+            Err(SessionError::BufferReset { actual, .. }) => {
+                self.instance
+                    .request_image(FrameRequest::latest().with_generation(actual))
+                    .await
+            }
+            res => res,
+        }
+    }
+
+    fn resync(&self) {
+        let Some(hook) = &self.resync_hook else {
+            return;
+        };
+        let fut = hook();
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                warn!("Failed to request a synchronization point: {}", e);
+            }
+        });
+    }
+}
+
+// Dropping it stops the time-lapse without waiting for the tick in progress
+pub struct TimeLapseHandle {
+    events_rx: broadcast::Receiver<TimeLapseEvent>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TimeLapseHandle {
+    // Outcomes of the ticks from now on. A receiver that falls behind loses the oldest ones.
+    pub fn events(&self) -> broadcast::Receiver<TimeLapseEvent> {
+        self.events_rx.resubscribe()
+    }
+
+    // Resolves once the task has exited, a frame being written is finished first and no request
+    // is made after it. Stop it before the session, e.g. through
+    // `SessionInstanceManager::shutdown_with`.
+    pub async fn stop(mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Some(task) = self.task.take() {
+            if let Err(e) = task.await {
+                error!("Time-lapse task failed while stopping: {:?}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Dependent for TimeLapseHandle {
+    async fn shutdown(self: Box<Self>) {
+        self.stop().await
+    }
+}
+
+impl Drop for TimeLapseHandle {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::{BufferPool, DecodedFrame, DecoderError, FrameFormat, ImageDecoder, PooledBuffer};
+
+// Same as `DecodedFrame` but not tied to the stage that produced it, so it can cross an await
+#[derive(Debug, Clone)]
+pub struct OwnedFrame {
+    pub data: Arc<PooledBuffer>,
+    pub format: FrameFormat,
+    pub stride: usize,
+}
+
+impl OwnedFrame {
+    pub fn pooled(f: DecodedFrame<'_>, pool: &BufferPool) -> Self {
+        Self {
+            data: Arc::new(pool.checkout(f.data)),
+            format: f.format,
+            stride: f.stride,
+        }
+    }
+}
+
+impl From<DecodedFrame<'_>> for OwnedFrame {
+    fn from(f: DecodedFrame<'_>) -> Self {
+        Self {
+            data: Arc::new(PooledBuffer::from(f.data.to_vec())),
+            format: f.format,
+            stride: f.stride,
+        }
+    }
+}
+
+// For stages that wait on something outside the process, e.g. a GPU fence or a decode service.
+// Every `ImageDecoder` is one too, decoding inline.
+#[async_trait]
+pub trait AsyncImageDecoder: Sync + Send {
+    async fn decode(&mut self, data: &[u8]) -> Result<OwnedFrame, DecoderError>;
+
+    // Same as `decode` with the output in a buffer of `pool`. Stages that allocate their output
+    // themselves can leave it as is.
+    async fn decode_pooled(
+        &mut self,
+        data: &[u8],
+        _pool: &BufferPool,
+    ) -> Result<OwnedFrame, DecoderError> {
+        self.decode(data).await
+    }
+
+    // See `ImageDecoder::reset`
+    fn reset(&mut self) {}
+}
+
+#[async_trait]
+impl<T: ImageDecoder> AsyncImageDecoder for T {
+    async fn decode(&mut self, data: &[u8]) -> Result<OwnedFrame, DecoderError> {
+        self.decode_frame(data).map(OwnedFrame::from)
+    }
+
+    async fn decode_pooled(
+        &mut self,
+        data: &[u8],
+        pool: &BufferPool,
+    ) -> Result<OwnedFrame, DecoderError> {
+        self.decode_frame(data).map(|f| OwnedFrame::pooled(f, pool))
+    }
+
+    fn reset(&mut self) {
+        ImageDecoder::reset(self);
+    }
+}
+
+// Wraps a boxed sync decoder, which the blanket impl above doesn't cover
+pub struct SyncDecoder {
+    inner: Box<dyn ImageDecoder>,
+}
+
+impl From<Box<dyn ImageDecoder + Sync + Send>> for SyncDecoder {
+    fn from(inner: Box<dyn ImageDecoder + Sync + Send>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl AsyncImageDecoder for SyncDecoder {
+    async fn decode(&mut self, data: &[u8]) -> Result<OwnedFrame, DecoderError> {
+        self.inner.decode_frame(data).map(OwnedFrame::from)
+    }
+
+    async fn decode_pooled(
+        &mut self,
+        data: &[u8],
+        pool: &BufferPool,
+    ) -> Result<OwnedFrame, DecoderError> {
+        self.inner
+            .decode_frame(data)
+            .map(|f| OwnedFrame::pooled(f, pool))
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+}
+
+pub trait AsyncChain<T: 'static + AsyncImageDecoder> {
+    fn chain_async(self, other: T) -> AsyncChainedDecoder;
+}
+
+// Works for sync stages as well, so either side of the chain can be sync or async
+impl<T: 'static + AsyncImageDecoder, U: 'static + AsyncImageDecoder> AsyncChain<T> for U {
+    fn chain_async(self, other: T) -> AsyncChainedDecoder {
+        AsyncChainedDecoder {
+            a: Box::new(self),
+            b: Box::new(other),
+        }
+    }
+}
+
+pub struct AsyncChainedDecoder {
+    a: Box<dyn AsyncImageDecoder>,
+    b: Box<dyn AsyncImageDecoder>,
+}
+
+#[async_trait]
+impl AsyncImageDecoder for AsyncChainedDecoder {
+    async fn decode(&mut self, data: &[u8]) -> Result<OwnedFrame, DecoderError> {
+        let intermediate = self.a.decode(data).await?;
+        self.b.decode(&intermediate.data).await
+    }
+
+    // The intermediate buffer goes back to the pool as soon as the second stage is done with it
+    async fn decode_pooled(
+        &mut self,
+        data: &[u8],
+        pool: &BufferPool,
+    ) -> Result<OwnedFrame, DecoderError> {
+        let intermediate = self.a.decode_pooled(data, pool).await?;
+        self.b.decode_pooled(&intermediate.data, pool).await
+    }
+
+    fn reset(&mut self) {
+        self.a.reset();
+        self.b.reset();
+    }
+}
@@ -0,0 +1,758 @@
+use std::time::Instant;
+
+use tracing::{debug, trace};
+
+use super::{DecodedFrame, DecoderError, FrameFormat, ImageDecoder, PixelFormat};
+
+// Converts one AVCC frame outside of a decoder chain, keeping every NAL
+pub fn avcc_to_annex_b(data: &[u8], length_size: usize) -> Result<Vec<u8>, DecoderError> {
+    let mut decoder = AVCCDecoder::new().with_length_size(length_size)?;
+    decoder.convert(data)?;
+    Ok(decoder.buf)
+}
+
+// H.264 NAL unit type, the low 5 bits of the header byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalType {
+    Slice,
+    Idr,
+    Sei,
+    Sps,
+    Pps,
+    Aud,
+    Filler,
+    Other(u8),
+}
+
+impl NalType {
+    pub fn from_header(header: u8) -> Self {
+        match header & 0x1F {
+            1 => Self::Slice,
+            5 => Self::Idr,
+            6 => Self::Sei,
+            7 => Self::Sps,
+            8 => Self::Pps,
+            9 => Self::Aud,
+            12 => Self::Filler,
+            other => Self::Other(other),
+        }
+    }
+}
+
+pub struct AVCCDecoder {
+    buf: Vec<u8>,
+    // Bytes of the NAL length prefix, `lengthSizeMinusOne + 1` in the avcC box
+    length_size: usize,
+    // Fall back to the other prefix sizes when a frame doesn't parse with `length_size`
+    auto_detect: bool,
+    // Fail frames that are already Annex B instead of passing them through
+    reject_annex_b: bool,
+    // NAL types left out of the output. Annex B input passed through is not filtered.
+    dropped: Vec<NalType>,
+    // Leave zero length NALs out instead of failing the frame with `MalformedNal`
+    skip_empty_nals: bool,
+    empty_nals_skipped: u64,
+    max_nals: usize,
+    // Last parameter sets seen, without start code
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl AVCCDecoder {
+    const LENGTH_SIZES: [usize; 3] = [4, 2, 1];
+    // Far past what an encoder splits one picture into, a guard against garbage length fields
+    const DEFAULT_MAX_NALS: usize = 512;
+    const FORMAT: FrameFormat = FrameFormat {
+        width: 0,
+        height: 0,
+        pixel_format: PixelFormat::H264AnnexB,
+    };
+
+    pub fn new() -> Self {
+        return Self {
+            buf: Vec::new(),
+            length_size: 4,
+            auto_detect: false,
+            reject_annex_b: false,
+            dropped: Vec::new(),
+            skip_empty_nals: false,
+            empty_nals_skipped: 0,
+            max_nals: Self::DEFAULT_MAX_NALS,
+            sps: None,
+            pps: None,
+        };
+    }
+
+    // 1, 2 or 4
+    pub fn with_length_size(mut self, length_size: usize) -> Result<Self, DecoderError> {
+        if !Self::LENGTH_SIZES.contains(&length_size) {
+            return Err(DecoderError::InvalidLengthSize(length_size));
+        }
+        self.length_size = length_size;
+        Ok(self)
+    }
+
+    // Once a frame parses with another size, that size is kept for the following frames
+    pub fn with_auto_detect(mut self, auto_detect: bool) -> Self {
+        self.auto_detect = auto_detect;
+        self
+    }
+
+    pub fn with_reject_annex_b(mut self, reject_annex_b: bool) -> Self {
+        self.reject_annex_b = reject_annex_b;
+        self
+    }
+
+    // E.g. `NalType::Sei` and `NalType::Aud` to strip camera metadata
+    pub fn with_dropped(mut self, dropped: &[NalType]) -> Self {
+        self.dropped = dropped.to_vec();
+        self
+    }
+
+    pub fn with_skip_empty_nals(mut self, skip: bool) -> Self {
+        self.skip_empty_nals = skip;
+        self
+    }
+
+    // Frames with more NALs fail with `TooManyNals`, empty ones included
+    pub fn with_max_nals(mut self, max_nals: usize) -> Self {
+        self.max_nals = max_nals.max(1);
+        self
+    }
+
+    // Zero length NALs left out so far under `with_skip_empty_nals`
+    pub fn empty_nals_skipped(&self) -> u64 {
+        self.empty_nals_skipped
+    }
+
+    pub fn sps(&self) -> Option<&[u8]> {
+        self.sps.as_deref()
+    }
+
+    pub fn pps(&self) -> Option<&[u8]> {
+        self.pps.as_deref()
+    }
+
+    // A start code also reads as a 1 byte NAL behind a 2 or 4 byte prefix, so it only counts
+    // when the buffer doesn't hold together as AVCC
+    fn is_annex_b(&self, data: &[u8]) -> bool {
+        let start_code = data.starts_with(&[0, 0, 0, 1]) || data.starts_with(&[0, 0, 1]);
+        start_code && !Self::plausible(data, self.length_size)
+    }
+
+    fn convert(&mut self, data: &[u8]) -> Result<(), DecoderError> {
+        let length_size = self.length_size;
+        self.buf.clear();
+        let mut index = 0;
+        let mut nal_index = 0;
+        let head = |offset: usize| data[offset..data.len().min(offset + 8)].to_vec();
+
+        while index < data.len() {
+            // Read the size field
+            if index + length_size > data.len() {
+                return Err(DecoderError::FieldOutOfBounds {
+                    offset: index,
+                    remaining: data.len() - index,
+                    head: head(index),
+                });
+            }
+
+            let nal_size = Self::read_length(&data[index..index + length_size]);
+
+            index += length_size; // Skip the size field
+
+            if index + nal_size > data.len() {
+                return Err(DecoderError::NalOutOfBounds {
+                    offset: index - length_size,
+                    nal_size,
+                    buffer_len: data.len(),
+                    nal_index,
+                    head: head(index - length_size),
+                });
+            }
+            nal_index += 1;
+            if nal_index > self.max_nals {
+                return Err(DecoderError::TooManyNals {
+                    limit: self.max_nals,
+                });
+            }
+            if nal_size == 0 && self.skip_empty_nals {
+                self.empty_nals_skipped += 1;
+                continue;
+            }
+
+            // Extract the NAL unit
+            let nal_unit = &data[index..index + nal_size];
+            let header = match nal_unit.first() {
+                Some(header) if header & 0x80 == 0 => *header,
+                _ => return Err(DecoderError::MalformedNal { offset: index }),
+            };
+            index += nal_size;
+
+            let nal_type = NalType::from_header(header);
+            match nal_type {
+                NalType::Sps => self.sps = Some(nal_unit.to_vec()),
+                NalType::Pps => self.pps = Some(nal_unit.to_vec()),
+                _ => {}
+            }
+            if self.dropped.contains(&nal_type) {
+                continue;
+            }
+
+            // Prepend the Annex B start code (0x00000001)
+            self.buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            self.buf.extend_from_slice(nal_unit);
+        }
+        // A bare start code would only fail further down the chain, far from the cause
+        if self.buf.is_empty() {
+            return Err(DecoderError::EmptyAccessUnit);
+        }
+        Ok(())
+    }
+
+    // Big endian
+    fn read_length(field: &[u8]) -> usize {
+        field.iter().fold(0, |size, b| size << 8 | *b as usize)
+    }
+
+    // A wrong prefix size usually still parses the first few bytes, but yields empty NALs or
+    // ones with the forbidden zero bit set
+    fn plausible(data: &[u8], length_size: usize) -> bool {
+        let mut index = 0;
+        while index < data.len() {
+            if index + length_size > data.len() {
+                return false;
+            }
+            let nal_size = Self::read_length(&data[index..index + length_size]);
+            index += length_size;
+            if nal_size == 0 || index + nal_size > data.len() || data[index] & 0x80 != 0 {
+                return false;
+            }
+            index += nal_size;
+        }
+        true
+    }
+}
+
+impl ImageDecoder for AVCCDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let b = Instant::now();
+        if data.is_empty() {
+            return Err(DecoderError::EmptyAccessUnit);
+        }
+        if self.is_annex_b(data) {
+            if self.reject_annex_b {
+                return Err(DecoderError::UnexpectedAnnexB);
+            }
+            self.buf.clear();
+            self.buf.extend_from_slice(data);
+            return Ok(DecodedFrame::new(&self.buf, Self::FORMAT));
+        }
+        if self.auto_detect && !Self::plausible(data, self.length_size) {
+            if let Some(size) = Self::LENGTH_SIZES
+                .into_iter()
+                .find(|size| *size != self.length_size && Self::plausible(data, *size))
+            {
+                debug!(
+                    "NAL length prefix is {} bytes, not {}",
+                    size, self.length_size
+                );
+                self.length_size = size;
+            }
+        }
+        self.convert(data)?;
+
+        trace!(
+            elapsed_ms = b.elapsed().as_millis() as u64,
+            "avcc to annex b"
+        );
+        Ok(DecodedFrame::new(&self.buf, Self::FORMAT))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(Self::FORMAT)
+    }
+}
+
+// Converts one Annex B access unit outside of a decoder chain, see `AnnexBToAVCC`
+pub fn annex_b_to_avcc(data: &[u8]) -> Result<Vec<u8>, DecoderError> {
+    let mut stage = AnnexBToAVCC::new();
+    stage.convert(data)?;
+    Ok(stage.buf)
+}
+
+// The inverse of `AVCCDecoder`, for MP4 and MSE style consumers: Annex B access units come out
+// with 4 byte big endian NAL lengths. Parameter sets stay in the output, the last ones seen are
+// also kept apart to build the avcC record from. NALs are copied as they are, emulation
+// prevention bytes included.
+pub struct AnnexBToAVCC {
+    buf: Vec<u8>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl Default for AnnexBToAVCC {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnnexBToAVCC {
+    const FORMAT: FrameFormat = FrameFormat {
+        width: 0,
+        height: 0,
+        pixel_format: PixelFormat::H264Avcc,
+    };
+
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            sps: None,
+            pps: None,
+        }
+    }
+
+    // Last SPS and PPS seen, without start code, once both went through
+    pub fn parameter_sets(&self) -> Option<(&[u8], &[u8])> {
+        Some((self.sps.as_deref()?, self.pps.as_deref()?))
+    }
+
+    fn convert(&mut self, data: &[u8]) -> Result<(), DecoderError> {
+        self.buf.clear();
+        let head = |offset: usize| data[offset..data.len().min(offset + 8)].to_vec();
+
+        // Where each NAL starts, past its start code. A 4 byte start code is found as a 3 byte
+        // one, its leading zero ends up with the trailing zeros stripped below.
+        let mut starts = Vec::new();
+        let mut index = 0;
+        while index + 3 <= data.len() {
+            if data[index..index + 3] == [0, 0, 1] {
+                starts.push(index + 3);
+                index += 3;
+            } else {
+                index += 1;
+            }
+        }
+        let Some(first) = starts.first() else {
+            return Err(DecoderError::NoStartCode {
+                len: data.len(),
+                head: head(0),
+            });
+        };
+        // Only zero bytes may come before the first start code
+        if data[..first - 3].iter().any(|b| *b != 0) {
+            return Err(DecoderError::LeadingGarbage {
+                len: first - 3,
+                head: head(0),
+            });
+        }
+
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(data.len(), |next| next - 3);
+            let nal = &data[*start..end];
+            // A NAL never ends on a zero byte, those are padding or part of the next start code
+            let nal = &nal[..nal.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1)];
+            let header = match nal.first() {
+                Some(header) if header & 0x80 == 0 => *header,
+                _ => return Err(DecoderError::MalformedNal { offset: *start }),
+            };
+            match NalType::from_header(header) {
+                NalType::Sps => self.sps = Some(nal.to_vec()),
+                NalType::Pps => self.pps = Some(nal.to_vec()),
+                _ => {}
+            }
+            self.buf
+                .extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            self.buf.extend_from_slice(nal);
+        }
+        Ok(())
+    }
+}
+
+impl ImageDecoder for AnnexBToAVCC {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let b = Instant::now();
+        self.convert(data)?;
+        trace!(
+            elapsed_ms = b.elapsed().as_millis() as u64,
+            "annex b to avcc"
+        );
+        Ok(DecodedFrame::new(&self.buf, Self::FORMAT))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(Self::FORMAT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::rtsp_session::fixtures, decoders::Chain};
+
+    const SPS: &[u8] = &[0x67, 0x42, 0x00];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84];
+    const EMPTY: &[u8] = &[];
+    // Ends on its stop bit like a real one, `AnnexBToAVCC` takes trailing zeros for padding
+    const STOPPED_SPS: &[u8] = &[0x67, 0x42, 0xc0, 0x0a];
+
+    fn avcc(nals: &[&[u8]], length_size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nals {
+            data.extend_from_slice(&(nal.len() as u64).to_be_bytes()[8 - length_size..]);
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nals {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    #[test]
+    fn every_length_size_converts() {
+        for length_size in [1, 2, 4] {
+            let mut decoder = AVCCDecoder::new().with_length_size(length_size).unwrap();
+            let frame = decoder
+                .decode_frame(&avcc(&[SPS, IDR], length_size))
+                .unwrap();
+            assert_eq!(frame.data, annex_b(&[SPS, IDR]), "{}", length_size);
+        }
+        assert!(matches!(
+            AVCCDecoder::new().with_length_size(3),
+            Err(DecoderError::InvalidLengthSize(3))
+        ));
+    }
+
+    #[test]
+    fn truncated_frames_are_refused() {
+        for length_size in [1, 2, 4] {
+            let mut decoder = AVCCDecoder::new().with_length_size(length_size).unwrap();
+            let mut data = avcc(&[SPS, IDR], length_size);
+            data.pop();
+            match decoder.decode_frame(&data) {
+                Err(DecoderError::NalOutOfBounds {
+                    offset,
+                    nal_size,
+                    buffer_len,
+                    nal_index,
+                    ..
+                }) => {
+                    assert_eq!(offset, length_size + SPS.len());
+                    assert_eq!(nal_size, IDR.len());
+                    assert_eq!(buffer_len, data.len());
+                    assert_eq!(nal_index, 1);
+                }
+                other => panic!("expected NalOutOfBounds, got {:?}", other),
+            }
+        }
+
+        // Cut inside the second length field
+        for length_size in [2, 4] {
+            let mut decoder = AVCCDecoder::new().with_length_size(length_size).unwrap();
+            let data = &avcc(&[SPS, IDR], length_size)[..length_size + SPS.len() + 1];
+            assert!(matches!(
+                decoder.decode_frame(data),
+                Err(DecoderError::FieldOutOfBounds { remaining: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn auto_detect_falls_back_and_keeps_the_size() {
+        let mut strict = AVCCDecoder::new();
+        assert!(strict.decode_frame(&avcc(&[SPS, IDR], 2)).is_err());
+
+        let mut decoder = AVCCDecoder::new().with_auto_detect(true);
+        for length_size in [2, 2, 1] {
+            let frame = decoder
+                .decode_frame(&avcc(&[SPS, IDR], length_size))
+                .unwrap();
+            assert_eq!(frame.data, annex_b(&[SPS, IDR]));
+            assert_eq!(decoder.length_size, length_size);
+        }
+        // Left alone when the configured size parses
+        let frame = decoder.decode_frame(&avcc(&[IDR], 1)).unwrap();
+        assert_eq!(frame.data, annex_b(&[IDR]));
+        assert_eq!(decoder.length_size, 1);
+    }
+
+    #[test]
+    fn annex_b_passes_through() {
+        let avcc_frame = fixtures::iframe(10);
+        let annex_b_frame = avcc_to_annex_b(&avcc_frame, 4).unwrap();
+        // Same NALs behind the shorter start code
+        let three_byte = annex_b_frame[1..].to_vec();
+        for data in [annex_b_frame, three_byte] {
+            let mut decoder = AVCCDecoder::new();
+            assert_eq!(decoder.decode_frame(&data).unwrap().data, data);
+
+            let mut strict = AVCCDecoder::new().with_reject_annex_b(true);
+            assert!(matches!(
+                strict.decode_frame(&data),
+                Err(DecoderError::UnexpectedAnnexB)
+            ));
+        }
+
+        // The AVCC form still converts under `with_reject_annex_b`
+        let mut strict = AVCCDecoder::new().with_reject_annex_b(true);
+        assert!(strict.decode_frame(&avcc_frame).is_ok());
+    }
+
+    #[test]
+    fn avcc_that_starts_like_a_start_code_is_converted() {
+        // A 1 byte NAL behind a 4 byte prefix reads `00 00 00 01`
+        let aud: &[u8] = &[0x09];
+        // and one of 256 bytes `00 00 01 00`
+        let mut long = vec![0x65];
+        long.resize(256, 0x11);
+        for nals in [[aud, IDR], [&long[..], IDR]] {
+            let data = avcc(&nals, 4);
+            assert!(data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1]));
+            let mut decoder = AVCCDecoder::new().with_reject_annex_b(true);
+            assert_eq!(decoder.decode_frame(&data).unwrap().data, annex_b(&nals));
+        }
+    }
+
+    #[test]
+    fn out_of_bounds_errors_locate_the_fault() {
+        let mut decoder = AVCCDecoder::new();
+        let data = [0, 0, 0, 3, 0x67, 0x42, 0x00, 0, 0, 1, 0, 0x65];
+        let e = decoder.decode_frame(&data).err().unwrap();
+        match &e {
+            DecoderError::NalOutOfBounds {
+                offset,
+                nal_size,
+                buffer_len,
+                nal_index,
+                head,
+            } => {
+                assert_eq!(
+                    (*offset, *nal_size, *buffer_len, *nal_index),
+                    (7, 256, 12, 1)
+                );
+                assert_eq!(head, &[0, 0, 1, 0, 0x65]);
+            }
+            e => panic!("expected NalOutOfBounds, got {:?}", e),
+        }
+        assert_eq!(
+            e.to_string(),
+            "NAL 1 at byte 7 claims 256 bytes but the buffer is 12 bytes: [00, 00, 01, 00, 65]"
+        );
+
+        let e = decoder.decode_frame(&data[..9]).err().unwrap();
+        match &e {
+            DecoderError::FieldOutOfBounds {
+                offset,
+                remaining,
+                head,
+            } => {
+                assert_eq!((*offset, *remaining), (7, 2));
+                assert_eq!(head, &[0, 0]);
+            }
+            e => panic!("expected FieldOutOfBounds, got {:?}", e),
+        }
+        assert_eq!(
+            e.to_string(),
+            "NAL length field at byte 7 runs past the buffer, 2 bytes left: [00, 00]"
+        );
+    }
+
+    #[test]
+    fn head_is_capped_at_eight_bytes() {
+        let mut data = vec![0, 0, 0, 0xff];
+        data.extend_from_slice(&[0x65; 20]);
+        match AVCCDecoder::new().decode_frame(&data) {
+            Err(DecoderError::NalOutOfBounds { head, .. }) => assert_eq!(head, data[..8]),
+            other => panic!("expected NalOutOfBounds, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn helper_converts_a_whole_frame() {
+        for length_size in [1, 2, 4] {
+            assert_eq!(
+                avcc_to_annex_b(&avcc(&[SPS, IDR], length_size), length_size).unwrap(),
+                annex_b(&[SPS, IDR])
+            );
+        }
+        let frame = fixtures::iframe(10);
+        let converted = avcc_to_annex_b(&frame, 4).unwrap();
+        // Only the prefixes change, every NAL is kept
+        assert_eq!(converted.len(), frame.len());
+        assert_eq!(
+            converted.windows(4).filter(|w| *w == [0, 0, 0, 1]).count(),
+            3
+        );
+    }
+
+    #[test]
+    fn helper_fails_like_the_stage() {
+        assert!(matches!(
+            avcc_to_annex_b(&avcc(&[IDR], 4), 3),
+            Err(DecoderError::InvalidLengthSize(3))
+        ));
+        assert!(matches!(
+            avcc_to_annex_b(&[], 4),
+            Err(DecoderError::EmptyAccessUnit)
+        ));
+        let mut truncated = avcc(&[SPS, IDR], 2);
+        truncated.pop();
+        assert!(matches!(
+            avcc_to_annex_b(&truncated, 2),
+            Err(DecoderError::NalOutOfBounds { nal_index: 1, .. })
+        ));
+        assert!(matches!(
+            avcc_to_annex_b(&[0, 0, 0, 1, 0x80], 4),
+            Err(DecoderError::MalformedNal { offset: 4 })
+        ));
+    }
+
+    #[test]
+    fn round_trip_reproduces_the_avcc_frame() {
+        // Emulation prevention bytes included, neither stage unescapes anything
+        let escaped: &[u8] = &[0x41, 0, 0, 3, 1, 0x80];
+        let frames = [
+            fixtures::iframe(10),
+            fixtures::pframe(1),
+            fixtures::sized_iframe(2, 1, 80),
+            fixtures::corrupt_pframe(),
+            avcc(&[STOPPED_SPS, escaped, IDR], 4),
+        ];
+        let mut chain = AVCCDecoder::new().chain(AnnexBToAVCC::new());
+        for frame in frames {
+            assert_eq!(chain.decode_frame(&frame).unwrap().data, frame);
+            let annex_b = avcc_to_annex_b(&frame, 4).unwrap();
+            assert_eq!(annex_b_to_avcc(&annex_b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn last_parameter_sets_are_kept() {
+        let mut stage = AnnexBToAVCC::new();
+        assert!(stage.parameter_sets().is_none());
+        let pps: &[u8] = &[0x68, 0xce, 0x38, 0x80];
+        stage
+            .decode_frame(&annex_b(&[STOPPED_SPS, pps, IDR]))
+            .unwrap();
+        // Frames without them leave them as they were
+        stage.decode_frame(&annex_b(&[&[0x41, 0x9a]])).unwrap();
+        assert_eq!(stage.parameter_sets(), Some((STOPPED_SPS, pps)));
+
+        let sps: &[u8] = &[0x67, 0x4d, 0x00, 0x1f];
+        stage.decode_frame(&annex_b(&[sps, IDR])).unwrap();
+        assert_eq!(stage.parameter_sets(), Some((sps, pps)));
+    }
+
+    #[test]
+    fn mixed_start_codes_and_padding_convert() {
+        let mut data = vec![0, 0, 1];
+        data.extend_from_slice(STOPPED_SPS);
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(IDR);
+        // Trailing zeros are padding, not part of the last NAL
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            annex_b_to_avcc(&data).unwrap(),
+            avcc(&[STOPPED_SPS, IDR], 4)
+        );
+    }
+
+    #[test]
+    fn degenerate_annex_b_is_refused() {
+        let mut stage = AnnexBToAVCC::new();
+        assert!(matches!(
+            stage.decode_frame(&[]),
+            Err(DecoderError::NoStartCode { len: 0, .. })
+        ));
+        // AVCC handed to the wrong stage
+        let avcc_frame = avcc(&[SPS, IDR], 4);
+        match stage.decode_frame(&avcc_frame[4..]) {
+            Err(DecoderError::NoStartCode { len, head }) => {
+                assert_eq!(len, avcc_frame.len() - 4);
+                assert_eq!(head, &avcc_frame[4..12]);
+            }
+            other => panic!("expected NoStartCode, got {:?}", other.err()),
+        }
+
+        let mut garbage = vec![0xff, 0xee];
+        garbage.extend_from_slice(&annex_b(&[IDR]));
+        assert!(matches!(
+            stage.decode_frame(&garbage),
+            Err(DecoderError::LeadingGarbage { len: 3, .. })
+        ));
+
+        // Empty, then with the forbidden bit set. The SPS before them loses its trailing zero,
+        // which doesn't move the offsets.
+        for nal in [&[][..], &[0x80 | 0x65, 0x88]] {
+            let data = annex_b(&[SPS, nal, IDR]);
+            assert!(matches!(
+                stage.decode_frame(&data),
+                Err(DecoderError::MalformedNal { offset: 11 })
+            ));
+        }
+    }
+
+    #[test]
+    fn access_units_without_nal_data_are_refused() {
+        let mut decoder = AVCCDecoder::new()
+            .with_skip_empty_nals(true)
+            .with_dropped(&[NalType::Sei]);
+        let sei: &[u8] = &[0x06, 0x05, 0x80];
+        for data in [Vec::new(), avcc(&[EMPTY, EMPTY], 4), avcc(&[sei], 4)] {
+            assert!(matches!(
+                decoder.decode_frame(&data),
+                Err(DecoderError::EmptyAccessUnit)
+            ));
+        }
+        assert_eq!(decoder.empty_nals_skipped(), 2);
+    }
+
+    #[test]
+    fn zero_length_nals_fail_unless_skipped() {
+        let data = avcc(&[SPS, EMPTY, IDR], 4);
+        let mut strict = AVCCDecoder::new();
+        assert!(matches!(
+            strict.decode_frame(&data),
+            Err(DecoderError::MalformedNal { offset: 11 })
+        ));
+
+        let mut skipping = AVCCDecoder::new().with_skip_empty_nals(true);
+        let out = skipping.decode_frame(&data).unwrap().data.to_vec();
+        assert_eq!(out, annex_b(&[SPS, IDR]));
+        assert_eq!(skipping.empty_nals_skipped(), 1);
+    }
+
+    #[test]
+    fn frames_after_a_refused_one_convert() {
+        let mut decoder = AVCCDecoder::new();
+        assert!(decoder.decode_frame(&[]).is_err());
+        assert!(decoder.decode_frame(&avcc(&[EMPTY], 4)).is_err());
+        let out = decoder
+            .decode_frame(&avcc(&[IDR], 4))
+            .unwrap()
+            .data
+            .to_vec();
+        assert_eq!(out, annex_b(&[IDR]));
+    }
+
+    #[test]
+    fn nal_count_is_capped() {
+        let mut decoder = AVCCDecoder::new()
+            .with_max_nals(2)
+            .with_skip_empty_nals(true);
+        assert!(decoder.decode_frame(&avcc(&[SPS, IDR], 4)).is_ok());
+        // Skipped ones count too
+        for nals in [[SPS, IDR, IDR], [SPS, EMPTY, IDR]] {
+            assert!(matches!(
+                decoder.decode_frame(&avcc(&nals, 4)),
+                Err(DecoderError::TooManyNals { limit: 2 })
+            ));
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex, Weak},
+};
+
+// Reusable output buffers, so decoding doesn't allocate a new frame sized buffer every time.
+// Buffers keep the capacity of the largest frame they held and go back to the pool once their
+// `PooledBuffer` is dropped. Past `capacity` buffers in use, plain allocations are handed out
+// instead of waiting for one to come back.
+#[derive(Clone)]
+pub struct BufferPool {
+    shelf: Arc<Mutex<Shelf>>,
+}
+
+struct Shelf {
+    free: Vec<Vec<u8>>,
+    // Buffers owned by the pool, free or handed out
+    owned: usize,
+    capacity: usize,
+}
+
+impl BufferPool {
+    // A capacity of 0 never pools anything
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shelf: Arc::new(Mutex::new(Shelf {
+                free: Vec::new(),
+                owned: 0,
+                capacity,
+            })),
+        }
+    }
+
+    // Copies `data` into a free buffer, or a new one once the pool is exhausted
+    pub fn checkout(&self, data: &[u8]) -> PooledBuffer {
+        let (buf, pooled) = {
+            let mut shelf = self.shelf.lock().unwrap_or_else(|e| e.into_inner());
+            match shelf.free.pop() {
+                Some(buf) => (Some(buf), true),
+                None if shelf.owned < shelf.capacity => {
+                    shelf.owned += 1;
+                    (None, true)
+                }
+                None => (None, false),
+            }
+        };
+        let mut buf = buf.unwrap_or_else(|| Vec::with_capacity(data.len()));
+        buf.clear();
+        buf.extend_from_slice(data);
+        PooledBuffer {
+            data: buf,
+            pool: pooled.then(|| Arc::downgrade(&self.shelf)),
+        }
+    }
+
+    // Buffers waiting to be reused
+    pub fn available(&self) -> usize {
+        self.shelf
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .free
+            .len()
+    }
+}
+
+// Derefs to the frame bytes. Buffers of a pool that is gone are freed as usual.
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Option<Weak<Mutex<Shelf>>>,
+}
+
+impl PooledBuffer {
+    pub fn is_pooled(&self) -> bool {
+        self.pool.is_some()
+    }
+}
+
+// Not tied to any pool
+impl From<Vec<u8>> for PooledBuffer {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data, pool: None }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("len", &self.data.len())
+            .field("pooled", &self.is_pooled())
+            .finish()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let Some(shelf) = self.pool.take().and_then(|p| p.upgrade()) else {
+            return;
+        };
+        let mut shelf = shelf.lock().unwrap_or_else(|e| e.into_inner());
+        shelf.free.push(std::mem::take(&mut self.data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returned_buffers_are_reused() {
+        let pool = BufferPool::new(2);
+        let first = pool.checkout(&[1; 64]);
+        let ptr = first.as_ptr();
+        drop(first);
+        assert_eq!(pool.available(), 1);
+
+        // Smaller frames fit the buffer as it is
+        for i in 0..3 {
+            let buf = pool.checkout(&[i; 32]);
+            assert_eq!(buf.as_ptr(), ptr);
+            assert_eq!(&buf[..], &[i; 32]);
+            assert_eq!(pool.available(), 0);
+        }
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn exhausted_pool_allocates() {
+        let pool = BufferPool::new(1);
+        let pooled = pool.checkout(&[1]);
+        let plain = pool.checkout(&[2]);
+        assert!(pooled.is_pooled());
+        assert!(!plain.is_pooled());
+
+        drop(plain);
+        assert_eq!(pool.available(), 0);
+        drop(pooled);
+        assert_eq!(pool.available(), 1);
+        assert!(!BufferPool::new(0).checkout(&[1]).is_pooled());
+    }
+
+    #[test]
+    fn buffers_outlive_their_pool() {
+        let pool = BufferPool::new(1);
+        let buf = pool.checkout(&[7; 4]);
+        drop(pool);
+        assert_eq!(&buf[..], &[7; 4]);
+    }
+}
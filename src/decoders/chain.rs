@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+use tracing::{field, trace_span};
+
+use super::{DecodedFrame, DecoderError, FrameFormat, ImageDecoder};
+
+pub trait Chain<T: 'static + ImageDecoder> {
+    fn chain(self, other: T) -> ChainedDecoder;
+}
+
+impl<T: 'static + ImageDecoder, U: 'static + ImageDecoder> Chain<T> for U {
+    fn chain(self, other: T) -> ChainedDecoder {
+        ChainedDecoder {
+            a: Box::new(self),
+            b: Box::new(other),
+        }
+    }
+}
+
+pub struct ChainedDecoder {
+    a: Box<dyn ImageDecoder>,
+    b: Box<dyn ImageDecoder>,
+}
+
+impl ImageDecoder for ChainedDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let span = trace_span!(
+            "chained_decode",
+            first_ms = field::Empty,
+            second_ms = field::Empty
+        )
+        .entered();
+        let b = Instant::now();
+        let intermediate = self.a.decode_frame(data)?;
+        span.record("first_ms", b.elapsed().as_millis() as u64);
+
+        let b = Instant::now();
+        let res = self.b.decode_frame(intermediate.data);
+        span.record("second_ms", b.elapsed().as_millis() as u64);
+        res
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.b.output_format()
+    }
+
+    fn reset(&mut self) {
+        self.a.reset();
+        self.b.reset();
+    }
+}
+
+// Runs any number of stages in order, each one fed the output of the previous. Flatter than
+// nesting `ChainedDecoder`s and keeps how long each stage took on the last frame.
+pub struct DecoderPipeline {
+    stages: Vec<Box<dyn ImageDecoder>>,
+    timings: Vec<Duration>,
+}
+
+impl Default for DecoderPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecoderPipeline {
+    pub fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            timings: Vec::new(),
+        }
+    }
+
+    pub fn then<T: 'static + ImageDecoder>(mut self, stage: T) -> Self {
+        self.stages.push(Box::new(stage));
+        self.timings.push(Duration::ZERO);
+        self
+    }
+
+    // Time each stage spent on the last decoded frame, in pipeline order. Stages after the one
+    // that failed keep the time of the frame before.
+    pub fn stage_timings(&self) -> &[Duration] {
+        &self.timings
+    }
+}
+
+impl ImageDecoder for DecoderPipeline {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let Some((first, rest)) = self.stages.split_first_mut() else {
+            return Err(DecoderError::EmptyPipeline);
+        };
+        let b = Instant::now();
+        let mut output = first.decode_frame(data)?;
+        self.timings[0] = b.elapsed();
+
+        for (stage, took) in rest.iter_mut().zip(self.timings[1..].iter_mut()) {
+            let b = Instant::now();
+            output = stage.decode_frame(output.data)?;
+            *took = b.elapsed();
+        }
+        Ok(output)
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.stages.last().and_then(|s| s.output_format())
+    }
+
+    fn reset(&mut self) {
+        self.stages.iter_mut().for_each(|s| s.reset());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer,
+    };
+
+    use super::*;
+    use crate::decoders::{
+        testing::{self, MockDecoder},
+        PngEncoder,
+    };
+
+    // Spans and events as `name field=value` lines, in the order they were seen
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<String>>>);
+
+    impl Captured {
+        fn push(&self, line: String) {
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn lines(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Captured {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, _: &span::Id, _: Context<'_, S>) {
+            self.push(format!("new {}", attrs.metadata().name()));
+        }
+
+        fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+            let name = ctx.span(id).map_or("?", |s| s.name());
+            let mut fields = Fields(format!("record {}", name));
+            values.record(&mut fields);
+            self.push(fields.0);
+        }
+
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            let mut fields = Fields(format!("event {}", event.metadata().level()));
+            event.record(&mut fields);
+            self.push(fields.0);
+        }
+    }
+
+    fn capture(f: impl FnOnce()) -> Vec<String> {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(captured.clone());
+        tracing::subscriber::with_default(subscriber, f);
+        captured.lines()
+    }
+
+    #[test]
+    fn chained_decode_records_stage_timings() {
+        let mut decoder = MockDecoder::new(1).chain(MockDecoder::new(2));
+        let lines = capture(|| {
+            let frame = decoder.decode_frame(&testing::frame(7)).unwrap();
+            assert_eq!(frame.data, [2, 7]);
+        });
+        assert_eq!(lines[0], "new chained_decode");
+        assert!(lines[1].starts_with("record chained_decode first_ms="));
+        assert!(lines[2].starts_with("record chained_decode second_ms="));
+    }
+
+    #[test]
+    fn stage_timings_are_trace_events() {
+        let mut encoder = PngEncoder::new((2, 1));
+        let lines = capture(|| {
+            encoder.decode_frame(&[0; 6]).unwrap();
+        });
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("event TRACE message=png encode elapsed_ms="));
+    }
+}
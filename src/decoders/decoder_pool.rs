@@ -0,0 +1,420 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tracing::debug;
+
+use super::{AsyncImageDecoder, DecoderError, ImageDecoder, OwnedFrame};
+
+// Called on a worker for every camera it takes on, decoders carry reference state and can't be
+// shared between cameras
+type Factory =
+    Arc<dyn Fn() -> Result<Box<dyn ImageDecoder + Sync + Send>, DecoderError> + Send + Sync>;
+
+// What `DecoderPool::decode` does once the worker of the camera has `queue_depth` frames queued
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Saturation {
+    // Waits for room, in submission order
+    #[default]
+    Wait,
+    // Fails with `PoolSaturated` right away
+    Reject,
+}
+
+// How cameras are spread over the workers. A camera stays on its worker until released, so its
+// frames decode in submission order on the same decoder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Affinity {
+    // The worker with the fewest cameras at the time of the first frame
+    #[default]
+    FewestCameras,
+    // Camera id modulo the worker count, the same camera always lands on the same worker
+    Hashed,
+}
+
+#[derive(Debug, Clone)]
+pub struct DecoderPoolConfig {
+    pub workers: usize,
+    // Frames queued per worker, including the one being decoded
+    pub queue_depth: usize,
+    pub saturation: Saturation,
+    pub affinity: Affinity,
+}
+
+impl Default for DecoderPoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_depth: 4,
+            saturation: Saturation::Wait,
+            affinity: Affinity::FewestCameras,
+        }
+    }
+}
+
+impl DecoderPoolConfig {
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    pub fn with_queue_depth(mut self, depth: usize) -> Self {
+        self.queue_depth = depth.max(1);
+        self
+    }
+
+    pub fn with_saturation(mut self, saturation: Saturation) -> Self {
+        self.saturation = saturation;
+        self
+    }
+
+    pub fn with_affinity(mut self, affinity: Affinity) -> Self {
+        self.affinity = affinity;
+        self
+    }
+}
+
+enum Job {
+    Decode {
+        camera: u64,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<OwnedFrame, DecoderError>>,
+        // Returned once the frame is decoded, frees a place in the queue
+        _permit: OwnedSemaphorePermit,
+    },
+    Reset(u64),
+    Release(u64),
+}
+
+struct Worker {
+    // Unbounded, the queue depth is kept by `permits` so resets and releases never wait
+    tx: mpsc::UnboundedSender<Job>,
+    permits: Arc<Semaphore>,
+    cameras: AtomicUsize,
+}
+
+struct Shared {
+    workers: Vec<Worker>,
+    config: DecoderPoolConfig,
+    assignments: Mutex<HashMap<u64, usize>>,
+    next_camera: AtomicU64,
+}
+
+// Bounds decode parallelism over many sessions to a fixed set of threads. Each worker owns one
+// decoder per camera it serves, built with the factory on the camera's first frame. Cheap to
+// clone, the workers stop once every clone and `PooledDecoder` is dropped.
+#[derive(Clone)]
+pub struct DecoderPool {
+    shared: Arc<Shared>,
+}
+
+impl DecoderPool {
+    pub fn new<F>(config: DecoderPoolConfig, factory: F) -> Self
+    where
+        F: Fn() -> Result<Box<dyn ImageDecoder + Sync + Send>, DecoderError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let factory: Factory = Arc::new(factory);
+        let workers = (0..config.workers.max(1))
+            .map(|i| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                let factory = Arc::clone(&factory);
+                thread::Builder::new()
+                    .name(format!("rtsp-decode-pool-{}", i))
+                    .spawn(move || run_worker(rx, factory))
+                    .expect("Failed to spawn a decoder pool thread");
+                Worker {
+                    tx,
+                    permits: Arc::new(Semaphore::new(config.queue_depth.max(1))),
+                    cameras: AtomicUsize::new(0),
+                }
+            })
+            .collect();
+        Self {
+            shared: Arc::new(Shared {
+                workers,
+                config,
+                assignments: Mutex::new(HashMap::new()),
+                next_camera: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    // A decoder for one session, see `SessionWrapper::new_pooled`. Its camera id is taken from a
+    // counter starting at 0, don't mix it with ids passed to `decode` directly.
+    pub fn decoder(&self) -> PooledDecoder {
+        PooledDecoder {
+            pool: self.clone(),
+            camera: self.shared.next_camera.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    // Frames of a camera decode in the order they were submitted in
+    pub async fn decode(&self, camera: u64, data: &[u8]) -> Result<OwnedFrame, DecoderError> {
+        let index = self.worker_of(camera);
+        let worker = &self.shared.workers[index];
+        let permits = Arc::clone(&worker.permits);
+        let permit = match self.shared.config.saturation {
+            Saturation::Wait => permits
+                .acquire_owned()
+                .await
+                .map_err(|_| DecoderError::PoolClosed)?,
+            Saturation::Reject => permits.try_acquire_owned().map_err(|e| match e {
+                TryAcquireError::NoPermits => DecoderError::PoolSaturated { worker: index },
+                TryAcquireError::Closed => DecoderError::PoolClosed,
+            })?,
+        };
+        let (reply, rx) = oneshot::channel();
+        worker
+            .tx
+            .send(Job::Decode {
+                camera,
+                data: data.to_vec(),
+                reply,
+                _permit: permit,
+            })
+            .map_err(|_| DecoderError::PoolClosed)?;
+        rx.await.map_err(|_| DecoderError::PoolClosed)?
+    }
+
+    // Goes after the frames already submitted for the camera, see `ImageDecoder::reset`
+    pub fn reset(&self, camera: u64) {
+        if let Some(index) = self.assigned(camera) {
+            let _ = self.shared.workers[index].tx.send(Job::Reset(camera));
+        }
+    }
+
+    // Drops the camera's decoder once its queued frames are done. A later frame starts over
+    // with a fresh one, possibly on another worker.
+    pub fn release(&self, camera: u64) {
+        let index = self
+            .shared
+            .assignments
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&camera);
+        if let Some(index) = index {
+            let worker = &self.shared.workers[index];
+            worker.cameras.fetch_sub(1, Ordering::Relaxed);
+            let _ = worker.tx.send(Job::Release(camera));
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.shared.workers.len()
+    }
+
+    // Frames queued on each worker, the one being decoded included
+    pub fn queued(&self) -> Vec<usize> {
+        let depth = self.shared.config.queue_depth.max(1);
+        self.shared
+            .workers
+            .iter()
+            .map(|w| depth - w.permits.available_permits())
+            .collect()
+    }
+
+    fn assigned(&self, camera: u64) -> Option<usize> {
+        self.shared
+            .assignments
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&camera)
+            .copied()
+    }
+
+    fn worker_of(&self, camera: u64) -> usize {
+        let mut assignments = self
+            .shared
+            .assignments
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *assignments.entry(camera).or_insert_with(|| {
+            let workers = &self.shared.workers;
+            let index = match self.shared.config.affinity {
+                Affinity::Hashed => (camera % workers.len() as u64) as usize,
+                Affinity::FewestCameras => (0..workers.len())
+                    .min_by_key(|i| workers[*i].cameras.load(Ordering::Relaxed))
+                    .unwrap_or(0),
+            };
+            workers[index].cameras.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                camera,
+                worker = index,
+                "Camera assigned to a decoder pool worker"
+            );
+            index
+        })
+    }
+}
+
+fn run_worker(mut rx: mpsc::UnboundedReceiver<Job>, factory: Factory) {
+    let mut decoders: HashMap<u64, Box<dyn ImageDecoder + Sync + Send>> = HashMap::new();
+    while let Some(job) = rx.blocking_recv() {
+        match job {
+            Job::Decode {
+                camera,
+                data,
+                reply,
+                _permit,
+            } => {
+                // Decoded even when the caller is gone, later frames reference this one
+                let decoder = match decoders.entry(camera) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match factory() {
+                        Ok(decoder) => entry.insert(decoder),
+                        Err(e) => {
+                            let _ = reply.send(Err(e));
+                            continue;
+                        }
+                    },
+                };
+                let _ = reply.send(decoder.decode_frame(&data).map(OwnedFrame::from));
+            }
+            Job::Reset(camera) => {
+                if let Some(decoder) = decoders.get_mut(&camera) {
+                    decoder.reset();
+                }
+            }
+            Job::Release(camera) => {
+                decoders.remove(&camera);
+            }
+        }
+    }
+}
+
+// One camera's share of a `DecoderPool`, released when dropped
+pub struct PooledDecoder {
+    pool: DecoderPool,
+    camera: u64,
+}
+
+impl PooledDecoder {
+    pub fn camera(&self) -> u64 {
+        self.camera
+    }
+}
+
+#[async_trait]
+impl AsyncImageDecoder for PooledDecoder {
+    async fn decode(&mut self, data: &[u8]) -> Result<OwnedFrame, DecoderError> {
+        self.pool.decode(self.camera, data).await
+    }
+
+    fn reset(&mut self) {
+        self.pool.reset(self.camera);
+    }
+}
+
+impl Drop for PooledDecoder {
+    fn drop(&mut self) {
+        self.pool.release(self.camera);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future;
+
+    use super::*;
+    use crate::decoders::testing::{self, Call, CallLog, MockDecoder};
+
+    // The decoders are tagged in the order the workers built them, their logs in the same order
+    fn pool(config: DecoderPoolConfig, delay: Duration) -> (DecoderPool, Arc<Mutex<Vec<CallLog>>>) {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let built = Arc::clone(&logs);
+        let pool = DecoderPool::new(config, move || {
+            let mut logs = built.lock().unwrap();
+            let decoder = MockDecoder::new(logs.len() as u8).with_delay(delay);
+            logs.push(decoder.log());
+            Ok(Box::new(decoder) as Box<dyn ImageDecoder + Sync + Send>)
+        });
+        (pool, logs)
+    }
+
+    // Submitted all at once, more than the queue holds
+    async fn submit(pool: &DecoderPool, camera: u64) -> Vec<OwnedFrame> {
+        let frames = future::join_all((0..20).map(|id| async move {
+            let data = testing::frame(id);
+            pool.decode(camera, &data).await
+        }))
+        .await;
+        frames.into_iter().map(|f| f.unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn frames_of_a_camera_decode_in_submission_order() {
+        let config = DecoderPoolConfig::default()
+            .with_workers(2)
+            .with_queue_depth(2)
+            .with_affinity(Affinity::Hashed);
+        let (pool, logs) = pool(config, Duration::from_millis(1));
+        let (first, second) = tokio::join!(submit(&pool, 0), submit(&pool, 1));
+
+        let logs = logs.lock().unwrap().clone();
+        assert_eq!(logs.len(), 2);
+        let mut tags = Vec::new();
+        for frames in [first, second] {
+            // One decoder for all of them, fed in order
+            let tag = frames[0].data[0];
+            let ids: Vec<u8> = frames.iter().map(|f| f.data[1]).collect();
+            assert!(frames.iter().all(|f| f.data[0] == tag));
+            assert_eq!(ids, (0..20).collect::<Vec<_>>());
+            assert_eq!(logs[tag as usize].decoded(), ids);
+            tags.push(tag);
+        }
+        assert_ne!(tags[0], tags[1]);
+    }
+
+    #[tokio::test]
+    async fn saturated_worker_rejects_frames() {
+        let config = DecoderPoolConfig::default()
+            .with_workers(1)
+            .with_queue_depth(1)
+            .with_saturation(Saturation::Reject);
+        let (pool, logs) = pool(config, Duration::from_millis(50));
+        let (first, second) = (testing::frame(1), testing::frame(2));
+        let (first, second) = tokio::join!(pool.decode(0, &first), pool.decode(1, &second));
+
+        assert_eq!(first.unwrap().data[..], [0, 1]);
+        assert!(matches!(
+            second.err(),
+            Some(DecoderError::PoolSaturated { worker: 0 })
+        ));
+        // The refused camera never got a decoder
+        assert_eq!(logs.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn resets_and_releases_follow_the_queued_frames() {
+        let config = DecoderPoolConfig::default().with_workers(1);
+        let (pool, logs) = pool(config, Duration::ZERO);
+        let mut decoder = pool.decoder();
+        decoder.decode(&testing::frame(1)).await.unwrap();
+        decoder.reset();
+        decoder.decode(&testing::frame(2)).await.unwrap();
+        let camera = decoder.camera();
+        drop(decoder);
+
+        // A fresh decoder once released
+        let frame = pool.decode(camera, &testing::frame(3)).await.unwrap();
+        assert_eq!(frame.data[..], [1, 3]);
+        let logs = logs.lock().unwrap().clone();
+        assert_eq!(
+            logs[0].calls(),
+            [Call::Decode(1), Call::Reset, Call::Decode(2)]
+        );
+        assert_eq!(logs[1].calls(), [Call::Decode(3)]);
+    }
+}
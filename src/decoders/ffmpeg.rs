@@ -0,0 +1,189 @@
+use std::time::Instant;
+
+use ffmpeg_next::{
+    codec,
+    decoder::{self, Video},
+    format::Pixel,
+    frame,
+    software::scaling::{self, Flags},
+    Packet,
+};
+use tracing::trace;
+
+use super::{DecodedFrame, DecoderError, FrameFormat, ImageDecoder, PixelFormat};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfmpegCodec {
+    H264,
+    H265,
+}
+
+impl FfmpegCodec {
+    fn id(&self) -> codec::Id {
+        match self {
+            Self::H264 => codec::Id::H264,
+            Self::H265 => codec::Id::HEVC,
+        }
+    }
+}
+
+// Decodes Annex B access units to RGB8 with ffmpeg's software decoders, for streams openh264
+// can't handle. Takes the same input as `H264RGBDecoder`.
+pub struct FfmpegDecoder {
+    inner: Video,
+    scaler: Option<scaling::Context>,
+    // SPS and PPS, plus the VPS for H.265, in Annex B. Fed ahead of the first frame for cameras
+    // that only send them in the SDP
+    parameter_sets: Option<Vec<u8>>,
+    decoded: frame::Video,
+    rgb: frame::Video,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+// The ffmpeg contexts are only ever touched through `&mut self`
+unsafe impl Send for FfmpegDecoder {}
+unsafe impl Sync for FfmpegDecoder {}
+
+impl FfmpegDecoder {
+    pub fn new(kind: FfmpegCodec) -> Result<Self, DecoderError> {
+        ffmpeg_next::init().map_err(DecoderError::FfmpegFail)?;
+        let codec = decoder::find(kind.id()).ok_or(DecoderError::UnsupportedCodec(kind))?;
+        let inner = codec::Context::new_with_codec(codec)
+            .decoder()
+            .video()
+            .map_err(DecoderError::FfmpegFail)?;
+        Ok(Self {
+            inner,
+            scaler: None,
+            parameter_sets: None,
+            decoded: frame::Video::empty(),
+            rgb: frame::Video::empty(),
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+
+    pub fn with_parameter_sets(mut self, annex_b: Vec<u8>) -> Self {
+        self.parameter_sets = Some(annex_b);
+        self
+    }
+
+    // Rebuilt whenever the picture size or pixel format changes
+    fn scaler_for(&mut self, src: Pixel, width: u32, height: u32) -> Result<(), DecoderError> {
+        let stale = self.scaler.as_ref().map_or(true, |s| {
+            let input = s.input();
+            input.format != src || input.width != width || input.height != height
+        });
+        if stale {
+            self.scaler = Some(
+                scaling::Context::get(
+                    src,
+                    width,
+                    height,
+                    Pixel::RGB24,
+                    width,
+                    height,
+                    Flags::BILINEAR,
+                )
+                .map_err(DecoderError::FfmpegFail)?,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ImageDecoder for FfmpegDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        if let Some(parameter_sets) = self.parameter_sets.take() {
+            self.inner
+                .send_packet(&Packet::copy(&parameter_sets))
+                .map_err(DecoderError::FfmpegFail)?;
+        }
+        self.inner
+            .send_packet(&Packet::copy(data))
+            .map_err(DecoderError::FfmpegFail)?;
+        // Parameter sets alone and frames the decoder still holds back produce no picture
+        if self.inner.receive_frame(&mut self.decoded).is_err() {
+            return Err(DecoderError::NoImageDecoded);
+        }
+
+        let b = Instant::now();
+        let (width, height) = (self.decoded.width(), self.decoded.height());
+        self.scaler_for(self.decoded.format(), width, height)?;
+        if let Some(scaler) = self.scaler.as_mut() {
+            scaler
+                .run(&self.decoded, &mut self.rgb)
+                .map_err(DecoderError::FfmpegFail)?;
+        }
+
+        // ffmpeg pads rows, the output is packed
+        let (width, height) = (width as usize, height as usize);
+        let stride = self.rgb.stride(0);
+        let plane = self.rgb.data(0);
+        self.buf.clear();
+        for row in 0..height {
+            self.buf
+                .extend_from_slice(&plane[row * stride..row * stride + width * 3]);
+        }
+        let format = FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Rgb8,
+        };
+        self.format = Some(format);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "yuv to rgb");
+        trace!(
+            elapsed_ms = bb.elapsed().as_millis() as u64,
+            "ffmpeg decode"
+        );
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    // Drops the reference frames without closing the codec
+    fn reset(&mut self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::rtsp_session::fixtures,
+        decoders::{avcc_to_annex_b, H264RGBDecoder},
+    };
+
+    #[test]
+    fn matches_openh264_on_the_same_stream() {
+        let mut ffmpeg = FfmpegDecoder::new(FfmpegCodec::H264).unwrap();
+        let mut openh264 = H264RGBDecoder::new(false).unwrap();
+        for au in [
+            fixtures::iframe(40),
+            fixtures::pframe(1),
+            fixtures::sized_iframe(2, 1, 200),
+        ] {
+            let au = avcc_to_annex_b(&au, 4).unwrap();
+            let expected = openh264.decode_frame(&au).unwrap();
+            let actual = ffmpeg.decode_frame(&au).unwrap();
+            assert_eq!(actual.format, expected.format);
+            // The YUV to RGB conversions round differently
+            for (a, e) in actual.data.iter().zip(expected.data) {
+                assert!(a.abs_diff(*e) <= 3, "{} against {}", a, e);
+            }
+            assert_eq!(actual.data.len(), expected.data.len());
+        }
+    }
+
+    #[test]
+    fn has_both_codecs() {
+        for codec in [FfmpegCodec::H264, FfmpegCodec::H265] {
+            assert!(FfmpegDecoder::new(codec).is_ok(), "{:?}", codec);
+        }
+    }
+}
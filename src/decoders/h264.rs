@@ -0,0 +1,434 @@
+use std::time::Instant;
+
+use openh264::{
+    decoder::{DecodedYUV, Decoder, DecoderConfig},
+    formats::YUVSource,
+    OpenH264API,
+};
+use tracing::{trace, warn};
+
+use super::{yuv, DecodedFrame, DecoderError, FrameFormat, ImageDecoder, PixelFormat};
+
+// openh264 setup shared by the H.264 decoders, they only differ in how the picture is converted
+struct H264Base {
+    inner: Decoder,
+    dbg: bool,
+}
+
+impl H264Base {
+    fn new(dbg: bool) -> Result<Self, DecoderError> {
+        let decoder =
+            Decoder::with_api_config(OpenH264API::from_source(), DecoderConfig::new().debug(dbg))
+                .map_err(|e| DecoderError::InitFail(e))?;
+        Ok(Self {
+            inner: decoder,
+            dbg,
+        })
+    }
+
+    // openh264 has no way to drop its reference frames, so the decoder is recreated. The old one
+    // is kept if that fails, the next IDR still resyncs it.
+    fn reset(&mut self) {
+        match Self::new(self.dbg) {
+            Ok(base) => *self = base,
+            Err(e) => warn!("failed to recreate the h264 decoder: {}", e),
+        }
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<DecodedYUV<'_>, DecoderError> {
+        self.inner
+            .decode(data)
+            .map_err(|source| DecoderError::DecodeFail {
+                source,
+                input_len: data.len(),
+                annex_b: data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1]),
+            })?
+            .ok_or(DecoderError::NoImageDecoded)
+    }
+}
+
+// See `yuv::write_bgr`, the luma dimensions bound the picture
+fn write_bgr(i: &DecodedYUV<'_>, buf: &mut [u8], bpp: usize) {
+    let (width, height) = i.dimensions();
+    let planes = yuv::Planes {
+        y: i.y(),
+        u: i.u(),
+        v: i.v(),
+        width,
+        height,
+        strides: i.strides(),
+    };
+    yuv::write_bgr(&planes, buf, bpp);
+}
+
+pub struct H264RGBDecoder {
+    base: H264Base,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+impl H264RGBDecoder {
+    // The output buffer is sized from the first decoded picture
+    pub fn new(dbg: bool) -> Result<Self, DecoderError> {
+        Ok(Self {
+            base: H264Base::new(dbg)?,
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+
+    // Allocates the output buffer upfront, it's still resized if the stream turns out different
+    pub fn with_expected_size(mut self, image_size: (usize, usize)) -> Self {
+        self.buf.resize(image_size.0 * image_size.1 * 3, 0);
+        self
+    }
+}
+
+impl ImageDecoder for H264RGBDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        let i = self.base.decode(data)?;
+
+        let b = Instant::now();
+        let (width, height) = i.dimensions();
+        // The camera can switch resolution mid-stream, a new SPS changes the picture size
+        self.buf.resize(width * height * 3, 0);
+        let format = FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Rgb8,
+        };
+        self.format = Some(format);
+        i.write_rgb8(&mut self.buf);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "yuv to rgb");
+        trace!(elapsed_ms = bb.elapsed().as_millis() as u64, "h264 decode");
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    fn reset(&mut self) {
+        self.base.reset();
+    }
+}
+pub struct H264BGRDecoder {
+    base: H264Base,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+impl H264BGRDecoder {
+    // The output buffer is sized from the first decoded picture
+    pub fn new(dbg: bool) -> Result<Self, DecoderError> {
+        Ok(Self {
+            base: H264Base::new(dbg)?,
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+
+    // Allocates the output buffer upfront, it's still resized if the stream turns out different
+    pub fn with_expected_size(mut self, image_size: (usize, usize)) -> Self {
+        self.buf.resize(image_size.0 * image_size.1 * 3, 0);
+        self
+    }
+}
+
+impl ImageDecoder for H264BGRDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        let i = self.base.decode(data)?;
+
+        let b = Instant::now();
+        let (width, height) = i.dimensions();
+        self.buf.resize(width * height * 3, 0);
+        let format = FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Bgr8,
+        };
+        self.format = Some(format);
+        write_bgr(&i, &mut self.buf, 3);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "yuv to bgr");
+        trace!(elapsed_ms = bb.elapsed().as_millis() as u64, "h264 decode");
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    fn reset(&mut self) {
+        self.base.reset();
+    }
+}
+
+// Four bytes per pixel with an opaque alpha, ready for texture upload
+pub struct H264RGBADecoder {
+    base: H264Base,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+impl H264RGBADecoder {
+    pub fn new(dbg: bool) -> Result<Self, DecoderError> {
+        Ok(Self {
+            base: H264Base::new(dbg)?,
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+
+    pub fn with_expected_size(mut self, image_size: (usize, usize)) -> Self {
+        self.buf.resize(image_size.0 * image_size.1 * 4, 0);
+        self
+    }
+}
+
+impl ImageDecoder for H264RGBADecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        let i = self.base.decode(data)?;
+
+        let b = Instant::now();
+        let (width, height) = i.dimensions();
+        self.buf.resize(width * height * 4, 0);
+        let format = FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Rgba8,
+        };
+        self.format = Some(format);
+        // Alpha is written in the same pass as the color
+        i.write_rgba8(&mut self.buf);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "yuv to rgba");
+        trace!(elapsed_ms = bb.elapsed().as_millis() as u64, "h264 decode");
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    fn reset(&mut self) {
+        self.base.reset();
+    }
+}
+
+pub struct H264BGRADecoder {
+    base: H264Base,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+impl H264BGRADecoder {
+    pub fn new(dbg: bool) -> Result<Self, DecoderError> {
+        Ok(Self {
+            base: H264Base::new(dbg)?,
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+
+    pub fn with_expected_size(mut self, image_size: (usize, usize)) -> Self {
+        self.buf.resize(image_size.0 * image_size.1 * 4, 0);
+        self
+    }
+}
+
+impl ImageDecoder for H264BGRADecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        let i = self.base.decode(data)?;
+
+        let b = Instant::now();
+        let (width, height) = i.dimensions();
+        self.buf.resize(width * height * 4, 0);
+        let format = FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Bgra8,
+        };
+        self.format = Some(format);
+        // Alpha is written in the same pass as the color
+        write_bgr(&i, &mut self.buf, 4);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "yuv to bgra");
+        trace!(elapsed_ms = bb.elapsed().as_millis() as u64, "h264 decode");
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    fn reset(&mut self) {
+        self.base.reset();
+    }
+}
+
+// Planar I420 straight from openh264, without any color conversion
+pub struct H264YUVDecoder {
+    base: H264Base,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+impl H264YUVDecoder {
+    pub fn new(dbg: bool) -> Result<Self, DecoderError> {
+        Ok(Self {
+            base: H264Base::new(dbg)?,
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+}
+
+impl ImageDecoder for H264YUVDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        let i = self.base.decode(data)?;
+
+        let b = Instant::now();
+        let (width, height) = i.dimensions();
+        let (width_uv, height_uv) = i.dimensions_uv();
+        let strides = i.strides();
+        // openh264 pads every plane up to its stride, the output planes are packed
+        self.buf.clear();
+        for (plane, stride, w, h) in [
+            (i.y(), strides.0, width, height),
+            (i.u(), strides.1, width_uv, height_uv),
+            (i.v(), strides.2, width_uv, height_uv),
+        ] {
+            for row in 0..h {
+                self.buf
+                    .extend_from_slice(&plane[row * stride..row * stride + w]);
+            }
+        }
+        let format = FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Yuv420Planar,
+        };
+        self.format = Some(format);
+        trace!(
+            elapsed_ms = b.elapsed().as_millis() as u64,
+            "yuv planes copy"
+        );
+        trace!(elapsed_ms = bb.elapsed().as_millis() as u64, "h264 decode");
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    fn reset(&mut self) {
+        self.base.reset();
+    }
+}
+
+// Luminance only, one byte per pixel. The chroma planes are never touched.
+pub struct H264GrayDecoder {
+    base: H264Base,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+impl H264GrayDecoder {
+    pub fn new(dbg: bool) -> Result<Self, DecoderError> {
+        Ok(Self {
+            base: H264Base::new(dbg)?,
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+
+    pub fn with_expected_size(mut self, image_size: (usize, usize)) -> Self {
+        self.buf.resize(image_size.0 * image_size.1, 0);
+        self
+    }
+}
+
+impl ImageDecoder for H264GrayDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        let i = self.base.decode(data)?;
+
+        let b = Instant::now();
+        let (width, height) = i.dimensions();
+        // Rows of the Y plane are padded up to the stride
+        let stride = i.strides().0;
+        let plane = i.y();
+        self.buf.clear();
+        for row in 0..height {
+            self.buf
+                .extend_from_slice(&plane[row * stride..row * stride + width]);
+        }
+        let format = FrameFormat {
+            width,
+            height,
+            pixel_format: PixelFormat::Gray8,
+        };
+        self.format = Some(format);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "y plane copy");
+        trace!(elapsed_ms = bb.elapsed().as_millis() as u64, "h264 decode");
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    fn reset(&mut self) {
+        self.base.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::rtsp_session::fixtures, decoders::avcc_to_annex_b};
+
+    #[test]
+    fn new_sps_resizes_the_output() {
+        let mut decoder = H264RGBDecoder::new(false).unwrap();
+        let mut previous = None;
+        for (width_mbs, height_mbs, luma) in [(1, 1, 60), (2, 1, 80), (1, 2, 100), (1, 1, 120)] {
+            let au = fixtures::sized_iframe(width_mbs, height_mbs, luma);
+            let frame = decoder
+                .decode_frame(&avcc_to_annex_b(&au, 4).unwrap())
+                .unwrap();
+            let (width, height) = (width_mbs * fixtures::WIDTH, height_mbs * fixtures::HEIGHT);
+            assert_eq!((frame.format.width, frame.format.height), (width, height));
+            assert_eq!(frame.data.len(), width * height * 3);
+            // Every pixel of the new picture, none left over from the old size
+            let gray = frame.data[0];
+            assert!(frame.data.iter().all(|&b| b == gray));
+            assert_ne!(previous, Some(gray));
+            previous = Some(gray);
+        }
+        assert_eq!(
+            decoder.output_format().map(|f| (f.width, f.height)),
+            Some((fixtures::WIDTH, fixtures::HEIGHT))
+        );
+    }
+
+    #[test]
+    fn new_idr_decodes_after_a_reset() {
+        let mut decoder = H264RGBDecoder::new(false).unwrap();
+        // Before anything was decoded too
+        decoder.reset();
+        for luma in [60, 80] {
+            let iframe = avcc_to_annex_b(&fixtures::iframe(luma), 4).unwrap();
+            let pframe = avcc_to_annex_b(&fixtures::pframe(1), 4).unwrap();
+            let gray = decoder.decode_frame(&iframe).unwrap().data[0];
+            assert_eq!(decoder.decode_frame(&pframe).unwrap().data[0], gray);
+            decoder.reset();
+        }
+        let iframe = avcc_to_annex_b(&fixtures::iframe(100), 4).unwrap();
+        let frame = decoder.decode_frame(&iframe).unwrap();
+        assert_eq!(frame.data.len(), fixtures::WIDTH * fixtures::HEIGHT * 3);
+    }
+}
@@ -0,0 +1,221 @@
+use std::{ffi::CString, path::PathBuf, ptr, time::Instant};
+
+use ffmpeg_next::{
+    codec,
+    decoder::{self, Video},
+    ffi,
+    format::Pixel,
+    frame,
+    software::scaling::{self, Flags},
+    Packet,
+};
+use tracing::trace;
+
+use super::{DecodedFrame, DecoderError, FrameFormat, ImageDecoder, PixelFormat};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HardwareDevice {
+    // DRM render node, e.g. `/dev/dri/renderD128`
+    Vaapi(PathBuf),
+    // GPU index
+    Cuda(usize),
+}
+
+// Decodes H.264 Annex B on the GPU, taking the same input as `H264RGBDecoder`. Every frame is
+// downloaded from the device as NV12, which costs a full frame copy over the bus, and then
+// converted on the CPU unless NV12 output was asked for.
+pub struct HardwareDecoder {
+    inner: Video,
+    output: PixelFormat,
+    scaler: Option<scaling::Context>,
+    decoded: frame::Video,
+    downloaded: frame::Video,
+    converted: frame::Video,
+    buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+// The ffmpeg contexts are only ever touched through `&mut self`
+unsafe impl Send for HardwareDecoder {}
+unsafe impl Sync for HardwareDecoder {}
+
+impl HardwareDecoder {
+    // `output` is one of `Rgb8`, `Bgr8` or `Nv12`. Fails with `HardwareUnavailable` when the
+    // device can't be opened or has no H.264 decoder, the caller can then fall back to
+    // `H264RGBDecoder`.
+    pub fn new(device: HardwareDevice, output: PixelFormat) -> Result<Self, DecoderError> {
+        if !matches!(
+            output,
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 | PixelFormat::Nv12
+        ) {
+            return Err(DecoderError::UnsupportedInput(output));
+        }
+        ffmpeg_next::init().map_err(DecoderError::HardwareFail)?;
+        let codec = decoder::find(codec::Id::H264).ok_or_else(|| {
+            DecoderError::HardwareUnavailable("ffmpeg has no h264 decoder".to_string())
+        })?;
+
+        let (kind, name) = match &device {
+            HardwareDevice::Vaapi(path) => (
+                ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+                path.to_string_lossy().to_string(),
+            ),
+            HardwareDevice::Cuda(index) => (
+                ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_CUDA,
+                index.to_string(),
+            ),
+        };
+        let name = CString::new(name)
+            .map_err(|_| DecoderError::HardwareUnavailable(format!("{:?}", device)))?;
+
+        let mut context = codec::Context::new_with_codec(codec);
+        unsafe {
+            let mut device_ctx = ptr::null_mut();
+            let res = ffi::av_hwdevice_ctx_create(
+                &mut device_ctx,
+                kind,
+                name.as_ptr(),
+                ptr::null_mut(),
+                0,
+            );
+            if res < 0 {
+                return Err(DecoderError::HardwareUnavailable(format!(
+                    "failed to open {:?}: {}",
+                    device,
+                    ffmpeg_next::Error::from(res)
+                )));
+            }
+            (*context.as_mut_ptr()).hw_device_ctx = ffi::av_buffer_ref(device_ctx);
+            ffi::av_buffer_unref(&mut device_ctx);
+        }
+        let inner = context
+            .decoder()
+            .video()
+            .map_err(DecoderError::HardwareFail)?;
+
+        Ok(Self {
+            inner,
+            output,
+            scaler: None,
+            decoded: frame::Video::empty(),
+            downloaded: frame::Video::empty(),
+            converted: frame::Video::empty(),
+            buf: Vec::new(),
+            format: None,
+        })
+    }
+
+    // Copies the device surface to system memory, returning whether it did. ffmpeg falls back to
+    // software decoding on its own for streams the device can't handle, those frames are already
+    // in memory.
+    fn download(&mut self) -> Result<bool, DecoderError> {
+        let on_device = matches!(self.decoded.format(), Pixel::VAAPI | Pixel::CUDA);
+        if !on_device {
+            return Ok(false);
+        }
+        let res = unsafe {
+            ffi::av_hwframe_transfer_data(self.downloaded.as_mut_ptr(), self.decoded.as_ptr(), 0)
+        };
+        if res < 0 {
+            return Err(DecoderError::HardwareFail(ffmpeg_next::Error::from(res)));
+        }
+        Ok(true)
+    }
+}
+
+// Copies the visible part of each plane, dropping the stride padding
+fn pack_planes(frame: &frame::Video, planes: &[(usize, usize)], buf: &mut Vec<u8>) {
+    buf.clear();
+    for (plane, (row_bytes, rows)) in planes.iter().enumerate() {
+        let stride = frame.stride(plane);
+        let data = frame.data(plane);
+        for row in 0..*rows {
+            buf.extend_from_slice(&data[row * stride..row * stride + row_bytes]);
+        }
+    }
+}
+
+impl ImageDecoder for HardwareDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let bb = Instant::now();
+        self.inner
+            .send_packet(&Packet::copy(data))
+            .map_err(DecoderError::HardwareFail)?;
+        if self.inner.receive_frame(&mut self.decoded).is_err() {
+            return Err(DecoderError::NoImageDecoded);
+        }
+
+        let b = Instant::now();
+        let output = self.output;
+        let (width, height) = (self.decoded.width(), self.decoded.height());
+        let frame = if self.download()? {
+            &self.downloaded
+        } else {
+            &self.decoded
+        };
+        let source = frame.format();
+        let (w, h) = (width as usize, height as usize);
+        if output == PixelFormat::Nv12 && source == Pixel::NV12 {
+            let chroma = (w.div_ceil(2) * 2, h.div_ceil(2));
+            pack_planes(frame, &[(w, h), chroma], &mut self.buf);
+        } else {
+            let target = match output {
+                PixelFormat::Bgr8 => Pixel::BGR24,
+                PixelFormat::Nv12 => Pixel::NV12,
+                _ => Pixel::RGB24,
+            };
+            let stale = self.scaler.as_ref().map_or(true, |s| {
+                let input = s.input();
+                input.format != source || input.width != width || input.height != height
+            });
+            if stale {
+                self.scaler = Some(
+                    scaling::Context::get(
+                        source,
+                        width,
+                        height,
+                        target,
+                        width,
+                        height,
+                        Flags::BILINEAR,
+                    )
+                    .map_err(DecoderError::HardwareFail)?,
+                );
+            }
+            if let Some(scaler) = self.scaler.as_mut() {
+                scaler
+                    .run(frame, &mut self.converted)
+                    .map_err(DecoderError::HardwareFail)?;
+            }
+            let planes: &[(usize, usize)] = match output {
+                PixelFormat::Nv12 => &[(w, h), (w.div_ceil(2) * 2, h.div_ceil(2))],
+                _ => &[(w * 3, h)],
+            };
+            pack_planes(&self.converted, planes, &mut self.buf);
+        }
+
+        let format = FrameFormat {
+            width: w,
+            height: h,
+            pixel_format: output,
+        };
+        self.format = Some(format);
+        trace!(
+            elapsed_ms = b.elapsed().as_millis() as u64,
+            "surface download"
+        );
+        trace!(
+            elapsed_ms = bb.elapsed().as_millis() as u64,
+            "hardware decode"
+        );
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+
+    fn reset(&mut self) {
+        self.inner.flush();
+    }
+}
@@ -1,225 +1,965 @@
-use std::time::Instant;
+mod async_decoder;
+mod avcc;
+mod buffer_pool;
+mod chain;
+mod decoder_pool;
+#[cfg(feature = "ffmpeg")]
+mod ffmpeg;
+mod h264;
+#[cfg(feature = "hwaccel")]
+mod hardware;
+#[cfg(feature = "hevc")]
+mod hevc;
+#[cfg(test)]
+pub(crate) mod testing;
+mod yuv;
 
-use openh264::{
-    decoder::{Decoder, DecoderConfig},
-    formats::YUVSource,
-    OpenH264API,
+use std::{fmt, time::Instant};
+
+use tracing::trace;
+
+pub use async_decoder::{
+    AsyncChain, AsyncChainedDecoder, AsyncImageDecoder, OwnedFrame, SyncDecoder,
+};
+pub use avcc::{annex_b_to_avcc, avcc_to_annex_b, AVCCDecoder, AnnexBToAVCC, NalType};
+pub use buffer_pool::{BufferPool, PooledBuffer};
+pub use chain::{Chain, ChainedDecoder, DecoderPipeline};
+pub use decoder_pool::{Affinity, DecoderPool, DecoderPoolConfig, PooledDecoder, Saturation};
+#[cfg(feature = "ffmpeg")]
+pub use ffmpeg::{FfmpegCodec, FfmpegDecoder};
+pub use h264::{
+    H264BGRADecoder, H264BGRDecoder, H264GrayDecoder, H264RGBADecoder, H264RGBDecoder,
+    H264YUVDecoder,
 };
+#[cfg(feature = "hwaccel")]
+pub use hardware::{HardwareDecoder, HardwareDevice};
+#[cfg(feature = "hevc")]
+pub use hevc::H265RGBDecoder;
 
 #[derive(Debug)]
 pub enum DecoderError {
     InitFail(openh264::Error),
-    DecodeFail(openh264::Error),
+    DecodeFail {
+        source: openh264::Error,
+        input_len: usize,
+        // Whether the input started with a start code, i.e. it went through `AVCCDecoder`
+        annex_b: bool,
+    },
     NoImageDecoded,
-    FieldOutOfBounds,
-    NalOutofBounds,
+    IndexOutOfBounds,
+    // Frames the requested one depends on are no longer buffered
+    MissingReference,
+    // The frame at `index` of the GOP failed to decode, neither it nor the frames after it are
+    // retried
+    FailedReference {
+        index: usize,
+    },
+    // `offset` is where the length field starts, `head` holds the first bytes from there
+    FieldOutOfBounds {
+        offset: usize,
+        remaining: usize,
+        head: Vec<u8>,
+    },
+    NalOutOfBounds {
+        offset: usize,
+        nal_size: usize,
+        buffer_len: usize,
+        nal_index: usize,
+        head: Vec<u8>,
+    },
+    // AVCC length prefixes are 1, 2 or 4 bytes
+    InvalidLengthSize(usize),
+    // The data handed to `AVCCDecoder` already has start codes, the stage isn't needed
+    UnexpectedAnnexB,
+    // Empty NAL or forbidden zero bit set, `offset` is where the NAL starts in the input
+    MalformedNal {
+        offset: usize,
+    },
+    // Nothing to decode, the input was empty or every NAL in it was empty or dropped
+    EmptyAccessUnit,
+    // More NALs than `AVCCDecoder::with_max_nals` allows in one access unit
+    TooManyNals {
+        limit: usize,
+    },
+    // `AnnexBToAVCC` input without a single start code, `head` holds its first bytes
+    NoStartCode {
+        len: usize,
+        head: Vec<u8>,
+    },
+    // `len` bytes that aren't zero padding come before the first start code
+    LeadingGarbage {
+        len: usize,
+        head: Vec<u8>,
+    },
+    JpegFail(turbojpeg::Error),
+    JpegEncodeFail(turbojpeg::Error),
+    PngEncodeFail(png::EncodingError),
+    // The buffer handed to a stage built for a fixed size doesn't match it
+    InputSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    // The stage only works on packed pixel formats
+    UnsupportedInput(PixelFormat),
+    EmptyPipeline,
+    // The camera's `DecoderPool` worker has `queue_depth` frames queued, see `Saturation::Reject`
+    PoolSaturated {
+        worker: usize,
+    },
+    // The pool worker stopped before answering
+    PoolClosed,
+    CropOutOfBounds {
+        rect: (usize, usize, usize, usize),
+        src: (usize, usize),
+    },
+    // A `ResizeDecoder` from or to a picture without pixels
+    EmptyResize {
+        src: (usize, usize),
+        dst: (usize, usize),
+    },
+    // Carries the ffmpeg error code, `i32::from` gets it back
+    #[cfg(feature = "ffmpeg")]
+    FfmpegFail(ffmpeg_next::Error),
+    // The linked ffmpeg was built without the decoder
+    #[cfg(feature = "ffmpeg")]
+    UnsupportedCodec(FfmpegCodec),
+    #[cfg(feature = "hwaccel")]
+    HardwareFail(ffmpeg_next::Error),
+    // The device can't be opened or can't decode the codec, fall back to a software decoder
+    #[cfg(feature = "hwaccel")]
+    HardwareUnavailable(String),
+}
+
+impl fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InitFail(e) => write!(f, "failed to initialize the decoder: {}", e),
+            Self::DecodeFail {
+                source,
+                input_len,
+                annex_b,
+            } => write!(
+                f,
+                "decoder rejected {} bytes of {}: {}",
+                input_len,
+                if *annex_b {
+                    "annex b"
+                } else {
+                    "data without a start code"
+                },
+                source
+            ),
+            Self::NoImageDecoded => write!(f, "decoder did not produce an image"),
+            Self::IndexOutOfBounds => write!(f, "requested frame is not buffered"),
+            Self::MissingReference => write!(f, "reference frames were evicted from the buffer"),
+            Self::FailedReference { index } => {
+                write!(f, "frame {} of the gop failed to decode earlier", index)
+            }
+            Self::FieldOutOfBounds {
+                offset,
+                remaining,
+                head,
+            } => write!(
+                f,
+                "NAL length field at byte {} runs past the buffer, {} bytes left: {:02x?}",
+                offset, remaining, head
+            ),
+            Self::NalOutOfBounds {
+                offset,
+                nal_size,
+                buffer_len,
+                nal_index,
+                head,
+            } => write!(
+                f,
+                "NAL {} at byte {} claims {} bytes but the buffer is {} bytes: {:02x?}",
+                nal_index, offset, nal_size, buffer_len, head
+            ),
+            Self::InvalidLengthSize(n) => write!(f, "invalid NAL length prefix size {}", n),
+            Self::UnexpectedAnnexB => write!(f, "data is already in Annex B format"),
+            Self::MalformedNal { offset } => write!(f, "malformed NAL header at byte {}", offset),
+            Self::EmptyAccessUnit => write!(f, "access unit holds no NAL data"),
+            Self::TooManyNals { limit } => {
+                write!(f, "access unit has more than {} NALs", limit)
+            }
+            Self::NoStartCode { len, head } => {
+                write!(f, "no start code in {} bytes: {:02x?}", len, head)
+            }
+            Self::LeadingGarbage { len, head } => write!(
+                f,
+                "{} bytes before the first start code: {:02x?}",
+                len, head
+            ),
+            Self::JpegFail(e) => write!(f, "failed to decode jpeg: {}", e),
+            Self::JpegEncodeFail(e) => write!(f, "failed to encode jpeg: {}", e),
+            Self::PngEncodeFail(e) => write!(f, "failed to encode png: {}", e),
+            Self::EmptyPipeline => write!(f, "decoder pipeline has no stages"),
+            Self::PoolSaturated { worker } => {
+                write!(f, "decoder pool worker {} has a full queue", worker)
+            }
+            Self::PoolClosed => write!(f, "decoder pool worker stopped"),
+            Self::CropOutOfBounds { rect, src } => write!(
+                f,
+                "crop {:?} does not fit in a {}x{} picture",
+                rect, src.0, src.1
+            ),
+            Self::EmptyResize { src, dst } => write!(
+                f,
+                "can't resize {}x{} to {}x{}, both need a width and height",
+                src.0, src.1, dst.0, dst.1
+            ),
+            Self::UnsupportedInput(format) => write!(f, "stage can't work on {:?} input", format),
+            Self::InputSizeMismatch { expected, actual } => write!(
+                f,
+                "expected a {} byte image but got {} bytes",
+                expected, actual
+            ),
+            #[cfg(feature = "ffmpeg")]
+            Self::FfmpegFail(e) => write!(f, "ffmpeg failed: {}", e),
+            #[cfg(feature = "ffmpeg")]
+            Self::UnsupportedCodec(codec) => write!(f, "ffmpeg has no {:?} decoder", codec),
+            #[cfg(feature = "hwaccel")]
+            Self::HardwareFail(e) => write!(f, "hardware decoder failed: {}", e),
+            #[cfg(feature = "hwaccel")]
+            Self::HardwareUnavailable(reason) => {
+                write!(f, "hardware decoding unavailable: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InitFail(e) | Self::DecodeFail { source: e, .. } => Some(e),
+            Self::JpegFail(e) | Self::JpegEncodeFail(e) => Some(e),
+            Self::PngEncodeFail(e) => Some(e),
+            #[cfg(feature = "ffmpeg")]
+            Self::FfmpegFail(e) => Some(e),
+            #[cfg(feature = "hwaccel")]
+            Self::HardwareFail(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PixelFormat {
+    Rgb8,
+    Bgr8,
+    // Luminance only, one byte per pixel
+    Gray8,
+    Rgba8,
+    Bgra8,
+    // Y, U and V planes back to back without padding, see `FrameFormat::yuv420_planes`
+    Yuv420Planar,
+    // Y plane followed by the interleaved UV plane, without padding
+    Nv12,
+    // Compressed, `width` and `height` are those of the encoded picture
+    Jpeg,
+    Png,
+    // Access units with start codes, as produced by `AVCCDecoder`. Dimensions are zero, the
+    // picture size is only known once decoded.
+    H264AnnexB,
+    // Access units with 4 byte NAL lengths, as produced by `AnnexBToAVCC`. Dimensions are zero.
+    H264Avcc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    pub width: usize,
+    pub height: usize,
+    pub pixel_format: PixelFormat,
+}
+
+impl PixelFormat {
+    // `None` for compressed and planar formats
+    pub fn bytes_per_pixel(&self) -> Option<usize> {
+        match self {
+            Self::Gray8 => Some(1),
+            Self::Rgb8 | Self::Bgr8 => Some(3),
+            Self::Rgba8 | Self::Bgra8 => Some(4),
+            Self::Jpeg
+            | Self::Png
+            | Self::H264AnnexB
+            | Self::H264Avcc
+            | Self::Yuv420Planar
+            | Self::Nv12 => None,
+        }
+    }
+}
+
+impl FrameFormat {
+    // Offset and length of the Y, U and V planes of a `Yuv420Planar` buffer. Chroma planes are
+    // half the size in each direction, rounded up.
+    pub fn yuv420_planes(&self) -> [(usize, usize); 3] {
+        let luma = self.width * self.height;
+        let chroma = self.width.div_ceil(2) * self.height.div_ceil(2);
+        [(0, luma), (luma, chroma), (luma + chroma, chroma)]
+    }
+
+    // Bytes per row of the first plane, 0 for compressed data
+    pub fn stride(&self) -> usize {
+        match self.pixel_format {
+            PixelFormat::Yuv420Planar | PixelFormat::Nv12 => self.width,
+            format => format.bytes_per_pixel().unwrap_or(0) * self.width,
+        }
+    }
+}
+
+// The output of a stage along with what it holds, borrowed from the stage until its next decode
+#[derive(Debug, Clone, Copy)]
+pub struct DecodedFrame<'a> {
+    pub data: &'a [u8],
+    pub format: FrameFormat,
+    pub stride: usize,
+}
+
+impl<'a> DecodedFrame<'a> {
+    pub fn new(data: &'a [u8], format: FrameFormat) -> Self {
+        Self {
+            data,
+            format,
+            stride: format.stride(),
+        }
+    }
 }
 
 // TODO: Cant decide between caching the buffer into each decoder, or just create the vec in
 // between decoders
 pub trait ImageDecoder: Sync + Send {
-    fn decode(&mut self, data: &[u8]) -> Result<&[u8], DecoderError>;
-}
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError>;
 
-pub trait Chain<T: 'static + ImageDecoder> {
-    fn chain(self, other: T) -> ChainedDecoder;
+    #[deprecated(note = "use `decode_frame`, which also reports the size and pixel format")]
+    fn decode(&mut self, data: &[u8]) -> Result<&[u8], DecoderError> {
+        self.decode_frame(data).map(|f| f.data)
+    }
+
+    // Format of the buffer returned by the last `decode`, if the stage outputs an image
+    fn output_format(&self) -> Option<FrameFormat> {
+        None
+    }
+
+    // Drops any state carried over from earlier frames, called when the stream resyncs on a new
+    // iframe after frames were lost or discarded
+    fn reset(&mut self) {}
 }
 
-pub struct AVCCDecoder {
+// Decodes the JPEG frames of an MJPEG stream to RGB8. Every frame stands on its own, the size is
+// read from each frame's header.
+pub struct MJPEGDecoder {
     buf: Vec<u8>,
+    format: Option<FrameFormat>,
+}
+
+impl Default for MJPEGDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl AVCCDecoder {
+impl MJPEGDecoder {
     pub fn new() -> Self {
-        return Self { buf: Vec::new() };
+        Self {
+            buf: Vec::new(),
+            format: None,
+        }
     }
 }
 
-impl ImageDecoder for AVCCDecoder {
-    fn decode(&mut self, data: &[u8]) -> Result<&[u8], DecoderError> {
+impl ImageDecoder for MJPEGDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
         let b = Instant::now();
-        self.buf.clear();
-        let mut index = 0;
+        let image = turbojpeg::decompress(data, turbojpeg::PixelFormat::RGB)
+            .map_err(DecoderError::JpegFail)?;
+        let format = FrameFormat {
+            width: image.width,
+            height: image.height,
+            pixel_format: PixelFormat::Rgb8,
+        };
+        self.format = Some(format);
+        self.buf = image.pixels;
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "jpeg decode");
+        Ok(DecodedFrame::new(&self.buf, format))
+    }
 
-        while index < data.len() {
-            // Read the 4-byte size field
-            if index + 4 > data.len() {
-                return Err(DecoderError::FieldOutOfBounds);
-            }
+    fn output_format(&self) -> Option<FrameFormat> {
+        self.format
+    }
+}
 
-            let nal_size = u32::from_be_bytes([
-                data[index],
-                data[index + 1],
-                data[index + 2],
-                data[index + 3],
-            ]) as usize;
+// Compresses the RGB8 or BGR8 output of a decoder to JPEG. Only the bytes are handed from one
+// stage to the next, so the size has to be known upfront and frames of any other size are refused.
+pub struct JpegEncoder {
+    size: (usize, usize),
+    input: PixelFormat,
+    quality: i32,
+    buf: Vec<u8>,
+}
 
-            index += 4; // Skip the size field
+impl JpegEncoder {
+    pub fn new(size: (usize, usize)) -> Self {
+        Self {
+            size,
+            input: PixelFormat::Rgb8,
+            quality: 90,
+            buf: Vec::new(),
+        }
+    }
 
-            if index + nal_size > data.len() {
-                return Err(DecoderError::NalOutofBounds);
-            }
+    // 1 to 100
+    pub fn with_quality(mut self, quality: i32) -> Self {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
 
-            // Extract the NAL unit
-            let nal_unit = &data[index..index + nal_size];
-            index += nal_size;
+    // For chaining after `H264BGRDecoder`
+    pub fn with_input_format(mut self, format: PixelFormat) -> Self {
+        self.input = format;
+        self
+    }
 
-            // Prepend the Annex B start code (0x00000001)
-            self.buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
-            self.buf.extend_from_slice(nal_unit);
+    fn format(&self) -> FrameFormat {
+        FrameFormat {
+            width: self.size.0,
+            height: self.size.1,
+            pixel_format: PixelFormat::Jpeg,
         }
-
-        println!(
-            "Avcc decoding time -> {}",
-            Instant::now().duration_since(b).as_millis()
-        );
-        Ok(&self.buf)
     }
 }
 
-impl<T: 'static + ImageDecoder> Chain<T> for AVCCDecoder {
-    fn chain(self, other: T) -> ChainedDecoder {
-        ChainedDecoder {
-            a: Box::new(self),
-            b: Box::new(other),
+impl ImageDecoder for JpegEncoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let b = Instant::now();
+        let (width, height) = self.size;
+        let expected = width * height * 3;
+        if data.len() != expected {
+            return Err(DecoderError::InputSizeMismatch {
+                expected,
+                actual: data.len(),
+            });
         }
+        let format = match self.input {
+            PixelFormat::Bgr8 => turbojpeg::PixelFormat::BGR,
+            _ => turbojpeg::PixelFormat::RGB,
+        };
+        let image = turbojpeg::Image {
+            pixels: data,
+            width,
+            pitch: width * 3,
+            height,
+            format,
+        };
+        let jpeg = turbojpeg::compress(image, self.quality, turbojpeg::Subsamp::Sub2x2)
+            .map_err(DecoderError::JpegEncodeFail)?;
+        self.buf.clear();
+        self.buf.extend_from_slice(&jpeg);
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "jpeg encode");
+        Ok(DecodedFrame::new(&self.buf, self.format()))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(self.format())
     }
 }
 
-pub struct H264RGBDecoder {
-    inner: Decoder,
+// Losslessly compresses the packed output of a decoder to PNG, RGB8 unless told otherwise. Like
+// `JpegEncoder`, it's built for one size and refuses frames of any other.
+pub struct PngEncoder {
+    size: (usize, usize),
+    input: PixelFormat,
+    compression: png::Compression,
     buf: Vec<u8>,
+    // BGR input reordered to RGB, PNG has no BGR color type
+    swizzled: Vec<u8>,
 }
 
-impl H264RGBDecoder {
-    pub fn new(dbg: bool, image_size: (usize, usize)) -> Result<Self, DecoderError> {
-        let decoder =
-            Decoder::with_api_config(OpenH264API::from_source(), DecoderConfig::new().debug(dbg))
-                .map_err(|e| DecoderError::InitFail(e))?;
-        Ok(Self {
-            inner: decoder,
-            buf: vec![0u8; image_size.0 * image_size.1 * 3],
-        })
+impl PngEncoder {
+    pub fn new(size: (usize, usize)) -> Self {
+        Self {
+            size,
+            input: PixelFormat::Rgb8,
+            compression: png::Compression::Fast,
+            buf: Vec::new(),
+            swizzled: Vec::new(),
+        }
+    }
+
+    pub fn with_compression(mut self, compression: png::Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // Rgb8, Bgr8, Rgba8, Bgra8 or Gray8, frames of any other format are refused with
+    // `UnsupportedInput`
+    pub fn with_input_format(mut self, format: PixelFormat) -> Self {
+        self.input = format;
+        self
+    }
+
+    fn format(&self) -> FrameFormat {
+        FrameFormat {
+            width: self.size.0,
+            height: self.size.1,
+            pixel_format: PixelFormat::Png,
+        }
     }
 }
 
-impl ImageDecoder for H264RGBDecoder {
-    fn decode(&mut self, data: &[u8]) -> Result<&[u8], DecoderError> {
-        let bb = Instant::now();
-        let a = self
-            .inner
-            .decode(&data)
-            .map_err(|e| DecoderError::DecodeFail(e))
-            .map(|o| o.ok_or(DecoderError::NoImageDecoded))?
-            .map(|i| {
-                let b = Instant::now();
-                i.write_rgb8(&mut self.buf);
-                println!(
-                    "Took {} ms to write into rgb",
-                    Instant::now().duration_since(b).as_millis()
-                );
-                self.buf.as_slice()
+impl ImageDecoder for PngEncoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let b = Instant::now();
+        let color = match self.input {
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => png::ColorType::Rgb,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => png::ColorType::Rgba,
+            PixelFormat::Gray8 => png::ColorType::Grayscale,
+            format => return Err(DecoderError::UnsupportedInput(format)),
+        };
+        let channels = color.samples();
+        let (width, height) = self.size;
+        let expected = width * height * channels;
+        if data.len() != expected {
+            return Err(DecoderError::InputSizeMismatch {
+                expected,
+                actual: data.len(),
             });
-        println!(
-            "Took {} ms to decode image",
-            Instant::now().duration_since(bb).as_millis()
-        );
-        a
+        }
+        let pixels = match self.input {
+            PixelFormat::Bgr8 | PixelFormat::Bgra8 => {
+                self.swizzled.clear();
+                self.swizzled.extend_from_slice(data);
+                for pixel in self.swizzled.chunks_exact_mut(channels) {
+                    pixel.swap(0, 2);
+                }
+                &self.swizzled[..]
+            }
+            _ => data,
+        };
+
+        // Keeps the allocation of the previous frame
+        self.buf.clear();
+        let mut encoder = png::Encoder::new(&mut self.buf, width as u32, height as u32);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(self.compression);
+        let mut writer = encoder
+            .write_header()
+            .map_err(DecoderError::PngEncodeFail)?;
+        writer
+            .write_image_data(pixels)
+            .map_err(DecoderError::PngEncodeFail)?;
+        writer.finish().map_err(DecoderError::PngEncodeFail)?;
+
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "png encode");
+        Ok(DecodedFrame::new(&self.buf, self.format()))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(self.format())
     }
 }
-pub struct H264BGRDecoder {
-    inner: Decoder,
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+}
+
+// Scales the output of a decoder to a fixed size. Frames of any other size than `src` are refused.
+pub struct ResizeDecoder {
+    src: (usize, usize),
+    dst: (usize, usize),
+    filter: ResizeFilter,
+    input: PixelFormat,
+    // Keeps the aspect ratio, padding the borders with black
+    letterbox: bool,
     buf: Vec<u8>,
 }
 
-impl H264BGRDecoder {
-    pub fn new(dbg: bool, image_size: (usize, usize)) -> Result<Self, DecoderError> {
-        let decoder =
-            Decoder::with_api_config(OpenH264API::from_source(), DecoderConfig::new().debug(dbg))
-                .map_err(|e| DecoderError::InitFail(e))?;
+impl ResizeDecoder {
+    // Fails with `EmptyResize` if either size has a zero dimension
+    pub fn new(
+        src: (usize, usize),
+        dst: (usize, usize),
+        filter: ResizeFilter,
+    ) -> Result<Self, DecoderError> {
+        if src.0 == 0 || src.1 == 0 || dst.0 == 0 || dst.1 == 0 {
+            return Err(DecoderError::EmptyResize { src, dst });
+        }
         Ok(Self {
-            inner: decoder,
-            buf: vec![0u8; image_size.0 * image_size.1 * 3],
+            src,
+            dst,
+            filter,
+            input: PixelFormat::Rgb8,
+            letterbox: false,
+            buf: vec![0u8; dst.0 * dst.1 * 3],
         })
     }
+
+    // Any of the packed formats, e.g. for chaining after `H264BGRDecoder`
+    pub fn with_input_format(mut self, format: PixelFormat) -> Self {
+        self.input = format;
+        let bpp = format.bytes_per_pixel().unwrap_or(3);
+        self.buf.resize(self.dst.0 * self.dst.1 * bpp, 0);
+        self
+    }
+
+    pub fn with_letterbox(mut self, letterbox: bool) -> Self {
+        self.letterbox = letterbox;
+        self
+    }
+
+    // Area of the output the picture is scaled into, as (x, y, width, height)
+    fn target(&self) -> (usize, usize, usize, usize) {
+        let ((sw, sh), (dw, dh)) = (self.src, self.dst);
+        if !self.letterbox {
+            return (0, 0, dw, dh);
+        }
+        let scale = (dw as f32 / sw as f32).min(dh as f32 / sh as f32);
+        let w = ((sw as f32 * scale).round() as usize).clamp(1, dw);
+        let h = ((sh as f32 * scale).round() as usize).clamp(1, dh);
+        ((dw - w) / 2, (dh - h) / 2, w, h)
+    }
+
+    fn format(&self) -> FrameFormat {
+        FrameFormat {
+            width: self.dst.0,
+            height: self.dst.1,
+            pixel_format: self.input,
+        }
+    }
 }
 
-impl ImageDecoder for H264BGRDecoder {
-    fn decode(&mut self, data: &[u8]) -> Result<&[u8], DecoderError> {
-        let bb = Instant::now();
-        let a = self
-            .inner
-            .decode(&data)
-            .map_err(|e| DecoderError::DecodeFail(e))
-            .map(|o| o.ok_or(DecoderError::NoImageDecoded))?
-            .map(|i| {
-                let b = Instant::now();
-                let dim = i.dimensions_uv();
-                let strides = i.strides();
-                let wanted = dim.0 * dim.1 * 3;
-
-                for y in 0..dim.1 {
-                    for x in 0..dim.0 {
-                        let base_tgt = (y * dim.0 + x) * 3;
-                        let base_y = y * strides.0 + x;
-                        let base_u = (y / 2 * strides.1) + (x / 2);
-                        let base_v = (y / 2 * strides.2) + (x / 2);
-
-                        let rgb_pixel = &mut self.buf[base_tgt..base_tgt + 3];
-
-                        let y = i.y()[base_y] as f32;
-                        let u = i.u()[base_u] as f32;
-                        let v = i.v()[base_v] as f32;
-
-                        rgb_pixel[2] = (y + 1.402 * (v - 128.0)) as u8;
-                        rgb_pixel[1] = (y - 0.344 * (u - 128.0) - 0.714 * (v - 128.0)) as u8;
-                        rgb_pixel[0] = (y + 1.772 * (u - 128.0)) as u8;
+impl ImageDecoder for ResizeDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let b = Instant::now();
+        let bpp = self
+            .input
+            .bytes_per_pixel()
+            .ok_or(DecoderError::UnsupportedInput(self.input))?;
+        let ((sw, sh), (dw, _)) = (self.src, self.dst);
+        let expected = sw * sh * bpp;
+        if data.len() != expected {
+            return Err(DecoderError::InputSizeMismatch {
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        let (ox, oy, tw, th) = self.target();
+        if self.letterbox {
+            self.buf.fill(0);
+        }
+        let (sx, sy) = (sw as f32 / tw as f32, sh as f32 / th as f32);
+        for y in 0..th {
+            let row = ((oy + y) * dw + ox) * bpp;
+            for x in 0..tw {
+                let out = &mut self.buf[row + x * bpp..row + (x + 1) * bpp];
+                match self.filter {
+                    ResizeFilter::Nearest => {
+                        let src_x = ((x as f32 + 0.5) * sx) as usize;
+                        let src_y = ((y as f32 + 0.5) * sy) as usize;
+                        let at = (src_y.min(sh - 1) * sw + src_x.min(sw - 1)) * bpp;
+                        out.copy_from_slice(&data[at..at + bpp]);
+                    }
+                    ResizeFilter::Bilinear => {
+                        let fx = ((x as f32 + 0.5) * sx - 0.5).clamp(0.0, (sw - 1) as f32);
+                        let fy = ((y as f32 + 0.5) * sy - 0.5).clamp(0.0, (sh - 1) as f32);
+                        let (x0, y0) = (fx as usize, fy as usize);
+                        let (x1, y1) = ((x0 + 1).min(sw - 1), (y0 + 1).min(sh - 1));
+                        let (wx, wy) = (fx - x0 as f32, fy - y0 as f32);
+                        let px = |x: usize, y: usize, c: usize| data[(y * sw + x) * bpp + c] as f32;
+                        for (c, out) in out.iter_mut().enumerate() {
+                            let top = px(x0, y0, c) * (1.0 - wx) + px(x1, y0, c) * wx;
+                            let bottom = px(x0, y1, c) * (1.0 - wx) + px(x1, y1, c) * wx;
+                            *out = (top * (1.0 - wy) + bottom * wy).round() as u8;
+                        }
                     }
                 }
+            }
+        }
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "resize");
+        Ok(DecodedFrame::new(&self.buf, self.format()))
+    }
 
-                //                i.write_rgb8(&mut self.buf);
-                println!(
-                    "Took {} ms to write into rgb",
-                    Instant::now().duration_since(b).as_millis()
-                );
-                self.buf.as_slice()
-            });
-        println!(
-            "Took {} ms to decode image",
-            Instant::now().duration_since(bb).as_millis()
-        );
-        a
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(self.format())
     }
 }
 
-impl<T: 'static + ImageDecoder> Chain<T> for H264RGBDecoder {
-    fn chain(self, other: T) -> ChainedDecoder {
-        ChainedDecoder {
-            a: Box::new(self),
-            b: Box::new(other),
+// Copies a region of the output of a decoder. Frames of any other size than `src` are refused.
+pub struct CropDecoder {
+    src: (usize, usize),
+    // x, y, width, height
+    rect: (usize, usize, usize, usize),
+    input: PixelFormat,
+    buf: Vec<u8>,
+}
+
+impl CropDecoder {
+    // Fails with `CropOutOfBounds` unless the rect lies fully inside the source picture
+    pub fn new(
+        src: (usize, usize),
+        rect: (usize, usize, usize, usize),
+    ) -> Result<Self, DecoderError> {
+        let (x, y, w, h) = rect;
+        if w == 0 || h == 0 || x + w > src.0 || y + h > src.1 {
+            return Err(DecoderError::CropOutOfBounds { rect, src });
+        }
+        Ok(Self {
+            src,
+            rect,
+            input: PixelFormat::Rgb8,
+            buf: Vec::with_capacity(w * h * 3),
+        })
+    }
+
+    // Any of the packed formats, e.g. for chaining after `H264BGRDecoder`
+    pub fn with_input_format(mut self, format: PixelFormat) -> Self {
+        self.input = format;
+        self
+    }
+
+    fn format(&self) -> FrameFormat {
+        FrameFormat {
+            width: self.rect.2,
+            height: self.rect.3,
+            pixel_format: self.input,
+        }
+    }
+}
+
+impl ImageDecoder for CropDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let b = Instant::now();
+        let bpp = self
+            .input
+            .bytes_per_pixel()
+            .ok_or(DecoderError::UnsupportedInput(self.input))?;
+        let expected = self.src.0 * self.src.1 * bpp;
+        if data.len() != expected {
+            return Err(DecoderError::InputSizeMismatch {
+                expected,
+                actual: data.len(),
+            });
         }
+
+        let (x, y, w, h) = self.rect;
+        let stride = self.src.0 * bpp;
+        self.buf.clear();
+        for row in y..y + h {
+            let start = row * stride + x * bpp;
+            self.buf.extend_from_slice(&data[start..start + w * bpp]);
+        }
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "crop");
+        Ok(DecodedFrame::new(&self.buf, self.format()))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(self.format())
     }
 }
 
-pub struct ChainedDecoder {
-    a: Box<dyn ImageDecoder>,
-    b: Box<dyn ImageDecoder>,
+// Rotations are clockwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
 }
 
-impl ImageDecoder for ChainedDecoder {
-    fn decode(&mut self, data: &[u8]) -> Result<&[u8], DecoderError> {
+impl Transform {
+    fn swaps_dimensions(&self) -> bool {
+        matches!(self, Self::Rotate90 | Self::Rotate270)
+    }
+}
+
+// Rotates or flips the output of a decoder. Frames of any other size than `src` are refused.
+pub struct TransformDecoder {
+    src: (usize, usize),
+    transform: Transform,
+    input: PixelFormat,
+    buf: Vec<u8>,
+}
+
+impl TransformDecoder {
+    // Side of the square blocks the picture is walked in, so both the rows read and the rows
+    // written stay in cache
+    const BLOCK: usize = 32;
+
+    pub fn new(src: (usize, usize), transform: Transform) -> Self {
+        Self {
+            src,
+            transform,
+            input: PixelFormat::Rgb8,
+            buf: vec![0u8; src.0 * src.1 * 3],
+        }
+    }
+
+    // Any of the packed formats, e.g. for chaining after `H264BGRDecoder`
+    pub fn with_input_format(mut self, format: PixelFormat) -> Self {
+        self.input = format;
+        let bpp = format.bytes_per_pixel().unwrap_or(3);
+        self.buf.resize(self.src.0 * self.src.1 * bpp, 0);
+        self
+    }
+
+    fn dst_dimensions(&self) -> (usize, usize) {
+        if self.transform.swaps_dimensions() {
+            (self.src.1, self.src.0)
+        } else {
+            self.src
+        }
+    }
+
+    fn format(&self) -> FrameFormat {
+        let (width, height) = self.dst_dimensions();
+        FrameFormat {
+            width,
+            height,
+            pixel_format: self.input,
+        }
+    }
+}
+
+impl ImageDecoder for TransformDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
         let b = Instant::now();
-        let res = self.b.decode(self.a.decode(data)?);
-        println!("Total decoding time => {}", b.elapsed().as_millis());
-        res
+        let bpp = self
+            .input
+            .bytes_per_pixel()
+            .ok_or(DecoderError::UnsupportedInput(self.input))?;
+        let (sw, sh) = self.src;
+        let expected = sw * sh * bpp;
+        if data.len() != expected {
+            return Err(DecoderError::InputSizeMismatch {
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        let (dw, _) = self.dst_dimensions();
+        let transform = self.transform;
+        let target = |x: usize, y: usize| match transform {
+            Transform::Rotate90 => (sh - 1 - y, x),
+            Transform::Rotate180 => (sw - 1 - x, sh - 1 - y),
+            Transform::Rotate270 => (y, sw - 1 - x),
+            Transform::FlipHorizontal => (sw - 1 - x, y),
+            Transform::FlipVertical => (x, sh - 1 - y),
+        };
+        for by in (0..sh).step_by(Self::BLOCK) {
+            for bx in (0..sw).step_by(Self::BLOCK) {
+                for y in by..(by + Self::BLOCK).min(sh) {
+                    for x in bx..(bx + Self::BLOCK).min(sw) {
+                        let (dx, dy) = target(x, y);
+                        let from = (y * sw + x) * bpp;
+                        let to = (dy * dw + dx) * bpp;
+                        self.buf[to..to + bpp].copy_from_slice(&data[from..from + bpp]);
+                    }
+                }
+            }
+        }
+        trace!(elapsed_ms = b.elapsed().as_millis() as u64, "transform");
+        Ok(DecodedFrame::new(&self.buf, self.format()))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(self.format())
     }
 }
 
-impl<T: 'static + ImageDecoder> Chain<T> for ChainedDecoder {
-    fn chain(self, other: T) -> ChainedDecoder {
-        ChainedDecoder {
-            a: Box::new(self),
-            b: Box::new(other),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height * 3)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect()
+    }
+
+    fn read_png(data: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let mut reader = png::Decoder::new(data).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        pixels.truncate(info.buffer_size());
+        (info, pixels)
+    }
+
+    #[test]
+    fn png_round_trips_the_pixels() {
+        let rgb = gradient(5, 3);
+        for compression in [png::Compression::Fast, png::Compression::Best] {
+            let mut encoder = PngEncoder::new((5, 3)).with_compression(compression);
+            let frame = encoder.decode_frame(&rgb).unwrap();
+            assert_eq!(frame.format.pixel_format, PixelFormat::Png);
+            let (info, pixels) = read_png(frame.data);
+            assert_eq!((info.width, info.height), (5, 3));
+            assert_eq!(info.color_type, png::ColorType::Rgb);
+            assert_eq!(info.bit_depth, png::BitDepth::Eight);
+            assert_eq!(pixels, rgb);
         }
     }
+
+    #[test]
+    fn png_output_buffer_is_reused() {
+        let mut encoder = PngEncoder::new((5, 3));
+        let first = encoder.decode_frame(&gradient(5, 3)).unwrap().data.as_ptr();
+        let second = encoder.decode_frame(&gradient(5, 3)).unwrap().data.as_ptr();
+        assert_eq!(first, second);
+
+        assert!(matches!(
+            encoder.decode_frame(&gradient(4, 3)),
+            Err(DecoderError::InputSizeMismatch {
+                expected: 45,
+                actual: 36
+            })
+        ));
+    }
+
+    #[test]
+    fn png_takes_bgr_and_gray_input() {
+        let bgr = gradient(5, 3);
+        let mut encoder = PngEncoder::new((5, 3)).with_input_format(PixelFormat::Bgr8);
+        let (info, pixels) = read_png(encoder.decode_frame(&bgr).unwrap().data);
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        let rgb: Vec<u8> = bgr.chunks(3).flat_map(|p| [p[2], p[1], p[0]]).collect();
+        assert_eq!(pixels, rgb);
+
+        let gray: Vec<u8> = (0..15).collect();
+        let mut encoder = PngEncoder::new((5, 3)).with_input_format(PixelFormat::Gray8);
+        let (info, pixels) = read_png(encoder.decode_frame(&gray).unwrap().data);
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        assert_eq!(pixels, gray);
+
+        let mut encoder = PngEncoder::new((5, 3)).with_input_format(PixelFormat::Nv12);
+        assert!(matches!(
+            encoder.decode_frame(&gray),
+            Err(DecoderError::UnsupportedInput(PixelFormat::Nv12))
+        ));
+    }
+
+    #[test]
+    fn resize_refuses_empty_sizes() {
+        for (src, dst) in [
+            ((0, 3), (2, 2)),
+            ((5, 0), (2, 2)),
+            ((5, 3), (0, 2)),
+            ((5, 3), (2, 0)),
+        ] {
+            assert!(matches!(
+                ResizeDecoder::new(src, dst, ResizeFilter::Bilinear),
+                Err(DecoderError::EmptyResize { .. })
+            ));
+        }
+
+        let mut resize = ResizeDecoder::new((5, 3), (1, 1), ResizeFilter::Nearest)
+            .unwrap()
+            .with_letterbox(true);
+        assert_eq!(resize.decode_frame(&gradient(5, 3)).unwrap().data.len(), 3);
+    }
 }
@@ -0,0 +1,134 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use super::{DecodedFrame, DecoderError, FrameFormat, ImageDecoder, PixelFormat};
+
+// What a `MockDecoder` was asked to do, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Call {
+    // With the id of the frame, see `frame`
+    Decode(u8),
+    Reset,
+}
+
+// Shared with the decoder, it keeps recording after being moved into a session or pool
+#[derive(Clone, Default)]
+pub(crate) struct CallLog(Arc<Mutex<Vec<Call>>>);
+
+impl CallLog {
+    fn push(&self, call: Call) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).push(call);
+    }
+
+    pub(crate) fn calls(&self) -> Vec<Call> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Ids of the frames fed, resets left out
+    pub(crate) fn decoded(&self) -> Vec<u8> {
+        self.calls()
+            .into_iter()
+            .filter_map(|c| match c {
+                Call::Decode(id) => Some(id),
+                Call::Reset => None,
+            })
+            .collect()
+    }
+}
+
+// AVCC access unit of a single NAL whose last byte is `id`, which is all `MockDecoder` looks at
+pub(crate) fn frame(id: u8) -> Vec<u8> {
+    vec![0, 0, 0, 2, 0x41, id]
+}
+
+// Stands in for a real decoder. Every picture is two gray pixels, the decoder's tag and the id of
+// the frame it came from, so tests can tell which decoder produced what out of which frame.
+pub(crate) struct MockDecoder {
+    tag: u8,
+    log: CallLog,
+    delay: Duration,
+    // Ids that fail with `MalformedNal`
+    failing: Vec<u8>,
+    // Pictures held back like for a stream with B-frames, ids being the presentation order
+    reorder: usize,
+    held: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl MockDecoder {
+    pub(crate) fn new(tag: u8) -> Self {
+        Self {
+            tag,
+            log: CallLog::default(),
+            delay: Duration::ZERO,
+            failing: Vec::new(),
+            reorder: 0,
+            held: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    // Every decode takes this long
+    pub(crate) fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub(crate) fn failing_on(mut self, id: u8) -> Self {
+        self.failing.push(id);
+        self
+    }
+
+    // Pictures come out `depth` frames late, lowest id first
+    pub(crate) fn with_reorder(mut self, depth: usize) -> Self {
+        self.reorder = depth;
+        self
+    }
+
+    pub(crate) fn log(&self) -> CallLog {
+        self.log.clone()
+    }
+
+    fn format(&self) -> FrameFormat {
+        FrameFormat {
+            width: 2,
+            height: 1,
+            pixel_format: PixelFormat::Gray8,
+        }
+    }
+}
+
+impl ImageDecoder for MockDecoder {
+    fn decode_frame(&mut self, data: &[u8]) -> Result<DecodedFrame<'_>, DecoderError> {
+        let id = data.last().copied().unwrap_or_default();
+        self.log.push(Call::Decode(id));
+        if !self.delay.is_zero() {
+            thread::sleep(self.delay);
+        }
+        if self.failing.contains(&id) {
+            return Err(DecoderError::MalformedNal { offset: 0 });
+        }
+        self.held.push(id);
+        if self.held.len() <= self.reorder {
+            return Err(DecoderError::NoImageDecoded);
+        }
+        let next = (0..self.held.len())
+            .min_by_key(|i| self.held[*i])
+            .unwrap_or_default();
+        let id = self.held.remove(next);
+        self.buf = vec![self.tag, id];
+        Ok(DecodedFrame::new(&self.buf, self.format()))
+    }
+
+    fn output_format(&self) -> Option<FrameFormat> {
+        Some(self.format())
+    }
+
+    fn reset(&mut self) {
+        self.log.push(Call::Reset);
+        self.held.clear();
+    }
+}
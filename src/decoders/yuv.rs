@@ -0,0 +1,364 @@
+// BT.601 in fixed point, coefficients scaled by 2^14. Rows go in pairs so each chroma sample is
+// loaded and weighted once for the four pixels sharing it. `bpp` of 4 fills in an opaque alpha.
+// With the `simd` feature runs of 8 pixels go through SSE4.1 or NEON, which give the exact same
+// bytes as the scalar loop.
+const SHIFT: i32 = 14;
+const ROUND: i32 = 1 << (SHIFT - 1);
+const V_R: i32 = 22970;
+const U_G: i32 = 5636;
+const V_G: i32 = 11698;
+const U_B: i32 = 29032;
+
+// An I420 picture, the chroma planes are subsampled by 2 both ways
+pub(super) struct Planes<'a> {
+    pub(super) y: &'a [u8],
+    pub(super) u: &'a [u8],
+    pub(super) v: &'a [u8],
+    // Of the luma plane
+    pub(super) width: usize,
+    pub(super) height: usize,
+    // Of the y, u and v planes
+    pub(super) strides: (usize, usize, usize),
+}
+
+pub(super) fn write_bgr(p: &Planes<'_>, buf: &mut [u8], bpp: usize) {
+    let clamp = |v: i32| (v >> SHIFT).clamp(0, 255) as u8;
+    let (stride_y, stride_u, stride_v) = p.strides;
+
+    for cy in 0..p.height.div_ceil(2) {
+        let rows = 2 * cy..(2 * cy + 2).min(p.height);
+        // Chroma columns done by the vector path, the scalar loop takes the rest
+        let done = vector::write_rows(p, buf, bpp, cy);
+        for cx in done..p.width.div_ceil(2) {
+            let u = p.u[cy * stride_u + cx] as i32 - 128;
+            let v = p.v[cy * stride_v + cx] as i32 - 128;
+            let (r, g, b) = (V_R * v, -U_G * u - V_G * v, U_B * u);
+
+            for row in rows.clone() {
+                for x in 2 * cx..(2 * cx + 2).min(p.width) {
+                    let y = ((p.y[row * stride_y + x] as i32) << SHIFT) + ROUND;
+                    let at = (row * p.width + x) * bpp;
+                    let pixel = &mut buf[at..at + bpp];
+                    pixel[0] = clamp(y + b);
+                    pixel[1] = clamp(y + g);
+                    pixel[2] = clamp(y + r);
+                    if bpp == 4 {
+                        pixel[3] = 255;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod vector {
+    use super::Planes;
+
+    pub(super) fn write_rows(_: &Planes<'_>, _: &mut [u8], _: usize, _: usize) -> usize {
+        0
+    }
+}
+
+// Blocks of 4 chroma samples, 8 pixels of each of the two rows. The channels are computed in
+// 32 bit lanes and narrowed with saturation, which is the same clamp the scalar loop does.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod vector {
+    use std::arch::x86_64::*;
+
+    use super::{Planes, ROUND, SHIFT, U_B, U_G, V_G, V_R};
+
+    pub(super) fn write_rows(p: &Planes<'_>, buf: &mut [u8], bpp: usize, cy: usize) -> usize {
+        if !is_x86_feature_detected!("sse4.1") {
+            return 0;
+        }
+        // Safe to call, SSE4.1 was detected
+        unsafe { write_rows_sse41(p, buf, bpp, cy) }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn write_rows_sse41(p: &Planes<'_>, buf: &mut [u8], bpp: usize, cy: usize) -> usize {
+        let (stride_y, stride_u, stride_v) = p.strides;
+        let blocks = p.width / 8;
+        let rows = 2 * cy..(2 * cy + 2).min(p.height);
+
+        for block in 0..blocks {
+            let cx = block * 4;
+            let u = chroma(&p.u[cy * stride_u + cx..][..4]);
+            let v = chroma(&p.v[cy * stride_v + cx..][..4]);
+            let r = _mm_mullo_epi32(v, _mm_set1_epi32(V_R));
+            let g = _mm_sub_epi32(
+                _mm_setzero_si128(),
+                _mm_add_epi32(
+                    _mm_mullo_epi32(u, _mm_set1_epi32(U_G)),
+                    _mm_mullo_epi32(v, _mm_set1_epi32(V_G)),
+                ),
+            );
+            let b = _mm_mullo_epi32(u, _mm_set1_epi32(U_B));
+            let channels = [spread(b), spread(g), spread(r)];
+
+            for row in rows.clone() {
+                let x = block * 8;
+                let luma =
+                    _mm_loadl_epi64(p.y[row * stride_y + x..][..8].as_ptr() as *const __m128i);
+                let y_lo = scale_luma(_mm_cvtepu8_epi32(luma));
+                let y_hi = scale_luma(_mm_cvtepu8_epi32(_mm_srli_si128::<4>(luma)));
+
+                let mut out = [[0u8; 8]; 3];
+                for (channel, (lo, hi)) in out.iter_mut().zip(channels) {
+                    let lo = _mm_srai_epi32::<SHIFT>(_mm_add_epi32(y_lo, lo));
+                    let hi = _mm_srai_epi32::<SHIFT>(_mm_add_epi32(y_hi, hi));
+                    let packed = _mm_packus_epi16(_mm_packs_epi32(lo, hi), _mm_setzero_si128());
+                    _mm_storel_epi64(channel.as_mut_ptr() as *mut __m128i, packed);
+                }
+
+                let start = (row * p.width + x) * bpp;
+                for (i, pixel) in buf[start..start + 8 * bpp]
+                    .chunks_exact_mut(bpp)
+                    .enumerate()
+                {
+                    pixel[0] = out[0][i];
+                    pixel[1] = out[1][i];
+                    pixel[2] = out[2][i];
+                    if bpp == 4 {
+                        pixel[3] = 255;
+                    }
+                }
+            }
+        }
+        blocks * 4
+    }
+
+    // 4 samples, centered on 0
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn chroma(samples: &[u8]) -> __m128i {
+        let mut packed = [0u8; 4];
+        packed.copy_from_slice(samples);
+        let packed = _mm_cvtsi32_si128(i32::from_le_bytes(packed));
+        _mm_sub_epi32(_mm_cvtepu8_epi32(packed), _mm_set1_epi32(128))
+    }
+
+    // Each chroma sample covers two pixels of the row
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn spread(c: __m128i) -> (__m128i, __m128i) {
+        (_mm_unpacklo_epi32(c, c), _mm_unpackhi_epi32(c, c))
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn scale_luma(y: __m128i) -> __m128i {
+        _mm_add_epi32(_mm_slli_epi32::<SHIFT>(y), _mm_set1_epi32(ROUND))
+    }
+}
+
+// Blocks of 4 chroma samples, 8 pixels of each of the two rows, stored interleaved by `vst3` or
+// `vst4`. `vqshrun` and `vqmovn` narrow with saturation, the same clamp the scalar loop does.
+#[cfg(all(feature = "simd", target_arch = "aarch64"))]
+mod vector {
+    use std::arch::aarch64::*;
+
+    use super::{Planes, ROUND, SHIFT, U_B, U_G, V_G, V_R};
+
+    pub(super) fn write_rows(p: &Planes<'_>, buf: &mut [u8], bpp: usize, cy: usize) -> usize {
+        // NEON is part of every aarch64 target
+        unsafe { write_rows_neon(p, buf, bpp, cy) }
+    }
+
+    unsafe fn write_rows_neon(p: &Planes<'_>, buf: &mut [u8], bpp: usize, cy: usize) -> usize {
+        let (stride_y, stride_u, stride_v) = p.strides;
+        let blocks = p.width / 8;
+        let rows = 2 * cy..(2 * cy + 2).min(p.height);
+        let round = vdupq_n_s32(ROUND);
+
+        for block in 0..blocks {
+            let cx = block * 4;
+            let chroma = |plane: &[u8], at: usize| {
+                let mut samples = [0u8; 8];
+                samples[..4].copy_from_slice(&plane[at..at + 4]);
+                let wide = vreinterpretq_s16_u16(vmovl_u8(vld1_u8(samples.as_ptr())));
+                vget_low_s16(vsubq_s16(wide, vdupq_n_s16(128)))
+            };
+            let u = chroma(p.u, cy * stride_u + cx);
+            let v = chroma(p.v, cy * stride_v + cx);
+            let r = vmull_n_s16(v, V_R as i16);
+            let g = vmlal_n_s16(vmull_n_s16(u, -U_G as i16), v, -V_G as i16);
+            let b = vmull_n_s16(u, U_B as i16);
+            // Each chroma sample covers two pixels of the row
+            let spread = |c: int32x4_t| (vzip1q_s32(c, c), vzip2q_s32(c, c));
+            let channels = [spread(b), spread(g), spread(r)];
+
+            for row in rows.clone() {
+                let x = block * 8;
+                let at = row * stride_y + x;
+                let luma = vmovl_u8(vld1_u8(p.y[at..at + 8].as_ptr()));
+                let widen = |half: uint16x4_t| {
+                    let y = vreinterpretq_s32_u32(vmovl_u16(half));
+                    vaddq_s32(vshlq_n_s32::<SHIFT>(y), round)
+                };
+                let (y_lo, y_hi) = (widen(vget_low_u16(luma)), widen(vget_high_u16(luma)));
+                let [b, g, r] = channels.map(|(lo, hi)| {
+                    let lo = vqshrun_n_s32::<SHIFT>(vaddq_s32(y_lo, lo));
+                    let hi = vqshrun_n_s32::<SHIFT>(vaddq_s32(y_hi, hi));
+                    vqmovn_u16(vcombine_u16(lo, hi))
+                });
+
+                let start = (row * p.width + x) * bpp;
+                let out = buf[start..start + 8 * bpp].as_mut_ptr();
+                if bpp == 4 {
+                    vst4_u8(out, uint8x8x4_t(b, g, r, vdup_n_u8(255)));
+                } else {
+                    vst3_u8(out, uint8x8x3_t(b, g, r));
+                }
+            }
+        }
+        blocks * 4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Straight per pixel, every pixel looks up its own chroma sample
+    fn reference(p: &Planes<'_>, bpp: usize) -> Vec<u8> {
+        let clamp = |v: i32| (v >> SHIFT).clamp(0, 255) as u8;
+        let (stride_y, stride_u, stride_v) = p.strides;
+        let mut out = vec![0u8; p.width * p.height * bpp];
+        for row in 0..p.height {
+            for x in 0..p.width {
+                let y = ((p.y[row * stride_y + x] as i32) << SHIFT) + ROUND;
+                let u = p.u[row / 2 * stride_u + x / 2] as i32 - 128;
+                let v = p.v[row / 2 * stride_v + x / 2] as i32 - 128;
+                let at = (row * p.width + x) * bpp;
+                out[at] = clamp(y + U_B * u);
+                out[at + 1] = clamp(y - U_G * u - V_G * v);
+                out[at + 2] = clamp(y + V_R * v);
+                if bpp == 4 {
+                    out[at + 3] = 255;
+                }
+            }
+        }
+        out
+    }
+
+    // Deterministic noise covering the whole sample range, saturated values included
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    struct Picture {
+        y: Vec<u8>,
+        u: Vec<u8>,
+        v: Vec<u8>,
+        width: usize,
+        height: usize,
+        strides: (usize, usize, usize),
+    }
+
+    impl Picture {
+        // Strides padded past the width like decoders do
+        fn new(width: usize, height: usize, seed: u32) -> Self {
+            let strides = (width + 13, width.div_ceil(2) + 5, width.div_ceil(2) + 7);
+            let chroma_rows = height.div_ceil(2);
+            Self {
+                y: noise(strides.0 * height, seed),
+                u: noise(strides.1 * chroma_rows, seed + 1),
+                v: noise(strides.2 * chroma_rows, seed + 2),
+                width,
+                height,
+                strides,
+            }
+        }
+
+        fn planes(&self) -> Planes<'_> {
+            Planes {
+                y: &self.y,
+                u: &self.u,
+                v: &self.v,
+                width: self.width,
+                height: self.height,
+                strides: self.strides,
+            }
+        }
+    }
+
+    #[test]
+    fn matches_reference() {
+        // Odd sizes leave a scalar tail after the 8 pixel blocks, and a last row of its own
+        for (width, height) in [(16, 16), (17, 9), (1, 1), (2, 3), (7, 5), (33, 2), (64, 31)] {
+            let picture = Picture::new(width, height, (width * 31 + height) as u32);
+            for bpp in [3, 4] {
+                let mut buf = vec![0u8; width * height * bpp];
+                write_bgr(&picture.planes(), &mut buf, bpp);
+                assert_eq!(
+                    buf,
+                    reference(&picture.planes(), bpp),
+                    "{}x{}, {} bytes per pixel",
+                    width,
+                    height,
+                    bpp
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn known_colors() {
+        // (y, u, v) to (b, g, r)
+        let cases = [
+            ((0, 128, 128), (0, 0, 0)),
+            ((255, 128, 128), (255, 255, 255)),
+            ((128, 128, 128), (128, 128, 128)),
+            // Saturated chroma clamps instead of wrapping around
+            ((255, 255, 255), (255, 134, 255)),
+            ((0, 0, 0), (0, 134, 0)),
+            ((0, 255, 0), (255, 22, 0)),
+            ((255, 0, 255), (33, 233, 255)),
+        ];
+        for ((y, u, v), bgr) in cases {
+            let planes = Planes {
+                y: &[y; 16],
+                u: &[u; 8],
+                v: &[v; 8],
+                width: 8,
+                height: 2,
+                strides: (8, 4, 4),
+            };
+            let mut buf = vec![0u8; 8 * 2 * 3];
+            write_bgr(&planes, &mut buf, 3);
+            for pixel in buf.chunks_exact(3) {
+                assert_eq!((pixel[0], pixel[1], pixel[2]), bgr, "yuv {:?}", (y, u, v));
+            }
+        }
+    }
+
+    #[test]
+    fn close_to_float_bt601() {
+        let picture = Picture::new(24, 6, 7);
+        let p = picture.planes();
+        let mut buf = vec![0u8; p.width * p.height * 3];
+        write_bgr(&p, &mut buf, 3);
+        for row in 0..p.height {
+            for x in 0..p.width {
+                let y = p.y[row * p.strides.0 + x] as f64;
+                let u = p.u[row / 2 * p.strides.1 + x / 2] as f64 - 128.0;
+                let v = p.v[row / 2 * p.strides.2 + x / 2] as f64 - 128.0;
+                let expected = [
+                    y + 1.772 * u,
+                    y - 0.344_136 * u - 0.714_136 * v,
+                    y + 1.402 * v,
+                ];
+                let at = (row * p.width + x) * 3;
+                for (got, want) in buf[at..at + 3].iter().zip(expected) {
+                    let want = want.round().clamp(0.0, 255.0);
+                    assert!((*got as f64 - want).abs() <= 1.0, "{} vs {}", got, want);
+                }
+            }
+        }
+    }
+}
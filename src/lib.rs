@@ -1,2 +1,6 @@
+pub mod analysis;
 pub mod camera;
+pub mod capture;
 pub mod decoders;
+pub mod pool;
+pub mod prelude;
@@ -1,100 +1,307 @@
-use std::{
-    env::args,
-    time::{Duration, Instant},
+use std::{error::Error, path::PathBuf, process::ExitCode, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rtsp_lib::{
+    camera::{
+        connect::{DecoderChoice, ProfileChoice},
+        rtsp_session::{probe, TransportPreference},
+    },
+    capture::{FrameSink, SinkEncoding},
+    prelude::*,
 };
+use tracing_subscriber::EnvFilter;
+use url::Url;
 
-mod camera;
-mod decoders;
+type BoxError = Box<dyn Error + Send + Sync>;
 
-use camera::{
-    onvif::{services, OnvifHelper},
-    rtsp_session::{SessionError, SessionWrapper},
-};
-use decoders::{AVCCDecoder, Chain, H264RGBDecoder};
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Grabs frames from an ONVIF camera, or straight from an RTSP url"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    camera: CameraArgs,
+    #[arg(long, value_enum, default_value_t = DecoderKind::Rgb)]
+    decoder: DecoderKind,
+    #[arg(long, value_enum, default_value_t = TransportKind::Tcp)]
+    transport: TransportKind,
+    #[arg(long, default_value_t = 1000, help = "Time between two captures")]
+    interval_ms: u64,
+    #[arg(
+        long,
+        help = "Stop after this many frames, runs until Ctrl-C otherwise"
+    )]
+    count: Option<u64>,
+    #[arg(
+        long,
+        help = "Write the frames under this directory, as png for rgb, jpeg for bgr and raw bytes for gray"
+    )]
+    output: Option<PathBuf>,
+}
 
-#[tokio::main]
-async fn main() {
-    let mut args = args().skip(1);
-    let ip = args.next().expect("Ip not found");
-    let (user, password) = (
-        args.next().expect("Credentials not inputted"),
-        args.next().expect("Credentials not inputted"),
-    );
-
-    let mut onvif_client = OnvifHelper::new(&ip)
-        .expect("Failed to create OnvifHelper")
-        .with_credentials(&user, &password);
-
-    let media_cli = onvif_client
-        .get_service::<services::MediaClient>()
-        .await
-        .expect("Couldn't fetch media client");
+#[derive(Args)]
+struct CameraArgs {
+    #[arg(long, global = true)]
+    ip: Option<String>,
+    #[arg(long, global = true)]
+    user: Option<String>,
+    #[arg(long, global = true)]
+    password: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "ip",
+        help = "Skip ONVIF and connect to this url"
+    )]
+    rtsp_url: Option<Url>,
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "profile_name",
+        help = "Position in the profile list, see list-profiles"
+    )]
+    profile_index: Option<usize>,
+    #[arg(long, global = true, help = "Profile name or token")]
+    profile_name: Option<String>,
+}
 
-    let (res, media_cli) = {
-        let mut profiles = media_cli
-            .get_profiles()
-            .await
-            .expect("Failed to get profiles");
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Print the media profiles of the camera and exit")]
+    ListProfiles,
+    #[command(about = "Print the streams the RTSP url offers and exit")]
+    Probe,
+    #[command(about = "Go through every frame in order, reporting the ones missed")]
+    Follow,
+}
 
-        if profiles.len() == 0 {
-            panic!("Empty profiles list");
+#[derive(Clone, Copy, ValueEnum)]
+enum DecoderKind {
+    Rgb,
+    Bgr,
+    Gray,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TransportKind {
+    Tcp,
+    Udp,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            let mut source = e.source();
+            while let Some(e) = source {
+                eprintln!("  caused by: {}", e);
+                source = e.source();
+            }
+            ExitCode::FAILURE
         }
+    }
+}
 
-        let first_profile = profiles.remove(0);
-        let token = first_profile.token;
-        if let Some(conf) = first_profile.video_encoder_configuration {
-            (
-                (
-                    conf.resolution.width as usize,
-                    conf.resolution.height as usize,
-                ),
-                media_cli.with_token(token),
-            )
-        } else {
-            panic!("Video encoder configuration is missing");
+async fn run(cli: Cli) -> Result<(), BoxError> {
+    match cli.command {
+        Some(Command::ListProfiles) => list_profiles(&cli.camera).await,
+        Some(Command::Probe) => {
+            let url = resolve_stream(&cli.camera).await?;
+            println!("Streams of {}:", url);
+            for stream in probe(url, credentials(&cli.camera)).await? {
+                println!(
+                    "{}: {} {} at {}Hz, {}",
+                    stream.index,
+                    stream.media,
+                    stream.encoding,
+                    stream.clock_rate,
+                    stream
+                        .dimensions
+                        .map_or("size unknown".to_string(), |(w, h)| format!("{}x{}", w, h))
+                );
+            }
+            Ok(())
         }
+        Some(Command::Follow) => follow(&cli).await,
+        None => capture(&cli).await,
+    }
+}
+
+// The camera of --ip, with the profile picked on the command line
+fn camera_config(camera: &CameraArgs) -> Result<CameraConfig, BoxError> {
+    let ip = camera
+        .ip
+        .as_deref()
+        .ok_or("either --ip or --rtsp-url is needed")?;
+    let profile = match (camera.profile_index, &camera.profile_name) {
+        (Some(index), _) => ProfileChoice::Index(index),
+        (None, Some(name)) => ProfileChoice::Name(name.clone()),
+        (None, None) => ProfileChoice::First,
     };
+    let mut config = CameraConfig::new(ip).with_profile(profile);
+    if let Some((user, password)) = credentials(camera) {
+        config = config.with_credentials(user, password);
+    }
+    Ok(config)
+}
 
-    println!("Fetching stream url...");
-    let stream_url = media_cli
-        .get_stream_uri()
-        .await
-        .expect("Stream url unavailable");
+async fn list_profiles(camera: &CameraArgs) -> Result<(), BoxError> {
+    let media = camera_config(camera)?.onvif()?.get_media().await?;
+    for (index, profile) in media.get_profile_summaries().await?.iter().enumerate() {
+        println!("{}: {}", index, profile);
+    }
+    Ok(())
+}
 
-    let decoder = Box::new(
-        AVCCDecoder::new()
-            .chain(H264RGBDecoder::new(true, res).expect("Failed to create h264 decoder")),
-    );
+async fn resolve_stream(camera: &CameraArgs) -> Result<Url, BoxError> {
+    if let Some(url) = &camera.rtsp_url {
+        return Ok(url.clone());
+    }
+    let (profile, url, _) = camera_config(camera)?.resolve_stream().await?;
+    println!("Using profile {}", profile);
+    Ok(url)
+}
+
+fn credentials(camera: &CameraArgs) -> Option<(&str, &str)> {
+    Some((camera.user.as_deref()?, camera.password.as_deref()?))
+}
 
-    let mut session = SessionWrapper::new(stream_url, decoder).start().await;
-    let instance = session
-        .request_instance()
+async fn start_session(cli: &Cli) -> Result<(SessionInstanceManager, SinkEncoding), BoxError> {
+    let (decoder, encoding) = match cli.decoder {
+        DecoderKind::Rgb => (DecoderChoice::Rgb, SinkEncoding::Png),
+        DecoderKind::Bgr => (DecoderChoice::Bgr, SinkEncoding::Jpeg(90)),
+        DecoderKind::Gray => (DecoderChoice::Gray, SinkEncoding::Raw),
+    };
+    let transport = match cli.transport {
+        TransportKind::Tcp => TransportPreference::Tcp,
+        TransportKind::Udp => TransportPreference::Udp,
+    };
+
+    let Some(url) = &cli.camera.rtsp_url else {
+        let config = camera_config(&cli.camera)?
+            .with_transport(transport)
+            .with_decoder(decoder);
+        println!("Connecting to {}...", config.host);
+        let camera = ConnectedCamera::connect(&config).await?;
+        println!(
+            "Using profile {}, streaming {}",
+            camera.profile, camera.stream_url
+        );
+        return Ok((camera.manager, encoding));
+    };
+    let config = SessionConfig::builder().with_transport(transport).build()?;
+    println!("Connecting to {}...", url);
+    let mut session = SessionWrapper::new_sync(url.clone(), decoder.build()?).with_config(config);
+    if let Some((user, password)) = credentials(&cli.camera) {
+        session = session.with_credentials(user, password);
+    }
+    Ok((session.start().await?, encoding))
+}
+
+async fn capture(cli: &Cli) -> Result<(), BoxError> {
+    let (mut manager, encoding) = start_session(cli).await?;
+    manager.wait_ready(Duration::from_secs(10)).await?;
+    let instance = manager.request_instance().await?;
+
+    let timelapse = TimeLapse::new(instance, Duration::from_millis(cli.interval_ms));
+    let handle = match &cli.output {
+        Some(dir) => timelapse.start(FrameSink::new(dir).with_encoding(encoding)),
+        None => timelapse.start_with(Box::new(|f| {
+            println!(
+                "Frame {} of generation {}, {}x{}",
+                f.index(),
+                f.generation(),
+                f.width(),
+                f.height()
+            );
+            Ok(())
+        })),
+    };
+
+    let mut events = handle.events();
+    let mut captured = 0;
+    while cli.count.map_or(true, |count| captured < count) {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        match event {
+            Ok(TimeLapseEvent::Captured { path, elapsed, .. }) => {
+                captured += 1;
+                match path {
+                    Some(path) => {
+                        println!("Saved {} in {} ms", path.display(), elapsed.as_millis())
+                    }
+                    None => println!("Captured in {} ms", elapsed.as_millis()),
+                }
+            }
+            Ok(TimeLapseEvent::Skipped(ticks)) => println!("Fell behind, skipped {} ticks", ticks),
+            Ok(TimeLapseEvent::RequestFailed(e)) => eprintln!("Request failed: {}", e),
+            Ok(TimeLapseEvent::SinkFailed(e)) => eprintln!("Failed to save the frame: {}", e),
+            Err(_) => break,
+        }
+    }
+
+    if !manager
+        .shutdown_with(vec![Box::new(handle)], Duration::from_secs(5))
         .await
-        .expect("Couldn't fetch session instance");
-
-    loop {
-        let b = Instant::now();
-        let _abc = match instance.request_image().await {
-            Ok(f) => Ok(f),
-            Err(SessionError::OldFrame) => {
-                media_cli
-                    .sync_iframe()
-                    .await
-                    .expect("Failed to sync iframes");
-                Err(SessionError::OldFrame)
+    {
+        eprintln!("The time-lapse or the session didn't shut down in time and was aborted");
+    }
+    Ok(())
+}
+
+// Asks for the frame after the last one seen, picking up after the oldest kept one when it
+// fell behind
+async fn follow(cli: &Cli) -> Result<(), BoxError> {
+    let (mut manager, encoding) = start_session(cli).await?;
+    let instance = manager.request_instance().await?;
+    let mut sink = cli
+        .output
+        .as_ref()
+        .map(|dir| FrameSink::new(dir).with_encoding(encoding));
+
+    let mut seq = 0;
+    let mut seen = 0;
+    while cli.count.map_or(true, |count| seen < count) {
+        let res = tokio::select! {
+            res = instance.request_image(FrameRequest::after(seq).wait_for_frame(true)) => res,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        match res {
+            Ok(frame) => {
+                seen += 1;
+                seq = frame.seq();
+                match &mut sink {
+                    Some(sink) => match sink.write_async(&frame).await {
+                        Ok(path) => println!("Saved frame {} to {}", seq, path.display()),
+                        Err(e) => eprintln!("Failed to save frame {}: {}", seq, e),
+                    },
+                    None => println!("Frame {}, {}x{}", seq, frame.width(), frame.height()),
+                }
+            }
+            Err(SessionError::FrameEvicted { oldest_available }) => {
+                println!("Missed frames {} to {}", seq + 1, oldest_available - 1);
+                seq = oldest_available - 1;
             }
             Err(e) => {
-                println!("Unexpected Error => {:?}", e);
-                Err(e)
+                eprintln!("Request failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(cli.interval_ms)).await;
             }
-        };
-        println!(
-            "Captured -> {:?} in {} ms",
-            _abc.as_ref().err(),
-            Instant::now().duration_since(b).as_millis()
-        );
-        if !_abc.is_err() {
-            tokio::time::sleep(Duration::from_millis(100)).await;
         }
     }
+
+    if !manager.shutdown(Duration::from_secs(5)).await {
+        eprintln!("The session didn't shut down in time and was aborted");
+    }
+    Ok(())
 }
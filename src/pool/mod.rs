@@ -0,0 +1,275 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use futures::future::join_all;
+use tracing::{info, warn};
+
+pub use crate::camera::connect::{CameraConfig, DecoderFactory, ProfileChoice};
+use crate::camera::{
+    connect::{CameraError, ConnectedCamera},
+    rtsp_session::{
+        FrameRequest, FrameResponse, SessionError, SessionInstance, SessionInstanceManager,
+        SessionStats,
+    },
+};
+
+#[derive(Debug)]
+pub enum PoolError {
+    Camera(CameraError),
+    Session(SessionError),
+    UnknownCamera(String),
+    DuplicateCamera(String),
+    // The camera failed to start or its session gave up, see `CameraPool::restart`
+    NotRunning(String),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Camera(e) => write!(f, "camera setup failed: {}", e),
+            Self::Session(e) => write!(f, "session failed: {}", e),
+            Self::UnknownCamera(id) => write!(f, "no camera {} in the pool", id),
+            Self::DuplicateCamera(id) => write!(f, "camera {} is already in the pool", id),
+            Self::NotRunning(id) => write!(f, "camera {} is not running", id),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Camera(e) => Some(e),
+            Self::Session(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<CameraError> for PoolError {
+    fn from(e: CameraError) -> Self {
+        Self::Camera(e)
+    }
+}
+
+impl From<SessionError> for PoolError {
+    fn from(e: SessionError) -> Self {
+        Self::Session(e)
+    }
+}
+
+// Starts the camera once ONVIF and the session are up, with an instance to request frames on
+async fn start(config: &CameraConfig) -> Result<Running, PoolError> {
+    let mut camera = ConnectedCamera::connect(config).await?;
+    let instance = camera.manager.request_instance().await?;
+    Ok(Running { camera, instance })
+}
+
+struct Running {
+    camera: ConnectedCamera,
+    instance: SessionInstance,
+}
+
+struct Camera {
+    config: CameraConfig,
+    state: Result<Running, String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CameraStatus {
+    Running(SessionStats),
+    // The session gave up reconnecting, `max_retries` ran out
+    Stopped,
+    Failed(String),
+}
+
+// Totals over the running cameras
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolSummary {
+    pub running: usize,
+    pub failed: usize,
+    pub frames_received: u64,
+    pub bytes_received: u64,
+    pub frames_dropped: u64,
+    pub reconnects: u64,
+}
+
+// Runs a session per camera. Cameras start, fail and restart independently, one that can't be
+// reached is kept as failed while the others run.
+pub struct CameraPool {
+    cameras: HashMap<String, Camera>,
+}
+
+impl CameraPool {
+    // Starts every camera concurrently, never fails. See `status` for those that didn't start.
+    pub async fn start(cameras: Vec<(String, CameraConfig)>) -> Self {
+        let mut pool = Self {
+            cameras: HashMap::new(),
+        };
+        let mut ids = HashSet::new();
+        let cameras: Vec<_> = cameras
+            .into_iter()
+            .filter(|(id, _)| {
+                let unique = ids.insert(id.clone());
+                if !unique {
+                    warn!(camera = %id, "Skipping a camera with a duplicate id");
+                }
+                unique
+            })
+            .collect();
+        let started = join_all(cameras.iter().map(|(_, c)| start(c))).await;
+        for ((id, config), state) in cameras.into_iter().zip(started) {
+            let _ = pool.insert(id, config, state);
+        }
+        pool
+    }
+
+    // The camera is kept even if it fails to start, so `restart` can retry it
+    pub async fn add(&mut self, id: &str, config: CameraConfig) -> Result<(), PoolError> {
+        if self.cameras.contains_key(id) {
+            return Err(PoolError::DuplicateCamera(id.to_string()));
+        }
+        let started = start(&config).await;
+        self.insert(id.to_string(), config, started)
+    }
+
+    // Closes the session of the camera and forgets it
+    pub async fn remove(&mut self, id: &str) -> Result<(), PoolError> {
+        let camera = self
+            .cameras
+            .remove(id)
+            .ok_or_else(|| PoolError::UnknownCamera(id.to_string()))?;
+        if let Ok(running) = camera.state {
+            running.camera.manager.close().await;
+        }
+        info!(camera = %id, "Camera removed from the pool");
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&SessionInstanceManager> {
+        self.cameras
+            .get(id)
+            .and_then(|c| c.state.as_ref().ok())
+            .map(|r| &r.camera.manager)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.cameras.keys().map(String::as_str)
+    }
+
+    // Closes the session if there is one and starts the camera again
+    pub async fn restart(&mut self, id: &str) -> Result<(), PoolError> {
+        let camera = self
+            .cameras
+            .remove(id)
+            .ok_or_else(|| PoolError::UnknownCamera(id.to_string()))?;
+        if let Ok(running) = camera.state {
+            running.camera.manager.close().await;
+        }
+        self.add(id, camera.config).await
+    }
+
+    // Restarts the cameras that failed to start or whose session stopped, concurrently. Returns
+    // how many are running again.
+    pub async fn restart_failed(&mut self) -> usize {
+        let ids: Vec<String> = self
+            .cameras
+            .iter()
+            .filter(|(_, c)| {
+                !c.state
+                    .as_ref()
+                    .is_ok_and(|r| r.camera.manager.is_running())
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut cameras = Vec::new();
+        for id in ids {
+            if let Some(camera) = self.cameras.remove(&id) {
+                if let Ok(running) = camera.state {
+                    running.camera.manager.close().await;
+                }
+                cameras.push((id, camera.config));
+            }
+        }
+        let started = join_all(cameras.iter().map(|(_, c)| start(c))).await;
+        let mut restarted = 0;
+        for ((id, config), state) in cameras.into_iter().zip(started) {
+            restarted += usize::from(self.insert(id, config, state).is_ok());
+        }
+        restarted
+    }
+
+    pub fn status(&self) -> HashMap<String, CameraStatus> {
+        self.cameras
+            .iter()
+            .map(|(id, c)| {
+                let status = match &c.state {
+                    Ok(r) if r.camera.manager.is_running() => {
+                        CameraStatus::Running(r.camera.manager.stats())
+                    }
+                    Ok(_) => CameraStatus::Stopped,
+                    Err(e) => CameraStatus::Failed(e.clone()),
+                };
+                (id.clone(), status)
+            })
+            .collect()
+    }
+
+    pub fn summary(&self) -> PoolSummary {
+        let mut summary = PoolSummary::default();
+        for status in self.status().into_values() {
+            match status {
+                CameraStatus::Running(stats) => {
+                    summary.running += 1;
+                    summary.frames_received += stats.frames_received;
+                    summary.bytes_received += stats.bytes_received;
+                    summary.frames_dropped += stats.frames_dropped;
+                    summary.reconnects += stats.reconnects;
+                }
+                CameraStatus::Stopped | CameraStatus::Failed(_) => summary.failed += 1,
+            }
+        }
+        summary
+    }
+
+    // Requests the latest frame of every camera at once, cameras that aren't running get
+    // `NotRunning`
+    pub async fn request_all_latest(&self) -> HashMap<String, Result<FrameResponse, PoolError>> {
+        let requests = self.cameras.iter().map(|(id, c)| async move {
+            let res = match &c.state {
+                Ok(r) => {
+                    let generation = r.instance.buffer_state().generation;
+                    r.instance
+                        .request_image(FrameRequest::latest().with_generation(generation))
+                        .await
+                        .map_err(PoolError::from)
+                }
+                Err(_) => Err(PoolError::NotRunning(id.clone())),
+            };
+            (id.clone(), res)
+        });
+        join_all(requests).await.into_iter().collect()
+    }
+
+    // Failed cameras are stored too, the error is handed back
+    fn insert(
+        &mut self,
+        id: String,
+        config: CameraConfig,
+        started: Result<Running, PoolError>,
+    ) -> Result<(), PoolError> {
+        let (state, res) = match started {
+            Ok(running) => {
+                info!(camera = %id, "Camera started");
+                (Ok(running), Ok(()))
+            }
+            Err(e) => {
+                warn!(camera = %id, "Camera failed to start: {}", e);
+                (Err(e.to_string()), Err(e))
+            }
+        };
+        self.cameras.insert(id, Camera { config, state });
+        res
+    }
+}
@@ -0,0 +1,23 @@
+// What most uses of the crate need, `use rtsp_lib::prelude::*`
+pub use crate::{
+    camera::{
+        connect::{CameraConfig, ConnectedCamera},
+        onvif::{
+            discovery::Discovery,
+            services::{MediaClient, ProfileSummary},
+            OnvifError, OnvifHelper,
+        },
+        rtsp_session::{
+            utils::SessionUrlBuilder, BatchRequest, BatchResponse, BufferPolicy, FrameBroadcast,
+            FrameRequest, FrameResponse, FrameStream, SessionConfig, SessionError, SessionEvent,
+            SessionInstance, SessionInstanceManager, SessionStats, SessionWrapper,
+        },
+    },
+    capture::{TimeLapse, TimeLapseEvent},
+    decoders::{
+        AVCCDecoder, AsyncChain, AsyncImageDecoder, Chain, DecoderError, FrameFormat,
+        H264BGRDecoder, H264GrayDecoder, H264RGBDecoder, H264YUVDecoder, ImageDecoder,
+        MJPEGDecoder, PixelFormat,
+    },
+    pool::CameraPool,
+};